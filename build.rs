@@ -0,0 +1,60 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Embed build metadata for the /version endpoint
+fn main() {
+    // Docker builds don't see the .git directory, so allow the commit to be passed in
+    let git_commit = env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string())
+    });
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", git_commit.unwrap_or_else(|| "unknown".to_string()));
+
+    // When this script last ran, which is on a new commit, checkout, migration
+    // or GIT_COMMIT rather than on every build (see the rerun-if lines below)
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version);
+
+    // Cargo exposes each enabled feature as CARGO_FEATURE_<NAME>
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+
+    // Migrations are embedded by sqlx::migrate!, so new files must trigger a rebuild
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    // HEAD changes on checkout; the branch it points at changes on commit,
+    // as a loose ref or, after `git pack-refs`, in packed-refs. Watching the
+    // refs directory alone misses commits, as its mtime only moves when
+    // entries are added or removed. Cargo treats a missing file as always
+    // changed, so packed-refs is only named when it exists.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if std::path::Path::new(".git/packed-refs").exists() {
+        println!("cargo:rerun-if-changed=.git/packed-refs");
+    }
+    let head = std::fs::read_to_string(".git/HEAD").unwrap_or_default();
+    if let Some(branch) = head.trim().strip_prefix("ref: ") {
+        println!("cargo:rerun-if-changed=.git/{}", branch);
+    }
+}
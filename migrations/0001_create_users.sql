@@ -0,0 +1,16 @@
+-- Users table shared by the SQL backends. Only types and functions both
+-- PostgreSQL and SQLite understand are used. UUIDv7 IDs sort by creation,
+-- so ordering by id gives insertion order. IF NOT EXISTS lets databases
+-- created before migrations existed adopt this one.
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY,
+    tenant TEXT NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    status TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    password_hash TEXT
+);
+
+CREATE INDEX IF NOT EXISTS users_tenant_email ON users (tenant, lower(email));
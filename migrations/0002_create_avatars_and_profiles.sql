@@ -0,0 +1,16 @@
+-- Avatars and profiles, one row per user and removed along with it. Avatar
+-- images are stored base64-encoded, as the two databases share no binary
+-- column type.
+CREATE TABLE IF NOT EXISTS avatars (
+    id UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    content_type TEXT NOT NULL,
+    data TEXT NOT NULL,
+    etag TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS profiles (
+    id UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    bio TEXT NOT NULL,
+    locale TEXT NOT NULL,
+    timezone TEXT NOT NULL
+);
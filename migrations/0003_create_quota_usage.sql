@@ -0,0 +1,10 @@
+-- Mutations per identity and day, for DAILY_MUTATION_QUOTA. Days are
+-- ISO 8601 dates, which sort as text; earlier days are dropped as an
+-- identity's next day starts.
+CREATE TABLE IF NOT EXISTS quota_usage (
+    tenant TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    day TEXT NOT NULL,
+    used BIGINT NOT NULL,
+    PRIMARY KEY (tenant, subject, day)
+);
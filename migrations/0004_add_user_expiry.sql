@@ -0,0 +1,5 @@
+-- Optional lifetime for demo users; the expiry reaper deletes rows once
+-- expires_at has passed
+ALTER TABLE users ADD COLUMN expires_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS users_tenant_expires_at ON users (tenant, expires_at);
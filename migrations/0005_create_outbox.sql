@@ -0,0 +1,12 @@
+-- Webhook events written in the same transaction as the user change they
+-- announce (OUTBOX_ENABLED). The relay deletes rows once delivered; UUIDv7
+-- IDs keep them in the order they were written.
+CREATE TABLE IF NOT EXISTS outbox (
+    id UUID PRIMARY KEY,
+    tenant TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS outbox_tenant_id ON outbox (tenant, id);
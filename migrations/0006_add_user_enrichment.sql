@@ -0,0 +1,3 @@
+-- What the enrichment service answered when the user was created, as JSON
+-- text so both databases store it the same way
+ALTER TABLE users ADD COLUMN enrichment TEXT;
@@ -0,0 +1,35 @@
+use listenfd::ListenFd;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+
+// A listening socket passed in by whatever started the server: systemd
+// socket activation, or systemfd during development
+// (`systemfd --no-pid -s http::8080 -- cargo watch -x run`). The supervisor
+// keeps the socket open across restarts, so connections arriving while the
+// server restarts wait in its backlog instead of being refused.
+pub enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+// Take every socket named by LISTEN_FDS. This clears LISTEN_FDS and
+// LISTEN_PID from the environment, so it runs at the start of main, before
+// any other thread could be reading the environment. No sockets means the
+// server binds its own.
+pub fn take_listeners() -> std::io::Result<Vec<Inherited>> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::with_capacity(fds.len());
+    for index in 0..fds.len() {
+        // A socket of the wrong kind stays in place, so the second take sees it
+        let listener = match fds.take_tcp_listener(index) {
+            Ok(Some(listener)) => Inherited::Tcp(listener),
+            Ok(None) => continue,
+            Err(_) => match fds.take_unix_listener(index)? {
+                Some(listener) => Inherited::Unix(listener),
+                None => continue,
+            },
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
@@ -0,0 +1,337 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{get, post, put, web, Error, HttpMessage, HttpResponse, Responder};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use opentelemetry_sdk::trace::TracerProvider;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{info, instrument, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+// Credentials accepted by the admin scope. Either a bearer token, HTTP basic
+// credentials, or both may be configured; with neither, every request is refused.
+#[derive(Clone)]
+pub struct AdminAuth {
+    pub token: Option<String>,
+    pub basic: Option<(String, String)>,
+}
+
+impl AdminAuth {
+    pub fn is_configured(&self) -> bool {
+        self.token.is_some() || self.basic.is_some()
+    }
+
+    // Returns the identity to record in the audit log when the header is valid
+    fn authenticate(&self, authorization: &str) -> Option<String> {
+        if let (Some(expected), Some(token)) = (&self.token, authorization.strip_prefix("Bearer ")) {
+            if constant_time_eq(expected.as_bytes(), token.trim().as_bytes()) {
+                return Some("token".to_string());
+            }
+        }
+
+        if let (Some((user, password)), Some(encoded)) = (&self.basic, authorization.strip_prefix("Basic ")) {
+            let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (given_user, given_password) = decoded.split_once(':')?;
+            // Evaluate both comparisons so timing doesn't reveal which one failed
+            let user_ok = constant_time_eq(user.as_bytes(), given_user.as_bytes());
+            let password_ok = constant_time_eq(password.as_bytes(), given_password.as_bytes());
+            if user_ok & password_ok {
+                return Some(given_user.to_string());
+            }
+        }
+
+        None
+    }
+}
+
+// Compare secrets without short-circuiting on the first differing byte
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Authenticated admin identity, stored in request extensions by the middleware
+#[derive(Clone)]
+pub struct AdminIdentity(pub String);
+
+// Middleware protecting the /admin scope
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let auth = req.app_data::<web::Data<AdminAuth>>().cloned();
+    let identity = auth.as_ref().and_then(|auth| {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| auth.authenticate(value))
+    });
+
+    match identity {
+        Some(identity) => {
+            req.extensions_mut().insert(AdminIdentity(identity));
+            next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+        }
+        None => {
+            let client = crate::client_ip::client_ip(req.request()).map(|ip| ip.to_string()).unwrap_or_default();
+            warn!(path = %req.path(), client = %client, "Rejected unauthenticated admin request");
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"admin\", Bearer"))
+                .body("Admin credentials required");
+            Ok(req.into_response(response))
+        }
+    }
+}
+
+// One recorded admin action
+#[derive(Clone, Serialize)]
+pub struct AuditEntry {
+    timestamp: DateTime<Utc>,
+    actor: String,
+    action: String,
+    detail: String,
+}
+
+// Bounded in-memory log of admin actions, newest last
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, actor: &str, action: &str, detail: impl Into<String>) {
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            detail: detail.into(),
+        };
+        info!(actor = %entry.actor, action = %entry.action, detail = %entry.detail, "Admin action");
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+    }
+
+    fn entries(&self) -> Vec<AuditEntry> {
+        self.entries
+            .lock()
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// While enabled, public routes answer 503 and only /admin stays usable
+pub struct MaintenanceMode {
+    enabled: AtomicBool,
+}
+
+impl MaintenanceMode {
+    pub fn new() -> Self {
+        MaintenanceMode { enabled: AtomicBool::new(false) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+// Middleware for the public scope that honours maintenance mode
+pub async fn maintenance_gate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let enabled = req
+        .app_data::<web::Data<MaintenanceMode>>()
+        .map(|mode| mode.is_enabled())
+        .unwrap_or(false);
+
+    if enabled {
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "60"))
+            .body("Service is under maintenance");
+        return Ok(req.into_response(response));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+// Runtime-adjustable log filter, built on a tracing-subscriber reload layer
+pub struct LogLevelControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    current: Mutex<String>,
+}
+
+impl LogLevelControl {
+    pub fn new(handle: reload::Handle<EnvFilter, Registry>, initial: &str) -> Self {
+        LogLevelControl {
+            handle,
+            current: Mutex::new(initial.to_string()),
+        }
+    }
+
+    // Swap in a filter already parsed from `directive`
+    pub fn apply(&self, filter: EnvFilter, directive: &str) -> Result<(), String> {
+        self.handle.reload(filter).map_err(|err| err.to_string())?;
+        if let Ok(mut current) = self.current.lock() {
+            *current = directive.to_string();
+        }
+        Ok(())
+    }
+}
+
+// SDK tracer provider, kept so spans can be flushed on demand
+pub struct TelemetryControl {
+    pub provider: Option<TracerProvider>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    directive: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MaintenanceState {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct FlushResult {
+    flushed: bool,
+    errors: Vec<String>,
+}
+
+// Handler for POST /admin/flush
+#[post("/flush")]
+#[instrument(name = "admin_flush_handler", skip_all, fields(service = "actix_example"))]
+async fn flush(
+    identity: web::ReqData<AdminIdentity>,
+    telemetry: web::Data<TelemetryControl>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let provider = match telemetry.provider.clone() {
+        Some(provider) => provider,
+        None => {
+            return HttpResponse::ServiceUnavailable().body("Telemetry exporter is not installed");
+        }
+    };
+
+    // force_flush blocks until the exporter answers, so keep it off the worker
+    let results = match web::block(move || provider.force_flush()).await {
+        Ok(results) => results,
+        Err(_) => return HttpResponse::InternalServerError().body("Flush task failed"),
+    };
+    let errors: Vec<String> = results
+        .into_iter()
+        .filter_map(|result| result.err().map(|err| err.to_string()))
+        .collect();
+
+    audit.record(&identity.0, "flush", format!("{} error(s)", errors.len()));
+    HttpResponse::Ok().json(FlushResult { flushed: errors.is_empty(), errors })
+}
+
+// Handler for GET /admin/log-level
+#[get("/log-level")]
+async fn get_log_level(control: web::Data<LogLevelControl>) -> impl Responder {
+    let directive = control.current.lock().map(|current| current.clone()).unwrap_or_default();
+    HttpResponse::Ok().json(LogLevel { directive })
+}
+
+// Handler for PUT /admin/log-level
+#[put("/log-level")]
+#[instrument(name = "admin_set_log_level_handler", skip_all, fields(service = "actix_example"))]
+async fn set_log_level(
+    identity: web::ReqData<AdminIdentity>,
+    body: web::Json<LogLevel>,
+    control: web::Data<LogLevelControl>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let filter = match EnvFilter::try_new(&body.directive) {
+        Ok(filter) => filter,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid log directive: {}", err)),
+    };
+
+    if let Err(err) = control.apply(filter, &body.directive) {
+        return HttpResponse::InternalServerError().body(format!("Failed to apply log level: {}", err));
+    }
+
+    audit.record(&identity.0, "set_log_level", body.directive.clone());
+    HttpResponse::Ok().json(body.into_inner())
+}
+
+// Handler for GET /admin/maintenance
+#[get("/maintenance")]
+async fn get_maintenance(mode: web::Data<MaintenanceMode>) -> impl Responder {
+    HttpResponse::Ok().json(MaintenanceState { enabled: mode.is_enabled() })
+}
+
+// Handler for PUT /admin/maintenance
+#[put("/maintenance")]
+#[instrument(name = "admin_set_maintenance_handler", skip_all, fields(service = "actix_example"))]
+async fn set_maintenance(
+    identity: web::ReqData<AdminIdentity>,
+    body: web::Json<MaintenanceState>,
+    mode: web::Data<MaintenanceMode>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    mode.enabled.store(body.enabled, Ordering::Relaxed);
+    audit.record(&identity.0, "set_maintenance", format!("enabled={}", body.enabled));
+    HttpResponse::Ok().json(body.into_inner())
+}
+
+// Handler for GET /admin/audit
+#[get("/audit")]
+async fn get_audit(audit: web::Data<AuditLog>) -> impl Responder {
+    HttpResponse::Ok().json(audit.entries())
+}
+
+// The /admin scope with its authentication middleware applied
+pub fn scope() -> actix_web::Scope<
+    impl actix_web::dev::ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    web::scope("/admin")
+        .wrap(actix_web::middleware::from_fn(require_admin))
+        .service(flush)
+        .service(get_log_level)
+        .service(set_log_level)
+        .service(get_maintenance)
+        .service(set_maintenance)
+        .service(get_audit)
+        .service(crate::drain::drain)
+        .service(crate::config_reload::reload_config)
+        .service(crate::backup::export_state)
+        .service(crate::backup::import_state)
+        .configure(crate::webhooks::configure)
+        .configure(crate::api_keys::configure)
+        .configure(crate::sampling::configure)
+        .configure(crate::features::configure)
+        .configure(crate::store_bench::configure)
+        .configure(crate::dashboard::configure)
+        .configure(crate::scheduler::configure)
+        .configure(crate::profiling::configure)
+        .configure(crate::memory::configure)
+        .default_service(web::to(crate::routes::default_handler))
+}
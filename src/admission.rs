@@ -0,0 +1,95 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::get_env_or_default;
+use crate::telemetry::record_load_shed;
+
+// Caps how many requests are handled at once across all workers. Past the
+// limit new requests are shed with a 503 rather than queued behind work the
+// server is already struggling with. MAX_IN_FLIGHT_REQUESTS=0 turns this off.
+pub struct AdmissionControl {
+    limit: usize,
+    retry_after_secs: u64,
+    in_flight: AtomicUsize,
+}
+
+// Held for the lifetime of an admitted request; dropping it, including when
+// the request is cancelled by a timeout, frees the slot
+struct Permit<'a>(&'a AtomicUsize);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl AdmissionControl {
+    pub fn from_env() -> Self {
+        AdmissionControl {
+            limit: get_env_or_default("MAX_IN_FLIGHT_REQUESTS", "512").parse().unwrap_or(512),
+            retry_after_secs: get_env_or_default("SHED_RETRY_AFTER_SECS", "1").parse().unwrap_or(1),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    fn admit(&self) -> Result<Permit<'_>, usize> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let permit = Permit(&self.in_flight);
+        if in_flight >= self.limit {
+            return Err(in_flight);
+        }
+        Ok(permit)
+    }
+}
+
+// Scope-level middleware, just inside request tracing so shed requests still
+// show up in traces with `load_shed=true`, but before any other work is done
+pub async fn shed(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(admission) = req.app_data::<web::Data<AdmissionControl>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if !admission.is_enabled() {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+
+    let _permit = match admission.admit() {
+        Ok(permit) => permit,
+        Err(in_flight) => {
+            warn!(in_flight = in_flight, limit = admission.limit, "Shedding request under overload");
+            Context::current().span().set_attribute(KeyValue::new("load_shed", true));
+            record_load_shed();
+
+            let retry_after = admission.retry_after_secs;
+            let mut response = AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "overloaded",
+                "The server is handling too many requests; try again shortly",
+            )
+            .with("retry_after_seconds", retry_after)
+            .error_response();
+            response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+            return Ok(req.into_response(response));
+        }
+    };
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,202 @@
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::admin::{constant_time_eq, AdminIdentity, AuditLog};
+use crate::error::AppError;
+use crate::locks::MeasuredLock;
+use crate::tenant::{Tenants, DEFAULT_TENANT};
+use crate::AppState;
+
+// Prefix on every issued key, so leaked keys are easy to spot in logs and scanners
+const KEY_PREFIX: &str = "ak_";
+
+// Characters of the plaintext key kept for display, e.g. "ak_3f9a1c"
+const DISPLAY_PREFIX_LEN: usize = 9;
+
+// A key issued to a machine client. Only the SHA-256 of the key is kept;
+// keys are 256 random bits, so a fast unsalted hash is enough.
+#[derive(Clone, Serialize)]
+pub struct ApiKey {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    #[serde(skip)]
+    key_hash: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+// Key used to authenticate a request, as stored in its identity
+#[derive(Clone, Debug)]
+pub struct ApiKeyIdentity {
+    pub id: Uuid,
+    pub name: String,
+}
+
+// Issued API keys, held in AppState
+#[derive(Default)]
+pub struct ApiKeyStore {
+    keys: Vec<ApiKey>,
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl ApiKeyStore {
+    // Create a key, returning its record and the plaintext, which is never stored
+    fn issue(&mut self, name: String) -> (ApiKey, String) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let plaintext = format!("{}{}", KEY_PREFIX, bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        let key = ApiKey {
+            id: Uuid::now_v7(),
+            name,
+            prefix: plaintext[..DISPLAY_PREFIX_LEN].to_string(),
+            key_hash: hash_key(&plaintext),
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+        self.keys.push(key.clone());
+        (key, plaintext)
+    }
+
+    fn revoke(&mut self, id: Uuid) -> Option<ApiKey> {
+        let index = self.keys.iter().position(|key| key.id == id)?;
+        Some(self.keys.remove(index))
+    }
+
+    // Look up the key presented by a client, recording its use
+    pub fn authenticate(&mut self, presented: &str) -> Option<ApiKeyIdentity> {
+        let hash = hash_key(presented.trim());
+        let key = self
+            .keys
+            .iter_mut()
+            .find(|key| constant_time_eq(key.key_hash.as_bytes(), hash.as_bytes()))?;
+        key.last_used_at = Some(Utc::now());
+        Some(ApiKeyIdentity { id: key.id, name: key.name.clone() })
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateApiKey {
+    name: String,
+}
+
+// `?tenant=` selects the tenant whose keys are managed; the default tenant otherwise
+#[derive(Deserialize)]
+struct TenantParam {
+    tenant: Option<String>,
+}
+
+fn tenant_state(
+    param: &TenantParam,
+    data: web::Data<RwLock<AppState>>,
+    tenants: &Tenants,
+) -> Result<web::Data<RwLock<AppState>>, HttpResponse> {
+    match param.tenant.as_deref() {
+        None | Some(DEFAULT_TENANT) => Ok(data),
+        Some(tenant) => tenants.users(tenant).map_err(|err| err.error_response()),
+    }
+}
+
+// Returned once on creation; the key itself is never shown again
+#[derive(Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    key: String,
+}
+
+// Handler for GET /admin/api-keys
+#[get("/api-keys")]
+async fn list_api_keys(
+    param: web::Query<TenantParam>,
+    data: web::Data<RwLock<AppState>>,
+    tenants: web::Data<Tenants>,
+) -> impl Responder {
+    let data = match tenant_state(&param, data, &tenants) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let response = match data.read_measured("app_state") {
+        Ok(state) => HttpResponse::Ok().json(&state.api_keys.keys),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to lock application state"),
+    };
+    response
+}
+
+// Handler for POST /admin/api-keys
+#[post("/api-keys")]
+#[instrument(name = "admin_create_api_key_handler", skip_all, fields(service = "actix_example"))]
+async fn create_api_key(
+    identity: web::ReqData<AdminIdentity>,
+    param: web::Query<TenantParam>,
+    body: web::Json<CreateApiKey>,
+    data: web::Data<RwLock<AppState>>,
+    tenants: web::Data<Tenants>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let data = match tenant_state(&param, data, &tenants) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let name = body.into_inner().name.trim().to_string();
+    if name.is_empty() {
+        return AppError::bad_request("invalid_name", "name must not be empty")
+            .with("field", "name")
+            .error_response();
+    }
+
+    let (api_key, key) = match data.write_measured("app_state") {
+        Ok(mut state) => state.api_keys.issue(name),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock application state"),
+    };
+
+    let tenant = param.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    audit.record(&identity.0, "create_api_key", format!("{} ({}) in tenant {}", api_key.id, api_key.name, tenant));
+    HttpResponse::Created().json(CreatedApiKey { api_key, key })
+}
+
+// Handler for DELETE /admin/api-keys/{id}
+#[delete("/api-keys/{id}")]
+#[instrument(name = "admin_revoke_api_key_handler", skip_all, fields(service = "actix_example"))]
+async fn revoke_api_key(
+    identity: web::ReqData<AdminIdentity>,
+    path: web::Path<Uuid>,
+    param: web::Query<TenantParam>,
+    data: web::Data<RwLock<AppState>>,
+    tenants: web::Data<Tenants>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let data = match tenant_state(&param, data, &tenants) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let id = path.into_inner();
+    let revoked = match data.write_measured("app_state") {
+        Ok(mut state) => state.api_keys.revoke(id),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock application state"),
+    };
+
+    let Some(api_key) = revoked else {
+        return AppError::new(StatusCode::NOT_FOUND, "not_found", format!("API key {} not found", id))
+            .error_response();
+    };
+    let tenant = param.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    audit.record(&identity.0, "revoke_api_key", format!("{} ({}) in tenant {}", api_key.id, api_key.name, tenant));
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_api_keys)
+        .service(create_api_key)
+        .service(revoke_api_key);
+}
@@ -0,0 +1,492 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ResourceDef, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{post, web, Error, HttpMessage, HttpResponse, Responder, ResponseError};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use tracing::{info, info_span, instrument, warn};
+use uuid::Uuid;
+
+use crate::api_keys::ApiKeyIdentity;
+use crate::error::AppError;
+use crate::locks::MeasuredLock;
+use crate::oidc::OidcValidator;
+use crate::repository::SharedUserRepository;
+use crate::sessions;
+use crate::tenant::TenantId;
+use crate::{config, AppState, User, UserId, UserStatus};
+
+// Stored login credentials for a user: an Argon2id hash in PHC string format
+// ("$argon2id$v=19$m=...,t=...,p=...$<salt>$<hash>"), which carries its own
+// parameters so they can be raised without invalidating existing hashes
+#[derive(Clone)]
+pub struct Credentials {
+    pub(crate) password_hash: String,
+}
+
+// Shortest password accepted on creation or change
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Verified against when a sign-in finds no password to check, so unknown
+// emails take as long to refuse as wrong passwords. Same parameters as
+// Argon2::default(), which new hashes use; nothing is known to match it.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$aFiyLPZnb652rlDTZ4ilTg$4uoACA/kzf98YAmT10YrOrp//kQcIdic63FPYcEHK88";
+
+pub fn validate_password(password: &str) -> Result<(), AppError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::bad_request(
+            "weak_password",
+            format!("password must be at least {} characters", MIN_PASSWORD_LENGTH),
+        )
+        .with("field", "password"));
+    }
+    Ok(())
+}
+
+impl Credentials {
+    // Argon2 is deliberately slow, so hashing runs on the blocking pool inside
+    // a `hash_password` child span that shows its cost in the request's trace
+    pub async fn from_password(password: String) -> Result<Self, String> {
+        let span = info_span!("hash_password", password.algorithm = "argon2id");
+        web::block(move || {
+            let _entered = span.enter();
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let salt = SaltString::encode_b64(&salt).map_err(|err| err.to_string())?;
+            let hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|err| err.to_string())?;
+            Ok(Credentials { password_hash: hash.to_string() })
+        })
+        .await
+        .map_err(|err| err.to_string())?
+    }
+
+    pub async fn verify(&self, password: &str) -> bool {
+        let span = info_span!("verify_password", password.algorithm = "argon2id");
+        let password_hash = self.password_hash.clone();
+        let password = password.to_string();
+        web::block(move || {
+            let _entered = span.enter();
+            PasswordHash::new(&password_hash)
+                .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        })
+        .await
+        .unwrap_or(false)
+    }
+}
+
+// Which of the two token kinds a JWT is; refresh tokens are only accepted by
+// POST /auth/refresh
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    pub sub: UserId,
+    pub typ: TokenType,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: Uuid,
+    // Tenant the token was issued in; absent for the default tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+// Signs and verifies the HS256 tokens handed out by /auth/login
+pub struct TokenIssuer {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    access_ttl: i64,
+    refresh_ttl: i64,
+}
+
+impl TokenIssuer {
+    // JWT_SIGNING_KEY sets the shared secret; without it a random key is used,
+    // so tokens stop validating when the process restarts
+    pub fn from_env() -> Self {
+        let settings = &config::settings().auth;
+        let secret = match settings.jwt_signing_key.as_ref().filter(|key| !key.is_empty()) {
+            Some(secret) => secret.clone().into_bytes(),
+            None => {
+                warn!("No JWT_SIGNING_KEY set; using a random per-process signing key");
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        TokenIssuer {
+            encoding_key: EncodingKey::from_secret(&secret),
+            decoding_key: DecodingKey::from_secret(&secret),
+            access_ttl: settings.jwt_access_ttl_secs,
+            refresh_ttl: settings.jwt_refresh_ttl_secs,
+        }
+    }
+
+    fn issue(&self, user_id: UserId, typ: TokenType, tenant: Option<String>) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now().timestamp();
+        let ttl = match typ {
+            TokenType::Access => self.access_ttl,
+            TokenType::Refresh => self.refresh_ttl,
+        };
+        let claims = Claims {
+            sub: user_id,
+            typ,
+            iat: now,
+            exp: now + ttl,
+            jti: Uuid::now_v7(),
+            tenant,
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+    }
+
+    // Decode a token, checking signature, expiry and that it is of the expected kind
+    pub fn verify(&self, token: &str, expected: TokenType) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding_key, &validation)?.claims;
+        if claims.typ != expected {
+            return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+        }
+        Ok(claims)
+    }
+
+    fn token_pair(&self, user_id: UserId, tenant: Option<String>) -> Result<TokenResponse, jsonwebtoken::errors::Error> {
+        Ok(TokenResponse {
+            access_token: self.issue(user_id, TokenType::Access, tenant.clone())?,
+            token_type: "Bearer",
+            expires_in: self.access_ttl,
+            refresh_token: self.issue(user_id, TokenType::Refresh, tenant)?,
+            refresh_expires_in: self.refresh_ttl,
+        })
+    }
+}
+
+// OAuth2-style token response
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+    refresh_expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+fn invalid_credentials() -> HttpResponse {
+    let mut response = AppError::new(StatusCode::UNAUTHORIZED, "invalid_credentials", "Invalid email or password")
+        .error_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    response
+}
+
+// Suspended users keep their credentials but can't obtain new tokens
+fn check_can_sign_in(user: &User) -> Result<(), HttpResponse> {
+    if user.status == UserStatus::Suspended {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "account_suspended", "This account is suspended")
+            .error_response());
+    }
+    Ok(())
+}
+
+fn token_response(issuer: &TokenIssuer, user_id: UserId, tenant: Option<String>) -> HttpResponse {
+    match issuer.token_pair(user_id, tenant) {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .json(tokens),
+        Err(err) => {
+            warn!(error = %err, "Failed to sign token");
+            HttpResponse::InternalServerError().body("Failed to sign token")
+        }
+    }
+}
+
+// Check an email/password pair, returning the user it signs in as or the
+// response to send instead
+pub(crate) async fn sign_in(repository: &SharedUserRepository, email: &str, password: &str) -> Result<User, HttpResponse> {
+    let user = repository.find_by_email(email).await.map_err(|err| err.error_response())?;
+
+    // Unknown emails and users without a password fail the same way as a
+    // wrong password, after the same hashing work
+    let verified = match user.as_ref().and_then(|user| user.credentials.as_ref()) {
+        Some(credentials) => credentials.verify(password).await,
+        None => {
+            let dummy = Credentials { password_hash: DUMMY_PASSWORD_HASH.to_string() };
+            dummy.verify(password).await;
+            false
+        }
+    };
+    let Some(user) = user.filter(|_| verified) else {
+        info!("Login rejected");
+        return Err(invalid_credentials());
+    };
+    if let Err(response) = check_can_sign_in(&user) {
+        info!(user_id = %user.id, "Login rejected for suspended user");
+        return Err(response);
+    }
+    Ok(user)
+}
+
+// Handler for POST /auth/login
+#[post("/auth/login")]
+#[instrument(name = "login_handler", skip_all, fields(service = "actix_example"))]
+async fn login(
+    body: web::Json<LoginRequest>,
+    repository: SharedUserRepository,
+    issuer: web::Data<TokenIssuer>,
+    tenant: Option<web::ReqData<TenantId>>,
+) -> impl Responder {
+    info!("Login attempt");
+
+    let user = match sign_in(&repository, &body.email, &body.password).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    info!(user_id = %user.id, "Login succeeded");
+    token_response(&issuer, user.id, tenant.and_then(|tenant| tenant.claim()))
+}
+
+// Handler for POST /auth/refresh
+#[post("/auth/refresh")]
+#[instrument(name = "refresh_token_handler", skip_all, fields(service = "actix_example"))]
+async fn refresh(
+    body: web::Json<RefreshRequest>,
+    repository: SharedUserRepository,
+    issuer: web::Data<TokenIssuer>,
+    tenant: Option<web::ReqData<TenantId>>,
+) -> impl Responder {
+    // Refresh tokens only work in the tenant they were issued in
+    let tenant = tenant.and_then(|tenant| tenant.claim());
+    let claims = match issuer.verify(&body.refresh_token, TokenType::Refresh) {
+        Ok(claims) if claims.tenant == tenant => claims,
+        Ok(_) => {
+            info!("Rejected refresh token from another tenant");
+            return AppError::new(StatusCode::UNAUTHORIZED, "invalid_token", "Refresh token is invalid or expired")
+                .error_response();
+        }
+        Err(err) => {
+            info!(error = %err, "Rejected refresh token");
+            return AppError::new(StatusCode::UNAUTHORIZED, "invalid_token", "Refresh token is invalid or expired")
+                .error_response();
+        }
+    };
+
+    let user = match repository.get(claims.sub).await {
+        Ok(user) => user,
+        Err(err) => return err.error_response(),
+    };
+    // Deleted users can't refresh, even with an unexpired token
+    let Some(user) = user else {
+        return AppError::new(StatusCode::UNAUTHORIZED, "invalid_token", "Refresh token is invalid or expired")
+            .error_response();
+    };
+    if let Err(response) = check_can_sign_in(&user) {
+        return response;
+    }
+
+    info!(user_id = %user.id, "Tokens refreshed");
+    token_response(&issuer, user.id, tenant)
+}
+
+// Header carrying API keys for machine clients
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+// Mutating routes that stay open to anonymous clients: obtaining tokens,
+// signing up, and the POST endpoints that only read
+const PUBLIC_MUTATIONS: &[(Method, &str)] = &[
+    (Method::POST, "/auth/login"),
+    (Method::POST, "/auth/refresh"),
+    (Method::POST, "/auth/session/login"),
+    (Method::POST, "/auth/session/logout"),
+    (Method::POST, "/users"),
+    (Method::POST, "/users/batch-get"),
+    (Method::POST, "/users/check-duplicates"),
+];
+
+// Whether requests to mutating routes must carry credentials (an access token
+// or API key). When not required, credentials that are present are still
+// validated and their identity used.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthConfig {
+    pub required: bool,
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Self {
+        AuthConfig {
+            required: config::settings().auth.require_auth,
+        }
+    }
+}
+
+// Identity of the caller, stored in request extensions by `authenticate`
+#[derive(Clone, Debug)]
+pub enum Identity {
+    // A user holding a JWT access token
+    User(UserId),
+    // A machine client presenting an X-Api-Key
+    ApiKey(ApiKeyIdentity),
+    // The subject of a token issued by the configured OIDC provider
+    External(String),
+    // A user signed in through a browser session cookie
+    Session { user_id: UserId, session_id: Uuid },
+}
+
+impl Identity {
+    // Stable identifier recorded as `enduser.id` and in logs
+    pub fn subject(&self) -> String {
+        match self {
+            Identity::User(id) => id.to_string(),
+            Identity::ApiKey(key) => format!("api-key:{}", key.id),
+            Identity::External(subject) => subject.clone(),
+            Identity::Session { user_id, .. } => user_id.to_string(),
+        }
+    }
+}
+
+fn requires_credentials(req: &ServiceRequest) -> bool {
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    mutating
+        && !PUBLIC_MUTATIONS
+            .iter()
+            .any(|(method, pattern)| method == req.method() && ResourceDef::new(*pattern).is_match(req.path()))
+}
+
+fn unauthorized(req: ServiceRequest, code: &'static str, detail: &str, challenge: &str) -> ServiceResponse<BoxBody> {
+    let mut response = AppError::new(StatusCode::UNAUTHORIZED, code, detail).error_response();
+    if let Ok(value) = header::HeaderValue::from_str(challenge) {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+    }
+    req.into_response(response)
+}
+
+fn lookup_api_key(req: &ServiceRequest, presented: &str) -> Option<ApiKeyIdentity> {
+    let data = req.app_data::<web::Data<RwLock<AppState>>>()?;
+    let mut state = data.write_measured("app_state").ok()?;
+    state.api_keys.authenticate(presented)
+}
+
+// Our own tokens are HS256; anything else is taken to come from the OIDC
+// provider, when one is configured. Our tokens stop working as soon as their
+// user is deleted or suspended, as refresh tokens do.
+async fn verify_bearer(req: &ServiceRequest, token: &str) -> Result<Identity, String> {
+    let algorithm = jsonwebtoken::decode_header(token).map_err(|err| err.to_string())?.alg;
+    if algorithm == Algorithm::HS256 {
+        let issuer = req
+            .app_data::<web::Data<TokenIssuer>>()
+            .ok_or("token issuer is not configured")?;
+        let claims = issuer.verify(token, TokenType::Access).map_err(|err| err.to_string())?;
+        // The tenant's repository, swapped in by tenant::resolve
+        let repository = req
+            .app_data::<SharedUserRepository>()
+            .ok_or("user repository is not configured")?;
+        let user = repository
+            .get(claims.sub)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or("user no longer exists")?;
+        check_can_sign_in(&user).map_err(|_| "user is suspended")?;
+        return Ok(Identity::User(user.id));
+    }
+
+    let oidc = req
+        .app_data::<web::Data<Option<OidcValidator>>>()
+        .and_then(|oidc| oidc.get_ref().as_ref())
+        .ok_or_else(|| format!("{:?} tokens are not accepted without an OIDC provider", algorithm))?;
+    let claims = oidc.verify(token).await?;
+    info!(issuer = %claims.iss, "Accepted OIDC provider token");
+    Ok(Identity::External(claims.sub))
+}
+
+// Scope-level middleware, inside RequestTracing, validating `X-Api-Key` keys
+// and `Authorization: Bearer` access tokens (ours or the OIDC provider's). Valid credentials put an
+// `Identity` into the request extensions and `enduser.id` on the request span;
+// invalid or expired ones are always a 401, missing ones only on protected routes.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let required = req
+        .app_data::<web::Data<AuthConfig>>()
+        .is_some_and(|config| config.required)
+        && requires_credentials(&req);
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    let identity = if let Some(api_key) = api_key {
+        match lookup_api_key(&req, &api_key) {
+            Some(key) => {
+                info!(api_key.id = %key.id, api_key.name = %key.name, "Authenticated API key client");
+                Identity::ApiKey(key)
+            }
+            None => {
+                info!(path = %req.path(), "Rejected API key");
+                return Ok(unauthorized(req, "invalid_api_key", "API key is invalid or revoked", "ApiKey"));
+            }
+        }
+    } else if let Some(token) = token {
+        match verify_bearer(&req, &token).await {
+            Ok(identity) => identity,
+            Err(err) => {
+                info!(path = %req.path(), error = %err, "Rejected access token");
+                return Ok(unauthorized(
+                    req,
+                    "invalid_token",
+                    "Access token is invalid or expired",
+                    "Bearer error=\"invalid_token\"",
+                ));
+            }
+        }
+    } else if let Some((session_id, user_id)) = sessions::session_from_request(&req) {
+        Identity::Session { user_id, session_id }
+    } else {
+        if required {
+            info!(path = %req.path(), "Rejected request without credentials");
+            return Ok(unauthorized(req, "invalid_token", "An access token or API key is required", "Bearer, ApiKey"));
+        }
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let span_context = Context::current();
+    let span = span_context.span();
+    span.set_attribute(KeyValue::new("enduser.id", identity.subject()));
+    if let Identity::Session { session_id, .. } = &identity {
+        span.set_attribute(KeyValue::new("session.id", session_id.to_string()));
+    }
+    req.extensions_mut().insert(identity);
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
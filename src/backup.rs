@@ -0,0 +1,282 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tracing::{info, instrument, warn};
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::auth::Credentials;
+use crate::error::AppError;
+use crate::locks::MeasuredLock;
+use crate::payload::{read_body, PayloadLimits};
+use crate::persistence::StateFile;
+use crate::repository::SharedUserRepository;
+use crate::wal::MutationLog;
+use crate::{compute_etag, validate_profile, AppState, Avatar, Profile, ProfileState, User, UserId, AVATAR_CONTENT_TYPES};
+
+// Bumped whenever the snapshot layout changes incompatibly
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct AvatarSnapshot {
+    user_id: UserId,
+    content_type: String,
+    // Base64-encoded image bytes
+    data: String,
+}
+
+// Full dump of the demo store, as produced by export and accepted by import
+#[derive(Serialize, Deserialize)]
+pub struct StateSnapshot {
+    version: u32,
+    #[serde(default = "Utc::now")]
+    exported_at: DateTime<Utc>,
+    user_counter: u32,
+    users: Vec<User>,
+    #[serde(default)]
+    profiles: HashMap<UserId, Profile>,
+    #[serde(default)]
+    avatars: Vec<AvatarSnapshot>,
+    // Only written to the state file; exports never carry credentials
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    password_hashes: HashMap<UserId, String>,
+}
+
+impl StateSnapshot {
+    // Copy the current state; callers hold the AppState lock before the profile lock
+    pub fn capture(app_state: &AppState, profile_state: &ProfileState, with_credentials: bool) -> Self {
+        let password_hashes = match with_credentials {
+            true => app_state
+                .users
+                .iter()
+                .filter_map(|user| Some((user.id, user.credentials.as_ref()?.password_hash.clone())))
+                .collect(),
+            false => HashMap::new(),
+        };
+        StateSnapshot {
+            version: SNAPSHOT_VERSION,
+            exported_at: Utc::now(),
+            user_counter: app_state.user_counter,
+            users: app_state.users.iter().cloned().collect(),
+            profiles: profile_state.profiles.clone(),
+            avatars: app_state
+                .avatars
+                .iter()
+                .map(|(user_id, avatar)| AvatarSnapshot {
+                    user_id: *user_id,
+                    content_type: avatar.content_type.clone(),
+                    data: base64::engine::general_purpose::STANDARD.encode(&avatar.data),
+                })
+                .collect(),
+            password_hashes,
+        }
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    // Validate the snapshot and replace the current state with it, returning
+    // the number of users restored. Nothing changes if validation fails.
+    pub fn restore(self, app_state: &mut AppState, profile_state: &mut ProfileState) -> Result<usize, AppError> {
+        let avatars = validate_snapshot(&self)?;
+
+        // Make sure freshly generated sequential IDs can't collide with restored ones
+        let max_sequential = self
+            .users
+            .iter()
+            .map(|user| user.id.as_u128())
+            .filter(|id| *id <= u32::MAX as u128)
+            .max()
+            .unwrap_or(0) as u32;
+        let user_count = self.users.len();
+        let mut password_hashes = self.password_hashes;
+
+        app_state.users = self
+            .users
+            .into_iter()
+            .map(|mut user| {
+                user.credentials = password_hashes
+                    .remove(&user.id)
+                    .map(|password_hash| Credentials { password_hash });
+                user
+            })
+            .collect();
+        app_state.user_counter = self.user_counter.max(max_sequential);
+        app_state.avatars = avatars;
+        app_state.reindex_emails();
+        app_state.domain_stats = None;
+        profile_state.profiles = self.profiles;
+        Ok(user_count)
+    }
+}
+
+pub fn invalid_snapshot(detail: impl Into<String>) -> AppError {
+    AppError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_snapshot", detail)
+}
+
+// Check the whole snapshot and decode avatars before anything is replaced
+fn validate_snapshot(snapshot: &StateSnapshot) -> Result<HashMap<UserId, Avatar>, AppError> {
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(invalid_snapshot(format!(
+            "unsupported snapshot version {}, expected {}",
+            snapshot.version, SNAPSHOT_VERSION
+        )));
+    }
+
+    let mut ids = HashSet::new();
+    for user in &snapshot.users {
+        if !ids.insert(user.id) {
+            return Err(invalid_snapshot(format!("duplicate user ID {}", user.id)));
+        }
+        if user.name.trim().is_empty() || user.email.trim().is_empty() {
+            return Err(invalid_snapshot(format!("user {} has an empty name or email", user.id)));
+        }
+    }
+
+    for (user_id, profile) in &snapshot.profiles {
+        if !ids.contains(user_id) {
+            return Err(invalid_snapshot(format!("profile for unknown user {}", user_id)));
+        }
+        validate_profile(profile)
+            .map_err(|message| invalid_snapshot(format!("profile for user {}: {}", user_id, message)))?;
+    }
+
+    let mut avatars = HashMap::new();
+    for avatar in &snapshot.avatars {
+        if !ids.contains(&avatar.user_id) {
+            return Err(invalid_snapshot(format!("avatar for unknown user {}", avatar.user_id)));
+        }
+        if !AVATAR_CONTENT_TYPES.contains(&avatar.content_type.as_str()) {
+            return Err(invalid_snapshot(format!(
+                "avatar for user {} has unsupported content type {}",
+                avatar.user_id, avatar.content_type
+            )));
+        }
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(&avatar.data)
+            .map_err(|err| invalid_snapshot(format!("avatar for user {} is not valid base64: {}", avatar.user_id, err)))?;
+        let data = web::Bytes::from(data);
+        avatars.insert(avatar.user_id, Avatar {
+            content_type: avatar.content_type.clone(),
+            etag: compute_etag(&data),
+            data,
+        });
+    }
+
+    Ok(avatars)
+}
+
+// Snapshots cover AppState, where only the memory backend keeps its users
+fn check_backend(repository: &SharedUserRepository) -> Result<(), AppError> {
+    match repository.name() {
+        "memory" => Ok(()),
+        other => Err(AppError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "snapshot_unavailable",
+            format!("The {} backend keeps its users outside the application state; use STORAGE_BACKEND=memory", other),
+        )),
+    }
+}
+
+// Handler for GET /admin/export
+#[get("/export")]
+#[instrument(name = "admin_export_handler", skip_all, fields(service = "actix_example"))]
+async fn export_state(
+    identity: web::ReqData<AdminIdentity>,
+    data: web::Data<RwLock<AppState>>,
+    profiles: web::Data<RwLock<ProfileState>>,
+    audit: web::Data<AuditLog>,
+    repository: SharedUserRepository,
+) -> impl Responder {
+    if let Err(err) = check_backend(&repository) {
+        return err.error_response();
+    }
+    // Same lock order as import (AppState, then profiles) so the two can't deadlock
+    let app_state = match data.read_measured("app_state") {
+        Ok(state) => state,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock application state"),
+    };
+    let profile_state = match profiles.read_measured("profiles") {
+        Ok(state) => state,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock profile state"),
+    };
+
+    let snapshot = StateSnapshot::capture(&app_state, &profile_state, false);
+    drop(profile_state);
+    drop(app_state);
+
+    audit.record(&identity.0, "export", format!("{} user(s)", snapshot.users.len()));
+    HttpResponse::Ok()
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"state-snapshot.json\"",
+        ))
+        .json(snapshot)
+}
+
+// Handler for POST /admin/import
+//
+// Snapshots are usually larger than regular JSON bodies, so the body is read
+// against the upload limit rather than the JSON one.
+#[post("/import")]
+#[instrument(name = "admin_import_handler", skip_all, fields(service = "actix_example"))]
+async fn import_state(
+    identity: web::ReqData<AdminIdentity>,
+    payload: web::Payload,
+    limits: web::Data<PayloadLimits>,
+    (data, profiles): (web::Data<RwLock<AppState>>, web::Data<RwLock<ProfileState>>),
+    audit: web::Data<AuditLog>,
+    repository: SharedUserRepository,
+    // Where the state is persisted, both told about the import below
+    (wal, state_file): (web::Data<Option<Arc<MutationLog>>>, web::Data<StateFile>),
+) -> impl Responder {
+    if let Err(err) = check_backend(&repository) {
+        return err.error_response();
+    }
+    let body = match read_body(payload, limits.upload).await {
+        Ok(body) => body,
+        Err(err) => return err.error_response(),
+    };
+    let snapshot: StateSnapshot = match serde_json::from_slice(&body) {
+        Ok(snapshot) => snapshot,
+        Err(err) => return invalid_snapshot(format!("snapshot is not valid JSON: {}", err)).error_response(),
+    };
+    // Hold both locks while swapping so readers never see a half-imported state
+    let (removed, users) = {
+        let mut app_state = match data.write_measured("app_state") {
+            Ok(state) => state,
+            Err(_) => return HttpResponse::InternalServerError().body("Failed to lock application state"),
+        };
+        let mut profile_state = match profiles.write_measured("profiles") {
+            Ok(state) => state,
+            Err(_) => return HttpResponse::InternalServerError().body("Failed to lock profile state"),
+        };
+        let previous: Vec<UserId> = app_state.users.iter().map(|user| user.id).collect();
+        if let Err(err) = snapshot.restore(&mut app_state, &mut profile_state) {
+            info!(error = %err, "Rejected state import");
+            return err.error_response();
+        }
+        let removed: Vec<UserId> = previous.into_iter().filter(|id| app_state.users.get(*id).is_none()).collect();
+        let users: Vec<User> = app_state.users.iter().cloned().collect();
+        (removed, users)
+    };
+    let user_count = users.len();
+    // Drop cached copies and bring STATE_SYNC peers in line with the import
+    repository.restored(&removed, &users).await;
+    state_file.mark_dirty();
+
+    // The log only replays on top of the previous snapshot; if this fails,
+    // the log stays dirty and the next scheduled compaction retries
+    if let Some(log) = wal.as_ref() {
+        if let Err(err) = log.rebase(&data, &profiles).await {
+            warn!(error = %err, "Failed to compact WAL after import");
+        }
+    }
+
+    audit.record(&identity.0, "import", format!("{} user(s)", user_count));
+    HttpResponse::Ok().json(serde_json::json!({ "imported_users": user_count }))
+}
@@ -0,0 +1,93 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{StatusCode, Uri};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, ResponseError};
+use tracing::debug;
+
+use crate::config;
+use crate::error::AppError;
+
+// The prefix the API is served under (server.base_path), e.g. "/api" when an
+// ingress routes /api/... to the server without stripping it. Empty serves the
+// API at the root. Only the public app has one; the admin listener doesn't.
+pub struct BasePath(String);
+
+impl BasePath {
+    // The prefix is checked by ServerSettings::validate at startup
+    pub fn from_env() -> Self {
+        BasePath(config::settings().server.base_path.clone())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // The in-app path of a request path, or None when it lies outside the
+    // prefix. "/api" and "/api/users" are under "/api"; "/apiary" isn't.
+    fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match path.strip_prefix(self.0.as_str())? {
+            "" => Some("/"),
+            rest if rest.starts_with('/') => Some(rest),
+            _ => None,
+        }
+    }
+
+    // An in-app path, e.g. "/users", as clients address it
+    fn external(&self, path: &str) -> String {
+        match path {
+            "/" if !self.0.is_empty() => self.0.clone(),
+            _ => format!("{}{}", self.0, path),
+        }
+    }
+}
+
+// `path` as clients address it: prefixed on the public app, unchanged on the
+// admin listener. For links, cookie paths and problem instances.
+pub fn external(req: &HttpRequest, path: &str) -> String {
+    match req.app_data::<web::Data<BasePath>>() {
+        Some(base_path) => base_path.external(path),
+        None => path.to_string(),
+    }
+}
+
+// App-level middleware, inside normalize_path, removing the prefix before
+// routing so routes, the route table and path checks in other middleware stay
+// as they are. Requests outside the prefix get a 404 without being routed.
+pub async fn strip(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(base_path) = req.app_data::<web::Data<BasePath>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if base_path.as_str().is_empty() {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    }
+
+    let path = req.path().to_string();
+    let Some(inner) = base_path.strip(&path) else {
+        debug!(path = %path, base_path = %base_path.as_str(), "Request outside the base path");
+        let response = AppError::new(StatusCode::NOT_FOUND, "not_found", format!("No resource found at {}", path))
+            .with("instance", path)
+            .error_response();
+        return Ok(req.into_response(response));
+    };
+
+    let path_and_query = match req.query_string() {
+        "" => inner.to_string(),
+        query => format!("{}?{}", inner, query),
+    };
+    let head = req.head_mut();
+    let mut parts = head.uri.clone().into_parts();
+    if let Ok(path_and_query) = path_and_query.parse() {
+        parts.path_and_query = Some(path_and_query);
+        if let Ok(uri) = Uri::from_parts(parts) {
+            head.uri = uri;
+            let uri = head.uri.clone();
+            req.match_info_mut().get_mut().update(&uri);
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,184 @@
+use actix_web::web;
+use async_trait::async_trait;
+use opentelemetry::global;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::telemetry::{record_bus_dropped, record_bus_publish};
+use crate::webhooks::RetryPolicy;
+
+// A message broker user events are published to, next to webhooks. The
+// dispatcher wraps every publish in a producer span and retries failures
+// under the bus's own policy.
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    // messaging.system, e.g. "kafka"
+    fn system(&self) -> &'static str;
+    // The topic, subject or exchange events go to
+    fn destination(&self) -> &str;
+    fn retry_policy(&self) -> RetryPolicy;
+    // Publish one event and wait for the broker to acknowledge it. `key` is
+    // the user's ID, keeping each user's events in order where the broker
+    // partitions by key; `headers` carry the event type and ID and the W3C
+    // trace context.
+    async fn publish(&self, key: &str, payload: &[u8], headers: &HashMap<String, String>) -> Result<(), String>;
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::EventBus;
+    use crate::webhooks::RetryPolicy;
+    use async_trait::async_trait;
+    use rdkafka::message::{Header, OwnedHeaders};
+    use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+    use rdkafka::ClientConfig;
+    use std::collections::HashMap;
+    use tracing::info;
+
+    // Kafka producer via rdkafka. librdkafka retries sends on its own until
+    // KAFKA_TIMEOUT_SECS runs out; the dispatcher retries past that.
+    pub struct KafkaBus {
+        producer: FutureProducer,
+        topic: String,
+        policy: RetryPolicy,
+    }
+
+    impl KafkaBus {
+        // KAFKA_BROKERS is a comma-separated bootstrap list; KAFKA_TOPIC
+        // defaults to user-events
+        pub fn from_env(brokers: &str) -> Result<Self, String> {
+            let policy = RetryPolicy::from_env_with_prefix("KAFKA");
+            let producer: FutureProducer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("client.id", "actix-web-server")
+                .set("acks", "all")
+                .set("message.timeout.ms", policy.timeout.as_millis().to_string())
+                .create()
+                .map_err(|err| format!("invalid Kafka producer config: {}", err))?;
+            let queued = producer.clone();
+            crate::telemetry::observe_kafka_queue(move || queued.in_flight_count().max(0) as u64);
+            Ok(KafkaBus {
+                producer,
+                topic: crate::get_env_or_default("KAFKA_TOPIC", "user-events"),
+                policy,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl EventBus for KafkaBus {
+        fn system(&self) -> &'static str {
+            "kafka"
+        }
+
+        fn destination(&self) -> &str {
+            &self.topic
+        }
+
+        fn retry_policy(&self) -> RetryPolicy {
+            self.policy
+        }
+
+        async fn publish(&self, key: &str, payload: &[u8], headers: &HashMap<String, String>) -> Result<(), String> {
+            let headers = headers
+                .iter()
+                .fold(OwnedHeaders::new_with_capacity(headers.len()), |owned, (key, value)| {
+                    owned.insert(Header { key, value: Some(value) })
+                });
+            let record = FutureRecord::to(&self.topic).key(key).payload(payload).headers(headers);
+            let (partition, offset) =
+                self.producer.send(record, self.policy.timeout).await.map_err(|(err, _)| err.to_string())?;
+            info!(partition = partition, offset = offset, "Kafka broker acknowledged event");
+            Ok(())
+        }
+    }
+}
+
+// The buses configured in the environment; KAFKA_BROKERS turns on Kafka,
+// NATS_URL NATS and AMQP_URL RabbitMQ
+pub async fn buses_from_env() -> Result<Vec<Arc<dyn EventBus>>, String> {
+    let mut buses = Vec::new();
+    if let Some(brokers) = std::env::var("KAFKA_BROKERS").ok().filter(|brokers| !brokers.is_empty()) {
+        buses.push(kafka_bus(&brokers)?);
+    }
+    if std::env::var("NATS_URL").is_ok_and(|url| !url.is_empty()) {
+        buses.push(nats_bus().await?);
+    }
+    if let Some(url) = std::env::var("AMQP_URL").ok().filter(|url| !url.is_empty()) {
+        buses.push(amqp_bus(&url)?);
+    }
+    Ok(buses)
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_bus(brokers: &str) -> Result<Arc<dyn EventBus>, String> {
+    Ok(Arc::new(kafka::KafkaBus::from_env(brokers)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_bus(_brokers: &str) -> Result<Arc<dyn EventBus>, String> {
+    Err("KAFKA_BROKERS requires building with the `kafka` feature".to_string())
+}
+
+#[cfg(feature = "nats")]
+async fn nats_bus() -> Result<Arc<dyn EventBus>, String> {
+    Ok(Arc::new(crate::nats::NatsBus::from_env().await?))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn nats_bus() -> Result<Arc<dyn EventBus>, String> {
+    Err("NATS_URL requires building with the `nats` feature".to_string())
+}
+
+#[cfg(feature = "amqp")]
+fn amqp_bus(url: &str) -> Result<Arc<dyn EventBus>, String> {
+    Ok(Arc::new(crate::amqp::AmqpBus::from_env(url)?))
+}
+
+#[cfg(not(feature = "amqp"))]
+fn amqp_bus(_url: &str) -> Result<Arc<dyn EventBus>, String> {
+    Err("AMQP_URL requires building with the `amqp` feature".to_string())
+}
+
+// Publish one event to one bus, retrying with exponential backoff; false
+// when every attempt failed. Runs in the producer span, whose context goes
+// out in the traceparent header so consumers continue the trace.
+pub async fn deliver(
+    bus: Arc<dyn EventBus>,
+    key: String,
+    payload: web::Bytes,
+    mut headers: HashMap<String, String>,
+) -> bool {
+    let span = Span::current();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&span.context(), &mut headers));
+    // tracestate is injected even when there is none
+    headers.retain(|_, value| !value.is_empty());
+    let policy = bus.retry_policy();
+    let mut backoff = policy.initial_backoff;
+    for attempt in 1..=policy.max_attempts {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(policy.timeout, bus.publish(&key, &payload, &headers)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("no acknowledgement within {:?}", policy.timeout)),
+        };
+        record_bus_publish(bus.system(), bus.destination(), result.is_ok(), started.elapsed());
+        match result {
+            Ok(()) => {
+                info!(attempt = attempt, "Event published");
+                return true;
+            }
+            Err(err) => warn!(attempt = attempt, error = %err, "Event publish failed"),
+        }
+        if attempt < policy.max_attempts {
+            actix_web::rt::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+    span.record("otel.status_code", "ERROR");
+    record_bus_dropped(bus.system(), bus.destination());
+    warn!(attempts = policy.max_attempts, destination = bus.destination(), "Giving up on publishing event");
+    false
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::get_env_or_default;
+use crate::telemetry::{record_circuit_rejection, record_circuit_transition};
+
+// Thresholds shared by every breaker
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerSettings {
+    // Outcomes kept in the rolling window; the breaker only trips once it is full
+    pub window: usize,
+    // Share of failed calls in the window that opens the circuit
+    pub failure_rate: f64,
+    // How long an open circuit refuses calls before letting a probe through
+    pub open_for: Duration,
+}
+
+impl BreakerSettings {
+    pub fn from_env() -> Self {
+        BreakerSettings {
+            window: get_env_or_default("CIRCUIT_WINDOW", "10").parse().unwrap_or(10).max(1),
+            failure_rate: get_env_or_default("CIRCUIT_FAILURE_RATE", "0.5").parse().unwrap_or(0.5),
+            open_for: Duration::from_secs(get_env_or_default("CIRCUIT_OPEN_SECS", "30").parse().unwrap_or(30)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    // One probe call is in flight; its outcome closes or reopens the circuit
+    HalfOpen,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    outcomes: VecDeque<bool>,
+}
+
+// Breaker guarding one outbound dependency. Callers ask `allow` before each
+// call and report the outcome with `record`.
+pub struct CircuitBreaker {
+    dependency: String,
+    settings: BreakerSettings,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    fn new(dependency: String, settings: BreakerSettings) -> Self {
+        CircuitBreaker {
+            dependency,
+            settings,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                outcomes: VecDeque::with_capacity(settings.window),
+            }),
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: State) {
+        let from = inner.state;
+        inner.state = to;
+        if from.name() != to.name() {
+            match to {
+                State::Open { .. } => warn!(dependency = %self.dependency, from = from.name(), to = to.name(), "Circuit breaker opened"),
+                _ => info!(dependency = %self.dependency, from = from.name(), to = to.name(), "Circuit breaker state changed"),
+            }
+            record_circuit_transition(&self.dependency, from.name(), to.name());
+        }
+    }
+
+    // Whether a call may go out now. An open circuit lets a single probe
+    // through once its cool-down has passed.
+    pub fn allow(&self) -> bool {
+        let Ok(mut inner) = self.inner.lock() else {
+            return true;
+        };
+        let allowed = match inner.state {
+            State::Closed => true,
+            State::Open { until } if Instant::now() >= until => {
+                self.transition(&mut inner, State::HalfOpen);
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        };
+        if !allowed {
+            record_circuit_rejection(&self.dependency);
+        }
+        allowed
+    }
+
+    pub fn record(&self, success: bool) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        match inner.state {
+            State::HalfOpen if success => {
+                inner.outcomes.clear();
+                self.transition(&mut inner, State::Closed);
+            }
+            State::HalfOpen => {
+                let until = Instant::now() + self.settings.open_for;
+                self.transition(&mut inner, State::Open { until });
+            }
+            State::Closed => {
+                if inner.outcomes.len() == self.settings.window {
+                    inner.outcomes.pop_front();
+                }
+                inner.outcomes.push_back(success);
+                let failures = inner.outcomes.iter().filter(|ok| !**ok).count();
+                let full = inner.outcomes.len() == self.settings.window;
+                if full && failures as f64 / self.settings.window as f64 >= self.settings.failure_rate {
+                    inner.outcomes.clear();
+                    let until = Instant::now() + self.settings.open_for;
+                    self.transition(&mut inner, State::Open { until });
+                }
+            }
+            // Late results from calls started before the circuit opened
+            State::Open { .. } => {}
+        }
+    }
+}
+
+// One breaker per outbound dependency (a webhook host, the OIDC provider),
+// so a single flaky receiver can't trip calls to the others
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    settings: BreakerSettings,
+    breakers: Arc<Mutex<HashMap<String, Arc<CircuitBreaker>>>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: BreakerSettings) -> Self {
+        CircuitBreakers {
+            settings,
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn for_dependency(&self, dependency: &str) -> Arc<CircuitBreaker> {
+        let mut breakers = self.breakers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        breakers
+            .entry(dependency.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(dependency.to_string(), self.settings)))
+            .clone()
+    }
+}
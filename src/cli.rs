@@ -0,0 +1,86 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::config::LogFormat;
+
+// Command line. Options given here override the config file and environment;
+// with no subcommand the server runs, as `serve` would.
+#[derive(Parser)]
+#[command(name = "actix-web-server", version, about = "Example actix-web service with OpenTelemetry tracing")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    // Kept from before subcommands existed; the same as `migrate`
+    #[arg(long, hide = true)]
+    pub migrate: bool,
+    #[arg(long, global = true, help = "Skip SEED_FILE, e.g. when restarting against an already seeded store")]
+    pub no_seed: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Run the HTTP server (the default)")]
+    Serve,
+    #[command(about = "Apply pending database migrations and exit")]
+    Migrate,
+    #[command(about = "Print the OpenAPI document for the HTTP API and exit")]
+    ExportOpenapi {
+        #[arg(long, short, help = "Write to this file instead of stdout")]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = DocumentFormat::Json, help = "Document format")]
+        format: DocumentFormat,
+    },
+    #[command(about = "Load and validate the configuration, then exit")]
+    CheckConfig,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum DocumentFormat {
+    Json,
+    Yaml,
+}
+
+// Settings the command line can override, by config key
+#[derive(Args)]
+pub struct Overrides {
+    #[arg(long, global = true, value_name = "FILE", help = "Config file to load instead of CONFIG_FILE or ./config.{toml,yaml}")]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "FILE", help = "Environment file to load instead of ENV_FILE or ./.env")]
+    pub env_file: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "HOST", help = "Address to listen on")]
+    pub bind: Option<String>,
+    #[arg(long, global = true, help = "Port to listen on")]
+    pub port: Option<u16>,
+    #[arg(long, global = true, value_name = "URL", help = "OTLP collector for traces and metrics")]
+    pub otlp_endpoint: Option<String>,
+    #[arg(long, global = true, value_name = "FILTER", help = "Log filter, e.g. \"info\" or \"actix_web_server=debug,warn\"")]
+    pub log_level: Option<String>,
+    #[arg(long, global = true, value_enum, help = "json (Bunyan) or text")]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Overrides {
+    // The overridden settings as config keys and values, for the top layer
+    // of the configuration
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = Vec::new();
+        if let Some(bind) = &self.bind {
+            settings.push(("server.host", bind.clone()));
+        }
+        if let Some(port) = self.port {
+            settings.push(("server.port", port.to_string()));
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            settings.push(("telemetry.otlp_endpoint", endpoint.clone()));
+        }
+        if let Some(level) = &self.log_level {
+            settings.push(("telemetry.log_level", level.clone()));
+        }
+        if let Some(format) = self.log_format {
+            settings.push(("telemetry.log_format", format.name().to_string()));
+        }
+        settings
+    }
+}
@@ -0,0 +1,135 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::dev::Extensions;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use ipnet::IpNet;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use std::any::Any;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config;
+
+// The proxies allowed to say who the client is (server.trusted_proxies)
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    // Invalid entries are rejected by ServerSettings::validate at startup
+    pub fn from_env() -> Self {
+        TrustedProxies(config::settings().server.trusted_proxies().unwrap_or_default())
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    // The peer, unless it is a trusted proxy. Then the forwarding chain is
+    // walked from the nearest hop back, skipping further trusted proxies;
+    // the first address not among them is the client. Entries before it
+    // could have been written by anyone, so they're never used. A hop that
+    // doesn't give an address (e.g. "unknown") ends the walk at the last one
+    // that did.
+    fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.trusts(&peer) {
+            return client;
+        }
+        for hop in forwarded_for(headers).into_iter().rev() {
+            let Some(ip) = hop else {
+                break;
+            };
+            client = ip;
+            if !self.trusts(&ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// The address a request is attributed to, in request extensions
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+// User ID of the process on the other end of a Unix socket connection,
+// which has no address to go by
+#[derive(Clone, Copy)]
+pub struct PeerUid(pub u32);
+
+// HttpServer::on_connect hook, alongside tls::on_connect
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<actix_web::rt::net::UnixStream>() {
+        if let Ok(credentials) = stream.peer_cred() {
+            extensions.insert(PeerUid(credentials.uid()));
+        }
+    }
+}
+
+// The client address of a request: as resolved by `resolve`, or the peer's
+// on routes it doesn't wrap
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    match req.extensions().get::<ClientIp>() {
+        Some(ClientIp(ip)) => Some(*ip),
+        None => req.peer_addr().map(|addr| addr.ip()),
+    }
+}
+
+// The forwarding chain, client first, from Forwarded or else X-Forwarded-For.
+// None for hops that don't name an IP address.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = headers.get_all(header::FORWARDED).filter_map(|value| value.to_str().ok()).collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                // Elements without a for= parameter say nothing about the hop
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then(|| parse_node(value.trim().trim_matches('"')))
+                })
+            })
+            .collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+// "192.0.2.1", "192.0.2.1:4711", "[2001:db8::1]" or "[2001:db8::1]:4711"
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+// Scope-level middleware, inside RequestTracing and outside everything that
+// attributes requests to clients. Stores the client address for them and
+// records it on the request span as client.address; http.client_ip is
+// overwritten too, since RequestTracing takes it from the headers whoever
+// sent them.
+pub async fn resolve(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let client = match (peer, req.app_data::<web::Data<TrustedProxies>>()) {
+        (Some(peer), Some(proxies)) => Some(proxies.resolve(peer, req.headers())),
+        (peer, _) => peer,
+    };
+    if let Some(client) = client {
+        req.extensions_mut().insert(ClientIp(client));
+        let span_context = Context::current();
+        let span = span_context.span();
+        span.set_attribute(KeyValue::new("client.address", client.to_string()));
+        span.set_attribute(KeyValue::new("http.client_ip", client.to_string()));
+    }
+
+    next.call(req).await
+}
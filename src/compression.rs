@@ -0,0 +1,137 @@
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, AcceptEncoding, Encoding, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use tracing::warn;
+
+use crate::get_env_or_default;
+
+// Which codecs actix's Compress middleware may pick and how small a body can
+// be before compressing it isn't worth it. COMPRESSION_ALGORITHMS is a
+// comma-separated list of br, zstd, gzip and deflate; empty turns
+// compression off.
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub algorithms: Vec<Encoding>,
+    pub min_bytes: u64,
+}
+
+impl CompressionConfig {
+    pub fn from_env() -> Self {
+        let algorithms = get_env_or_default("COMPRESSION_ALGORITHMS", "br,zstd,gzip")
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| match name.parse::<Encoding>() {
+                Ok(encoding @ Encoding::Known(_)) if encoding != Encoding::identity() => Some(encoding),
+                _ => {
+                    warn!(algorithm = %name, "Ignoring unknown compression algorithm");
+                    None
+                }
+            })
+            .collect();
+        CompressionConfig {
+            algorithms,
+            min_bytes: get_env_or_default("COMPRESSION_MIN_BYTES", "1024").parse().unwrap_or(1024),
+        }
+    }
+
+    // The encoding to use for a request, limited to the configured codecs
+    fn negotiate(&self, req: &ServiceRequest) -> Option<Encoding> {
+        let accept = req.get_header::<AcceptEncoding>()?;
+        let identity = Encoding::identity();
+        Some(
+            accept
+                .negotiate(self.algorithms.iter().chain(std::iter::once(&identity)))
+                .unwrap_or(identity),
+        )
+    }
+}
+
+// Left by `prepare` on responses it kept Compress away from
+struct BelowThreshold;
+
+// Body size before compression, for the ratio
+struct UncompressedSize(u64);
+
+// Runs just inside Compress. Compress skips responses that already carry a
+// Content-Encoding, so bodies under the threshold are marked "identity";
+// `observe` removes the marker again before the response leaves.
+pub async fn prepare(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let min_bytes = req
+        .app_data::<web::Data<CompressionConfig>>()
+        .map_or(0, |config| config.min_bytes);
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    if let BodySize::Sized(size) = res.response().body().size() {
+        if size < min_bytes && !res.headers().contains_key(header::CONTENT_ENCODING) {
+            res.headers_mut()
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+            res.response_mut().extensions_mut().insert(BelowThreshold);
+        } else {
+            res.response_mut().extensions_mut().insert(UncompressedSize(size));
+        }
+    }
+    Ok(res)
+}
+
+// Runs just outside Compress and inside RequestTracing. Pins Compress to a
+// configured codec by rewriting Accept-Encoding to the negotiated one, then
+// records the encoding actually used and, for bodies of known size, the
+// compression ratio on the request span.
+pub async fn observe(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(config) = req.app_data::<web::Data<CompressionConfig>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    if let Some(encoding) = config.negotiate(&req) {
+        if let Ok(value) = HeaderValue::from_str(&encoding.to_string()) {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, value);
+        }
+    }
+
+    let mut res = next.call(req).await?;
+    if res.response_mut().extensions_mut().remove::<BelowThreshold>().is_some() {
+        res.headers_mut().remove(header::CONTENT_ENCODING);
+    }
+    let encoding = res
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("identity")
+        .to_string();
+    let context = Context::current();
+    let span = context.span();
+    span.set_attribute(KeyValue::new("http.response.content_encoding", encoding.clone()));
+
+    let uncompressed = res.response_mut().extensions_mut().remove::<UncompressedSize>();
+    let Some(UncompressedSize(uncompressed)) = uncompressed.filter(|_| encoding != "identity") else {
+        return Ok(res.map_into_boxed_body());
+    };
+
+    // The body was already in memory, so buffering its compressed form to
+    // measure it costs little
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let compressed = body::to_bytes(body)
+        .await
+        .map_err(|err| actix_web::error::ErrorInternalServerError(err.into()))?;
+    span.set_attribute(KeyValue::new("http.response.uncompressed_size", uncompressed as i64));
+    span.set_attribute(KeyValue::new("http.response.compressed_size", compressed.len() as i64));
+    if !compressed.is_empty() {
+        span.set_attribute(KeyValue::new(
+            "http.response.compression_ratio",
+            uncompressed as f64 / compressed.len() as f64,
+        ));
+    }
+
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(compressed))))
+}
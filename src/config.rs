@@ -0,0 +1,719 @@
+use clap::ValueEnum;
+use figment::providers::{Format, Serialized, Toml, Yaml};
+use figment::Figment;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Environment variables that override a setting, by the key they set. The
+// names are the ones the server always read, so existing deployments keep
+// working without a config file.
+const ENV: &[(&str, &str)] = &[
+    ("HOST", "server.host"),
+    ("PORT", "server.port"),
+    ("WORKERS", "server.workers"),
+    ("MAX_BLOCKING_THREADS", "server.max_blocking_threads"),
+    ("LISTEN_BACKLOG", "server.backlog"),
+    ("KEEP_ALIVE_SECS", "server.keep_alive_secs"),
+    ("CLIENT_REQUEST_TIMEOUT_MS", "server.client_request_timeout_ms"),
+    ("CLIENT_DISCONNECT_TIMEOUT_MS", "server.client_disconnect_timeout_ms"),
+    ("SHUTDOWN_TIMEOUT_SECS", "server.shutdown_timeout_secs"),
+    ("UNIX_SOCKET", "server.unix_socket"),
+    ("UNIX_SOCKET_MODE", "server.unix_socket_mode"),
+    ("ADMIN_BIND", "server.admin_bind"),
+    ("TRUSTED_PROXIES", "server.trusted_proxies"),
+    ("BASE_PATH", "server.base_path"),
+    ("TLS_CERT_FILE", "server.tls_cert_file"),
+    ("TLS_KEY_FILE", "server.tls_key_file"),
+    ("TLS_WATCH_INTERVAL_SECS", "server.tls_watch_interval_secs"),
+    ("H2_INITIAL_WINDOW_SIZE", "server.h2_initial_window_size"),
+    ("H2_INITIAL_CONNECTION_WINDOW_SIZE", "server.h2_initial_connection_window_size"),
+    ("RESPONSE_ENVELOPE", "server.response_envelope"),
+    ("OTLP_ENDPOINT", "telemetry.otlp_endpoint"),
+    ("LOG_LEVEL", "telemetry.log_level"),
+    ("LOG_FORMAT", "telemetry.log_format"),
+    ("TRACE_SAMPLING_RATIO", "telemetry.sampling_ratio"),
+    ("TRACE_URL_TEMPLATE", "telemetry.trace_url_template"),
+    ("STORAGE_BACKEND", "storage.backend"),
+    ("USER_CACHE", "storage.cache"),
+    ("STORE_SHARDS", "storage.shards"),
+    ("STATE_SYNC", "storage.state_sync"),
+    ("DATABASE_URL", "storage.database_url"),
+    ("DATABASE_MAX_CONNECTIONS", "storage.database_max_connections"),
+    ("DATABASE_AUTO_MIGRATE", "storage.database_auto_migrate"),
+    ("REDIS_URL", "storage.redis_url"),
+    ("MONGODB_URL", "storage.mongodb_url"),
+    ("MONGODB_DATABASE", "storage.mongodb_database"),
+    ("ADMIN_TOKEN", "auth.admin_token"),
+    ("ADMIN_USERNAME", "auth.admin_username"),
+    ("ADMIN_PASSWORD", "auth.admin_password"),
+    ("JWT_SIGNING_KEY", "auth.jwt_signing_key"),
+    ("JWT_ACCESS_TTL_SECS", "auth.jwt_access_ttl_secs"),
+    ("JWT_REFRESH_TTL_SECS", "auth.jwt_refresh_ttl_secs"),
+    ("REQUIRE_AUTH", "auth.require_auth"),
+    ("RATE_LIMIT_PER_SECOND", "rate_limit.per_second"),
+    ("RATE_LIMIT_BURST", "rate_limit.burst"),
+    ("CORS_ALLOWED_ORIGINS", "cors.allowed_origins"),
+    ("CORS_ALLOWED_METHODS", "cors.allowed_methods"),
+    ("CORS_ALLOWED_HEADERS", "cors.allowed_headers"),
+    ("CORS_EXPOSED_HEADERS", "cors.exposed_headers"),
+    ("CORS_ALLOW_CREDENTIALS", "cors.allow_credentials"),
+    ("CORS_MAX_AGE_SECS", "cors.max_age_secs"),
+    ("SCHEDULE_STATE_SNAPSHOT", "scheduler.state_snapshot"),
+    ("SCHEDULE_STATS_REFRESH", "scheduler.stats_refresh"),
+    ("SCHEDULE_SESSION_CLEANUP", "scheduler.session_cleanup"),
+];
+
+// Keys whose environment variable holds a comma-separated list
+const LIST_KEYS: &[&str] = &[
+    "server.trusted_proxies",
+    "cors.allowed_origins",
+    "cors.allowed_methods",
+    "cors.allowed_headers",
+    "cors.exposed_headers",
+];
+
+// Field names whose values are secrets, masked in the configuration logged
+// at startup
+const SECRET_FIELDS: &[&str] = &["admin_token", "admin_password", "jwt_signing_key"];
+const REDACTED: &str = "[redacted]";
+
+// Keys config_reload applies to the running server; changes to any other key
+// only take effect on restart
+pub const RELOADABLE_KEYS: &[&str] = &[
+    "telemetry.log_level",
+    "telemetry.sampling_ratio",
+    "rate_limit.per_second",
+    "rate_limit.burst",
+    "cors.allowed_origins",
+];
+
+// actix's limit on workers, and the blocking threads it shares between
+// workers by default
+const MAX_WORKERS: usize = 512;
+const BLOCKING_THREAD_BUDGET: usize = 512;
+
+// Largest flow control window HTTP/2 allows (RFC 9113, section 6.9.1)
+const MAX_H2_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+// Files looked for in the working directory when neither --config nor
+// CONFIG_FILE is given
+const DEFAULT_FILES: &[&str] = &["config.toml", "config.yaml", "config.yml"];
+
+// Settings for the server, built once at startup from, lowest precedence
+// first: the defaults below, a TOML or YAML config file, environment
+// variables, then command-line options. Settings of other features are still
+// read from the environment by their own modules.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub telemetry: TelemetrySettings,
+    pub storage: StorageSettings,
+    pub auth: AuthSettings,
+    pub rate_limit: RateLimitSettings,
+    pub cors: CorsSettings,
+    pub scheduler: SchedulerSettings,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    // An IP address or a host name resolving to the addresses to listen on
+    pub host: String,
+    // 0 picks a free port; the one bound is logged at startup
+    pub port: u16,
+    // Worker threads serving the API; unset means one per available CPU
+    pub workers: Option<usize>,
+    // Blocking pool size of each worker, for web::block and similar; unset
+    // shares 512 threads between the available CPUs
+    pub max_blocking_threads: Option<usize>,
+    // Pending connections the OS queues on each listener before refusing more
+    pub backlog: u32,
+    // How long an idle connection is kept open for another request
+    pub keep_alive_secs: u64,
+    // How long a new connection has to send its first request head before
+    // getting a 408
+    pub client_request_timeout_ms: u64,
+    // How long a closing connection waits for the client to finish the
+    // shutdown before it is dropped
+    pub client_disconnect_timeout_ms: u64,
+    // How long a stopping server waits for in-flight requests before
+    // dropping them, after SIGTERM, SIGINT or POST /admin/drain
+    pub shutdown_timeout_secs: u64,
+    // Serve the API on this Unix socket instead of host and port, e.g. for a
+    // proxy sidecar sharing the pod. The socket is plain HTTP.
+    pub unix_socket: Option<String>,
+    // Octal permissions for the socket, e.g. "660"; unset leaves the umask's
+    pub unix_socket_mode: Option<String>,
+    // Separate listener for /admin, e.g. "127.0.0.1:9090"
+    pub admin_bind: Option<String>,
+    // Addresses or CIDR ranges of proxies in front of the server, e.g.
+    // "10.0.0.0/8". Requests from these may name the client in Forwarded or
+    // X-Forwarded-For; from anywhere else those headers are ignored.
+    pub trusted_proxies: Vec<String>,
+    // Path prefix the API is served under, e.g. "/api" behind an ingress that
+    // forwards /api/... unchanged. Requests outside it get a 404; the admin
+    // listener isn't prefixed.
+    pub base_path: String,
+    // Further listeners, set in the config file only
+    pub listeners: Vec<ListenerSettings>,
+    // PEM files; with both set the API listeners serve HTTPS
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // How often the TLS files are checked for changes; 0 reloads on SIGHUP only
+    pub tls_watch_interval_secs: u64,
+    // HTTP/2 flow control windows in bytes, for connections that negotiated
+    // h2 over TLS; unset keeps actix's 1 MiB per stream and 2 MiB per connection
+    pub h2_initial_window_size: Option<u32>,
+    pub h2_initial_connection_window_size: Option<u32>,
+    // Wrap responses in an envelope unless a request opts out
+    pub response_envelope: bool,
+}
+
+// An extra address to listen on and what it serves. The API listeners share
+// the app bound to host and port; admin listeners share the admin app, which
+// leaves the API listeners as it would with ADMIN_BIND.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    pub address: String,
+    #[serde(default)]
+    pub serves: ListenerRole,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    #[default]
+    Api,
+    Admin,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            workers: None,
+            max_blocking_threads: None,
+            backlog: 1024,
+            keep_alive_secs: 5,
+            client_request_timeout_ms: 5000,
+            client_disconnect_timeout_ms: 1000,
+            shutdown_timeout_secs: 30,
+            unix_socket: None,
+            unix_socket_mode: None,
+            admin_bind: None,
+            trusted_proxies: Vec::new(),
+            base_path: String::new(),
+            listeners: Vec::new(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_watch_interval_secs: 30,
+            h2_initial_window_size: None,
+            h2_initial_connection_window_size: None,
+            response_envelope: false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetrySettings {
+    pub otlp_endpoint: String,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    // Share of traces sampled at startup; /admin/telemetry/sampling changes it later
+    pub sampling_ratio: f64,
+    // Link to a trace in the trace backend's UI, with {trace_id} standing for
+    // the ID, e.g. "http://localhost:16686/trace/{trace_id}" for Jaeger. Used
+    // by the admin dashboard.
+    pub trace_url_template: Option<String>,
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        TelemetrySettings {
+            otlp_endpoint: "http://localhost:4317".to_string(),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Json,
+            sampling_ratio: 1.0,
+            trace_url_template: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    // The socket addresses `host` and `port` stand for, all of which the
+    // server listens on
+    pub fn addresses(&self) -> Result<Vec<SocketAddr>, String> {
+        if self.host.is_empty() {
+            return Err("HOST is empty".to_string());
+        }
+        let addresses: Vec<SocketAddr> = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|err| format!("invalid HOST '{}': {}", self.host, err))?
+            .collect();
+        if addresses.is_empty() {
+            return Err(format!("HOST '{}' resolves to no addresses", self.host));
+        }
+        Ok(addresses)
+    }
+
+    // The worker count and per-worker blocking pool size in effect, working
+    // out actix's defaults for those not set
+    pub fn worker_count(&self) -> usize {
+        self.workers.unwrap_or_else(|| available_parallelism().min(MAX_WORKERS))
+    }
+
+    pub fn blocking_threads_per_worker(&self) -> usize {
+        self.max_blocking_threads.unwrap_or_else(|| (BLOCKING_THREAD_BUDGET / available_parallelism()).max(1))
+    }
+
+    pub fn unix_socket_mode(&self) -> Result<Option<u32>, String> {
+        let Some(mode) = &self.unix_socket_mode else {
+            return Ok(None);
+        };
+        match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+            _ => Err(format!("UNIX_SOCKET_MODE '{}' isn't an octal mode such as 660", mode)),
+        }
+    }
+
+    // trusted_proxies as networks; a plain address is a network of one
+    pub fn trusted_proxies(&self) -> Result<Vec<IpNet>, String> {
+        self.trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("TRUSTED_PROXIES: '{}' is neither an IP address nor a CIDR range", proxy))
+            })
+            .collect()
+    }
+
+    // Where the API is mounted: the base path, or "/" without one
+    pub fn mount_point(&self) -> &str {
+        match self.base_path.as_str() {
+            "" => "/",
+            base_path => base_path,
+        }
+    }
+
+    // Empty, or a path such as "/api" or "/internal/users-api": a leading
+    // slash, no trailing one, and nothing a route pattern would treat specially
+    fn validate_base_path(&self) -> Result<(), String> {
+        let path = &self.base_path;
+        if path.is_empty() {
+            return Ok(());
+        }
+        let valid = path.starts_with('/')
+            && !path.ends_with('/')
+            && !path.contains("//")
+            && path.chars().all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c));
+        if !valid {
+            return Err(format!("BASE_PATH '{}' must look like /api: a leading slash and no trailing one", path));
+        }
+        Ok(())
+    }
+
+    // Addresses of the extra listeners serving `role`; for admin, ADMIN_BIND
+    // comes first
+    pub fn listeners(&self, role: ListenerRole) -> Vec<&str> {
+        let admin_bind = self.admin_bind.as_deref().filter(|bind| !bind.is_empty() && role == ListenerRole::Admin);
+        admin_bind
+            .into_iter()
+            .chain(self.listeners.iter().filter(|listener| listener.serves == role).map(|listener| listener.address.as_str()))
+            .collect()
+    }
+
+    // Every configured address must resolve, binding them is left to startup,
+    // and the thread counts, socket mode, base path and HTTP/2 windows must be
+    // in range
+    pub fn validate(&self) -> Result<(), String> {
+        if self.workers.is_some_and(|workers| !(1..=MAX_WORKERS).contains(&workers)) {
+            return Err(format!("WORKERS must be between 1 and {}", MAX_WORKERS));
+        }
+        if self.max_blocking_threads == Some(0) {
+            return Err("MAX_BLOCKING_THREADS must be at least 1".to_string());
+        }
+        if self.backlog == 0 {
+            return Err("LISTEN_BACKLOG must be at least 1".to_string());
+        }
+        self.unix_socket_mode()?;
+        self.trusted_proxies()?;
+        self.validate_base_path()?;
+        if self.unix_socket.is_none() {
+            self.addresses()?;
+        }
+        let windows = [
+            ("H2_INITIAL_WINDOW_SIZE", self.h2_initial_window_size),
+            ("H2_INITIAL_CONNECTION_WINDOW_SIZE", self.h2_initial_connection_window_size),
+        ];
+        for (name, size) in windows {
+            if size.is_some_and(|size| size > MAX_H2_WINDOW_SIZE) {
+                return Err(format!("{} must be at most {}", name, MAX_H2_WINDOW_SIZE));
+            }
+        }
+        for listener in self.listeners(ListenerRole::Api).into_iter().chain(self.listeners(ListenerRole::Admin)) {
+            let mut addresses = listener
+                .to_socket_addrs()
+                .map_err(|err| format!("invalid listener address '{}': {}", listener, err))?;
+            if addresses.next().is_none() {
+                return Err(format!("listener address '{}' resolves to no addresses", listener));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn available_parallelism() -> usize {
+    std::thread::available_parallelism().map_or(2, |parallelism| parallelism.get())
+}
+
+// How log lines are written to stdout: Bunyan JSON, or plain text for
+// reading in a terminal
+#[derive(Clone, Copy, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+impl LogFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            LogFormat::Text => "text",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub backend: String,
+    pub cache: String,
+    pub shards: usize,
+    pub state_sync: String,
+    // Connection settings stay unset unless given, so storage can tell a URL
+    // meant for another backend from a default
+    pub database_url: Option<String>,
+    // Defaults to 10 for PostgreSQL and 5 for SQLite
+    pub database_max_connections: Option<u32>,
+    pub database_auto_migrate: bool,
+    pub redis_url: Option<String>,
+    pub mongodb_url: Option<String>,
+    pub mongodb_database: String,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            backend: "memory".to_string(),
+            cache: "none".to_string(),
+            shards: 16,
+            state_sync: "none".to_string(),
+            database_url: None,
+            database_max_connections: None,
+            database_auto_migrate: true,
+            redis_url: None,
+            mongodb_url: None,
+            mongodb_database: "actix_example".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    pub admin_token: Option<String>,
+    pub admin_username: Option<String>,
+    pub admin_password: Option<String>,
+    pub jwt_signing_key: Option<String>,
+    pub jwt_access_ttl_secs: i64,
+    pub jwt_refresh_ttl_secs: i64,
+    // Require an access token on mutating routes
+    pub require_auth: bool,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            admin_token: None,
+            admin_username: None,
+            admin_password: None,
+            jwt_signing_key: None,
+            jwt_access_ttl_secs: 900,
+            jwt_refresh_ttl_secs: 1_209_600,
+            require_auth: false,
+        }
+    }
+}
+
+// Per-client token bucket: each client may burst up to `burst` requests and
+// is refilled at `per_second`; 0 turns limiting off
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings { per_second: 50.0, burst: 100.0 }
+    }
+}
+
+// Cross-origin settings for browser frontends. CORS stays off until
+// allowed_origins lists an origin, or "*" for any.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        let list = |items: &str| items.split(", ").map(str::to_string).collect();
+        CorsSettings {
+            allowed_origins: Vec::new(),
+            allowed_methods: list("GET, POST, PUT, DELETE"),
+            allowed_headers: list("Authorization, Content-Type, Content-Encoding, X-Api-Key, X-CSRF-Token, X-Tenant-Id"),
+            // Lets frontends read pagination, caching and quota headers
+            exposed_headers: list("X-Total-Count, Link, ETag, X-Cache, Age, X-Quota-Limit, X-Quota-Remaining, X-Quota-Reset"),
+            allow_credentials: false,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsSettings {
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+}
+
+// When the background jobs run: cron expressions with a leading seconds field,
+// in UTC, e.g. "0 */5 * * * *" for every five minutes. An empty expression or
+// "off" turns the job off; environment variables need "off", since empty ones
+// count as unset.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerSettings {
+    // Writes a state snapshot when SNAPSHOT_DIR is set
+    pub state_snapshot: String,
+    // Recomputes the cached per-domain user counts
+    pub stats_refresh: String,
+    // Drops expired browser sessions nobody has presented since
+    pub session_cleanup: String,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        SchedulerSettings {
+            state_snapshot: "0 */5 * * * *".to_string(),
+            stats_refresh: "30 * * * * *".to_string(),
+            session_cleanup: "0 */15 * * * *".to_string(),
+        }
+    }
+}
+
+impl SchedulerSettings {
+    // Each job's name and expression, disabled jobs included
+    pub fn jobs(&self) -> [(&'static str, &str); 3] {
+        [
+            ("state_snapshot", &self.state_snapshot),
+            ("stats_refresh", &self.stats_refresh),
+            ("session_cleanup", &self.session_cleanup),
+        ]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (job, expression) in self.jobs() {
+            if !job_disabled(expression) {
+                cron::Schedule::from_str(expression)
+                    .map_err(|err| format!("scheduler.{}: invalid cron expression '{}': {}", job, expression, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn job_disabled(expression: &str) -> bool {
+    expression.is_empty() || expression.eq_ignore_ascii_case("off")
+}
+
+impl Settings {
+    // `file` (from --config) or else CONFIG_FILE names the config file, which
+    // must then exist; otherwise the first of config.toml, config.yaml and
+    // config.yml present is used, if any. Empty environment variables count
+    // as unset. `overrides` (from the command line) go on top of everything.
+    pub fn load(file: Option<PathBuf>, overrides: &[(&'static str, String)]) -> Result<Self, String> {
+        let named = file.or_else(|| std::env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty()).map(PathBuf::from));
+        let file = match named {
+            Some(path) if !path.is_file() => return Err(format!("config file {} does not exist", path.display())),
+            Some(path) => Some(path),
+            None => DEFAULT_FILES.iter().map(PathBuf::from).find(|path| path.is_file()),
+        };
+
+        let mut figment = Figment::from(Serialized::defaults(Settings::default()));
+        if let Some(path) = &file {
+            figment = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("yaml" | "yml") => figment.merge(Yaml::file(path)),
+                _ => figment.merge(Toml::file(path)),
+            };
+        }
+        // Values are merged as strings; lossy extraction turns "true" or "10"
+        // into the field's type, as the config file would spell them
+        for (var, key) in ENV {
+            let Some(value) = std::env::var(var).ok().filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            figment = if LIST_KEYS.contains(key) {
+                let items: Vec<&str> = value.split(',').map(str::trim).filter(|item| !item.is_empty()).collect();
+                figment.merge(Serialized::default(key, items))
+            } else {
+                figment.merge(Serialized::default(key, value))
+            };
+        }
+        for (key, value) in overrides {
+            figment = figment.merge(Serialized::default(key, value));
+        }
+        // figment's own message names the code location that merged a value,
+        // which for environment and command-line values is this function;
+        // only config files are worth pointing at
+        figment.extract_lossy().map_err(|err| {
+            let errors: Vec<String> = err
+                .into_iter()
+                .map(|err| {
+                    let key = err.path.join(".");
+                    match err.metadata.as_ref().and_then(|metadata| metadata.source.as_ref()) {
+                        Some(figment::Source::File(path)) => format!("{} in {}: {}", key, path.display(), err.kind),
+                        _ => format!("{}: {}", key, err.kind),
+                    }
+                })
+                .collect();
+            format!("invalid configuration: {}", errors.join("; "))
+        })
+    }
+
+    // The settings as JSON with secrets masked, for logging: secret fields
+    // entirely, and the password of any URL with credentials, such as a
+    // database URL or an OTLP endpoint behind basic auth
+    pub fn redacted(&self) -> serde_json::Value {
+        fn redact(name: &str, value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(fields) => {
+                    for (name, value) in fields.iter_mut() {
+                        redact(name, value);
+                    }
+                }
+                serde_json::Value::Null => {}
+                _ if SECRET_FIELDS.contains(&name) => {
+                    *value = serde_json::Value::from(REDACTED);
+                }
+                serde_json::Value::String(text) => {
+                    if let Some(masked) = mask_url_password(text) {
+                        *text = masked;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact("", &mut value);
+        value
+    }
+
+    // Dotted keys whose values differ between `self` and `other`
+    pub fn changed_keys(&self, other: &Settings) -> Vec<String> {
+        fn diff(prefix: &str, old: &serde_json::Value, new: &serde_json::Value, changed: &mut Vec<String>) {
+            match (old, new) {
+                (serde_json::Value::Object(old), serde_json::Value::Object(new)) => {
+                    for (name, value) in old {
+                        let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                        diff(&key, value, new.get(name).unwrap_or(&serde_json::Value::Null), changed);
+                    }
+                }
+                (old, new) if old != new => changed.push(prefix.to_string()),
+                _ => {}
+            }
+        }
+
+        let mut changed = Vec::new();
+        let (old, new) = (serde_json::to_value(self), serde_json::to_value(other));
+        if let (Ok(old), Ok(new)) = (old, new) {
+            diff("", &old, &new, &mut changed);
+        }
+        changed
+    }
+}
+
+// Load variables from an env file into the environment, for local
+// development. `file` (from --env-file) or else ENV_FILE names the file, which
+// must then exist; otherwise ./.env is loaded if present. Variables already
+// set in the environment win over the file. Called before anything reads the
+// environment, so the file can set any variable the server looks at.
+pub fn load_env_file(file: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+    let named = file.or_else(|| std::env::var("ENV_FILE").ok().filter(|path| !path.is_empty()).map(PathBuf::from));
+    let path = match named {
+        Some(path) if !path.is_file() => return Err(format!("env file {} does not exist", path.display())),
+        Some(path) => path,
+        None if Path::new(".env").is_file() => PathBuf::from(".env"),
+        None => return Ok(None),
+    };
+    dotenvy::from_path(&path).map_err(|err| format!("invalid env file {}: {}", path.display(), err))?;
+    Ok(Some(path))
+}
+
+// "postgres://app:secret@db/users" becomes "postgres://app:[redacted]@db/users";
+// None for anything without a password
+fn mask_url_password(text: &str) -> Option<String> {
+    let (scheme, rest) = text.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let (userinfo, host) = authority.rsplit_once('@')?;
+    let (user, _) = userinfo.split_once(':')?;
+    Some(format!("{}://{}:{}@{}{}", scheme, user, REDACTED, host, &rest[authority.len()..]))
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+// Where the settings came from, for reload: the config file named on the
+// command line and the command-line overrides
+struct Sources {
+    file: Option<PathBuf>,
+    overrides: Vec<(&'static str, String)>,
+}
+
+static SOURCES: OnceLock<Sources> = OnceLock::new();
+
+// Load the settings for the rest of the process; called first thing in main
+pub fn init(file: Option<PathBuf>, overrides: &[(&'static str, String)]) -> Result<&'static Settings, String> {
+    let settings = Settings::load(file.clone(), overrides)?;
+    let _ = SOURCES.set(Sources { file, overrides: overrides.to_vec() });
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+// Load the settings again from the same config file, environment and
+// command line. settings() keeps returning those loaded at startup;
+// config_reload applies whichever changes can take effect without a restart.
+pub fn reload() -> Result<Settings, String> {
+    let sources = SOURCES.get().expect("settings are loaded at startup");
+    Settings::load(sources.file.clone(), &sources.overrides)
+}
+
+pub fn settings() -> &'static Settings {
+    SETTINGS.get().expect("settings are loaded at startup")
+}
@@ -0,0 +1,142 @@
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use std::sync::Mutex;
+use tracing::{info, instrument, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::admin::{AdminIdentity, AuditLog, LogLevelControl};
+use crate::config::{self, Settings, RELOADABLE_KEYS};
+use crate::cors::AllowedOrigins;
+use crate::error::AppError;
+use crate::ratelimit::RateLimiter;
+use crate::sampling::ReloadableSampler;
+
+// Re-reads the configuration on SIGHUP or POST /admin/config/reload and
+// applies what can change at runtime: the log level, the sampling ratio, rate
+// limits and CORS origins. Only settings whose configured value changed are
+// applied, so adjustments made through the other admin endpoints stay until
+// the configuration changes the same setting. Changes to anything else are
+// logged and left for the next restart.
+pub struct ConfigReloader {
+    // The configuration as applied: startup values, plus what reloads changed
+    applied: Mutex<Settings>,
+    log_level: web::Data<LogLevelControl>,
+    sampler: web::Data<ReloadableSampler>,
+    rate_limiter: web::Data<RateLimiter>,
+    cors_origins: AllowedOrigins,
+}
+
+#[derive(Serialize)]
+pub struct ReloadOutcome {
+    applied: Vec<String>,
+    restart_required: Vec<String>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        log_level: web::Data<LogLevelControl>,
+        sampler: web::Data<ReloadableSampler>,
+        rate_limiter: web::Data<RateLimiter>,
+        cors_origins: AllowedOrigins,
+    ) -> Self {
+        ConfigReloader {
+            applied: Mutex::new(config::settings().clone()),
+            log_level,
+            sampler,
+            rate_limiter,
+            cors_origins,
+        }
+    }
+
+    // Load and apply the configuration. Nothing is applied unless every
+    // changed setting is valid.
+    pub fn reload(&self, trigger: &'static str) -> Result<ReloadOutcome, String> {
+        let loaded = config::reload()?;
+        let mut applied = self.applied.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Turning CORS on or off changes the middleware stack, which is
+        // built at startup
+        let cors_toggled = applied.cors.is_enabled() != loaded.cors.is_enabled();
+        let (reloadable, restart_required): (Vec<String>, Vec<String>) =
+            applied.changed_keys(&loaded).into_iter().partition(|key| {
+                RELOADABLE_KEYS.contains(&key.as_str()) && !(key == "cors.allowed_origins" && cors_toggled)
+            });
+        let changed = |key: &str| reloadable.iter().any(|reloaded| reloaded == key);
+
+        let filter = if changed("telemetry.log_level") {
+            let filter = EnvFilter::try_new(&loaded.telemetry.log_level)
+                .map_err(|err| format!("telemetry.log_level: invalid log directive: {}", err))?;
+            Some(filter)
+        } else {
+            None
+        };
+        if !(0.0..=1.0).contains(&loaded.telemetry.sampling_ratio) {
+            return Err("telemetry.sampling_ratio: must be between 0 and 1".to_string());
+        }
+
+        if let Some(filter) = filter {
+            self.log_level
+                .apply(filter, &loaded.telemetry.log_level)
+                .map_err(|err| format!("telemetry.log_level: {}", err))?;
+            applied.telemetry.log_level = loaded.telemetry.log_level.clone();
+        }
+        if changed("telemetry.sampling_ratio") {
+            let mut policy = self.sampler.policy();
+            policy.ratio = loaded.telemetry.sampling_ratio;
+            self.sampler.replace(policy);
+            applied.telemetry.sampling_ratio = loaded.telemetry.sampling_ratio;
+        }
+        if changed("rate_limit.per_second") || changed("rate_limit.burst") {
+            self.rate_limiter.set_limits(loaded.rate_limit.per_second, loaded.rate_limit.burst);
+            applied.rate_limit = loaded.rate_limit.clone();
+        }
+        if changed("cors.allowed_origins") {
+            self.cors_origins.set(&loaded.cors.allowed_origins);
+            applied.cors.allowed_origins = loaded.cors.allowed_origins.clone();
+        }
+
+        if !restart_required.is_empty() {
+            warn!(trigger, keys = ?restart_required, "Configuration changes need a restart to take effect; not applied");
+        }
+        info!(trigger, applied = ?reloadable, "Configuration reloaded");
+        Ok(ReloadOutcome { applied: reloadable, restart_required })
+    }
+}
+
+// Background task reloading on SIGHUP, started in phase 3
+pub async fn reload_on_hangup(reloader: web::Data<ConfigReloader>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(error = %err, "Failed to listen for SIGHUP; configuration reloads through the admin API only");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(err) = reloader.reload("sighup") {
+            warn!(error = %err, "Configuration reload failed; keeping the current settings");
+        }
+    }
+}
+
+// Handler for POST /admin/config/reload
+#[post("/config/reload")]
+#[instrument(name = "admin_reload_config_handler", skip_all, fields(service = "actix_example"))]
+async fn reload_config(
+    identity: web::ReqData<AdminIdentity>,
+    reloader: web::Data<ConfigReloader>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    match reloader.reload("admin") {
+        Ok(outcome) => {
+            audit.record(&identity.0, "reload_config", format!("applied {:?}", outcome.applied));
+            HttpResponse::Ok().json(outcome)
+        }
+        Err(err) => {
+            warn!(error = %err, "Configuration reload failed; keeping the current settings");
+            AppError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_config", err).error_response()
+        }
+    }
+}
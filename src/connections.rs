@@ -0,0 +1,55 @@
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::telemetry::record_connection_timeout;
+
+// Where actix's HTTP/1 dispatcher logs its timers running out
+const DISPATCHER: &str = "actix_http::h1::dispatcher";
+
+// actix doesn't report why it closed a connection other than through its
+// own trace events, so timeouts are counted by watching for those. The
+// messages are matched as actix-http 3 words them; HTTP/2 connections
+// aren't covered.
+const TIMEOUTS: &[(&str, &str)] = &[
+    ("timer timed out; closing connection", "keep_alive"),
+    ("timed out on slow request; replying with 408 and closing connection", "client_request"),
+    ("timed-out during shutdown", "client_disconnect"),
+];
+
+// Layer counting the connections closed by KEEP_ALIVE_SECS,
+// CLIENT_REQUEST_TIMEOUT_MS and CLIENT_DISCONNECT_TIMEOUT_MS, added to the
+// subscriber with `filter()` so it sees actix's trace events whatever the
+// log level
+pub struct ConnectionTimeouts;
+
+impl ConnectionTimeouts {
+    pub fn filter() -> Targets {
+        Targets::new().with_target(DISPATCHER, Level::TRACE)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for ConnectionTimeouts {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = Message(None);
+        event.record(&mut message);
+        let Some(message) = message.0 else {
+            return;
+        };
+        if let Some((_, timeout)) = TIMEOUTS.iter().find(|(text, _)| *text == message) {
+            record_connection_timeout(timeout);
+        }
+    }
+}
+
+struct Message(Option<String>);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use actix_cors::Cors;
+use actix_web::http::header::HeaderValue;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+use crate::config::CorsSettings;
+
+// Origins the CORS middleware accepts, shared by every worker's middleware so
+// a config reload can change them without rebuilding it
+#[derive(Clone)]
+pub struct AllowedOrigins(Arc<RwLock<Vec<String>>>);
+
+impl AllowedOrigins {
+    pub fn new(origins: &[String]) -> Self {
+        AllowedOrigins(Arc::new(RwLock::new(well_formed(origins))))
+    }
+
+    pub fn set(&self, origins: &[String]) {
+        *self.0.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = well_formed(origins);
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        let origins = self.0.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        origins.iter().any(|allowed| allowed == "*" || origin.as_bytes() == allowed.as_bytes())
+    }
+}
+
+// "*" or origins with a scheme, e.g. "https://app.example.com"
+fn well_formed(origins: &[String]) -> Vec<String> {
+    origins
+        .iter()
+        .filter(|origin| {
+            let valid = *origin == "*" || origin.parse::<actix_web::http::Uri>().is_ok_and(|uri| uri.scheme().is_some());
+            if !valid {
+                warn!(origin = %origin, "Ignoring malformed CORS origin");
+            }
+            valid
+        })
+        .cloned()
+        .collect()
+}
+
+// Build the middleware; called once per worker
+pub fn middleware(settings: &CorsSettings, origins: &AllowedOrigins) -> Cors {
+    let origins = origins.clone();
+    let mut cors = Cors::default()
+        .allowed_origin_fn(move |origin, _| origins.allows(origin))
+        .allowed_methods(settings.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(settings.allowed_headers.iter().map(String::as_str))
+        .expose_headers(settings.exposed_headers.iter().map(String::as_str))
+        .max_age(settings.max_age_secs);
+    if settings.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors
+}
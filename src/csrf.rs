@@ -0,0 +1,69 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use tracing::info;
+
+use crate::admin::constant_time_eq;
+use crate::auth::Identity;
+use crate::error::AppError;
+use crate::sessions::SessionConfig;
+
+// Cookie set next to the session cookie on login
+pub const COOKIE_NAME: &str = "csrf_token";
+
+// Header the client copies the cookie value into
+const HEADER_NAME: &str = "X-CSRF-Token";
+
+// Starting a new session needs no token; the old one is replaced anyway
+const EXEMPT_PATHS: &[&str] = &["/auth/session/login"];
+
+fn forbidden(req: ServiceRequest, detail: &str) -> ServiceResponse<BoxBody> {
+    let response = AppError::new(StatusCode::FORBIDDEN, "csrf_token_mismatch", detail).error_response();
+    req.into_response(response)
+}
+
+// Double-submit CSRF check for requests authenticated by the session cookie.
+// Browsers attach that cookie to cross-site requests too, so mutating
+// requests must also echo the csrf_token cookie in X-CSRF-Token, which only
+// pages on our own origin can read. Token and bearer/API key requests are
+// not exposed to CSRF and pass straight through. Runs inside `authenticate`.
+pub async fn verify(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let session_id = match req.extensions().get::<Identity>() {
+        Some(Identity::Session { session_id, .. }) => Some(*session_id),
+        _ => None,
+    };
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let (Some(session_id), true) = (session_id, mutating && !EXEMPT_PATHS.contains(&req.path())) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    let Some(config) = req.app_data::<web::Data<SessionConfig>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let cookie = req.cookie(COOKIE_NAME).map(|cookie| cookie.value().to_string());
+    let header = req
+        .headers()
+        .get(HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (Some(cookie), Some(header)) = (cookie, header) else {
+        info!(path = %req.path(), "Rejected session request without CSRF token");
+        return Ok(forbidden(req, "A CSRF token is required in the X-CSRF-Token header"));
+    };
+
+    let expected = config.csrf_token(session_id);
+    // Evaluate both comparisons so timing doesn't reveal which one failed
+    let cookie_ok = constant_time_eq(cookie.as_bytes(), expected.as_bytes());
+    let header_ok = constant_time_eq(header.as_bytes(), expected.as_bytes());
+    if !(cookie_ok & header_ok) {
+        info!(path = %req.path(), "Rejected session request with mismatched CSRF token");
+        return Ok(forbidden(req, "CSRF token does not match the session"));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
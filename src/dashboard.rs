@@ -0,0 +1,191 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{get, web, Error, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::instrument;
+
+use crate::{config, get_env_or_default};
+
+// The page styles itself inline and loads nothing else
+const DASHBOARD_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'; base-uri 'none'; form-action 'none'";
+
+// One request as the dashboard shows it
+#[derive(Clone)]
+struct RequestRecord {
+    timestamp: DateTime<Utc>,
+    method: String,
+    path: String,
+    // The matched route pattern; None for requests no route matched
+    route: Option<String>,
+    status: u16,
+    duration_ms: f64,
+    trace_id: Option<String>,
+    // Unsampled traces never reach the backend, so they get no link
+    sampled: bool,
+}
+
+// Bounded in-memory log of the most recent API requests, newest last.
+// REQUEST_LOG_SIZE sets how many are kept; 0 turns recording off.
+pub struct RequestLog {
+    entries: Mutex<VecDeque<RequestRecord>>,
+    capacity: usize,
+}
+
+impl RequestLog {
+    pub fn from_env() -> Self {
+        let capacity = get_env_or_default("REQUEST_LOG_SIZE", "200").parse().unwrap_or(200);
+        RequestLog {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn record(&self, record: RequestRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(record);
+    }
+
+    fn entries(&self) -> Vec<RequestRecord> {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.iter().cloned().collect()
+    }
+}
+
+// Scope-level middleware, inside RequestTracing so the request's trace is
+// current, adding every request to the RequestLog once it has a response
+pub async fn record(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(log) = req.app_data::<web::Data<RequestLog>>().cloned() else {
+        return next.call(req).await;
+    };
+    let span_context = Context::current().span().span_context().clone();
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+
+    let res = next.call(req).await?;
+    log.record(RequestRecord {
+        timestamp: Utc::now(),
+        method,
+        path,
+        route: res.request().match_pattern(),
+        status: res.status().as_u16(),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        trace_id: span_context.is_valid().then(|| span_context.trace_id().to_string()),
+        sampled: span_context.is_sampled(),
+    });
+    Ok(res)
+}
+
+// Handler for GET /admin/dashboard: the request log as an HTML table, newest
+// first. With telemetry.trace_url_template set, trace IDs link into the
+// trace backend.
+#[get("/dashboard")]
+#[instrument(name = "admin_dashboard_handler", skip_all, fields(service = "actix_example"))]
+async fn dashboard(log: web::Data<RequestLog>) -> impl Responder {
+    let entries = log.entries();
+    let trace_url_template = config::settings().telemetry.trace_url_template.as_deref();
+
+    let mut rows = String::new();
+    for entry in entries.iter().rev() {
+        let trace = match (&entry.trace_id, trace_url_template) {
+            (Some(trace_id), Some(template)) if entry.sampled => format!(
+                "<a href=\"{}\"><code>{}</code></a>",
+                escape(&template.replace("{trace_id}", trace_id)),
+                trace_id
+            ),
+            (Some(trace_id), _) => format!("<code>{}</code>", trace_id),
+            (None, _) => String::new(),
+        };
+        let _ = writeln!(
+            rows,
+            "<tr class=\"s{}xx\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{}</td></tr>",
+            entry.status / 100,
+            entry.timestamp.format("%H:%M:%S%.3f"),
+            escape(&entry.method),
+            escape(&entry.path),
+            entry.route.as_deref().map(escape).unwrap_or_default(),
+            entry.status,
+            entry.duration_ms,
+            trace,
+        );
+    }
+
+    let page = format!(
+        r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="5">
+<title>Recent requests</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 1rem 2rem; }}
+table {{ border-collapse: collapse; width: 100%; font-size: 0.9em; }}
+th, td {{ border-bottom: 1px solid #d0d7de; padding: 0.3rem; text-align: left; }}
+.s4xx td {{ background: #fff8c5; }}
+.s5xx td {{ background: #ffebe9; }}
+</style>
+</head>
+<body>
+<h1>Recent requests</h1>
+<p>{} of the last {} requests, newest first; refreshes every 5 seconds.{}</p>
+<table>
+<thead><tr><th>Time (UTC)</th><th>Method</th><th>Path</th><th>Route</th><th>Status</th><th>ms</th><th>Trace</th></tr></thead>
+<tbody>
+{}</tbody>
+</table>
+</body>
+</html>
+"#,
+        entries.len(),
+        log.capacity(),
+        if trace_url_template.is_none() { " Set TRACE_URL_TEMPLATE to link traces." } else { "" },
+        rows,
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, DASHBOARD_CSP))
+        .body(page)
+}
+
+// Request paths and methods come from clients, so everything is escaped
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(dashboard);
+}
@@ -0,0 +1,125 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use futures_util::StreamExt;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use std::io::Read;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::payload::{payload_too_large, PayloadLimits};
+
+// Request Content-Encodings we inflate
+const SUPPORTED: &str = "gzip, deflate";
+
+// The body limit that applies to a request, matching what its extractor enforces
+fn body_limit(req: &ServiceRequest) -> usize {
+    let limits = req
+        .app_data::<web::Data<PayloadLimits>>()
+        .map(|limits| *limits.get_ref())
+        .unwrap_or_else(PayloadLimits::from_env);
+    let content_type = req.content_type();
+    if content_type.ends_with("json") {
+        limits.json
+    } else if content_type.starts_with("multipart/") {
+        limits.multipart
+    } else {
+        limits.upload
+    }
+}
+
+// Inflate `compressed`, refusing to produce more than `limit` bytes so a
+// small compressed body can't expand without bound
+fn inflate(encoding: &str, compressed: &[u8], limit: usize) -> Result<Vec<u8>, AppError> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(compressed)),
+        _ => Box::new(ZlibDecoder::new(compressed)),
+    };
+    let mut body = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| {
+            AppError::bad_request("invalid_compressed_body", format!("Could not decompress {} body: {}", encoding, err))
+        })?;
+    if body.len() > limit {
+        return Err(payload_too_large(limit));
+    }
+    Ok(body)
+}
+
+// Scope-level middleware for clients that send `Content-Encoding: gzip` (or
+// deflate) bodies. The body is inflated up front under the same limit as the
+// uncompressed body would be, and handed on with the encoding header removed,
+// so extractors see plain JSON. Other codings get a 415.
+pub async fn decompress(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let encoding = req
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let encoding = match encoding.as_deref() {
+        None | Some("") | Some("identity") => {
+            return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+        }
+        Some(encoding @ ("gzip" | "x-gzip" | "deflate")) => encoding.to_string(),
+        Some(other) => {
+            let mut response = AppError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_content_encoding",
+                format!("Request bodies may only be encoded with: {}", SUPPORTED),
+            )
+            .with("content_encoding", other)
+            .error_response();
+            // RFC 7694: tell the client which codings it may use instead
+            response
+                .headers_mut()
+                .insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static(SUPPORTED));
+            return Ok(req.into_response(response));
+        }
+    };
+
+    let limit = body_limit(&req);
+    // The compressed body can't sensibly be larger than the inflated one
+    let mut payload = req.take_payload();
+    let mut compressed = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let response = AppError::bad_request("invalid_body", err.to_string()).error_response();
+                return Ok(req.into_response(response));
+            }
+        };
+        if compressed.len() + chunk.len() > limit {
+            return Ok(req.into_response(payload_too_large(limit).error_response()));
+        }
+        compressed.extend_from_slice(&chunk);
+    }
+
+    let body = match inflate(&encoding, &compressed, limit) {
+        Ok(body) => body,
+        Err(app_error) => {
+            info!(content_encoding = %encoding, error = %app_error, "Rejected compressed body");
+            return Ok(req.into_response(app_error.error_response()));
+        }
+    };
+
+    let context = Context::current();
+    let span = context.span();
+    span.set_attribute(KeyValue::new("http.request.content_encoding", encoding));
+    span.set_attribute(KeyValue::new("http.request.compressed_size", compressed.len() as i64));
+    span.set_attribute(KeyValue::new("http.request.body.size", body.len() as i64));
+
+    req.headers_mut().remove(header::CONTENT_ENCODING);
+    req.headers_mut().insert(header::CONTENT_LENGTH, header::HeaderValue::from(body.len()));
+    req.set_payload(Payload::from(body));
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,160 @@
+use actix_web::dev::ServerHandle;
+use actix_web::http::StatusCode;
+use actix_web::rt::signal::unix::Signal;
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+use std::future::poll_fn;
+use std::sync::OnceLock;
+use std::task::Poll;
+use std::time::Duration;
+use tracing::{info, instrument, warn, Instrument};
+
+use crate::admin::{AdminIdentity, AuditLog, TelemetryControl};
+use crate::error::AppError;
+use crate::get_env_or_default;
+use crate::status::AppStatus;
+
+// Drives a zero-downtime shutdown, requested through POST /admin/drain or by
+// SIGTERM / SIGINT. The servers run with their own signal handling disabled,
+// so every shutdown goes through here: readiness flips, the grace period runs,
+// spans are flushed and the servers stop, giving in-flight requests up to
+// SHUTDOWN_TIMEOUT_SECS. main then runs the shutdown hooks.
+pub struct DrainControl {
+    // How long readiness fails before the listeners close, so load balancers
+    // notice and stop routing here first
+    grace: Duration,
+    // Public server first, then the admin server when it runs separately
+    handles: OnceLock<Vec<ServerHandle>>,
+}
+
+impl DrainControl {
+    pub fn from_env() -> Self {
+        DrainControl {
+            grace: Duration::from_secs(get_env_or_default("DRAIN_GRACE_SECS", "10").parse().unwrap_or(10)),
+            handles: OnceLock::new(),
+        }
+    }
+
+    // Servers only have handles once they run, after app data is built
+    pub fn register(&self, handles: Vec<ServerHandle>) {
+        if self.handles.set(handles).is_err() {
+            warn!("Server handles registered twice; keeping the first set");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DrainStarted {
+    status: &'static str,
+    grace_period_secs: u64,
+}
+
+// Runs once readiness is failing: wait out the grace period, flush spans,
+// then stop the servers gracefully, which closes the listeners and waits for
+// in-flight requests. main pushes the last metrics once the servers are down.
+async fn run_drain(control: web::Data<DrainControl>, telemetry: web::Data<TelemetryControl>, grace: Duration) {
+    info!(grace_secs = grace.as_secs(), "Draining; readiness now failing");
+    actix_web::rt::time::sleep(grace).await;
+
+    if let Some(provider) = telemetry.provider.clone() {
+        match web::block(move || provider.force_flush()).await {
+            Ok(results) => {
+                for err in results.into_iter().filter_map(Result::err) {
+                    warn!(error = %err, "Failed to flush spans before shutdown");
+                }
+            }
+            Err(_) => warn!("Flush task failed before shutdown"),
+        }
+    }
+
+    let Some(handles) = control.handles.get() else {
+        warn!("No server handles registered; drain cannot stop the servers");
+        return;
+    };
+    info!("Grace period over; stopping listeners and waiting for in-flight requests");
+    // Every stop signal goes out immediately. This task may run on one of the
+    // workers being stopped, so it must not wait before signalling the rest.
+    let stops: Vec<_> = handles.iter().map(|handle| handle.stop(true)).collect();
+    for stop in stops {
+        stop.await;
+    }
+}
+
+// Handler for POST /admin/drain
+#[post("/drain")]
+#[instrument(name = "admin_drain_handler", skip_all, fields(service = "actix_example"))]
+async fn drain(
+    identity: web::ReqData<AdminIdentity>,
+    control: web::Data<DrainControl>,
+    status: web::Data<AppStatus>,
+    telemetry: web::Data<TelemetryControl>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    if !status.begin_drain() {
+        return AppError::new(StatusCode::CONFLICT, "already_draining", "A drain is already in progress")
+            .error_response();
+    }
+
+    audit.record(&identity.0, "drain", format!("grace period {}s", control.grace.as_secs()));
+    let grace = control.grace;
+    actix_web::rt::spawn(run_drain(control.clone(), telemetry, grace).in_current_span());
+    HttpResponse::Accepted().json(DrainStarted {
+        status: "draining",
+        grace_period_secs: control.grace.as_secs(),
+    })
+}
+
+// Background task, spawned once the servers run, turning SIGTERM and SIGINT
+// into a drain. SIGTERM, as sent by orchestrators, waits out the grace period
+// so load balancers see readiness fail first; SIGINT is usually Ctrl-C at a
+// terminal and stops straight away. A second signal stops the servers without
+// waiting for in-flight requests.
+pub async fn handle_signals(control: web::Data<DrainControl>, status: web::Data<AppStatus>, telemetry: web::Data<TelemetryControl>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let (mut terminate, mut interrupt) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(error = %err, "Failed to listen for SIGTERM and SIGINT; only POST /admin/drain stops the server");
+            return;
+        }
+    };
+    let received = next_signal(&mut terminate, &mut interrupt).await;
+    // Nothing here may keep a telemetry handle past the servers stopping:
+    // the tracer provider only shuts down once the last one is gone
+    let draining = if status.begin_drain() {
+        let grace = if received == "SIGTERM" { control.grace } else { Duration::ZERO };
+        info!(signal = received, "Shutting down");
+        Some(actix_web::rt::spawn(run_drain(control.clone(), telemetry, grace)))
+    } else {
+        info!(signal = received, "Shutdown signal received during a drain already in progress");
+        drop(telemetry);
+        None
+    };
+
+    let received = next_signal(&mut terminate, &mut interrupt).await;
+    warn!(signal = received, "Second shutdown signal; stopping without waiting for in-flight requests");
+    if let Some(draining) = draining {
+        draining.abort();
+    }
+    if let Some(handles) = control.handles.get() {
+        let stops: Vec<_> = handles.iter().map(|handle| handle.stop(false)).collect();
+        for stop in stops {
+            stop.await;
+        }
+    }
+}
+
+// Name of whichever signal arrives first
+async fn next_signal(terminate: &mut Signal, interrupt: &mut Signal) -> &'static str {
+    poll_fn(|cx| {
+        if terminate.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGTERM")
+        } else if interrupt.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGINT")
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
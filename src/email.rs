@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, info_span, warn, Instrument, Span};
+
+use crate::get_env_or_default;
+
+// How many jobs may wait before new ones are dropped
+const QUEUE_CAPACITY: usize = 1024;
+// How often drained() checks the queue
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
+pub struct EmailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivery mechanism for outgoing mail
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn send(&self, message: &EmailMessage) -> Result<(), String>;
+}
+
+// Default transport: logs the message instead of delivering it
+pub struct StubTransport;
+
+#[async_trait]
+impl EmailTransport for StubTransport {
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+
+    async fn send(&self, message: &EmailMessage) -> Result<(), String> {
+        info!(to = %message.to, subject = %message.subject, body_len = message.body.len(), "Stub transport: email not actually sent");
+        Ok(())
+    }
+}
+
+#[cfg(feature = "smtp")]
+mod smtp {
+    use super::{EmailMessage, EmailTransport};
+    use async_trait::async_trait;
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+    // SMTP delivery via lettre, using STARTTLS unless SMTP_TLS=false
+    pub struct SmtpTransport {
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+        from: String,
+    }
+
+    impl SmtpTransport {
+        pub fn from_env() -> Result<Self, String> {
+            let host = std::env::var("SMTP_HOST").map_err(|_| "SMTP_HOST is required".to_string())?;
+            let port = crate::get_env_or_default("SMTP_PORT", "587")
+                .parse::<u16>()
+                .map_err(|err| format!("invalid SMTP_PORT: {}", err))?;
+            let mut builder = if crate::get_env_or_default("SMTP_TLS", "true") == "false" {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host)
+            } else {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).map_err(|err| err.to_string())?
+            };
+            builder = builder.port(port);
+            if let (Ok(user), Ok(password)) = (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD")) {
+                builder = builder.credentials(Credentials::new(user, password));
+            }
+            Ok(SmtpTransport {
+                transport: builder.build(),
+                from: crate::get_env_or_default("EMAIL_FROM", "noreply@example.com"),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl EmailTransport for SmtpTransport {
+        fn name(&self) -> &'static str {
+            "smtp"
+        }
+
+        async fn send(&self, message: &EmailMessage) -> Result<(), String> {
+            let email = Message::builder()
+                .from(self.from.parse().map_err(|err| format!("invalid EMAIL_FROM: {}", err))?)
+                .to(message.to.parse().map_err(|err| format!("invalid recipient: {}", err))?)
+                .subject(message.subject.clone())
+                .body(message.body.clone())
+                .map_err(|err| err.to_string())?;
+            self.transport.send(email).await.map(|_| ()).map_err(|err| err.to_string())
+        }
+    }
+}
+
+// Pick the transport named by EMAIL_TRANSPORT (stub or smtp)
+pub fn transport_from_env() -> Result<Box<dyn EmailTransport>, String> {
+    match get_env_or_default("EMAIL_TRANSPORT", "stub").as_str() {
+        "stub" => Ok(Box::new(StubTransport)),
+        #[cfg(feature = "smtp")]
+        "smtp" => Ok(Box::new(smtp::SmtpTransport::from_env()?)),
+        #[cfg(not(feature = "smtp"))]
+        "smtp" => Err("EMAIL_TRANSPORT=smtp requires building with the `smtp` feature".to_string()),
+        other => Err(format!("unknown EMAIL_TRANSPORT '{}'", other)),
+    }
+}
+
+// A queued email together with the span of the request that caused it
+struct EmailJob {
+    kind: &'static str,
+    message: EmailMessage,
+    parent: Span,
+}
+
+// Handle used by handlers to enqueue mail. Sending happens on a background
+// worker; each job runs in a span parented to the enqueuing request's span,
+// so it shows up in the same trace even though the response went out first.
+#[derive(Clone)]
+pub struct Mailer {
+    sender: mpsc::Sender<EmailJob>,
+}
+
+impl Mailer {
+    // Create the mailer and the worker future that must be spawned to drain it
+    pub fn new(transport: Box<dyn EmailTransport>) -> (Self, impl std::future::Future<Output = ()>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        (Mailer { sender }, run_worker(transport, receiver))
+    }
+
+    pub fn send_welcome(&self, name: &str, email: &str) {
+        let message = EmailMessage {
+            to: email.to_string(),
+            subject: "Welcome!".to_string(),
+            body: format!("Hi {},\n\nThanks for signing up.\n", name),
+        };
+        self.enqueue("welcome", message);
+    }
+
+    // Resolves once the worker has taken every queued job, for the shutdown
+    // hook; a job being sent at that point is left to finish
+    pub async fn drained(&self) {
+        while self.sender.capacity() < self.sender.max_capacity() {
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    // Fire-and-forget: a full or closed queue only costs us the email
+    fn enqueue(&self, kind: &'static str, message: EmailMessage) {
+        let job = EmailJob { kind, message, parent: Span::current() };
+        if let Err(err) = self.sender.try_send(job) {
+            warn!(kind = kind, error = %err, "Dropping email job");
+        } else {
+            info!(kind = kind, "Email job enqueued");
+        }
+    }
+}
+
+async fn run_worker(transport: Box<dyn EmailTransport>, mut receiver: mpsc::Receiver<EmailJob>) {
+    info!(transport = transport.name(), "Email worker started");
+    while let Some(job) = receiver.recv().await {
+        let span = info_span!(
+            parent: &job.parent,
+            "send_email",
+            email.kind = job.kind,
+            email.transport = transport.name(),
+            otel.kind = "producer",
+        );
+        async {
+            match transport.send(&job.message).await {
+                Ok(()) => info!("Email sent"),
+                Err(error) => warn!(error = %error, "Failed to send email"),
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+    info!("Email worker stopped");
+}
@@ -0,0 +1,129 @@
+use actix_web::http::header;
+use actix_web_opentelemetry::ClientExt;
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{info, instrument, warn, Span};
+
+use crate::circuit::{CircuitBreaker, CircuitBreakers};
+use crate::get_env_or_default;
+
+// Largest response body kept on a user
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+// What the enrichment service answered about a new user, stored on the user
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Enrichment {
+    // Host of the service that answered
+    pub source: String,
+    pub fetched_at: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+// Looks new users up in an external HTTP service, such as a Gravatar profile
+// or an email verification API, while they are created. A service that is
+// down, slow or behind an open circuit only costs the enrichment: the user is
+// created without it, and the span says why.
+pub struct Enricher {
+    url_template: String,
+    source: String,
+    api_key: Option<String>,
+    timeout: Duration,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl Enricher {
+    // Enabled by ENRICHMENT_URL, in which {email} and {email_sha256} stand for
+    // the user's address, e.g. https://api.gravatar.com/v3/profiles/{email_sha256}.
+    // ENRICHMENT_API_KEY is sent as a bearer token; ENRICHMENT_TIMEOUT_MS
+    // bounds the lookup, since user creation waits for it.
+    pub fn from_env(breakers: &CircuitBreakers) -> Option<Self> {
+        let url_template = std::env::var("ENRICHMENT_URL").ok().filter(|url| !url.is_empty())?;
+        let source = match url_template.parse::<actix_web::http::Uri>().ok().and_then(|uri| uri.host().map(str::to_string)) {
+            Some(host) => host,
+            None => {
+                warn!(url = %url_template, "ENRICHMENT_URL is not an absolute URL; user enrichment disabled");
+                return None;
+            }
+        };
+        info!(source = %source, "User enrichment enabled");
+        Some(Enricher {
+            url_template,
+            breaker: breakers.for_dependency(&format!("enrichment:{}", source)),
+            source,
+            api_key: std::env::var("ENRICHMENT_API_KEY").ok().filter(|key| !key.is_empty()),
+            timeout: Duration::from_millis(get_env_or_default("ENRICHMENT_TIMEOUT_MS", "1000").parse().unwrap_or(1000)),
+        })
+    }
+
+    // None when the service knows nothing about the address or couldn't be
+    // asked; enrichment.outcome on the span tells which
+    #[instrument(name = "enrich_user", skip_all, fields(enrichment.source = %self.source, enrichment.outcome = Empty))]
+    pub async fn lookup(&self, email: &str) -> Option<Enrichment> {
+        let span = Span::current();
+        if !self.breaker.allow() {
+            span.record("enrichment.outcome", "circuit_open");
+            warn!("Enrichment service circuit is open; creating the user without enrichment");
+            return None;
+        }
+        let fetched = self.fetch(email).await;
+        self.breaker.record(fetched.is_ok());
+        match fetched {
+            Ok(Some(data)) => {
+                span.record("enrichment.outcome", "enriched");
+                Some(Enrichment { source: self.source.clone(), fetched_at: Utc::now(), data })
+            }
+            Ok(None) => {
+                span.record("enrichment.outcome", "not_found");
+                info!("Enrichment service knows nothing about the user");
+                None
+            }
+            Err(err) => {
+                span.record("enrichment.outcome", "unavailable");
+                warn!(error = %err, "Enrichment service unavailable; creating the user without enrichment");
+                None
+            }
+        }
+    }
+
+    async fn fetch(&self, email: &str) -> Result<Option<serde_json::Value>, String> {
+        let email_sha256: String =
+            Sha256::digest(email.trim().to_lowercase().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+        let url = self
+            .url_template
+            .replace("{email}", &utf8_percent_encode(email, NON_ALPHANUMERIC).to_string())
+            .replace("{email_sha256}", &email_sha256);
+
+        let client = awc::Client::builder().timeout(self.timeout).finish();
+        let mut request = client.get(&url).insert_header((header::ACCEPT, "application/json"));
+        if let Some(api_key) = &self.api_key {
+            request = request.insert_header((header::AUTHORIZATION, format!("Bearer {}", api_key)));
+        }
+        let mut response = request
+            .trace_request()
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if response.status() == awc::http::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(format!("service answered {}", response.status()));
+        }
+        let data = response
+            .json()
+            .limit(MAX_RESPONSE_BYTES)
+            .await
+            .map_err(|err| format!("invalid response: {}", err))?;
+        Ok(Some(data))
+    }
+}
+
+// Enrichment for a new user, when ENRICHMENT_URL is set
+pub async fn lookup(enricher: &Option<Enricher>, email: &str) -> Option<Enrichment> {
+    enricher.as_ref()?.lookup(email).await
+}
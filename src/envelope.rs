@@ -0,0 +1,70 @@
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::pagination::Pagination;
+use crate::telemetry::current_trace_id;
+
+// Whether responses are wrapped by default; `?envelope=true|false` overrides it per request
+#[derive(Clone, Copy, Debug)]
+pub struct EnvelopeConfig {
+    pub enabled_by_default: bool,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeParam {
+    envelope: Option<bool>,
+}
+
+fn is_plain_json(res: &ServiceResponse<impl MessageBody>) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+// Response-mapping middleware producing `{ "data": ..., "meta": { ... } }`.
+// Only successful application/json bodies are wrapped; problem documents and
+// binary responses pass through untouched. Must run inside RequestTracing so
+// the trace ID is available.
+pub async fn envelope(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let default = req
+        .app_data::<web::Data<EnvelopeConfig>>()
+        .is_some_and(|config| config.enabled_by_default);
+    let wanted = web::Query::<EnvelopeParam>::from_query(req.query_string())
+        .ok()
+        .and_then(|param| param.envelope)
+        .unwrap_or(default);
+
+    let res = next.call(req).await?;
+    if !wanted || !res.status().is_success() || !is_plain_json(&res) {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let pagination = res.response().extensions().get::<Pagination>().cloned();
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let bytes = body::to_bytes(body)
+        .await
+        .map_err(|err| actix_web::error::ErrorInternalServerError(err.into()))?;
+
+    let data: Value = match serde_json::from_slice(&bytes) {
+        Ok(data) => data,
+        Err(_) => return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes)))),
+    };
+
+    let mut meta = json!({ "trace_id": current_trace_id() });
+    if let Some(pagination) = pagination {
+        meta["pagination"] = json!(pagination);
+    }
+    let wrapped = serde_json::to_vec(&json!({ "data": data, "meta": meta }))?;
+
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(wrapped))))
+}
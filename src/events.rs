@@ -0,0 +1,379 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::error::AppError;
+use crate::locks::MeasuredLock;
+use crate::outbox::{Outbox, OutboxEntry};
+use crate::query::ListQuery;
+use crate::quota::QuotaStore;
+use crate::repository::{self, transaction, NewUser, RepositoryError, SharedUserRepository, StoreSize, UserRepository};
+use crate::store::UserStore;
+use crate::webhooks;
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+// A change to one user. Events are never edited or removed once recorded;
+// a user's current state is whatever folding its events in order produces.
+#[derive(Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum UserEvent {
+    #[serde(rename = "UserCreated")]
+    Created { user: User },
+    #[serde(rename = "UserUpdated")]
+    Updated { name: String, email: String },
+    #[serde(rename = "UserStatusChanged")]
+    StatusChanged { from: UserStatus, to: UserStatus },
+    // The hash is kept for the fold but never shown in the history
+    #[serde(rename = "UserPasswordChanged")]
+    PasswordChanged {
+        #[serde(skip)]
+        credentials: Credentials,
+    },
+    // Likewise the image itself; the history shows its type and ETag
+    #[serde(rename = "UserAvatarUploaded")]
+    AvatarUploaded {
+        content_type: String,
+        etag: String,
+        #[serde(skip)]
+        data: web::Bytes,
+    },
+    #[serde(rename = "UserProfileUpdated")]
+    ProfileUpdated { profile: Profile },
+    #[serde(rename = "UserDeleted")]
+    Deleted { expired: bool },
+}
+
+// An event as recorded, numbered in the order it was appended
+#[derive(Clone, Serialize)]
+pub struct StoredEvent {
+    pub sequence: u64,
+    pub user_id: UserId,
+    pub recorded_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: UserEvent,
+}
+
+impl StoredEvent {
+    // Fold this event into the current state
+    fn apply(&self, current: &mut Projection) {
+        if let UserEvent::Created { user } = &self.event {
+            current.users.insert(user.clone());
+            return;
+        }
+        if let UserEvent::Deleted { .. } = &self.event {
+            current.users.remove(self.user_id);
+            current.avatars.remove(&self.user_id);
+            current.profiles.remove(&self.user_id);
+            return;
+        }
+        let Some(user) = current.users.get_mut(self.user_id) else {
+            return;
+        };
+        user.updated_at = self.recorded_at;
+        match &self.event {
+            UserEvent::Updated { name, email } => {
+                user.name = name.clone();
+                user.email = email.clone();
+            }
+            UserEvent::StatusChanged { to, .. } => user.status = *to,
+            UserEvent::PasswordChanged { credentials } => user.credentials = Some(credentials.clone()),
+            UserEvent::AvatarUploaded { content_type, etag, data } => {
+                let avatar = Avatar { content_type: content_type.clone(), data: data.clone(), etag: etag.clone() };
+                current.avatars.insert(self.user_id, avatar);
+            }
+            UserEvent::ProfileUpdated { profile } => {
+                current.profiles.insert(self.user_id, profile.clone());
+            }
+            UserEvent::Created { .. } | UserEvent::Deleted { .. } => {}
+        }
+    }
+}
+
+// The users, avatars and profiles that folding the events produces
+#[derive(Default)]
+struct Projection {
+    users: UserStore,
+    avatars: HashMap<UserId, Avatar>,
+    profiles: HashMap<UserId, Profile>,
+}
+
+#[derive(Default)]
+struct EventLog {
+    events: Vec<StoredEvent>,
+    // Positions in `events` of each user's events, for history lookups
+    by_user: HashMap<UserId, Vec<usize>>,
+    // The fold of `events`, advanced as each one is appended rather than
+    // replayed on every read
+    current: Projection,
+    // Webhook events awaiting the relay when OUTBOX_ENABLED is set
+    outbox: Outbox,
+}
+
+impl EventLog {
+    fn append(&mut self, user_id: UserId, event: UserEvent) {
+        let event = StoredEvent { sequence: self.events.len() as u64 + 1, user_id, recorded_at: Utc::now(), event };
+        event.apply(&mut self.current);
+        self.by_user.entry(user_id).or_default().push(self.events.len());
+        self.events.push(event);
+    }
+
+    fn create(&mut self, user: NewUser) -> User {
+        let user = user.into_user();
+        self.append(user.id, UserEvent::Created { user: user.clone() });
+        user
+    }
+
+    // Append `event` if the user exists, returning the user as it then is
+    fn change(&mut self, id: UserId, event: UserEvent) -> Option<User> {
+        if !self.current.users.contains(id) {
+            return None;
+        }
+        self.append(id, event);
+        self.current.users.get(id).cloned()
+    }
+
+    fn history(&self, id: UserId) -> Option<Vec<StoredEvent>> {
+        let positions = self.by_user.get(&id)?;
+        Some(positions.iter().map(|&position| self.events[position].clone()).collect())
+    }
+}
+
+// Users kept as an append-only log of events (STORAGE_BACKEND=events), so
+// GET /users/{id}/events can show how a user got to its current state, even
+// after it was deleted. Like the memory backend it lives in the process and
+// starts empty after a restart.
+pub struct EventSourcedUserRepository {
+    log: RwLock<EventLog>,
+    outbox: bool,
+    // Quota counts aren't user events, so they stay out of the log
+    quotas: QuotaStore,
+}
+
+impl EventSourcedUserRepository {
+    pub fn new(outbox: bool) -> Self {
+        EventSourcedUserRepository { log: RwLock::new(EventLog::default()), outbox, quotas: QuotaStore::default() }
+    }
+}
+
+fn poisoned<T>(_: T) -> RepositoryError {
+    RepositoryError::Storage("event log lock is poisoned".to_string())
+}
+
+#[async_trait]
+impl UserRepository for EventSourcedUserRepository {
+    fn name(&self) -> &'static str {
+        "events"
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.current.users.get(id).cloned())
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(query.apply(log.current.users.iter()))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let user = log.create(user);
+        if self.outbox {
+            log.outbox.record(webhooks::USER_CREATED, &user);
+        }
+        Ok(user)
+    }
+
+    // One write lock covers the whole batch, so readers never see part of it
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        transaction(self.name(), "create_many", async {
+            let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+            let created: Vec<User> = users.into_iter().map(|user| log.create(user)).collect();
+            if self.outbox {
+                for user in &created {
+                    log.outbox.record(webhooks::USER_CREATED, user);
+                }
+            }
+            Ok(created)
+        })
+        .await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let updated = log.change(id, UserEvent::Updated { name, email });
+        if let Some(user) = updated.as_ref().filter(|_| self.outbox) {
+            log.outbox.record(webhooks::USER_UPDATED, user);
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let Some(user) = log.current.users.get(id).cloned() else {
+            return Ok(None);
+        };
+        log.append(id, UserEvent::Deleted { expired: false });
+        if self.outbox {
+            log.outbox.record(webhooks::USER_DELETED, &user);
+        }
+        Ok(Some(user))
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let expired: Vec<User> = log
+            .current
+            .users
+            .iter()
+            .filter(|user| user.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .cloned()
+            .collect();
+        for user in &expired {
+            log.append(user.id, UserEvent::Deleted { expired: true });
+            if self.outbox {
+                log.outbox.record(webhooks::USER_DELETED, user);
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        let user = log.current.users.iter().find(|user| user.email.eq_ignore_ascii_case(email)).cloned();
+        Ok(user)
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(ids.iter().filter_map(|&id| log.current.users.get(id).cloned()).collect())
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.current.users.len())
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(UserStats::tally(log.current.users.iter().map(|user| (user.email.as_str(), user.created_at))))
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(DuplicateMatch::among(log.current.users.iter(), name, email))
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let (from, to) = repository::next_status(log.current.users.get(id).map(|user| user.status), action)?;
+        let user = log.change(id, UserEvent::StatusChanged { from, to }).ok_or(TransitionError::NotFound)?;
+        if self.outbox {
+            log.outbox.record(webhooks::USER_UPDATED, &user);
+        }
+        Ok((from, user))
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        Ok(log.change(id, UserEvent::PasswordChanged { credentials }))
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.current.avatars.get(&id).cloned())
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        let Avatar { content_type, data, etag } = avatar;
+        Ok(log.change(id, UserEvent::AvatarUploaded { content_type, etag, data }))
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.current.profiles.get(&id).cloned())
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        Ok(log.change(id, UserEvent::ProfileUpdated { profile }))
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        Ok(self.quotas.charge(subject, day, limit))
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.quotas.refund(subject, day);
+        Ok(())
+    }
+
+    fn keeps_history(&self) -> bool {
+        true
+    }
+
+    async fn history(&self, id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.history(id))
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.outbox
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        Ok(log.outbox.pending(limit))
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        let mut log = self.log.write_measured("user_events").map_err(poisoned)?;
+        log.outbox.acknowledge(ids);
+        Ok(())
+    }
+
+    // The log itself dominates: every event is kept, while the projection
+    // holds only the current users. Event payloads' strings aren't counted.
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let log = self.log.read_measured("user_events").map_err(poisoned)?;
+        let bytes = log.current.users.estimated_bytes() + log.events.capacity() * std::mem::size_of::<StoredEvent>();
+        Ok(StoreSize { entries: log.current.users.len() as u64, bytes: Some(bytes as u64) })
+    }
+}
+
+#[derive(Serialize)]
+struct UserHistory {
+    user_id: UserId,
+    events: Vec<StoredEvent>,
+}
+
+// Handler for GET /users/{id}/events
+#[get("/users/{id}/events")]
+#[instrument(name = "user_events_handler", skip(repository), fields(service = "actix_example"))]
+async fn user_events(path: web::Path<UserId>, repository: SharedUserRepository) -> impl Responder {
+    let user_id = path.into_inner();
+    info!(user_id = %user_id, "Reading user event history");
+
+    if !repository.keeps_history() {
+        return AppError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "history_unavailable",
+            format!("The {} backend keeps no event history; use STORAGE_BACKEND=events", repository.name()),
+        )
+        .error_response();
+    }
+    match repository.history(user_id).await {
+        Err(err) => err.error_response(),
+        Ok(Some(events)) => HttpResponse::Ok().json(UserHistory { user_id, events }),
+        Ok(None) => AppError::new(StatusCode::NOT_FOUND, "not_found", format!("User {} has no recorded events", user_id))
+            .error_response(),
+    }
+}
@@ -0,0 +1,113 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{info, instrument, warn, Span};
+
+use crate::error::AppError;
+use crate::get_env_or_default;
+use crate::locks::MeasuredLock;
+use crate::outbox;
+use crate::persistence::StateFile;
+use crate::telemetry::record_user_evictions;
+use crate::tenant::{TenantState, Tenants, DEFAULT_TENANT};
+use crate::webhooks::{self, WebhookPublisher};
+
+// Lifetimes for demo users, so a long-lived public demo doesn't fill up.
+// USER_TTL_SECS gives every new user a lifetime; clients can ask for a
+// shorter one with `ttl_secs`, or for any lifetime when USER_TTL_SECS is
+// unset. The reaper runs every USER_REAP_INTERVAL_SECS.
+#[derive(Clone, Copy)]
+pub struct ExpiryPolicy {
+    default_ttl: Option<Duration>,
+    reap_interval: Duration,
+}
+
+impl ExpiryPolicy {
+    pub fn from_env() -> Self {
+        ExpiryPolicy {
+            default_ttl: std::env::var("USER_TTL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            reap_interval: Duration::from_secs(
+                get_env_or_default("USER_REAP_INTERVAL_SECS", "60").parse().unwrap_or(60).max(1),
+            ),
+        }
+    }
+
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl
+    }
+
+    pub fn reap_interval(&self) -> Duration {
+        self.reap_interval
+    }
+
+    // When a user created now expires, given the TTL the client asked for
+    pub fn expires_at(&self, requested_secs: Option<u64>) -> Result<Option<DateTime<Utc>>, AppError> {
+        if requested_secs == Some(0) {
+            return Err(AppError::bad_request("invalid_ttl", "ttl_secs must be positive").with("field", "ttl_secs"));
+        }
+        let requested = requested_secs.map(Duration::from_secs);
+        let ttl = match (requested, self.default_ttl) {
+            (Some(requested), Some(default)) => Some(requested.min(default)),
+            (requested, default) => requested.or(default),
+        };
+        Ok(ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl)))
+    }
+}
+
+// Background task deleting expired users in every tenant, started in phase 3
+pub async fn run_reaper(
+    policy: ExpiryPolicy,
+    default_tenant: TenantState,
+    tenants: web::Data<Tenants>,
+    webhooks: web::Data<WebhookPublisher>,
+    state_file: web::Data<StateFile>,
+) {
+    let mut ticks = actix_web::rt::time::interval(policy.reap_interval);
+    loop {
+        ticks.tick().await;
+        let mut evicted = reap(DEFAULT_TENANT, &default_tenant, &webhooks).await;
+        for (tenant, state) in tenants.active() {
+            evicted += reap(&tenant, &state, &webhooks).await;
+        }
+        if evicted > 0 {
+            state_file.mark_dirty();
+        }
+    }
+}
+
+// One pass over a tenant. Users are deleted through the repository, along
+// with their avatars and profiles, then their sessions are ended as
+// DELETE /users/{id} does.
+#[instrument(level = "debug", name = "reap_expired_users", skip(state, webhooks), fields(evicted = Empty))]
+async fn reap(tenant: &str, state: &TenantState, webhooks: &WebhookPublisher) -> usize {
+    let expired = match state.repository.delete_expired(Utc::now()).await {
+        Ok(expired) => expired,
+        Err(err) => {
+            warn!(tenant.id = %tenant, error = %err, "Failed to delete expired users");
+            return 0;
+        }
+    };
+    Span::current().record("evicted", expired.len());
+    if expired.is_empty() {
+        return 0;
+    }
+
+    if let Ok(mut users) = state.users.write_measured("app_state") {
+        for user in &expired {
+            users.forget_user(user.id);
+        }
+    }
+    for user in &expired {
+        outbox::publish(&state.repository, webhooks, webhooks::USER_DELETED, user);
+    }
+    record_user_evictions(tenant, expired.len() as u64);
+    info!(tenant.id = %tenant, evicted = expired.len(), "Expired users deleted");
+    expired.len()
+}
@@ -0,0 +1,198 @@
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, put, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::sync::RwLock;
+use tracing::{instrument, warn};
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::auth::Identity;
+use crate::error::AppError;
+use crate::get_env_or_default;
+
+// Lists pagination Link headers on GET /users
+pub const PAGINATION_LINKS: &str = "pagination_links";
+
+// A named toggle. With `percentage` set, an enabled flag is only on for that
+// share of callers, picked by a stable hash so each caller keeps its answer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Flag {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<u8>,
+}
+
+impl Flag {
+    fn evaluate(&self, name: &str, subject: &str) -> bool {
+        match self.percentage {
+            _ if !self.enabled => false,
+            None | Some(100..) => true,
+            Some(percentage) => {
+                let digest = Sha256::digest(format!("{}:{}", name, subject).as_bytes());
+                let bucket = u16::from_be_bytes([digest[0], digest[1]]) % 100;
+                bucket < u16::from(percentage)
+            }
+        }
+    }
+}
+
+// Flags shared by every worker, managed under /admin/features
+pub struct FeatureFlags {
+    flags: RwLock<BTreeMap<String, Flag>>,
+}
+
+impl FeatureFlags {
+    // FEATURE_FLAGS seeds flags as comma-separated `name=on`, `name=off` or `name=25%`
+    pub fn from_env() -> Self {
+        let mut flags = BTreeMap::new();
+        for entry in get_env_or_default("FEATURE_FLAGS", "").split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let parsed = entry.split_once('=').and_then(|(name, value)| {
+                let flag = match value.trim() {
+                    "on" | "true" => Flag { enabled: true, percentage: None },
+                    "off" | "false" => Flag { enabled: false, percentage: None },
+                    other => Flag {
+                        enabled: true,
+                        percentage: Some(other.strip_suffix('%')?.parse().ok().filter(|p| *p <= 100)?),
+                    },
+                };
+                valid_name(name.trim()).then(|| (name.trim().to_string(), flag))
+            });
+            match parsed {
+                Some((name, flag)) => {
+                    flags.insert(name, flag);
+                }
+                None => warn!(entry = %entry, "Ignoring malformed FEATURE_FLAGS entry"),
+            }
+        }
+        FeatureFlags { flags: RwLock::new(flags) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flags.read().map(|flags| flags.len()).unwrap_or(0)
+    }
+
+    fn get(&self, name: &str) -> Option<Flag> {
+        self.flags.read().ok()?.get(name).cloned()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '-' | '.'))
+}
+
+// Extractor giving handlers flag lookups for the current caller. Every
+// evaluation is recorded on the request span as `feature_flag.<name>`.
+pub struct Features {
+    flags: Option<web::Data<FeatureFlags>>,
+    // What percentage rollouts are keyed on: the caller's identity, else its address
+    subject: String,
+}
+
+impl Features {
+    // Whether `name` is on for this caller; `default` applies to unknown flags
+    pub fn is_enabled(&self, name: &str, default: bool) -> bool {
+        let enabled = self
+            .flags
+            .as_ref()
+            .and_then(|flags| flags.get(name))
+            .map_or(default, |flag| flag.evaluate(name, &self.subject));
+        Context::current()
+            .span()
+            .set_attribute(KeyValue::new(format!("feature_flag.{}", name), enabled));
+        enabled
+    }
+}
+
+impl FromRequest for Features {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let subject = match req.extensions().get::<Identity>() {
+            Some(identity) => identity.subject(),
+            None => crate::client_ip::client_ip(req)
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        };
+        ready(Ok(Features {
+            flags: req.app_data::<web::Data<FeatureFlags>>().cloned(),
+            subject,
+        }))
+    }
+}
+
+// Handler for GET /admin/features
+#[get("/features")]
+async fn list_features(flags: web::Data<FeatureFlags>) -> impl Responder {
+    let flags = flags.flags.read().map(|flags| flags.clone()).unwrap_or_default();
+    HttpResponse::Ok().json(flags)
+}
+
+// Handler for PUT /admin/features/{name}
+#[put("/features/{name}")]
+#[instrument(name = "admin_set_feature_handler", skip_all, fields(service = "actix_example"))]
+async fn set_feature(
+    identity: web::ReqData<AdminIdentity>,
+    path: web::Path<String>,
+    body: web::Json<Flag>,
+    flags: web::Data<FeatureFlags>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let name = path.into_inner();
+    if !valid_name(&name) {
+        return AppError::bad_request("invalid_flag_name", "flag names are 1-64 of a-z, 0-9, '_', '-' and '.'")
+            .with("field", "name")
+            .error_response();
+    }
+    let flag = body.into_inner();
+    if flag.percentage.is_some_and(|percentage| percentage > 100) {
+        return AppError::bad_request("invalid_percentage", "percentage must be between 0 and 100")
+            .with("field", "percentage")
+            .error_response();
+    }
+
+    match flags.flags.write() {
+        Ok(mut flags) => {
+            flags.insert(name.clone(), flag.clone());
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock feature flags"),
+    }
+    audit.record(&identity.0, "set_feature", format!("{} enabled={} percentage={:?}", name, flag.enabled, flag.percentage));
+    HttpResponse::Ok().json(flag)
+}
+
+// Handler for DELETE /admin/features/{name}
+#[delete("/features/{name}")]
+#[instrument(name = "admin_delete_feature_handler", skip_all, fields(service = "actix_example"))]
+async fn delete_feature(
+    identity: web::ReqData<AdminIdentity>,
+    path: web::Path<String>,
+    flags: web::Data<FeatureFlags>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let name = path.into_inner();
+    let removed = match flags.flags.write() {
+        Ok(mut flags) => flags.remove(&name),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock feature flags"),
+    };
+    if removed.is_none() {
+        return AppError::new(StatusCode::NOT_FOUND, "not_found", format!("Feature flag '{}' not found", name))
+            .error_response();
+    }
+    audit.record(&identity.0, "delete_feature", name);
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_features)
+        .service(set_feature)
+        .service(delete_feature);
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::query::invalid_parameter;
+
+// Sparse fieldset requested via `?fields=id,name`. Responses are serialized
+// as usual and then filtered down to these top-level keys, so no per-combination
+// structs are needed.
+#[derive(Clone, Debug)]
+pub struct FieldSet(Vec<String>);
+
+impl FieldSet {
+    fn parse(raw: &str) -> Self {
+        FieldSet(
+            raw.split(',')
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+                .collect(),
+        )
+    }
+
+    // Reject empty selections and names the resource doesn't have
+    pub fn validate(&self, allowed: &[&str]) -> Result<(), AppError> {
+        if self.0.is_empty() {
+            return Err(invalid_parameter("fields", "at least one field name is required"));
+        }
+        match self.0.iter().find(|field| !allowed.contains(&field.as_str())) {
+            Some(unknown) => Err(invalid_parameter(
+                "fields",
+                format!("unknown field '{}', expected any of: {}", unknown, allowed.join(", ")),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn retain(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => map.retain(|key, _| self.0.iter().any(|field| field == key)),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.retain(item)),
+            _ => {}
+        }
+    }
+}
+
+// Serialize `value`, keeping only the selected fields of the object (or of each
+// object in an array). Without a selection the value is returned unfiltered.
+pub fn apply<T: Serialize>(value: &T, fields: Option<&FieldSet>) -> Value {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    if let Some(fields) = fields {
+        fields.retain(&mut value);
+    }
+    value
+}
+
+pub fn deserialize_fields<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<FieldSet>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(|raw| FieldSet::parse(&raw)))
+}
+
+// Query parameters for endpoints that only support field selection
+#[derive(Deserialize)]
+pub struct FieldsQuery {
+    #[serde(default, deserialize_with = "deserialize_fields")]
+    pub fields: Option<FieldSet>,
+}
@@ -0,0 +1,143 @@
+use actix_web::web;
+use std::sync::RwLock;
+
+use crate::shutdown::ShutdownCoordinator;
+use crate::status::AppStatus;
+use crate::AppState;
+
+// GRPC_PORT, when set, is the port the gRPC server listens on
+fn port_from_env() -> Result<Option<u16>, String> {
+    match std::env::var("GRPC_PORT").ok().filter(|port| !port.is_empty()) {
+        Some(port) => port.parse().map(Some).map_err(|_| format!("invalid GRPC_PORT: {}", port)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod health {
+    use actix_web::web;
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+    use std::time::Duration;
+    use tonic_health::server::HealthReporter;
+    use tonic_health::ServingStatus;
+    use tracing::info;
+
+    use crate::health::{readiness_checks, CheckResult};
+    use crate::status::AppStatus;
+    use crate::AppState;
+
+    // How often the readiness checks are re-run for gRPC clients
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+    // Mirrors the /readyz checks into the health service: "" is the overall
+    // status, as the protocol defines it, and every check is a service of its
+    // own, e.g. "storage", so a probe can name the dependency it cares about.
+    // Only changes are reported, so Watch streams see transitions, not ticks.
+    pub struct HealthSync {
+        reporter: HealthReporter,
+        data: web::Data<RwLock<AppState>>,
+        status: web::Data<AppStatus>,
+        reported: HashMap<&'static str, bool>,
+    }
+
+    impl HealthSync {
+        pub fn new(reporter: HealthReporter, data: web::Data<RwLock<AppState>>, status: web::Data<AppStatus>) -> Self {
+            HealthSync { reporter, data, status, reported: HashMap::new() }
+        }
+
+        pub async fn report(&mut self) {
+            let checks = readiness_checks(&self.data, &self.status);
+            let ready = checks.iter().all(CheckResult::passed);
+            let statuses = std::iter::once(("", ready)).chain(checks.iter().map(|check| (check.name, check.passed())));
+            for (service, serving) in statuses {
+                if self.reported.insert(service, serving) == Some(serving) {
+                    continue;
+                }
+                if service.is_empty() {
+                    info!(serving = serving, "gRPC health status changed");
+                }
+                let status = if serving { ServingStatus::Serving } else { ServingStatus::NotServing };
+                self.reporter.set_service_status(service, status).await;
+            }
+        }
+
+        // Background task keeping the health service current
+        pub async fn run(mut self) {
+            loop {
+                actix_web::rt::time::sleep(REFRESH_INTERVAL).await;
+                self.report().await;
+            }
+        }
+    }
+}
+
+// Starts the gRPC server on GRPC_PORT, serving the grpc.health.v1.Health
+// service so gRPC load balancers can probe the process. It answers from the
+// same checks as /readyz, keeps answering NOT_SERVING through a drain, and
+// stops from a shutdown hook once the HTTP servers are down.
+#[cfg(feature = "grpc")]
+pub async fn start(
+    host: &str,
+    data: web::Data<RwLock<AppState>>,
+    status: web::Data<AppStatus>,
+    shutdown: &mut ShutdownCoordinator,
+) -> Result<(), String> {
+    use std::time::Duration;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tracing::{info, warn};
+
+    let Some(port) = port_from_env()? else {
+        return Ok(());
+    };
+    let address = format!("{}:{}", host, port);
+    let listener = crate::bound(std::net::TcpListener::bind((host, port)), "grpc", &address);
+    let listener = listener
+        .set_nonblocking(true)
+        .and_then(|()| tokio::net::TcpListener::from_std(listener))
+        .map_err(|err| format!("gRPC listener: {}", err))?;
+    if let Ok(addr) = listener.local_addr() {
+        info!(listener = "grpc", address = %addr, "gRPC health service listening on {}", addr);
+    }
+
+    // The reporter starts out SERVING; the first report corrects that before
+    // any probe can connect
+    let (reporter, service) = tonic_health::server::health_reporter();
+    let mut sync = health::HealthSync::new(reporter, data, status);
+    sync.report().await;
+    actix_web::rt::spawn(sync.run());
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+            stopped.await.ok();
+        });
+    let mut serving = actix_web::rt::spawn(async move {
+        if let Err(err) = server.await {
+            warn!(error = %err, "gRPC server failed");
+        }
+    });
+    shutdown.register("grpc server", Duration::from_secs(10), move || async move {
+        stop.send(()).ok();
+        // Watch streams never end on their own, so connections still open
+        // after a moment are cut off rather than holding up shutdown
+        if actix_web::rt::time::timeout(Duration::from_secs(1), &mut serving).await.is_err() {
+            serving.abort();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+pub async fn start(
+    _host: &str,
+    _data: web::Data<RwLock<AppState>>,
+    _status: web::Data<AppStatus>,
+    _shutdown: &mut ShutdownCoordinator,
+) -> Result<(), String> {
+    match port_from_env()? {
+        Some(_) => Err("GRPC_PORT requires building with the `grpc` feature".to_string()),
+        None => Ok(()),
+    }
+}
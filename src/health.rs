@@ -0,0 +1,152 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::RwLock;
+use std::time::Instant;
+use tracing::info;
+
+use crate::locks::MeasuredLock;
+use crate::status::{AppStatus, SubsystemState};
+use crate::storage::StorageInfo;
+use crate::AppState;
+
+// Process-level facts captured once at startup
+pub struct ProcessInfo {
+    started_at: DateTime<Utc>,
+    started_instant: Instant,
+}
+
+impl ProcessInfo {
+    pub fn new() -> Self {
+        ProcessInfo {
+            started_at: Utc::now(),
+            started_instant: Instant::now(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LivenessResponse {
+    status: &'static str,
+    pid: u32,
+    version: &'static str,
+    started_at: DateTime<Utc>,
+    uptime_seconds: u64,
+}
+
+// Handler for GET /healthz
+//
+// Liveness only answers "is the process able to serve HTTP at all", so it
+// deliberately touches no shared state and keeps answering while draining.
+// It is registered outside the traced scope to keep probes out of the traces.
+#[get("/healthz")]
+pub async fn healthz(process: web::Data<ProcessInfo>) -> impl Responder {
+    HttpResponse::Ok().json(LivenessResponse {
+        status: "ok",
+        pid: std::process::id(),
+        version: env!("CARGO_PKG_VERSION"),
+        started_at: process.started_at,
+        uptime_seconds: process.started_instant.elapsed().as_secs(),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Pass,
+    Fail,
+}
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str) -> Self {
+        CheckResult { name, status: CheckStatus::Pass, detail: None }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>) -> Self {
+        CheckResult { name, status: CheckStatus::Fail, detail: Some(detail.into()) }
+    }
+
+    pub fn passed(&self) -> bool {
+        matches!(self.status, CheckStatus::Pass)
+    }
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    status: &'static str,
+    // Which backend the storage check refers to
+    storage: StorageInfo,
+    checks: Vec<CheckResult>,
+}
+
+// One check per startup subsystem: telemetry, storage, state loading, background workers
+fn check_subsystems(status: &AppStatus) -> Vec<CheckResult> {
+    status
+        .snapshot()
+        .into_iter()
+        .map(|(name, state)| match state {
+            SubsystemState::Ready => CheckResult::pass(name),
+            SubsystemState::Starting => CheckResult::fail(name, "still initializing"),
+            SubsystemState::Failed(detail) => CheckResult::fail(name, detail),
+        })
+        .collect()
+}
+
+fn check_draining(status: &AppStatus) -> CheckResult {
+    if status.is_draining() {
+        CheckResult::fail("drain", "instance is draining for shutdown")
+    } else {
+        CheckResult::pass("drain")
+    }
+}
+
+fn check_state_store(data: &RwLock<AppState>) -> CheckResult {
+    // A poisoned lock means a handler panicked mid-update and the data can't be trusted
+    match data.read_measured("app_state") {
+        Ok(_) => CheckResult::pass("state_store"),
+        Err(_) => CheckResult::fail("state_store", "application state lock is poisoned"),
+    }
+}
+
+// Every readiness check, as served by /readyz and the gRPC health service
+pub fn readiness_checks(data: &RwLock<AppState>, status: &AppStatus) -> Vec<CheckResult> {
+    let mut checks = check_subsystems(status);
+    checks.push(check_draining(status));
+    checks.push(check_state_store(data));
+    checks
+}
+
+// Handler for GET /readyz
+//
+// Readiness reports whether this instance should receive traffic. Every
+// dependency gets its own entry so a failing probe says exactly what is wrong.
+#[get("/readyz")]
+pub async fn readyz(
+    data: web::Data<RwLock<AppState>>,
+    status: web::Data<AppStatus>,
+    storage: web::Data<StorageInfo>,
+) -> impl Responder {
+    let checks = readiness_checks(&data, &status);
+
+    let ready = checks.iter().all(CheckResult::passed);
+    let body = ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        storage: storage.get_ref().clone(),
+        checks,
+    };
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        info!(status = body.status, "Readiness check failed");
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
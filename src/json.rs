@@ -0,0 +1,79 @@
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use tracing::info;
+
+use crate::error::AppError;
+use crate::payload::payload_too_large;
+
+// Pull the backtick-quoted name out of serde messages like "missing field `email`"
+fn quoted_name(message: &str) -> Option<&str> {
+    let start = message.find('`')? + 1;
+    let end = start + message[start..].find('`')?;
+    Some(&message[start..end])
+}
+
+// Describe a serde_json error as an AppError. serde_json only reports the
+// offending field for missing/unknown/duplicate fields; for type mismatches the
+// expected type and the line/column of the bad value are reported instead.
+fn deserialize_error(err: &serde_json::Error) -> AppError {
+    // serde_json appends " at line N column M"; strip it, we report those separately
+    let full = err.to_string();
+    let message = full
+        .rsplit_once(" at line ")
+        .map(|(message, _)| message)
+        .unwrap_or(&full)
+        .to_string();
+
+    let (code, field) = if let Some(rest) = message.strip_prefix("missing field ") {
+        ("missing_field", quoted_name(rest))
+    } else if let Some(rest) = message.strip_prefix("unknown field ") {
+        ("unknown_field", quoted_name(rest))
+    } else if let Some(rest) = message.strip_prefix("duplicate field ") {
+        ("duplicate_field", quoted_name(rest))
+    } else if err.is_syntax() || err.is_eof() {
+        ("malformed_json", None)
+    } else {
+        ("invalid_field_type", None)
+    };
+
+    let expected = message
+        .split_once(", expected ")
+        .map(|(_, expected)| expected.to_string());
+
+    let mut app_error = AppError::bad_request(code, format!("Invalid JSON body: {}", message))
+        .with("line", err.line())
+        .with("column", err.column());
+    if let Some(field) = field {
+        app_error = app_error.with("field", field);
+    }
+    if let Some(expected) = expected {
+        app_error = app_error.with("expected", expected);
+    }
+    app_error
+}
+
+// JsonConfig error handler mapping every JSON extraction failure to an AppError
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let app_error = match &err {
+        JsonPayloadError::Deserialize(inner) => deserialize_error(inner),
+        JsonPayloadError::ContentType => AppError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Request body must be sent with Content-Type: application/json",
+        ),
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            payload_too_large(*limit)
+        }
+        other => AppError::bad_request("invalid_body", other.to_string()),
+    };
+
+    info!(path = %req.path(), error = %app_error, "Rejected JSON body");
+    app_error.into()
+}
+
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(json_error_handler)
+}
@@ -0,0 +1,46 @@
+use std::sync::{LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::Instant;
+
+use crate::telemetry::record_lock_acquisition;
+
+// RwLock access that reports contention. An acquisition that can't be granted
+// straight away is counted as contended and its wait is recorded, per lock
+// name and mode, so read-heavy traffic blocking on writers shows up in metrics.
+pub trait MeasuredLock<T> {
+    fn read_measured(&self, name: &'static str) -> LockResult<RwLockReadGuard<'_, T>>;
+    fn write_measured(&self, name: &'static str) -> LockResult<RwLockWriteGuard<'_, T>>;
+}
+
+impl<T> MeasuredLock<T> for RwLock<T> {
+    fn read_measured(&self, name: &'static str) -> LockResult<RwLockReadGuard<'_, T>> {
+        match self.try_read() {
+            Ok(guard) => {
+                record_lock_acquisition(name, "read", None);
+                Ok(guard)
+            }
+            Err(TryLockError::Poisoned(err)) => Err(err),
+            Err(TryLockError::WouldBlock) => {
+                let started = Instant::now();
+                let guard = self.read();
+                record_lock_acquisition(name, "read", Some(started.elapsed()));
+                guard
+            }
+        }
+    }
+
+    fn write_measured(&self, name: &'static str) -> LockResult<RwLockWriteGuard<'_, T>> {
+        match self.try_write() {
+            Ok(guard) => {
+                record_lock_acquisition(name, "write", None);
+                Ok(guard)
+            }
+            Err(TryLockError::Poisoned(err)) => Err(err),
+            Err(TryLockError::WouldBlock) => {
+                let started = Instant::now();
+                let guard = self.write();
+                record_lock_acquisition(name, "write", Some(started.elapsed()));
+                guard
+            }
+        }
+    }
+}
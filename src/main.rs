@@ -1,32 +1,701 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, get, post};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError, delete, get, post, put};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware;
 use actix_web_opentelemetry::RequestTracing;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use futures_util::future::join_all;
 use opentelemetry::global;
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry_otlp::WithExportConfig;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::sync::Mutex;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tracing::{info, instrument};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::fields::{FieldSet, FieldsQuery};
+use crate::locks::MeasuredLock;
+use crate::query::ListQuery;
+use crate::repository::{NewUser, RepositoryError, SharedUserRepository};
+use crate::storage::Storage;
+
+mod activation;
+mod admin;
+mod admission;
+#[cfg(feature = "amqp")]
+mod amqp;
+mod api_keys;
+mod auth;
+mod backup;
+mod base_path;
+mod bus;
+mod circuit;
+mod client_ip;
+mod cli;
+mod compression;
+mod config;
+mod config_reload;
+mod connections;
+mod cors;
+mod csrf;
+mod dashboard;
+mod decompress;
+mod drain;
+mod email;
+mod enrichment;
+mod envelope;
+mod error;
+mod events;
+mod expiry;
+mod features;
+mod fields;
+mod grpc;
+mod health;
+mod json;
+mod locks;
+mod memory;
+#[cfg(feature = "moka")]
+mod moka_cache;
+#[cfg(feature = "mongodb")]
+mod mongo;
+#[cfg(feature = "nats")]
+mod nats;
+mod normalize;
+mod oidc;
+mod openapi;
+mod outbox;
+mod pagination;
+mod payload;
+mod persistence;
+mod profiling;
+mod pushgateway;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod query;
+mod quota;
+mod ratelimit;
+#[cfg(feature = "redis")]
+mod redis_cache;
+#[cfg(feature = "redis")]
+mod redis_client;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod repository;
+mod routes;
+mod sampling;
+mod scheduler;
+mod security;
+mod seed;
+mod sessions;
+mod sharded;
+mod shutdown;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statsd;
+mod status;
+mod storage;
+mod store;
+mod store_bench;
+mod store_metrics;
+#[cfg(feature = "redis")]
+mod sync;
+mod telemetry;
+mod tenant;
+mod timeout;
+mod tls;
+mod ui;
+mod version;
+mod wal;
+mod webhooks;
+
+// Users are identified by time-ordered UUIDv7s, which are non-guessable and
+// can be generated independently on every instance
+type UserId = Uuid;
 
 // Data structures using Serde for JSON serialization/deserialization
 #[derive(Serialize, Deserialize, Clone)]
 struct User {
-    id: u32,
+    id: UserId,
     name: String,
     email: String,
+    // Snapshots predating the status field import as pending
+    #[serde(default)]
+    status: UserStatus,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    // When the expiry reaper deletes the user; None keeps it indefinitely
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    // Never serialized, so it stays out of responses, webhooks and exports
+    #[serde(skip)]
+    credentials: Option<auth::Credentials>,
+    // What the ENRICHMENT_URL service answered when the user was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enrichment: Option<enrichment::Enrichment>,
+}
+
+// Field names selectable through `?fields=` on user endpoints
+const USER_FIELDS: &[&str] = &["id", "name", "email", "status", "created_at", "updated_at", "expires_at", "enrichment"];
+
+// Account lifecycle. New users start pending; only the transitions in
+// `UserStatus::apply` are legal.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum UserStatus {
+    #[default]
+    Pending,
+    Active,
+    Suspended,
+}
+
+// Lifecycle actions exposed as POST /users/{id}/<action>
+#[derive(Clone, Copy, Debug)]
+enum StatusAction {
+    Activate,
+    Suspend,
+}
+
+impl StatusAction {
+    fn name(self) -> &'static str {
+        match self {
+            StatusAction::Activate => "activate",
+            StatusAction::Suspend => "suspend",
+        }
+    }
+}
+
+impl UserStatus {
+    fn name(self) -> &'static str {
+        match self {
+            UserStatus::Pending => "pending",
+            UserStatus::Active => "active",
+            UserStatus::Suspended => "suspended",
+        }
+    }
+
+    // Inverse of name(), for backends that store the status as text
+    #[cfg_attr(not(any(feature = "postgres", feature = "sqlite", feature = "redis", feature = "mongodb")), allow(dead_code))]
+    fn from_name(name: &str) -> Option<UserStatus> {
+        match name {
+            "pending" => Some(UserStatus::Pending),
+            "active" => Some(UserStatus::Active),
+            "suspended" => Some(UserStatus::Suspended),
+            _ => None,
+        }
+    }
+
+    // The status reached by applying `action`, or None if it isn't allowed from here
+    fn apply(self, action: StatusAction) -> Option<UserStatus> {
+        match (self, action) {
+            (UserStatus::Pending, StatusAction::Activate) => Some(UserStatus::Active),
+            (UserStatus::Suspended, StatusAction::Activate) => Some(UserStatus::Active),
+            (UserStatus::Active, StatusAction::Suspend) => Some(UserStatus::Suspended),
+            _ => None,
+        }
+    }
 }
 
+// Why a lifecycle action could not be applied
+enum TransitionError {
+    NotFound,
+    Illegal(UserStatus),
+    Storage(RepositoryError),
+}
+
+impl From<RepositoryError> for TransitionError {
+    fn from(err: RepositoryError) -> Self {
+        TransitionError::Storage(err)
+    }
+}
+
+// Request body for POST /users and PUT /users/{id}
 #[derive(Deserialize)]
 struct CreateUser {
     name: String,
     email: String,
+    // Only read on creation; users created without one can't log in
+    #[serde(default)]
+    password: Option<String>,
+    // Only read on creation; capped by USER_TTL_SECS when that is set
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+// Request body for POST /users/{id}/change-password. The current password is
+// required once one is set.
+#[derive(Deserialize)]
+struct ChangePassword {
+    #[serde(default)]
+    current_password: Option<String>,
+    new_password: String,
+}
+
+// Per-user profile details, served as a sub-resource of the user
+#[derive(Serialize, Deserialize, Clone)]
+struct Profile {
+    #[serde(default)]
+    bio: String,
+    #[serde(default = "default_locale")]
+    locale: String,
+    #[serde(default = "default_timezone")]
+    timezone: String,
+}
+
+fn default_locale() -> String {
+    "en-US".to_string()
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            bio: String::new(),
+            locale: default_locale(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+// Maximum length of a profile bio, in characters
+const MAX_BIO_LENGTH: usize = 500;
+
+// Basic shape checks; returns a description of the first invalid field
+fn validate_profile(profile: &Profile) -> Result<(), String> {
+    if profile.bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(format!("bio must be at most {} characters", MAX_BIO_LENGTH));
+    }
+    // Accept BCP 47 style tags such as "en", "en-US" or "zh-Hant-TW"
+    let locale_ok = !profile.locale.is_empty()
+        && profile.locale.split('-').all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !locale_ok {
+        return Err(format!("locale '{}' is not a valid language tag", profile.locale));
+    }
+    // Accept "UTC" or IANA names such as "Europe/Berlin"
+    let timezone_ok = profile.timezone == "UTC"
+        || (profile.timezone.contains('/')
+            && profile.timezone.split('/').all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+            }));
+    if !timezone_ok {
+        return Err(format!("timezone '{}' is not a valid IANA time zone", profile.timezone));
+    }
+    Ok(())
+}
+
+// Uploaded avatar image, kept alongside the user it belongs to
+#[derive(Clone)]
+struct Avatar {
+    content_type: String,
+    data: web::Bytes,
+    etag: String,
+}
+
+// How new user IDs are generated
+#[derive(Clone, Copy, Debug)]
+enum IdMode {
+    // Random, time-ordered UUIDv7 (the default)
+    V7,
+    // Counter-based UUIDs such as 00000000-0000-0000-0000-000000000001, which are
+    // easy to type in demos but guessable and only unique within one instance
+    Sequential,
+}
+
+impl IdMode {
+    fn from_env() -> Self {
+        match get_env_or_default("USER_ID_MODE", "v7").to_lowercase().as_str() {
+            "sequential" => IdMode::Sequential,
+            _ => IdMode::V7,
+        }
+    }
 }
 
 // In-memory database (for demonstration)
 struct AppState {
-    users: Vec<User>,
+    users: store::UserStore,
+    id_mode: IdMode,
     user_counter: u32,
+    avatars: HashMap<UserId, Avatar>,
+    // Lowercased email -> ID of the first user (in insertion order) with
+    // that email. Rebuilt whenever a user is removed or an email changes.
+    email_index: HashMap<String, UserId>,
+    // Lazily computed per-domain counts for GET /stats/domains, dropped on
+    // any change to the user list or a user's email
+    domain_stats: Option<Arc<BTreeMap<String, usize>>>,
+    // Keys for machine clients, managed under /admin/api-keys
+    api_keys: api_keys::ApiKeyStore,
+    // Browser sessions created by /auth/session/login
+    sessions: sessions::SessionStore,
+    // Webhook events awaiting the relay when OUTBOX_ENABLED is set
+    outbox: outbox::Outbox,
+}
+
+// Response body for GET /users/count
+#[derive(Serialize)]
+struct UserCount {
+    total: usize,
+}
+
+// Aggregates returned by GET /users/stats
+#[derive(Serialize)]
+struct UserStats {
+    total: usize,
+    by_email_domain: BTreeMap<String, usize>,
+    created_per_day: BTreeMap<String, usize>,
+}
+
+impl UserStats {
+    // Count users by email domain and creation day, given each one's email
+    // and created_at
+    fn tally<'a>(users: impl IntoIterator<Item = (&'a str, DateTime<Utc>)>) -> Self {
+        let mut stats = UserStats { total: 0, by_email_domain: BTreeMap::new(), created_per_day: BTreeMap::new() };
+        for (email, created_at) in users {
+            stats.total += 1;
+            *stats.by_email_domain.entry(email_domain(email)).or_insert(0) += 1;
+            let day = created_at.date_naive().to_string();
+            *stats.created_per_day.entry(day).or_insert(0) += 1;
+        }
+        stats
+    }
+}
+
+// Request body for POST /users/batch-get
+#[derive(Deserialize)]
+struct BatchGetRequest {
+    ids: Vec<UserId>,
+}
+
+// Upper bound on IDs per batch lookup, keeping single requests cheap
+const MAX_BATCH_IDS: usize = 100;
+
+// Result of a batch lookup: found users in request order, plus the misses
+#[derive(Serialize)]
+struct BatchGetResponse {
+    users: Vec<User>,
+    missing_ids: Vec<UserId>,
+}
+
+// Request body for POST /users/bulk
+#[derive(Deserialize)]
+struct BulkCreateRequest {
+    users: Vec<CreateUser>,
+}
+
+// Upper bound on users per bulk create, as for batch lookups
+const MAX_BULK_USERS: usize = 100;
+
+// Why an existing user was flagged as a possible duplicate
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MatchReason {
+    SameEmail,
+    SimilarName,
+}
+
+// One potential match returned by POST /users/check-duplicates
+#[derive(Serialize)]
+struct DuplicateMatch {
+    user: User,
+    reasons: Vec<MatchReason>,
+}
+
+impl DuplicateMatch {
+    // The users among `users` that look like a user named `name` with address
+    // `email`, in the order given
+    fn among<'a>(users: impl IntoIterator<Item = &'a User>, name: &str, email: &str) -> Vec<DuplicateMatch> {
+        let email = email.trim();
+        let name = normalize_name(name);
+        users
+            .into_iter()
+            .filter_map(|user| {
+                let mut reasons = Vec::new();
+                if !email.is_empty() && user.email.eq_ignore_ascii_case(email) {
+                    reasons.push(MatchReason::SameEmail);
+                }
+                if !name.is_empty() && normalize_name(&user.name) == name {
+                    reasons.push(MatchReason::SimilarName);
+                }
+                (!reasons.is_empty()).then(|| DuplicateMatch { user: user.clone(), reasons })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct DuplicateCheckResponse {
+    matches: Vec<DuplicateMatch>,
+}
+
+// Comparison key for names: case, punctuation and word order are ignored, so
+// "Smith, John" and "john  smith" compare equal
+fn normalize_name(name: &str) -> String {
+    let lowered = name.to_lowercase();
+    let mut words: Vec<&str> = lowered
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+// Lowercased domain part of an email address, grouping malformed ones together
+fn email_domain(email: &str) -> String {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Query helpers over the stored users. Aggregations live here rather than in
+// the handlers so they can later be pushed down into a database query.
+impl AppState {
+    fn new() -> Self {
+        AppState {
+            users: store::UserStore::default(),
+            id_mode: IdMode::from_env(),
+            user_counter: 0,
+            avatars: HashMap::new(),
+            email_index: HashMap::new(),
+            domain_stats: None,
+            api_keys: api_keys::ApiKeyStore::default(),
+            sessions: sessions::SessionStore::default(),
+            outbox: outbox::Outbox::default(),
+        }
+    }
+
+    fn next_user_id(&mut self) -> UserId {
+        self.user_counter += 1;
+        match self.id_mode {
+            IdMode::V7 => Uuid::now_v7(),
+            IdMode::Sequential => Uuid::from_u128(self.user_counter as u128),
+        }
+    }
+
+    fn create_user(
+        &mut self,
+        name: String,
+        email: String,
+        credentials: Option<auth::Credentials>,
+        expires_at: Option<DateTime<Utc>>,
+        enrichment: Option<enrichment::Enrichment>,
+    ) -> User {
+        let now = Utc::now();
+        let user = User {
+            id: self.next_user_id(),
+            name,
+            email,
+            status: UserStatus::Pending,
+            created_at: now,
+            updated_at: now,
+            expires_at,
+            credentials,
+            enrichment,
+        };
+        self.email_index.entry(user.email.to_lowercase()).or_insert(user.id);
+        self.users.insert(user.clone());
+        self.domain_stats = None;
+        user
+    }
+
+    fn reindex_emails(&mut self) {
+        self.email_index.clear();
+        for user in self.users.iter() {
+            self.email_index.entry(user.email.to_lowercase()).or_insert(user.id);
+        }
+    }
+
+    fn find_by_email(&self, email: &str) -> Option<&User> {
+        self.email_index
+            .get(&email.to_lowercase())
+            .and_then(|&id| self.users.get(id))
+    }
+
+    // One page of users matching the query's filters, plus the total match count.
+    // Without a sort parameter users come back in insertion order; time bounds are exclusive.
+    fn list_users(&self, query: &ListQuery) -> (Vec<User>, usize) {
+        query.apply(self.users.iter())
+    }
+
+    // Existing users that look like the given name and email, in storage order
+    fn find_duplicates(&self, name: &str, email: &str) -> Vec<DuplicateMatch> {
+        DuplicateMatch::among(self.users.iter(), name, email)
+    }
+
+    // Replace a user's name and email; None if the user doesn't exist
+    fn update_user(&mut self, id: UserId, name: String, email: String) -> Option<User> {
+        let user = self.users.get_mut(id)?;
+        let email_changed = !user.email.eq_ignore_ascii_case(&email);
+        user.name = name;
+        user.email = email;
+        user.updated_at = Utc::now();
+        let user = user.clone();
+        if email_changed {
+            self.reindex_emails();
+            self.domain_stats = None;
+        }
+        Some(user)
+    }
+
+    // Remove a user and its avatar; None if the user doesn't exist
+    fn delete_user(&mut self, id: UserId) -> Option<User> {
+        let user = self.users.remove(id)?;
+        self.avatars.remove(&id);
+        self.reindex_emails();
+        self.domain_stats = None;
+        Some(user)
+    }
+
+    fn delete_expired(&mut self, now: DateTime<Utc>) -> Vec<User> {
+        let expired: Vec<UserId> = self
+            .users
+            .iter()
+            .filter(|user| user.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|user| user.id)
+            .collect();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+        let users: Vec<User> = expired.into_iter().filter_map(|id| self.users.remove(id)).collect();
+        for user in &users {
+            self.avatars.remove(&user.id);
+        }
+        self.reindex_emails();
+        self.domain_stats = None;
+        users
+    }
+
+    // Move a user through the lifecycle, returning the previous status and the updated user
+    fn transition_user(&mut self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let user = self.users.get_mut(id).ok_or(TransitionError::NotFound)?;
+        let from = user.status;
+        user.status = from.apply(action).ok_or(TransitionError::Illegal(from))?;
+        user.updated_at = Utc::now();
+        Ok((from, user.clone()))
+    }
+
+    // Replace a user's password; None if the user doesn't exist
+    fn set_credentials(&mut self, id: UserId, credentials: auth::Credentials) -> Option<User> {
+        let user = self.users.get_mut(id)?;
+        user.credentials = Some(credentials);
+        user.updated_at = Utc::now();
+        Some(user.clone())
+    }
+
+    // Record that a user or one of its sub-resources changed; None if the user doesn't exist
+    fn touch_user(&mut self, id: UserId) -> Option<User> {
+        let user = self.users.get_mut(id)?;
+        user.updated_at = Utc::now();
+        Some(user.clone())
+    }
+
+    // Drop the avatar and sessions of a deleted user, wherever the user was stored
+    fn forget_user(&mut self, id: UserId) {
+        self.avatars.remove(&id);
+        self.sessions.remove_user(id);
+    }
+
+    fn count_users(&self) -> usize {
+        self.users.len()
+    }
+
+    // User counts per email domain, served from the cache when it's still valid
+    fn domain_stats(&mut self) -> Arc<BTreeMap<String, usize>> {
+        if let Some(stats) = &self.domain_stats {
+            telemetry::record_cache_lookup("domain_stats", true);
+            return stats.clone();
+        }
+        telemetry::record_cache_lookup("domain_stats", false);
+
+        let mut by_domain = BTreeMap::new();
+        for user in self.users.iter() {
+            *by_domain.entry(email_domain(&user.email)).or_insert(0) += 1;
+        }
+        let stats = Arc::new(by_domain);
+        self.domain_stats = Some(stats.clone());
+        stats
+    }
+
+    fn user_stats(&self) -> UserStats {
+        UserStats::tally(self.users.iter().map(|user| (user.email.as_str(), user.created_at)))
+    }
+}
+
+// Profiles live behind their own lock, separate from AppState, so profile
+// reads and writes never hold up requests that only touch the user list
+#[derive(Default)]
+struct ProfileState {
+    profiles: HashMap<UserId, Profile>,
+}
+
+// Image types accepted for avatar uploads
+const AVATAR_CONTENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// How long clients and proxies may reuse an avatar before revalidating
+const AVATAR_CACHE_CONTROL: &str = "public, max-age=3600, must-revalidate";
+
+// Strong ETag derived from the SHA-256 of the response body
+fn compute_etag(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    let hex: String = digest.iter().take(16).map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+// Check whether the client's If-None-Match header already covers this ETag
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        }))
+        .unwrap_or(false)
+}
+
+// Generate a GitHub-style 5x5 mirrored identicon as SVG, seeded from the email
+fn generate_identicon(seed: &str) -> String {
+    let digest = Sha256::digest(seed.trim().to_lowercase().as_bytes());
+    let color = format!("#{:02x}{:02x}{:02x}", digest[0], digest[1], digest[2]);
+
+    let mut cells = String::new();
+    for row in 0..5 {
+        for col in 0..3 {
+            // One bit per cell of the left half; the right half mirrors it
+            let bit_index = row * 3 + col;
+            let filled = (digest[3 + bit_index / 8] >> (bit_index % 8)) & 1 == 1;
+            if !filled {
+                continue;
+            }
+            for x in [col, 4 - col] {
+                cells.push_str(&format!(
+                    "<rect x=\"{}\" y=\"{}\" width=\"50\" height=\"50\"/>",
+                    25 + x * 50,
+                    25 + row * 50
+                ));
+                if x == 2 {
+                    break;
+                }
+            }
+        }
+    }
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"300\" height=\"300\" viewBox=\"0 0 300 300\">\
+         <rect width=\"300\" height=\"300\" fill=\"#f0f0f0\"/><g fill=\"{}\">{}</g></svg>",
+        color, cells
+    )
 }
 
 // Handler for GET /
@@ -36,99 +705,791 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello, actix-web!")
 }
 
-// Handler for GET /users
+// Parse a comma-separated `ids` query value of user UUIDs
+fn parse_id_list(raw: &str) -> Result<Vec<UserId>, String> {
+    let ids = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<UserId>().map_err(|_| format!("'{}' is not a valid user ID", part)))
+        .collect::<Result<Vec<_>, _>>()?;
+    validate_id_list(&ids)?;
+    Ok(ids)
+}
+
+fn validate_id_list(ids: &[UserId]) -> Result<(), String> {
+    if ids.is_empty() {
+        return Err("at least one user ID is required".to_string());
+    }
+    if ids.len() > MAX_BATCH_IDS {
+        return Err(format!("at most {} user IDs may be requested at once", MAX_BATCH_IDS));
+    }
+    Ok(())
+}
+
+// Look up a batch of users in one repository call, answering in request order
+async fn batch_get_response(repository: &SharedUserRepository, ids: &[UserId], fields: Option<&FieldSet>) -> HttpResponse {
+    let mut found: HashMap<UserId, User> = match repository.get_many(ids).await {
+        Ok(users) => users.into_iter().map(|user| (user.id, user)).collect(),
+        Err(err) => return err.error_response(),
+    };
+
+    let mut result = BatchGetResponse { users: Vec::new(), missing_ids: Vec::new() };
+    let mut seen = std::collections::HashSet::new();
+    for &id in ids {
+        // Repeated IDs are answered once
+        if !seen.insert(id) {
+            continue;
+        }
+        match found.remove(&id) {
+            Some(user) => result.users.push(user),
+            None => result.missing_ids.push(id),
+        }
+    }
+    info!(
+        requested = ids.len(),
+        found = result.users.len(),
+        missing = result.missing_ids.len(),
+        "Batch fetched users"
+    );
+    HttpResponse::Ok().json(serde_json::json!({
+        "users": fields::apply(&result.users, fields),
+        "missing_ids": result.missing_ids,
+    }))
+}
+
+// Handler for GET /users, or GET /users?ids=1,2,5 for a batch lookup
 #[get("/users")]
-#[instrument(name = "get_users_handler", skip(data), fields(service = "actix_example"))]
-async fn get_users(data: web::Data<Mutex<AppState>>) -> impl Responder {
+#[instrument(name = "get_users_handler", skip(req, query, repository, features), fields(service = "actix_example"))]
+async fn get_users(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    repository: SharedUserRepository,
+    features: features::Features,
+) -> impl Responder {
+    if let Err(err) = query.validate() {
+        info!(error = %err, "Invalid listing query");
+        return err.error_response();
+    }
+
+    if let Some(raw_ids) = &query.ids {
+        return match parse_id_list(raw_ids) {
+            Ok(ids) => batch_get_response(&repository, &ids, query.fields.as_ref()).await,
+            Err(message) => {
+                info!(error = %message, "Invalid ids parameter");
+                query::invalid_parameter("ids", message).error_response()
+            }
+        };
+    }
+
     info!("Fetching all users");
 
-    let app_state = match data.lock() {
-        Ok(state) => state,
-        Err(_) => {
-            info!("Failed to lock application state");
-            return HttpResponse::InternalServerError().body("Failed to lock application state");
-        }
+    let (users, total) = match repository.list(&query).await {
+        Ok(page) => page,
+        Err(err) => return err.error_response(),
     };
-    
-    let users = app_state.users.clone();
     let user_count = users.len();
-    info!(user_count = user_count, "Successfully fetched users");
+    info!(user_count = user_count, total = total, page = query.page, "Successfully fetched users");
     
 
-    HttpResponse::Ok().json(users)
+    let pagination = pagination::Pagination::new(query.page, query.limit, total);
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", total.to_string()));
+    if features.is_enabled(features::PAGINATION_LINKS, true) {
+        response.insert_header((header::LINK, pagination.link_header(&req)));
+    }
+    let mut response = response.json(fields::apply(&users, query.fields.as_ref()));
+    response.extensions_mut().insert(pagination);
+    response
+}
+
+// Handler for GET /users/by-email/{email}
+#[get("/users/by-email/{email}")]
+#[instrument(name = "get_user_by_email_handler", skip(path, query, repository), fields(service = "actix_example"))]
+async fn get_user_by_email(
+    path: web::Path<String>,
+    query: web::Query<FieldsQuery>,
+    repository: SharedUserRepository,
+) -> impl Responder {
+    // The router leaves %2B, %2F and %25 encoded in path segments, so decode
+    // once more to get the literal address (e.g. "alice%2Btag@example.com")
+    let email = match percent_encoding::percent_decode_str(&path).decode_utf8() {
+        Ok(email) => email.into_owned(),
+        Err(_) => {
+            return AppError::bad_request("invalid_email", "email is not valid UTF-8 once percent-decoded")
+                .error_response()
+        }
+    };
+    info!("Looking up user by email");
+
+    if let Some(Err(err)) = query.fields.as_ref().map(|fields| fields.validate(USER_FIELDS)) {
+        return err.error_response();
+    }
+
+    match repository.find_by_email(&email).await {
+        Err(err) => err.error_response(),
+        Ok(Some(user)) => {
+            info!(user_id = %user.id, "User found");
+            HttpResponse::Ok().json(fields::apply(&user, query.fields.as_ref()))
+        }
+        Ok(None) => {
+            info!("User not found");
+            HttpResponse::NotFound().body(format!("User with email {} not found", email))
+        }
+    }
+}
+
+// Handler for POST /users/check-duplicates
+#[post("/users/check-duplicates")]
+#[instrument(name = "check_duplicates_handler", skip(candidate, repository), fields(service = "actix_example"))]
+async fn check_duplicates(candidate: web::Json<CreateUser>, repository: SharedUserRepository) -> impl Responder {
+    info!("Checking candidate user for duplicates");
+
+    let matches = match repository.find_duplicates(&candidate.name, &candidate.email).await {
+        Ok(matches) => matches,
+        Err(err) => return err.error_response(),
+    };
+
+    info!(match_count = matches.len(), "Duplicate check complete");
+    HttpResponse::Ok().json(DuplicateCheckResponse { matches })
+}
+
+// Handler for POST /users/batch-get
+#[post("/users/batch-get")]
+#[instrument(name = "batch_get_users_handler", skip(body, query, repository), fields(service = "actix_example"))]
+async fn batch_get_users(
+    body: web::Json<BatchGetRequest>,
+    query: web::Query<FieldsQuery>,
+    repository: SharedUserRepository,
+) -> impl Responder {
+    if let Err(message) = validate_id_list(&body.ids) {
+        info!(error = %message, "Invalid batch request");
+        return AppError::bad_request("invalid_ids", message).with("field", "ids").error_response();
+    }
+    if let Some(Err(err)) = query.fields.as_ref().map(|fields| fields.validate(USER_FIELDS)) {
+        return err.error_response();
+    }
+    batch_get_response(&repository, &body.ids, query.fields.as_ref()).await
+}
+
+// Handler for GET /users/count
+#[get("/users/count")]
+#[instrument(name = "count_users_handler", skip(repository), fields(service = "actix_example"))]
+async fn count_users(repository: SharedUserRepository) -> impl Responder {
+    let total = match repository.count().await {
+        Ok(total) => total,
+        Err(err) => return err.error_response(),
+    };
+    info!(user_count = total, "Counted users");
+    HttpResponse::Ok().json(UserCount { total })
+}
+
+// Handler for GET /users/stats
+#[get("/users/stats")]
+#[instrument(name = "user_stats_handler", skip(repository), fields(service = "actix_example"))]
+async fn user_stats(repository: SharedUserRepository) -> impl Responder {
+    let stats = match repository.stats().await {
+        Ok(stats) => stats,
+        Err(err) => return err.error_response(),
+    };
+    info!(
+        user_count = stats.total,
+        domain_count = stats.by_email_domain.len(),
+        "Computed user statistics"
+    );
+    HttpResponse::Ok().json(stats)
+}
+
+// Response body for GET /stats/domains
+#[derive(Serialize)]
+struct DomainStats {
+    domains: Arc<BTreeMap<String, usize>>,
+}
+
+// Handler for GET /stats/domains
+#[get("/stats/domains")]
+#[instrument(name = "domain_stats_handler", skip(repository), fields(service = "actix_example"))]
+async fn domain_stats(repository: SharedUserRepository) -> impl Responder {
+    info!("Computing per-domain user counts");
+
+    let domains = match repository.domain_counts().await {
+        Ok(domains) => domains,
+        Err(err) => return err.error_response(),
+    };
+
+    info!(domain_count = domains.len(), "Domain stats ready");
+    HttpResponse::Ok().json(DomainStats { domains })
 }
 
 // Handler for GET /users/{id}
 #[get("/users/{id}")]
-#[instrument(name = "get_user_handler", skip(data), fields(service = "actix_example"))]
-async fn get_user(path: web::Path<u32>, data: web::Data<Mutex<AppState>>) -> impl Responder {
+#[instrument(name = "get_user_handler", skip(query, repository), fields(service = "actix_example"))]
+async fn get_user(
+    path: web::Path<UserId>,
+    query: web::Query<FieldsQuery>,
+    repository: SharedUserRepository,
+) -> impl Responder {
     let user_id = path.into_inner();
-    info!(user_id = user_id, "Looking up user by ID");
+    info!(user_id = %user_id, "Looking up user by ID");
 
-    
-    let app_state = match data.lock() {
-        Ok(state) => state,
-        Err(_) => {
-            info!("Failed to lock application state");
-            return HttpResponse::InternalServerError().body("Failed to lock application state");
-        }
+    if let Some(Err(err)) = query.fields.as_ref().map(|fields| fields.validate(USER_FIELDS)) {
+        return err.error_response();
+    }
+
+    let (user, lookup) = match repository.get_with_cache_status(user_id).await {
+        Ok(found) => found,
+        Err(err) => return err.error_response(),
     };
-    
-    match app_state.users.iter().find(|u| u.id == user_id) {
+    let mut response = match &user {
+        Some(_) => HttpResponse::Ok(),
+        None => HttpResponse::NotFound(),
+    };
+    if let Some(lookup) = lookup {
+        lookup.headers(&mut response);
+    }
+    match user {
         Some(user) => {
-            info!(user_id = user_id, "User found");
-            HttpResponse::Ok().json(user.clone())
+            info!(user_id = %user_id, "User found");
+            response.json(fields::apply(&user, query.fields.as_ref()))
         },
         None => {
-            info!(user_id = user_id, "User not found");
-            HttpResponse::NotFound().body(format!("User with ID {} not found", user_id))
+            info!(user_id = %user_id, "User not found");
+            response.body(format!("User with ID {} not found", user_id))
+        }
+    }
+}
+
+// Validate and hash the optional password of a user being created
+async fn new_credentials(password: Option<String>) -> Result<Option<auth::Credentials>, HttpResponse> {
+    let Some(password) = password else {
+        return Ok(None);
+    };
+    if let Err(err) = auth::validate_password(&password) {
+        return Err(err.error_response());
+    }
+    match auth::Credentials::from_password(password).await {
+        Ok(credentials) => Ok(Some(credentials)),
+        Err(err) => {
+            tracing::warn!(error = %err, "Failed to hash password");
+            Err(HttpResponse::InternalServerError().body("Failed to hash password"))
         }
     }
 }
 
 // Handler for POST /users
 #[post("/users")]
-#[instrument(name = "create_user_handler", skip(user, data), fields(service = "actix_example"))]
-async fn create_user(user: web::Json<CreateUser>, data: web::Data<Mutex<AppState>>) -> impl Responder {
+#[instrument(name = "create_user_handler", skip(user, repository, expiry, enricher, mailer, webhooks), fields(service = "actix_example"))]
+async fn create_user(
+    user: web::Json<CreateUser>,
+    repository: SharedUserRepository,
+    expiry: web::Data<expiry::ExpiryPolicy>,
+    enricher: web::Data<Option<enrichment::Enricher>>,
+    mailer: web::Data<email::Mailer>,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+) -> impl Responder {
     info!(name = %user.name, email = %user.email, "Creating new user");
 
-    // Lock the mutex to get exclusive access to app state
-    let mut app_state = match data.lock() {
-        Ok(state) => state,
-        Err(_) => {
-            info!("Failed to lock application state");
-            return HttpResponse::InternalServerError().body("Failed to lock application state");
-        }
+    // Hash before taking the lock so other requests aren't kept waiting
+    let user = user.into_inner();
+    let expires_at = match expiry.expires_at(user.ttl_secs) {
+        Ok(expires_at) => expires_at,
+        Err(err) => return err.error_response(),
     };
-    
-    // Create a new user with auto-incremented ID
-    let user_id = app_state.user_counter + 1;
-    let new_user = User {
-        id: user_id,
-        name: user.name.clone(),
-        email: user.email.clone(),
+    let credentials = match new_credentials(user.password).await {
+        Ok(credentials) => credentials,
+        Err(response) => return response,
     };
-    
-    // Update the shared state
-    app_state.users.push(new_user.clone());
-    app_state.user_counter = user_id;
 
-    info!(user_id = user_id, "User created successfully");
+    // An unavailable enrichment service leaves the user without enrichment
+    let enrichment = enrichment::lookup(&enricher, &user.email).await;
+
+    // Create the user with a freshly generated ID and store it
+    let new_user = match repository.create(NewUser { name: user.name, email: user.email, credentials, expires_at, enrichment }).await {
+        Ok(user) => user,
+        Err(err) => return err.error_response(),
+    };
+
+    info!(user_id = %new_user.id, "User created successfully");
+
+    // Sent in the background; the job's span joins this request's trace
+    mailer.send_welcome(&new_user.name, &new_user.email);
+    outbox::publish(&repository, &webhooks, webhooks::USER_CREATED, &new_user);
     
     // Return the created user with 201 Created status
     HttpResponse::Created().json(new_user)
 }
 
+// Command taken from NATS_COMMAND_SUBJECT: create a user from a POST /users
+// body, replying with the created user
+#[cfg(feature = "nats")]
+async fn create_user_command(
+    payload: web::Bytes,
+    repository: SharedUserRepository,
+    expiry: web::Data<expiry::ExpiryPolicy>,
+    enricher: web::Data<Option<enrichment::Enricher>>,
+    mailer: web::Data<email::Mailer>,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+) -> Result<serde_json::Value, String> {
+    let user: CreateUser = serde_json::from_slice(&payload).map_err(|err| format!("invalid command: {}", err))?;
+    info!(name = %user.name, email = %user.email, "Creating new user from a command");
+    let expires_at = expiry.expires_at(user.ttl_secs).map_err(|err| err.to_string())?;
+    let credentials = match user.password {
+        Some(password) => {
+            auth::validate_password(&password).map_err(|err| err.to_string())?;
+            let credentials = auth::Credentials::from_password(password).await;
+            Some(credentials.map_err(|err| format!("failed to hash password: {}", err))?)
+        }
+        None => None,
+    };
+    let enrichment = enrichment::lookup(&enricher, &user.email).await;
+    let new_user = repository
+        .create(NewUser { name: user.name, email: user.email, credentials, expires_at, enrichment })
+        .await
+        .map_err(|err| err.to_string())?;
+    info!(user_id = %new_user.id, "User created successfully");
+    mailer.send_welcome(&new_user.name, &new_user.email);
+    outbox::publish(&repository, &webhooks, webhooks::USER_CREATED, &new_user);
+    serde_json::to_value(new_user).map_err(|err| err.to_string())
+}
+
+// Handler for POST /users/bulk. The users are created in one transaction:
+// if any of them can't be stored, none are.
+#[post("/users/bulk")]
+#[instrument(name = "bulk_create_users_handler", skip_all, fields(service = "actix_example", user_count = body.users.len()))]
+async fn bulk_create_users(
+    body: web::Json<BulkCreateRequest>,
+    repository: SharedUserRepository,
+    expiry: web::Data<expiry::ExpiryPolicy>,
+    enricher: web::Data<Option<enrichment::Enricher>>,
+    mailer: web::Data<email::Mailer>,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+) -> impl Responder {
+    let users = body.into_inner().users;
+    if users.is_empty() || users.len() > MAX_BULK_USERS {
+        return AppError::bad_request(
+            "invalid_batch",
+            format!("between 1 and {} users may be created at once", MAX_BULK_USERS),
+        )
+        .with("field", "users")
+        .error_response();
+    }
+
+    // Lookups run concurrently, so a batch waits about as long as one user
+    let enrichments = join_all(users.iter().map(|user| enrichment::lookup(&enricher, &user.email))).await;
+    let mut new_users = Vec::with_capacity(users.len());
+    for (index, (user, enrichment)) in users.into_iter().zip(enrichments).enumerate() {
+        let expires_at = match expiry.expires_at(user.ttl_secs) {
+            Ok(expires_at) => expires_at,
+            Err(err) => return err.with("index", index).error_response(),
+        };
+        match new_credentials(user.password).await {
+            Ok(credentials) => new_users.push(NewUser { name: user.name, email: user.email, credentials, expires_at, enrichment }),
+            Err(response) => {
+                info!(index, "Rejected bulk create; invalid password");
+                return response;
+            }
+        }
+    }
+
+    let created = match repository.create_many(new_users).await {
+        Ok(created) => created,
+        Err(err) => return err.error_response(),
+    };
+    info!(user_count = created.len(), "Users created in bulk");
+
+    // Side effects only once the whole batch is committed
+    for user in &created {
+        mailer.send_welcome(&user.name, &user.email);
+        outbox::publish(&repository, &webhooks, webhooks::USER_CREATED, user);
+    }
+    HttpResponse::Created().json(created)
+}
+
+// Handler for PUT /users/{id}
+#[put("/users/{id}")]
+#[instrument(name = "update_user_handler", skip(user, repository, webhooks, caller), fields(service = "actix_example"))]
+async fn update_user(
+    path: web::Path<UserId>,
+    user: web::Json<CreateUser>,
+    repository: SharedUserRepository,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+    caller: Option<web::ReqData<auth::Identity>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let actor = caller.map(|caller| caller.subject());
+    info!(user_id = %user_id, actor = ?actor, "Updating user");
+
+    let user = user.into_inner();
+    match repository.update(user_id, user.name, user.email).await {
+        Err(err) => err.error_response(),
+        Ok(Some(user)) => {
+            info!(user_id = %user_id, "User updated successfully");
+            outbox::publish(&repository, &webhooks, webhooks::USER_UPDATED, &user);
+            HttpResponse::Ok().json(user)
+        }
+        Ok(None) => {
+            info!(user_id = %user_id, "User not found");
+            HttpResponse::NotFound().body(format!("User with ID {} not found", user_id))
+        }
+    }
+}
+
+// End a user's browser sessions, which live in AppState whatever the backend
+fn end_sessions(data: &web::Data<RwLock<AppState>>, user_id: UserId) {
+    match data.write_measured("app_state") {
+        Ok(mut state) => state.sessions.remove_user(user_id),
+        Err(_) => info!("Failed to lock application state; leaving sessions open"),
+    }
+}
+
+// Shared body of the lifecycle endpoints. Each transition is logged as an
+// event on the handler span, so it shows up as a span event in the trace.
+// With the outbox enabled the repository records the event along with the
+// transition, as it does for its other mutations.
+async fn transition_user(
+    data: &web::Data<RwLock<AppState>>,
+    repository: &SharedUserRepository,
+    webhooks: &webhooks::WebhookPublisher,
+    user_id: UserId,
+    action: StatusAction,
+) -> HttpResponse {
+    match repository.set_status(user_id, action).await {
+        Ok((from, user)) => {
+            info!(user_id = %user_id, action = action.name(), from = from.name(), to = user.status.name(), "User status transition");
+            // Suspension signs the user out of the browser too
+            if user.status == UserStatus::Suspended {
+                end_sessions(data, user_id);
+            }
+            outbox::publish(repository, webhooks, webhooks::USER_UPDATED, &user);
+            HttpResponse::Ok().json(user)
+        }
+        Err(TransitionError::NotFound) => {
+            info!(user_id = %user_id, "User not found");
+            HttpResponse::NotFound().body(format!("User with ID {} not found", user_id))
+        }
+        Err(TransitionError::Illegal(from)) => {
+            info!(user_id = %user_id, action = action.name(), from = from.name(), "Rejected user status transition");
+            AppError::new(
+                StatusCode::CONFLICT,
+                "invalid_status_transition",
+                format!("Cannot {} a user whose status is {}", action.name(), from.name()),
+            )
+            .with("current_status", from)
+            .with("action", action.name())
+            .error_response()
+        }
+        Err(TransitionError::Storage(err)) => err.error_response(),
+    }
+}
+
+// Handler for POST /users/{id}/activate
+#[post("/users/{id}/activate")]
+#[instrument(name = "activate_user_handler", skip(data, repository, webhooks), fields(service = "actix_example"))]
+async fn activate_user(
+    path: web::Path<UserId>,
+    data: web::Data<RwLock<AppState>>,
+    repository: SharedUserRepository,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+) -> impl Responder {
+    transition_user(&data, &repository, &webhooks, path.into_inner(), StatusAction::Activate).await
+}
+
+// Handler for POST /users/{id}/suspend
+#[post("/users/{id}/suspend")]
+#[instrument(name = "suspend_user_handler", skip(data, repository, webhooks), fields(service = "actix_example"))]
+async fn suspend_user(
+    path: web::Path<UserId>,
+    data: web::Data<RwLock<AppState>>,
+    repository: SharedUserRepository,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+) -> impl Responder {
+    transition_user(&data, &repository, &webhooks, path.into_inner(), StatusAction::Suspend).await
+}
+
+// Handler for POST /users/{id}/change-password
+#[post("/users/{id}/change-password")]
+#[instrument(name = "change_password_handler", skip(body, data, repository, caller), fields(service = "actix_example"))]
+async fn change_password(
+    path: web::Path<UserId>,
+    body: web::Json<ChangePassword>,
+    data: web::Data<RwLock<AppState>>,
+    repository: SharedUserRepository,
+    caller: Option<web::ReqData<auth::Identity>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    info!(user_id = %user_id, "Changing user password");
+
+    // Signed-in users may only change their own password
+    let caller_user = caller.and_then(|caller| match *caller {
+        auth::Identity::User(id) | auth::Identity::Session { user_id: id, .. } => Some(id),
+        _ => None,
+    });
+    if caller_user.is_some_and(|id| id != user_id) {
+        info!(user_id = %user_id, "Rejected password change for another user");
+        return AppError::new(StatusCode::FORBIDDEN, "forbidden", "Cannot change another user's password")
+            .error_response();
+    }
+
+    let body = body.into_inner();
+    if let Err(err) = auth::validate_password(&body.new_password) {
+        return err.error_response();
+    }
+
+    let current = match repository.credentials(user_id).await {
+        Ok(current) => current,
+        Err(err) => return err.error_response(),
+    };
+    let Some(current) = current else {
+        info!(user_id = %user_id, "User not found");
+        return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+    };
+    if let Some(credentials) = current {
+        let confirmed = match &body.current_password {
+            Some(password) => credentials.verify(password).await,
+            None => false,
+        };
+        if !confirmed {
+            info!(user_id = %user_id, "Current password did not match");
+            return AppError::new(StatusCode::FORBIDDEN, "invalid_current_password", "Current password is incorrect")
+                .with("field", "current_password")
+                .error_response();
+        }
+    }
+
+    let credentials = match auth::Credentials::from_password(body.new_password).await {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            tracing::warn!(error = %err, "Failed to hash password");
+            return HttpResponse::InternalServerError().body("Failed to hash password");
+        }
+    };
+    let updated = match repository.set_credentials(user_id, credentials).await {
+        Ok(updated) => updated,
+        Err(err) => return err.error_response(),
+    };
+    // The user may have been deleted while the new password was being hashed
+    if updated.is_none() {
+        info!(user_id = %user_id, "User not found");
+        return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+    }
+    end_sessions(&data, user_id);
+
+    info!(user_id = %user_id, "Password changed");
+    HttpResponse::NoContent().finish()
+}
+
+// Handler for DELETE /users/{id}
+#[delete("/users/{id}")]
+#[instrument(name = "delete_user_handler", skip(repository, data, webhooks, caller), fields(service = "actix_example"))]
+async fn delete_user(
+    path: web::Path<UserId>,
+    repository: SharedUserRepository,
+    data: web::Data<RwLock<AppState>>,
+    webhooks: web::Data<webhooks::WebhookPublisher>,
+    caller: Option<web::ReqData<auth::Identity>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let actor = caller.map(|caller| caller.subject());
+    info!(user_id = %user_id, actor = ?actor, "Deleting user");
+
+    let deleted = match repository.delete(user_id).await {
+        Ok(deleted) => deleted,
+        Err(err) => return err.error_response(),
+    };
+    let Some(user) = deleted else {
+        info!(user_id = %user_id, "User not found");
+        return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+    };
+
+    // The repository dropped the avatar and profile; sessions live outside it
+    end_sessions(&data, user_id);
+
+    info!(user_id = %user_id, "User deleted successfully");
+    outbox::publish(&repository, &webhooks, webhooks::USER_DELETED, &user);
+    HttpResponse::NoContent().finish()
+}
+
+// Handler for GET /users/{id}/avatar
+#[get("/users/{id}/avatar")]
+#[instrument(name = "get_user_avatar_handler", skip(req, repository), fields(service = "actix_example"))]
+async fn get_user_avatar(req: HttpRequest, path: web::Path<UserId>, repository: SharedUserRepository) -> impl Responder {
+    let user_id = path.into_inner();
+    info!(user_id = %user_id, "Fetching user avatar");
+
+    let user = match repository.get(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            info!(user_id = %user_id, "User not found");
+            return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+        }
+        Err(err) => return err.error_response(),
+    };
+    let avatar = match repository.avatar(user_id).await {
+        Ok(avatar) => avatar,
+        Err(err) => return err.error_response(),
+    };
+
+    // Fall back to an identicon so every user has something to display
+    let (content_type, body, etag) = match avatar {
+        Some(avatar) => (avatar.content_type, avatar.data, avatar.etag),
+        None => {
+            let svg = web::Bytes::from(generate_identicon(&user.email));
+            let etag = compute_etag(&svg);
+            ("image/svg+xml".to_string(), svg, etag)
+        }
+    };
+
+    if etag_matches(&req, &etag) {
+        info!(user_id = %user_id, "Avatar not modified");
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, AVATAR_CACHE_CONTROL))
+            .finish();
+    }
+
+    info!(user_id = %user_id, content_type = %content_type, size = body.len(), "Serving avatar");
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, AVATAR_CACHE_CONTROL))
+        .body(body)
+}
+
+// Handler for PUT /users/{id}/avatar
+//
+// Accepts either the raw image as the body, or multipart/form-data with the
+// image in an `avatar` part. Both are size-limited while streaming.
+#[put("/users/{id}/avatar")]
+#[instrument(name = "upload_user_avatar_handler", skip(req, payload, repository, limits), fields(service = "actix_example"))]
+async fn upload_user_avatar(
+    req: HttpRequest,
+    path: web::Path<UserId>,
+    payload: web::Payload,
+    repository: SharedUserRepository,
+    limits: web::Data<payload::PayloadLimits>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+
+    let request_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase())
+        .unwrap_or_default();
+
+    let upload = if request_type == "multipart/form-data" {
+        payload::read_multipart_file(req.headers(), payload, "avatar", limits.multipart).await
+    } else {
+        payload::read_body(payload, limits.upload)
+            .await
+            .map(|body| (request_type, body))
+    };
+    let (content_type, body) = match upload {
+        Ok(upload) => upload,
+        Err(err) => {
+            info!(user_id = %user_id, error = %err, "Failed to read avatar upload");
+            return err.error_response();
+        }
+    };
+    info!(user_id = %user_id, size = body.len(), "Uploading user avatar");
+
+    if !AVATAR_CONTENT_TYPES.contains(&content_type.as_str()) {
+        info!(content_type = %content_type, "Unsupported avatar content type");
+        return HttpResponse::UnsupportedMediaType()
+            .body(format!("Avatar must be one of: {}", AVATAR_CONTENT_TYPES.join(", ")));
+    }
+
+    if body.is_empty() {
+        return HttpResponse::BadRequest().body("Avatar body must not be empty");
+    }
+
+    let etag = compute_etag(&body);
+    let avatar = Avatar {
+        content_type,
+        data: body,
+        etag: etag.clone(),
+    };
+    match repository.set_avatar(user_id, avatar).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            info!(user_id = %user_id, "User not found");
+            return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+        }
+        Err(err) => return err.error_response(),
+    }
+
+    info!(user_id = %user_id, "Avatar stored successfully");
+    HttpResponse::NoContent()
+        .insert_header((header::ETAG, etag))
+        .finish()
+}
+
+// Handler for GET /users/{id}/profile
+#[get("/users/{id}/profile")]
+#[instrument(name = "get_user_profile_handler", skip(repository), fields(service = "actix_example"))]
+async fn get_user_profile(path: web::Path<UserId>, repository: SharedUserRepository) -> impl Responder {
+    let user_id = path.into_inner();
+    info!(user_id = %user_id, "Fetching user profile");
+
+    match repository.get(user_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            info!(user_id = %user_id, "User not found");
+            return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+        }
+        Err(err) => return err.error_response(),
+    }
+
+    // Users without a stored profile get the defaults
+    match repository.profile(user_id).await {
+        Ok(profile) => HttpResponse::Ok().json(profile.unwrap_or_default()),
+        Err(err) => err.error_response(),
+    }
+}
+
+// Handler for PUT /users/{id}/profile
+#[put("/users/{id}/profile")]
+#[instrument(name = "update_user_profile_handler", skip(profile, repository), fields(service = "actix_example"))]
+async fn update_user_profile(
+    path: web::Path<UserId>,
+    profile: web::Json<Profile>,
+    repository: SharedUserRepository,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    info!(user_id = %user_id, locale = %profile.locale, timezone = %profile.timezone, "Updating user profile");
+
+    if let Err(message) = validate_profile(&profile) {
+        info!(user_id = %user_id, error = %message, "Invalid profile");
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    // Stored along with a bump of the user's updated_at
+    let profile = profile.into_inner();
+    match repository.set_profile(user_id, profile.clone()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            info!(user_id = %user_id, "User not found");
+            return HttpResponse::NotFound().body(format!("User with ID {} not found", user_id));
+        }
+        Err(err) => return err.error_response(),
+    }
+
+    info!(user_id = %user_id, "Profile updated successfully");
+    HttpResponse::Ok().json(profile)
+}
+
 // Get env var from environment variable or default
 fn get_env_or_default(env_var: &str, default: &str) -> String {
-    let result = env::var(env_var)
-        .unwrap_or_else(|_| default.to_string());
-    result
+    env::var(env_var)
+        .unwrap_or_else(|_| default.to_string())
 }
 
 
 // Initialize OpenTelemetry with OTLP exporter
-fn init_telemetry() -> opentelemetry::sdk::trace::Tracer {
+fn init_telemetry(
+    endpoint: &str,
+    sampler: sampling::ReloadableSampler,
+) -> Result<opentelemetry::sdk::trace::Tracer, opentelemetry::trace::TraceError> {
     global::set_text_map_propagator(TraceContextPropagator::new());
     
     // Set up the OTLP exporter
@@ -137,12 +1498,11 @@ fn init_telemetry() -> opentelemetry::sdk::trace::Tracer {
         .with_exporter(
             opentelemetry_otlp::new_exporter()
                 .tonic() // Using gRPC protocol
-                .with_endpoint(
-                    get_env_or_default("OTLP_ENDPOINT","http://localhost:4317")
-                )
+                .with_endpoint(endpoint)
         )
         .with_trace_config(
             opentelemetry_sdk::trace::config()
+                .with_sampler(sampler)
                 .with_resource(opentelemetry_sdk::Resource::new(vec![
                     opentelemetry::KeyValue::new("service.name", "actix-web-server"),
                     opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
@@ -150,65 +1510,996 @@ fn init_telemetry() -> opentelemetry::sdk::trace::Tracer {
                 ]))
         )
         .install_batch(opentelemetry_sdk::runtime::Tokio)
-        .expect("Failed to install OpenTelemetry tracer")
 }
 
+// Export metrics over the same OTLP endpoint as traces. Installs the global
+// meter provider, so instruments created through `global::meter` report here.
+fn init_metrics(endpoint: &str) -> Result<opentelemetry_sdk::metrics::controllers::BasicController, opentelemetry::metrics::MetricsError> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(
+            opentelemetry_sdk::metrics::selectors::simple::inexpensive(),
+            opentelemetry_sdk::export::metrics::aggregation::cumulative_temporality_selector(),
+            opentelemetry_sdk::runtime::Tokio,
+        )
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+        )
+        .with_resource(opentelemetry_sdk::Resource::new(vec![
+            opentelemetry::KeyValue::new("service.name", "actix-web-server"),
+            opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]))
+        .build()
+}
+
+// `export-openapi`: print or write the API description
+fn export_openapi(output: Option<&std::path::Path>, format: cli::DocumentFormat) -> std::io::Result<()> {
+    let document = openapi::document();
+    let rendered = match format {
+        cli::DocumentFormat::Json => serde_json::to_string_pretty(&document).map_err(std::io::Error::other)?,
+        cli::DocumentFormat::Yaml => serde_yaml::to_string(&document).map_err(std::io::Error::other)?,
+    };
+    let rendered = rendered + "\n";
+    match output {
+        Some(path) => std::fs::write(path, rendered),
+        // Written rather than println!'d so a closed pipe is an error, not a panic
+        None => std::io::Write::write_all(&mut std::io::stdout(), rendered.as_bytes()),
+    }
+}
+
+// `check-config`: report every problem with the settings that startup would
+// otherwise only surface as a failed readiness probe, then exit
+fn check_config(settings: &config::Settings) -> ! {
+    let mut errors = Vec::new();
+    if let Err(err) = EnvFilter::try_new(&settings.telemetry.log_level) {
+        errors.push(format!("invalid log level '{}': {}", settings.telemetry.log_level, err));
+    }
+    if let Err(err) = settings.server.validate() {
+        errors.push(err);
+    }
+    if let Err(err) = settings.scheduler.validate() {
+        errors.push(err);
+    }
+    if let Err(err) = tls::Certificates::from_env() {
+        errors.push(err);
+    }
+    if let Err(err) = Storage::from_env() {
+        errors.push(err);
+    }
+    if errors.is_empty() {
+        println!("Configuration OK");
+        std::process::exit(0);
+    }
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    std::process::exit(1);
+}
+
+// Bind the Unix socket at `path`, replacing a socket file left behind by a
+// previous run unless a server still answers on it
+fn unix_listener(path: &str, mode: Option<u32>) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::other("the path exists and isn't a socket"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+// Binding a listener is the last chance startup has to fail; report which
+// address couldn't be bound, and why, before exiting
+fn bound<T>(result: std::io::Result<T>, listener: &str, address: &str) -> T {
+    match result {
+        Ok(bound) => bound,
+        Err(err) => {
+            let reason = match err.kind() {
+                std::io::ErrorKind::AddrInUse => "the address is already in use by another process".to_string(),
+                std::io::ErrorKind::AddrNotAvailable => "the address doesn't belong to this host".to_string(),
+                std::io::ErrorKind::PermissionDenied => "permission denied; ports below 1024 need privileges".to_string(),
+                _ => err.to_string(),
+            };
+            tracing::error!(listener, address, error = %err, "Failed to bind {} listener to {}: {}", listener, address, reason);
+            std::process::exit(1);
+        }
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Initialize OpenTelemetry
-    let tracer = init_telemetry();
+    let cli = cli::Cli::parse();
+    // Before anything else can read the environment; see take_listeners
+    let inherited = activation::take_listeners();
+    // Nothing is logged yet, so a bad env or config file is reported on stderr
+    let env_file = match config::load_env_file(cli.overrides.env_file.clone()) {
+        Ok(env_file) => env_file,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let settings = match config::init(cli.overrides.config.clone(), &cli.overrides.settings()) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    match &cli.command {
+        Some(cli::Command::ExportOpenapi { output, format }) => return export_openapi(output.as_deref(), *format),
+        Some(cli::Command::CheckConfig) => check_config(settings),
+        _ => {}
+    }
+
+    let app_status = web::Data::new(status::AppStatus::new(&[
+        status::TELEMETRY,
+        status::STORAGE,
+        status::STATE,
+        status::WORKERS,
+    ]));
+
+    // Phase 1: telemetry. A failure here keeps the server running with local
+    // logs only, and is surfaced through the readiness probe instead.
+    let otlp_endpoint = settings.telemetry.otlp_endpoint.clone();
+    // Sampling can be changed later through /admin/telemetry/sampling
+    let sampler = sampling::ReloadableSampler::new(sampling::SamplingPolicy::from_env());
+    let tracer = init_telemetry(&otlp_endpoint, sampler.clone());
+    let sampler = web::Data::new(sampler);
+    let telemetry_error = tracer.as_ref().err().map(|err| err.to_string());
+    let telemetry_control = web::Data::new(admin::TelemetryControl {
+        provider: tracer.as_ref().ok().and_then(|tracer| tracer.provider()),
+    });
+
+    // The filter sits behind a reload layer so /admin/log-level can change it
+    let log_level = settings.telemetry.log_level.clone();
+    let (filter_layer, filter_handle) = reload::Layer::new(EnvFilter::new(&log_level));
+    let log_level_control = web::Data::new(admin::LogLevelControl::new(filter_handle, &log_level));
 
     // Initialize tracing subscriber with OpenTelemetry
+    let log_format = settings.telemetry.log_format;
+    // The level filter applies to the exporting layers only, so the timeout
+    // counter can still see actix's trace events
+    let otel_layer = tracer.ok().map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let json_layer = matches!(log_format, config::LogFormat::Json).then(|| {
+        tracing_bunyan_formatter::BunyanFormattingLayer::new("actix-web-server".into(), std::io::stdout)
+    });
+    let text_layer = matches!(log_format, config::LogFormat::Text).then(tracing_subscriber::fmt::layer);
+    let exporting = Layer::and_then(otel_layer, json_layer).and_then(text_layer).with_filter(filter_layer);
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new("info"))
-        .with(tracing_opentelemetry::layer().with_tracer(tracer))
-        .with(tracing_bunyan_formatter::BunyanFormattingLayer::new(
-            "actix-web-server".into(), std::io::stdout,
-        ))
+        .with(exporting)
+        .with(connections::ConnectionTimeouts.with_filter(connections::ConnectionTimeouts::filter()))
         .init();
     
     info!("Tracing initialized");
-    info!("Sending traces to: {}", get_env_or_default("OTLP_ENDPOINT", "http://localhost:4317"));
-
-    // Initialize application state with Mutex for thread safety
-    let app_state = web::Data::new(Mutex::new(AppState {
-        users: vec![
-            User { id: 1, name: "Alice".to_string(), email: "alice@example.com".to_string() },
-            User { id: 2, name: "Bob".to_string(), email: "bob@example.com".to_string() },
-        ],
-        user_counter: 2,
-    }));
-    
-    info!("Starting HTTP server at http://127.0.0.1:8080");
+    if let Some(path) = &env_file {
+        info!(path = %path.display(), "Environment loaded from file");
+    }
+    // Everything the process runs with, after the config file, environment
+    // and command line are merged
+    info!(config = %settings.redacted(), "Configuration resolved");
+
+    // Metrics are best-effort; without an exporter the instruments are no-ops.
+    // With PUSHGATEWAY_URL or STATSD_ADDR they go to the Pushgateway or a
+    // DogStatsD agent instead, set up below.
+    let pushgateway_url = pushgateway::url_from_env();
+    let statsd_addr = statsd::addr_from_env();
+    let metrics_controller = match (&pushgateway_url, &statsd_addr) {
+        (Some(_), _) | (_, Some(_)) => None,
+        (None, None) => match init_metrics(&otlp_endpoint) {
+            Ok(controller) => Some(controller),
+            Err(err) => {
+                tracing::warn!(error = %err, "OTLP metrics exporter is not installed");
+                None
+            }
+        },
+    };
+    // Registered first so it runs last, once the other hooks have logged.
+    // Both calls block until the exporters' background tasks finish, and
+    // those tasks need this runtime thread, so they run on the blocking pool
+    // instead. The provider only shuts down once its last handle is gone, so
+    // the admin flush handle goes too; main drops its own before the hooks run.
+    let mut shutdown = shutdown::ShutdownCoordinator::new();
+    shutdown.register("telemetry", Duration::from_secs(10), {
+        let telemetry_control = telemetry_control.clone();
+        move || async move {
+            let flushed = web::block(move || {
+                if let Some(controller) = metrics_controller {
+                    if let Err(err) = controller.stop(&opentelemetry::Context::current()) {
+                        tracing::warn!(error = %err, "Failed to stop metrics controller");
+                    }
+                }
+                drop(telemetry_control);
+                global::shutdown_tracer_provider();
+            })
+            .await;
+            if flushed.is_err() {
+                tracing::warn!("Telemetry shutdown task failed");
+            }
+        }
+    });
+    // Registered after telemetry so the last push or flush runs before it
+    // shuts down
+    let metrics_error = match (pushgateway_url, statsd_addr) {
+        (Some(_), Some(_)) => Some("PUSHGATEWAY_URL and STATSD_ADDR can't both be set".to_string()),
+        (Some(url), None) => pushgateway::start(&url, &mut shutdown).err().map(|err| format!("Pushgateway: {}", err)),
+        (None, Some(addr)) => statsd::start(&addr, &mut shutdown).err().map(|err| format!("StatsD: {}", err)),
+        (None, None) => None,
+    };
+    match (telemetry_error, metrics_error) {
+        (None, None) => {
+            info!("Sending traces to: {}", otlp_endpoint);
+            app_status.mark_ready(status::TELEMETRY);
+        }
+        (Some(error), _) => app_status.mark_failed(
+            status::TELEMETRY,
+            format!("OTLP exporter for {} is not installed: {}", otlp_endpoint, error),
+        ),
+        (None, Some(error)) => app_status.mark_failed(status::TELEMETRY, error),
+    }
+
+    // Shared state starts out empty and is filled in once the listener is up,
+    // so probes can answer while the rest of initialization runs
+    let app_state = web::Data::new(RwLock::new(AppState::new()));
+    let profile_state = web::Data::new(RwLock::new(ProfileState::default()));
+    // Unusable storage settings fall back to memory and fail readiness in phase 2
+    let (storage, storage_error) = match Storage::from_env() {
+        Ok(storage) => (storage, None),
+        Err(error) => (Storage::memory(), Some(error)),
+    };
+    // `migrate` applies pending schema migrations and exits without serving,
+    // for deploys that run DATABASE_AUTO_MIGRATE=false
+    if matches!(cli.command, Some(cli::Command::Migrate)) || cli.migrate {
+        let result = match storage_error {
+            Some(error) => Err(error),
+            None => storage.migrate().await.map_err(|err| err.to_string()),
+        };
+        match &result {
+            Ok(applied) => info!(applied, "Migrations complete"),
+            Err(error) => tracing::error!(error = %error, "Migrations failed"),
+        }
+        drop(telemetry_control);
+        shutdown.run().await;
+        return result.map(|_| ()).map_err(std::io::Error::other);
+    }
+    let storage_info = web::Data::new(storage.info());
+    let user_repository = storage.repository(tenant::DEFAULT_TENANT, app_state.clone(), profile_state.clone());
+    let mutation_log = storage.mutation_log();
+    // For /admin/import, which replaces the state behind the log's back
+    let wal = web::Data::new(mutation_log.clone());
+    #[cfg(feature = "redis")]
+    let state_sync = storage.state_sync();
+    if let Some(log) = &mutation_log {
+        info!(path = %log.path().display(), "User mutations logged to WAL");
+    }
+    info!(backend = user_repository.name(), "User repository ready");
+    let state_file = web::Data::new(persistence::StateFile::from_env());
+    if let Some(path) = state_file.path() {
+        info!(path = %path.display(), "State persisted to file");
+    }
+    // Seed errors fail readiness in phase 2, like other state loading errors
+    let seeds = seed::Seeds::from_env(cli.no_seed);
+    if let Ok(Some(seeds)) = &seeds {
+        info!(path = %seeds.path().display(), users = seeds.len(), "Seed file loaded");
+    }
+    let snapshot_schedule = persistence::SnapshotSchedule::from_env();
+    if let Some(schedule) = &snapshot_schedule {
+        info!(
+            dir = %schedule.dir().display(),
+            keep = schedule.keep(),
+            "Periodic state snapshots enabled"
+        );
+    }
+    let expiry_policy = web::Data::new(expiry::ExpiryPolicy::from_env());
+    if let Some(ttl) = expiry_policy.default_ttl() {
+        info!(ttl_secs = ttl.as_secs(), reap_interval_secs = expiry_policy.reap_interval().as_secs(), "New users expire");
+    }
+    let feature_flags = web::Data::new(features::FeatureFlags::from_env());
+    info!(flags = feature_flags.len(), "Feature flags loaded");
+    // Requests without a tenant use app_state/profile_state; others get their own
+    let tenants = web::Data::new(tenant::Tenants::from_env(storage));
+    let drain_control = web::Data::new(drain::DrainControl::from_env());
+    match tenants.allowed() {
+        Some(allowed) => info!(tenants = allowed.len(), "Multi-tenancy limited to configured tenants"),
+        None => info!("Multi-tenancy enabled; tenants are created on first use"),
+    }
+    // Jobs run from phase 3; the admin API lists and triggers them
+    if let Err(err) = settings.scheduler.validate() {
+        tracing::error!(error = %err, "Invalid scheduler settings");
+        std::process::exit(1);
+    }
+    let mut scheduler = scheduler::Scheduler::new();
+    let default_tenant = tenant::TenantState {
+        users: app_state.clone(),
+        profiles: profile_state.clone(),
+        repository: user_repository.clone(),
+    };
+    if let Some(schedule) = snapshot_schedule.map(Arc::new) {
+        let (users, profiles) = (app_state.clone(), profile_state.clone());
+        scheduler.register("state_snapshot", &settings.scheduler.state_snapshot, move || {
+            let (schedule, users, profiles) = (schedule.clone(), users.clone(), profiles.clone());
+            async move {
+                schedule
+                    .take(&users, &profiles)
+                    .await
+                    .map_err(|err| format!("writing a snapshot to {}: {}", schedule.dir().display(), err))
+            }
+        });
+    }
+    scheduler.register("stats_refresh", &settings.scheduler.stats_refresh, {
+        let (default_tenant, tenants) = (default_tenant.clone(), tenants.clone());
+        move || {
+            let states = tenants.all(&default_tenant);
+            async move {
+                for (tenant, state) in &states {
+                    state.repository.domain_counts().await.map_err(|err| format!("tenant {}: {}", tenant, err))?;
+                }
+                info!(tenants = states.len(), "Domain stats refreshed");
+                Ok(())
+            }
+        }
+    });
+    scheduler.register("session_cleanup", &settings.scheduler.session_cleanup, {
+        let (default_tenant, tenants) = (default_tenant.clone(), tenants.clone());
+        move || {
+            let states = tenants.all(&default_tenant);
+            async move {
+                let mut purged = 0;
+                for (tenant, state) in &states {
+                    let mut users = state
+                        .users
+                        .write_measured("app_state")
+                        .map_err(|_| format!("tenant {}: failed to lock application state", tenant))?;
+                    purged += users.sessions.purge_expired();
+                }
+                info!(purged = purged, "Expired sessions purged");
+                Ok(())
+            }
+        }
+    });
+    let scheduler = web::Data::new(scheduler);
+    let process_info = web::Data::new(health::ProcessInfo::new());
+    let payload_limits = payload::PayloadLimits::from_env();
+    let path_normalization = normalize::PathNormalization::from_env();
+    let base_path = web::Data::new(base_path::BasePath::from_env());
+    let serve_ui = ui::is_enabled();
+    let envelope_config = envelope::EnvelopeConfig {
+        enabled_by_default: settings.server.response_envelope,
+    };
+    if serve_ui {
+        info!(path = %format!("{}/ui", base_path.as_str()), "Demo UI enabled");
+    }
+    if !base_path.as_str().is_empty() {
+        info!(base_path = %base_path.as_str(), "API served under a base path");
+    }
+    info!(trailing_slash = ?path_normalization.trailing_slash, merge_slashes = path_normalization.merge_slashes, "Path normalization");
+    info!(json = payload_limits.json, upload = payload_limits.upload, multipart = payload_limits.multipart, "Payload size limits");
+
+    // Admin endpoints authenticate separately from the public API
+    let admin_auth = web::Data::new(admin::AdminAuth {
+        token: settings.auth.admin_token.clone(),
+        basic: settings.auth.admin_username.clone().zip(settings.auth.admin_password.clone()),
+    });
+    if !admin_auth.is_configured() {
+        tracing::warn!("No ADMIN_TOKEN or ADMIN_USERNAME/ADMIN_PASSWORD set; /admin will reject all requests");
+    }
+    let audit_log = web::Data::new(admin::AuditLog::new(100));
+    let request_log = web::Data::new(dashboard::RequestLog::from_env());
+    info!(capacity = request_log.capacity(), "Recent requests kept for /admin/dashboard");
+    let maintenance_mode = web::Data::new(admin::MaintenanceMode::new());
+
+    // Outgoing mail is queued by handlers and delivered by a background worker
+    // started in phase 3
+    let (email_transport, email_transport_error) = match email::transport_from_env() {
+        Ok(transport) => (transport, None),
+        Err(error) => (Box::new(email::StubTransport) as Box<dyn email::EmailTransport>, Some(error)),
+    };
+    let (mailer, email_worker) = email::Mailer::new(email_transport);
+    let mailer = web::Data::new(mailer);
+
+    // User mutations fan out to registered webhooks from a dispatcher started in phase 3
+    let webhook_registry = web::Data::new(webhooks::WebhookRegistry::new());
+    let breaker_settings = circuit::BreakerSettings::from_env();
+    info!(
+        window = breaker_settings.window,
+        failure_rate = breaker_settings.failure_rate,
+        open_secs = breaker_settings.open_for.as_secs(),
+        "Outbound circuit breakers"
+    );
+    let circuit_breakers = circuit::CircuitBreakers::new(breaker_settings);
+    // A misconfigured bus fails readiness in phase 3, like the email transport
+    let (event_buses, event_bus_error) = match bus::buses_from_env().await {
+        Ok(buses) => (buses, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+    for event_bus in &event_buses {
+        info!(system = event_bus.system(), destination = event_bus.destination(), "Publishing user events to a message broker");
+    }
+    let (webhook_publisher, webhook_dispatcher) = webhooks::WebhookPublisher::new(
+        webhook_registry.clone(),
+        webhooks::RetryPolicy::from_env(),
+        circuit_breakers.clone(),
+        event_buses,
+    );
+    let webhook_publisher = web::Data::new(webhook_publisher);
+    let token_issuer = web::Data::new(auth::TokenIssuer::from_env());
+    let auth_config = auth::AuthConfig::from_env();
+    // Optional external provider whose tokens are accepted next to our own
+    let oidc_validator = web::Data::new(oidc::OidcValidator::from_env(&circuit_breakers));
+    let enricher = web::Data::new(enrichment::Enricher::from_env(&circuit_breakers));
+    let session_config = web::Data::new(sessions::SessionConfig::from_env());
+    let cors_settings = &settings.cors;
+    // Config reloads can change the origins; the rest of CORS is fixed at startup
+    let cors_origins = cors::AllowedOrigins::new(&cors_settings.allowed_origins);
+    let security_headers = security::SecurityHeaders::from_env();
+    let trusted_proxies = web::Data::new(client_ip::TrustedProxies::from_env());
+    info!(proxies = ?settings.server.trusted_proxies, "Forwarded client addresses trusted from");
+    let rate_limiter = web::Data::new(ratelimit::RateLimiter::from_env());
+    let (per_second, burst) = rate_limiter.limits();
+    info!(enabled = rate_limiter.is_enabled(), per_second = per_second, burst = burst, "Per-client rate limiting");
+    let config_reloader = web::Data::new(config_reload::ConfigReloader::new(
+        log_level_control.clone(),
+        sampler.clone(),
+        rate_limiter.clone(),
+        cors_origins.clone(),
+    ));
+    let admission_control = web::Data::new(admission::AdmissionControl::from_env());
+    info!(enabled = admission_control.is_enabled(), limit = admission_control.limit(), "Load shedding");
+    let compression_config = compression::CompressionConfig::from_env();
+    info!(
+        algorithms = %compression_config.algorithms.iter().map(ToString::to_string).collect::<Vec<_>>().join(","),
+        min_bytes = compression_config.min_bytes,
+        "Response compression"
+    );
+    let timeout_config = timeout::TimeoutConfig::from_env();
+    info!(default_ms = timeout_config.default.as_millis() as u64, overrides = timeout_config.overrides.len(), "Request timeouts");
+    let quota_config = quota::QuotaConfig::from_env();
+    info!(daily_limit = quota_config.daily_limit, "Per-identity mutation quota");
+    if cors_settings.is_enabled() {
+        info!(origins = ?cors_settings.allowed_origins, credentials = cors_settings.allow_credentials, "CORS enabled");
+    }
+    info!(required = auth_config.required, "Access token enforcement on mutating routes");
+
+    // With ADMIN_BIND or admin listeners set, /admin moves to its own
+    // listeners (e.g. localhost only)
+    let admin_binds = settings.server.listeners(config::ListenerRole::Admin);
+    let mount_admin_on_public = admin_binds.is_empty();
+    let address = format!("{}:{}", settings.server.host, settings.server.port);
+    let unix_socket = settings.server.unix_socket.as_deref();
+    let addresses = match settings.server.validate() {
+        // The socket takes the place of host and port
+        Ok(()) if unix_socket.is_some() => Vec::new(),
+        Ok(()) => settings.server.addresses().unwrap_or_default(),
+        Err(err) => {
+            tracing::error!(error = %err, "Invalid server settings");
+            std::process::exit(1);
+        }
+    };
+    let certificates = match tls::Certificates::from_env() {
+        Ok(certificates) => certificates,
+        Err(err) => {
+            tracing::error!(error = %err, "Invalid TLS settings");
+            std::process::exit(1);
+        }
+    };
+    let scheme = if certificates.is_some() { "https" } else { "http" };
     
     // Create and start the HTTP server
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
-            .wrap(RequestTracing::new()) // Add OpenTelemetry middleware
-            .service(hello)
-            .service(get_users)
-            .service(get_user)
-            .service(create_user)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run();
+    let server = HttpServer::new({
+        let app_state = app_state.clone();
+        let profile_state = profile_state.clone();
+        let user_repository = user_repository.clone();
+        let tenants = tenants.clone();
+        let drain_control = drain_control.clone();
+        let app_status = app_status.clone();
+        let storage_info = storage_info.clone();
+        let admin_auth = admin_auth.clone();
+        let audit_log = audit_log.clone();
+        let request_log = request_log.clone();
+        let scheduler = scheduler.clone();
+        let maintenance_mode = maintenance_mode.clone();
+        let log_level_control = log_level_control.clone();
+        let config_reloader = config_reloader.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let telemetry_control = telemetry_control.clone();
+        let sampler = sampler.clone();
+        let feature_flags = feature_flags.clone();
+        let state_file = state_file.clone();
+        let wal = wal.clone();
+        let webhook_registry = webhook_registry.clone();
+        let mailer = mailer.clone();
+        let webhook_publisher = webhook_publisher.clone();
+        let expiry_policy = expiry_policy.clone();
+        let enricher = enricher.clone();
+        let security_headers = security_headers.clone();
+        let base_path = base_path.clone();
+        move || {
+            App::new()
+                .app_data(app_state.clone())
+                .app_data(profile_state.clone())
+                .app_data(user_repository.clone())
+                .app_data(tenants.clone())
+                .app_data(process_info.clone())
+                .app_data(mailer.clone())
+                .app_data(webhook_publisher.clone())
+                .app_data(expiry_policy.clone())
+                .app_data(enricher.clone())
+                .app_data(webhook_registry.clone())
+                .app_data(token_issuer.clone())
+                .app_data(web::Data::new(auth_config))
+                .app_data(oidc_validator.clone())
+                .app_data(session_config.clone())
+                .app_data(rate_limiter.clone())
+                .app_data(trusted_proxies.clone())
+                .app_data(admission_control.clone())
+                .app_data(web::Data::new(quota_config))
+                .app_data(web::Data::new(timeout_config.clone()))
+                .app_data(web::Data::new(compression_config.clone()))
+                .app_data(app_status.clone())
+                .app_data(storage_info.clone())
+                .app_data(drain_control.clone())
+                .app_data(query::query_config())
+                .app_data(json::json_config(payload_limits.json))
+                .app_data(web::Data::new(payload_limits))
+                .app_data(web::Data::new(envelope_config))
+                .app_data(admin_auth.clone())
+                .app_data(audit_log.clone())
+                .app_data(request_log.clone())
+                .app_data(scheduler.clone())
+                .app_data(maintenance_mode.clone())
+                .app_data(log_level_control.clone())
+                .app_data(config_reloader.clone())
+                .app_data(telemetry_control.clone())
+                .app_data(sampler.clone())
+                .app_data(feature_flags.clone())
+                .app_data(state_file.clone())
+                .app_data(wal.clone())
+                // Probes are registered ahead of the traced scope so they stay out of traces
+                .service(health::healthz)
+                .service(health::readyz)
+                .configure(|cfg| {
+                    if mount_admin_on_public {
+                        cfg.service(
+                            admin::scope()
+                                .wrap(middleware::from_fn(persistence::track_mutations))
+                                .wrap(middleware::from_fn(normalize::record_normalization))
+                                .wrap(middleware::from_fn(client_ip::resolve))
+                                .wrap(middleware::from_fn(tls::record_protocol))
+                                .wrap(RequestTracing::new()),
+                        );
+                    }
+                })
+                .service(
+                    web::scope("")
+                        .wrap(middleware::from_fn(persistence::track_mutations))
+                        .wrap(middleware::from_fn(decompress::decompress))
+                        .wrap(middleware::from_fn(quota::enforce))
+                        .wrap(middleware::from_fn(csrf::verify))
+                        .wrap(middleware::from_fn(ratelimit::rate_limit_api_keys))
+                        .wrap(middleware::from_fn(auth::authenticate))
+                        .wrap(middleware::from_fn(ratelimit::rate_limit))
+                        .wrap(middleware::from_fn(tenant::resolve))
+                        .wrap(middleware::from_fn(admin::maintenance_gate))
+                        .wrap(middleware::from_fn(status::startup_gate))
+                        .wrap(middleware::from_fn(envelope::envelope))
+                        .wrap(middleware::from_fn(timeout::enforce))
+                        .wrap(middleware::from_fn(admission::shed))
+                        // Compress sits inside the span so the encoding and ratio can be recorded on it
+                        .wrap(middleware::from_fn(compression::prepare))
+                        .wrap(middleware::Compress::default())
+                        .wrap(middleware::from_fn(compression::observe))
+                        .wrap(middleware::from_fn(dashboard::record))
+                        .wrap(middleware::from_fn(normalize::record_normalization))
+                        .wrap(middleware::from_fn(client_ip::resolve))
+                        .wrap(middleware::from_fn(tls::record_protocol))
+                        .wrap(RequestTracing::new()) // Add OpenTelemetry middleware
+                        .service(hello)
+                        .service(version::version)
+                        .service(auth::login)
+                        .service(auth::refresh)
+                        .service(sessions::login)
+                        .service(sessions::logout)
+                        .service(get_users)
+                        // Literal routes must be registered before /users/{id} to take precedence
+                        .service(count_users)
+                        .service(batch_get_users)
+                        .service(bulk_create_users)
+                        .service(get_user_by_email)
+                        .service(check_duplicates)
+                        .service(user_stats)
+                        .service(domain_stats)
+                        .service(get_user)
+                        .service(create_user)
+                        .service(update_user)
+                        .service(delete_user)
+                        .service(activate_user)
+                        .service(suspend_user)
+                        .service(change_password)
+                        .service(get_user_avatar)
+                        .service(upload_user_avatar)
+                        .service(get_user_profile)
+                        .service(events::user_events)
+                        .service(update_user_profile)
+                        .configure(|cfg| {
+                            if serve_ui {
+                                cfg.service(ui::ui);
+                            }
+                        })
+                        .default_service(web::to(routes::default_handler))
+                )
+                .app_data(web::Data::new(path_normalization))
+                .app_data(base_path.clone())
+                // Sees the normalized path, and hands routing the path below the prefix
+                .wrap(middleware::from_fn(base_path::strip))
+                .wrap(middleware::from_fn(normalize::normalize_path))
+                .wrap(security_headers.middleware())
+                // Outermost, so preflights are answered before routing and never traced
+                .wrap(middleware::Condition::new(cors_settings.is_enabled(), cors::middleware(cors_settings, &cors_origins)))
+        }
+    });
+    let server_settings = &settings.server;
+    info!(
+        workers = server_settings.worker_count(),
+        max_blocking_threads = server_settings.blocking_threads_per_worker(),
+        backlog = server_settings.backlog,
+        "Server threads"
+    );
+    info!(
+        keep_alive_secs = server_settings.keep_alive_secs,
+        client_request_timeout_ms = server_settings.client_request_timeout_ms,
+        client_disconnect_timeout_ms = server_settings.client_disconnect_timeout_ms,
+        shutdown_timeout_secs = server_settings.shutdown_timeout_secs,
+        "Connection timeouts"
+    );
+    // The backlog applies to the sockets bound after it is set
+    let mut server = server
+        .workers(server_settings.worker_count())
+        .worker_max_blocking_threads(server_settings.blocking_threads_per_worker())
+        .backlog(server_settings.backlog)
+        // 0 turns each of these off
+        .keep_alive(match server_settings.keep_alive_secs {
+            0 => actix_web::http::KeepAlive::Disabled,
+            secs => actix_web::http::KeepAlive::Timeout(Duration::from_secs(secs)),
+        })
+        .client_request_timeout(Duration::from_millis(server_settings.client_request_timeout_ms))
+        .client_disconnect_timeout(Duration::from_millis(server_settings.client_disconnect_timeout_ms))
+        .shutdown_timeout(server_settings.shutdown_timeout_secs)
+        // drain::handle_signals stops the servers instead
+        .disable_signals()
+        .on_connect(|connection, extensions| {
+            tls::on_connect(connection, extensions);
+            client_ip::on_connect(connection, extensions);
+        });
+    if let Some(size) = settings.server.h2_initial_window_size {
+        server = server.h2_initial_window_size(size);
+    }
+    if let Some(size) = settings.server.h2_initial_connection_window_size {
+        server = server.h2_initial_connection_window_size(size);
+    }
+    // Sockets handed over by a supervisor replace host and port (or the Unix
+    // socket); the extra listeners are still bound as configured
+    let mut inherited = bound(inherited, "public", "LISTEN_FDS");
+    let unix_socket = unix_socket.filter(|_| inherited.is_empty());
+    // Unix sockets go first: actix lists each as a placeholder TCP address,
+    // which the log of bound addresses below skips
+    inherited.sort_by_key(|listener| !matches!(listener, activation::Inherited::Unix(_)));
+    let mut unix_sockets = 0;
+    let mut server = match (unix_socket, &certificates) {
+        _ if !inherited.is_empty() => {
+            for listener in inherited {
+                server = match (listener, &certificates) {
+                    (activation::Inherited::Tcp(listener), Some(certificates)) => bound(
+                        server.listen_rustls_0_23(listener, certificates.server_config()),
+                        "public",
+                        "LISTEN_FDS",
+                    ),
+                    (activation::Inherited::Tcp(listener), None) => bound(server.listen(listener), "public", "LISTEN_FDS"),
+                    (activation::Inherited::Unix(listener), _) => {
+                        let path = listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+                        let path = path.unwrap_or_else(|| "(unnamed)".to_string());
+                        info!(listener = "public", socket = %path, "Listening on inherited Unix socket {}", path);
+                        unix_sockets += 1;
+                        bound(server.listen_uds(listener), "public", "LISTEN_FDS")
+                    }
+                };
+            }
+            info!("Serving on sockets inherited from the supervisor");
+            server
+        }
+        (Some(path), _) => {
+            let listener = unix_listener(path, settings.server.unix_socket_mode().ok().flatten());
+            let server = bound(listener.and_then(|listener| server.listen_uds(listener)), "public", path);
+            info!(listener = "public", socket = %path, "Listening on Unix socket {}", path);
+            unix_sockets += 1;
+            server
+        }
+        (None, Some(certificates)) => {
+            bound(server.bind_rustls_0_23(&addresses[..], certificates.server_config()), "public", &address)
+        }
+        (None, None) => bound(server.bind(&addresses[..]), "public", &address),
+    };
+    for listener in settings.server.listeners(config::ListenerRole::Api) {
+        server = match &certificates {
+            Some(certificates) => bound(server.bind_rustls_0_23(listener, certificates.server_config()), "public", listener),
+            None => bound(server.bind(listener), "public", listener),
+        };
+    }
+    // The bound addresses, which differ from the configured ones for port 0
+    for addr in server.addrs().into_iter().skip(unix_sockets) {
+        info!(listener = "public", address = %addr, "Listening on {}://{}", scheme, addr);
+    }
+    let server = server.run();
 
     info!("Server started");
 
-    // Ensure we flush the tracer when the server stops
+    let admin_server = if admin_binds.is_empty() {
+        None
+    } else {
+        let app_state = app_state.clone();
+        let profile_state = profile_state.clone();
+        let app_status = app_status.clone();
+        let storage_info = storage_info.clone();
+        let tenants = tenants.clone();
+        let drain_control = drain_control.clone();
+        let telemetry_control = telemetry_control.clone();
+        let sampler = sampler.clone();
+        let config_reloader = config_reloader.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let feature_flags = feature_flags.clone();
+        let state_file = state_file.clone();
+        let wal = wal.clone();
+        let scheduler = scheduler.clone();
+        let mut admin_server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_state.clone())
+                .app_data(app_status.clone())
+                .app_data(storage_info.clone())
+                .app_data(drain_control.clone())
+                .app_data(profile_state.clone())
+                .app_data(tenants.clone())
+                .app_data(query::query_config())
+                .app_data(web::Data::new(payload_limits))
+                .app_data(json::json_config(payload_limits.json))
+                .app_data(admin_auth.clone())
+                .app_data(audit_log.clone())
+                .app_data(request_log.clone())
+                .app_data(scheduler.clone())
+                .app_data(maintenance_mode.clone())
+                .app_data(log_level_control.clone())
+                .app_data(config_reloader.clone())
+                .app_data(telemetry_control.clone())
+                .app_data(sampler.clone())
+                .app_data(trusted_proxies.clone())
+                .app_data(feature_flags.clone())
+                .app_data(state_file.clone())
+                .app_data(wal.clone())
+                .app_data(webhook_registry.clone())
+                .service(
+                    admin::scope()
+                        .wrap(middleware::from_fn(persistence::track_mutations))
+                        .wrap(middleware::from_fn(normalize::record_normalization))
+                        .wrap(middleware::from_fn(client_ip::resolve))
+                        .wrap(middleware::from_fn(tls::record_protocol))
+                        .wrap(RequestTracing::new()),
+                )
+                .app_data(web::Data::new(path_normalization))
+                .wrap(middleware::from_fn(normalize::normalize_path))
+                .wrap(security_headers.middleware())
+        })
+        .workers(1)
+        .shutdown_timeout(settings.server.shutdown_timeout_secs)
+        .disable_signals();
+        for bind in &admin_binds {
+            admin_server = bound(admin_server.bind(bind), "admin", bind);
+        }
+        for addr in admin_server.addrs() {
+            info!(listener = "admin", address = %addr, "Admin server listening on http://{}", addr);
+        }
+        Some(admin_server.run())
+    };
+
+    // Shutdown runs through DrainControl, for signals as for POST /admin/drain
     let server_handle = server.handle();
-    ctrlc::set_handler(move || {
-        info!("Shutting down server");
-        server_handle.stop(true);;
-        global::shutdown_tracer_provider();
-    }).expect("Failed to set Ctrl-C handler");
-    
-    server.await?;
+    let admin_handle = admin_server.as_ref().map(|server| server.handle());
+    drain_control.register(std::iter::once(server_handle).chain(admin_handle).collect());
+    actix_web::rt::spawn(drain::handle_signals(drain_control.clone(), app_status.clone(), telemetry_control.clone()));
+
+    let server_task = actix_web::rt::spawn(server);
+    let admin_task = admin_server.map(actix_web::rt::spawn);
+    // Up from here so gRPC probes see NOT_SERVING until startup completes
+    if let Err(err) = grpc::start(&settings.server.host, app_state.clone(), app_status.clone(), &mut shutdown).await {
+        tracing::error!(error = %err, "Invalid gRPC settings");
+        std::process::exit(1);
+    }
+
+    // Phase 2: prepare storage (connect, migrate), then load application
+    // state. The state file is loaded before seeding so seeding only adds
+    // users it doesn't already hold.
+    let prepared = match storage_error {
+        Some(error) => Err(error),
+        None => user_repository.prepare().await.map_err(|err| err.to_string()),
+    };
+    match &prepared {
+        Ok(()) => {
+            info!(backend = storage_info.backend, "Storage ready");
+            app_status.mark_ready(status::STORAGE);
+        }
+        Err(error) => app_status.mark_failed(status::STORAGE, error.clone()),
+    }
+    let loaded = match state_file.load(&app_state, &profile_state) {
+        Ok(Some(users)) => {
+            info!(users, "State file loaded");
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(error) => Err(format!("state file: {}", error)),
+    };
+    let loaded = match (loaded, &mutation_log) {
+        (Ok(()), Some(log)) => match log.replay(&app_state, &profile_state).await {
+            Ok(entries) => {
+                info!(entries, "WAL replayed");
+                Ok(())
+            }
+            Err(error) => Err(format!("wal: {}", error)),
+        },
+        (loaded, _) => loaded,
+    };
+    let seeded = match (prepared, loaded) {
+        (Err(_), _) => Err("not seeded; storage is unavailable".to_string()),
+        (Ok(()), Err(error)) => Err(error),
+        (Ok(()), Ok(())) => match seeds {
+            Ok(Some(seeds)) => match seeds.apply(&user_repository).await {
+                Ok(created) => {
+                    info!(created, "Seed users created");
+                    Ok(())
+                }
+                Err(error) => Err(format!("seeding: {}", error)),
+            },
+            Ok(None) => Ok(()),
+            Err(error) => Err(format!("seed file: {}", error)),
+        },
+    };
+    match seeded {
+        Ok(()) => {
+            info!(backend = user_repository.name(), "Application state loaded");
+            app_status.mark_ready(status::STATE);
+        }
+        Err(error) => app_status.mark_failed(status::STATE, error),
+    }
 
-    // Shut down tracer provider
-    global::shutdown_tracer_provider();
+    // In-flight requests have finished by the time these run, so they
+    // capture every mutation
+    if let Some(log) = mutation_log.clone() {
+        let (app_state, profile_state) = (app_state.clone(), profile_state.clone());
+        shutdown.register("wal", Duration::from_secs(30), move || async move {
+            if let Err(err) = log.compact(&app_state, &profile_state).await {
+                tracing::warn!(error = %err, "Failed to compact WAL on shutdown");
+            }
+        });
+    }
+    shutdown.register("state file", Duration::from_secs(30), {
+        let (state_file, app_state, profile_state) = (state_file.clone(), app_state.clone(), profile_state.clone());
+        move || async move {
+            if let Err(err) = state_file.save(&app_state, &profile_state).await {
+                tracing::warn!(error = %err, "Failed to save state file on shutdown");
+            }
+        }
+    });
+
+    // Phase 3: background workers, started before readiness flips. Their
+    // shutdown hooks wait for queued work, which the requests that finished
+    // during the drain may have just added.
+    actix_web::rt::spawn(email_worker);
+    shutdown.register("email worker", Duration::from_secs(10), {
+        let mailer = mailer.clone();
+        move || async move { mailer.drained().await }
+    });
+    actix_web::rt::spawn(webhook_dispatcher);
+    shutdown.register("webhook dispatcher", Duration::from_secs(10), {
+        let webhook_publisher = webhook_publisher.clone();
+        move || async move { webhook_publisher.drained().await }
+    });
+    actix_web::rt::spawn(persistence::run_saver(state_file.clone(), app_state.clone(), profile_state.clone()));
+    if let Some(log) = mutation_log.clone() {
+        actix_web::rt::spawn(wal::run_compaction(log, app_state.clone(), profile_state.clone()));
+    }
+    actix_web::rt::spawn(config_reload::reload_on_hangup(config_reloader));
+    if let Some(certificates) = certificates {
+        actix_web::rt::spawn(certificates.clone().reload_on_hangup());
+        if !tls::watch_interval().is_zero() {
+            actix_web::rt::spawn(certificates.watch_files(tls::watch_interval()));
+        }
+    }
+    actix_web::rt::spawn(scheduler::run(scheduler.clone()));
+    #[cfg(feature = "nats")]
+    if let Some(subject) = nats::command_subject() {
+        let repository = user_repository.clone();
+        let expiry = expiry_policy.clone();
+        let enricher = enricher.clone();
+        let mailer = mailer.clone();
+        let webhooks = webhook_publisher.clone();
+        actix_web::rt::spawn(nats::run_commands(subject, move |payload| {
+            create_user_command(payload, repository.clone(), expiry.clone(), enricher.clone(), mailer.clone(), webhooks.clone())
+        }));
+    }
+    actix_web::rt::spawn(expiry::run_reaper(
+        **expiry_policy,
+        tenant::TenantState {
+            users: app_state.clone(),
+            profiles: profile_state.clone(),
+            repository: user_repository.clone(),
+        },
+        tenants.clone(),
+        webhook_publisher.clone(),
+        state_file.clone(),
+    ));
+    #[cfg(feature = "redis")]
+    if let Some(sync) = state_sync {
+        actix_web::rt::spawn(sync.run(app_state.clone(), tenants.clone()));
+    }
+    #[cfg(feature = "jemalloc")]
+    memory::export_metrics();
+    actix_web::rt::spawn(store_metrics::run_sampler(
+        store_metrics::sample_interval(),
+        tenant::TenantState {
+            users: app_state.clone(),
+            profiles: profile_state.clone(),
+            repository: user_repository.clone(),
+        },
+        tenants.clone(),
+    ));
+    if storage_info.outbox {
+        actix_web::rt::spawn(outbox::run_relay(
+            outbox::RelayConfig::from_env(),
+            tenant::TenantState {
+                users: app_state.clone(),
+                profiles: profile_state.clone(),
+                repository: user_repository.clone(),
+            },
+            tenants.clone(),
+            webhook_publisher.clone(),
+        ));
+    }
+    match (email_transport_error, event_bus_error) {
+        (None, None) => app_status.mark_ready(status::WORKERS),
+        (Some(error), _) => app_status.mark_failed(status::WORKERS, format!("email transport: {}", error)),
+        (None, Some(error)) => app_status.mark_failed(status::WORKERS, format!("event bus: {}", error)),
+    }
+
+    if app_status.is_ready() {
+        info!("Initialization complete, accepting traffic");
+    }
+
+    server_task
+        .await
+        .map_err(|err| std::io::Error::other(err.to_string()))??;
+    if let Some(admin_task) = admin_task {
+        admin_task
+            .await
+            .map_err(|err| std::io::Error::other(err.to_string()))??;
+    }
+
+    info!("Servers stopped");
+    if let Some(path) = unix_socket {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::warn!(socket = %path, error = %err, "Failed to remove Unix socket");
+        }
+    }
+
+    drop(telemetry_control);
+    shutdown.run().await;
+    info!("Shutdown complete");
     Ok(())
 
-}
\ No newline at end of file
+}
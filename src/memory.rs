@@ -0,0 +1,84 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+
+use crate::error::AppError;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// Allocator counters, in bytes
+#[derive(Clone, Copy, Serialize)]
+pub struct AllocatorStats {
+    // Held by live allocations
+    pub allocated: u64,
+    // In pages with at least one live allocation
+    pub active: u64,
+    // Physically resident in memory, including allocator metadata
+    pub resident: u64,
+    // Mapped from the OS
+    pub mapped: u64,
+    // Unmapped but kept for reuse rather than returned to the OS
+    pub retained: u64,
+    // The allocator's own bookkeeping
+    pub metadata: u64,
+    // Share of active memory not holding live allocations
+    pub fragmentation: f64,
+}
+
+// Current jemalloc stats. They are cached, so the epoch is advanced first to
+// read fresh values.
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> Result<AllocatorStats, String> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    epoch::advance().map_err(|err| err.to_string())?;
+    let read = |value: tikv_jemalloc_ctl::Result<usize>| value.map(|bytes| bytes as u64).map_err(|err| err.to_string());
+    let allocated = read(stats::allocated::read())?;
+    let active = read(stats::active::read())?;
+    Ok(AllocatorStats {
+        allocated,
+        active,
+        resident: read(stats::resident::read())?,
+        mapped: read(stats::mapped::read())?,
+        retained: read(stats::retained::read())?,
+        metadata: read(stats::metadata::read())?,
+        fragmentation: if active == 0 { 0.0 } else { 1.0 - allocated as f64 / active as f64 },
+    })
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn stats() -> Result<AllocatorStats, String> {
+    Err("Allocator stats require building with the `jemalloc` feature".to_string())
+}
+
+// Export the allocator stats as gauges, read whenever metrics are collected,
+// so memory growth of the in-memory store can be followed over time
+#[cfg(feature = "jemalloc")]
+pub fn export_metrics() {
+    crate::telemetry::observe_allocator(|| stats().ok());
+}
+
+#[derive(Serialize)]
+struct MemoryReport {
+    allocator: &'static str,
+    #[serde(flatten)]
+    stats: AllocatorStats,
+}
+
+// Handler for GET /admin/debug/memory
+#[get("/debug/memory")]
+async fn memory() -> impl Responder {
+    match stats() {
+        Ok(stats) => HttpResponse::Ok().json(MemoryReport { allocator: "jemalloc", stats }),
+        Err(err) if cfg!(feature = "jemalloc") => {
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "allocator_stats_failed", err).error_response()
+        }
+        Err(err) => AppError::new(StatusCode::NOT_IMPLEMENTED, "allocator_stats_unavailable", err).error_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(memory);
+}
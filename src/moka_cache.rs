@@ -0,0 +1,218 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use moka::future::Cache;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::events::StoredEvent;
+use crate::get_env_or_default;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::repository::{CacheLookup, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::telemetry::record_cache_lookup;
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+// Users cached in the process, shared by every tenant's cache layer and
+// bounded by USER_CACHE_MAX_ENTRIES and USER_CACHE_TTL_SECS. Entries
+// remember when they were cached, for the Age header.
+#[derive(Clone)]
+pub struct MokaCache {
+    users: Cache<(Arc<str>, UserId), (User, Instant)>,
+}
+
+impl MokaCache {
+    pub fn from_env() -> Self {
+        let max_entries = get_env_or_default("USER_CACHE_MAX_ENTRIES", "10000").parse().unwrap_or(10_000);
+        let ttl_secs = get_env_or_default("USER_CACHE_TTL_SECS", "60").parse().unwrap_or(60);
+        info!(max_entries, ttl_secs, "In-process user cache enabled");
+        MokaCache {
+            users: Cache::builder()
+                .max_capacity(max_entries)
+                .time_to_live(Duration::from_secs(ttl_secs))
+                .build(),
+        }
+    }
+
+    pub fn wrap(&self, inner: Arc<dyn UserRepository>, tenant: &str) -> Arc<dyn UserRepository> {
+        Arc::new(CachingUserRepository { inner, cache: self.clone(), tenant: Arc::from(tenant) })
+    }
+}
+
+// Read-through cache in front of another repository, like the Redis one but
+// without a network hop. Lookups by ID are cached on a miss; every mutation
+// drops the cached copy.
+struct CachingUserRepository {
+    inner: Arc<dyn UserRepository>,
+    cache: MokaCache,
+    tenant: Arc<str>,
+}
+
+impl CachingUserRepository {
+    fn key(&self, id: UserId) -> (Arc<str>, UserId) {
+        (self.tenant.clone(), id)
+    }
+}
+
+#[async_trait]
+impl UserRepository for CachingUserRepository {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        self.inner.prepare().await
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.get_with_cache_status(id).await.map(|(user, _)| user)
+    }
+
+    async fn get_with_cache_status(&self, id: UserId) -> Result<(Option<User>, Option<CacheLookup>), RepositoryError> {
+        if let Some((user, cached_at)) = self.cache.users.get(&self.key(id)).await {
+            record_cache_lookup("user_moka", true);
+            return Ok((Some(user), Some(CacheLookup { hit: true, age: Some(cached_at.elapsed()) })));
+        }
+        record_cache_lookup("user_moka", false);
+
+        let user = self.inner.get(id).await?;
+        if let Some(user) = &user {
+            self.cache.users.insert(self.key(id), (user.clone(), Instant::now())).await;
+        }
+        Ok((user, Some(CacheLookup { hit: false, age: None })))
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        self.inner.list(query).await
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        self.inner.create(user).await
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        self.inner.create_many(users).await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.update(id, name, email).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        updated
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let deleted = self.inner.delete(id).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        deleted
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let deleted = self.inner.delete_expired(now).await?;
+        for user in &deleted {
+            self.cache.users.invalidate(&self.key(user.id)).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn restored(&self, removed: &[UserId], users: &[User]) {
+        self.inner.restored(removed, users).await;
+        for id in removed.iter().copied().chain(users.iter().map(|user| user.id)) {
+            self.cache.users.invalidate(&self.key(id)).await;
+        }
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.inner.find_by_email(email).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        self.inner.count().await
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        self.inner.stats().await
+    }
+
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        self.inner.domain_counts().await
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        self.inner.find_duplicates(name, email).await
+    }
+
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        self.inner.credentials(id).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        self.inner.avatar(id).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        self.inner.profile(id).await
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let result = self.inner.set_status(id, action).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        result
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.set_credentials(id, credentials).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        updated
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_avatar(id, avatar).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        touched
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_profile(id, profile).await;
+        self.cache.users.invalidate(&self.key(id)).await;
+        touched
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        self.inner.charge_quota(subject, day, limit).await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.inner.refund_quota(subject, day).await
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.inner.keeps_history()
+    }
+
+    async fn history(&self, id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        self.inner.history(id).await
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.inner.has_outbox()
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.inner.outbox_pending(limit).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        self.inner.outbox_acknowledge(ids).await
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        self.inner.size().await
+    }
+}
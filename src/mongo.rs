@@ -0,0 +1,601 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::TryStreamExt;
+use mongodb::bson::{self, doc, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{ClientOptions, IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tracing::field::Empty;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::enrichment::Enrichment;
+use crate::config;
+use crate::query::{ListQuery, SortField};
+use crate::repository::{self, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+const COLLECTION: &str = "users";
+// Mutation counts per tenant, identity and day, removed by a TTL index once
+// the day is over
+const QUOTA_COLLECTION: &str = "quota_usage";
+
+// Server error code for a unique index violation
+const DUPLICATE_KEY: i32 = 11000;
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(err: mongodb::error::Error) -> Self {
+        RepositoryError::Storage(err.to_string())
+    }
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == DUPLICATE_KEY,
+        ErrorKind::Command(error) => error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// Client shared by every tenant's repository
+#[derive(Clone)]
+pub struct MongoConnection {
+    url: String,
+    database: String,
+    // Connected on first use, so startup doesn't wait on the server
+    client: Arc<OnceCell<Client>>,
+}
+
+impl MongoConnection {
+    // MONGODB_URL names the deployment and MONGODB_DATABASE the database
+    pub fn from_env() -> Result<Self, String> {
+        let settings = &config::settings().storage;
+        let url = settings.mongodb_url.clone().unwrap_or_else(|| "mongodb://127.0.0.1:27017".to_string());
+        mongodb::options::ConnectionString::parse(&url).map_err(|err| format!("invalid MONGODB_URL: {}", err))?;
+        Ok(MongoConnection {
+            url,
+            database: settings.mongodb_database.clone(),
+            client: Arc::new(OnceCell::new()),
+        })
+    }
+
+    async fn collection(&self) -> Result<Collection<UserDocument>, mongodb::error::Error> {
+        Ok(self.client().await?.database(&self.database).collection(COLLECTION))
+    }
+
+    async fn quota_collection(&self) -> Result<Collection<QuotaDocument>, mongodb::error::Error> {
+        Ok(self.client().await?.database(&self.database).collection(QUOTA_COLLECTION))
+    }
+
+    async fn client(&self) -> Result<&Client, mongodb::error::Error> {
+        self
+            .client
+            .get_or_try_init(|| async {
+                let mut options = ClientOptions::parse(&self.url).await?;
+                // Fail requests quickly rather than waiting out the driver's 30s default
+                options.server_selection_timeout.get_or_insert(Duration::from_secs(5));
+                options.connect_timeout.get_or_insert(Duration::from_secs(5));
+                options.app_name.get_or_insert_with(|| "actix-web-server".to_string());
+                Client::with_options(options)
+            })
+            .await
+    }
+}
+
+// How a user is stored: one document per user, tagged with its tenant. The
+// lowercased copies back case-insensitive sorting and the unique email index.
+// Timestamps are BSON dates, which keep millisecond precision. The avatar and
+// profile live in the same document, under `avatar` and `profile`, so they go
+// when the user does; user reads leave them out.
+#[derive(Serialize, Deserialize)]
+struct UserDocument {
+    // UUIDv7 in its hyphenated form, so ordering by _id gives creation order
+    #[serde(rename = "_id")]
+    id: String,
+    tenant: String,
+    name: String,
+    name_lower: String,
+    email: String,
+    email_lower: String,
+    status: String,
+    created_at: bson::DateTime,
+    updated_at: bson::DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enrichment: Option<Enrichment>,
+}
+
+impl UserDocument {
+    fn new(tenant: &str, user: &User) -> Self {
+        UserDocument {
+            id: user.id.to_string(),
+            tenant: tenant.to_string(),
+            name: user.name.clone(),
+            name_lower: user.name.to_lowercase(),
+            email: user.email.clone(),
+            email_lower: user.email.to_lowercase(),
+            status: user.status.name().to_string(),
+            created_at: bson::DateTime::from_millis(user.created_at.timestamp_millis()),
+            updated_at: bson::DateTime::from_millis(user.updated_at.timestamp_millis()),
+            expires_at: user.expires_at.map(|expires_at| bson::DateTime::from_millis(expires_at.timestamp_millis())),
+            password_hash: user.credentials.as_ref().map(|credentials| credentials.password_hash.clone()),
+            enrichment: user.enrichment.clone(),
+        }
+    }
+
+    fn into_user(self) -> Result<User, RepositoryError> {
+        let timestamp = |value: bson::DateTime| {
+            DateTime::from_timestamp_millis(value.timestamp_millis())
+                .ok_or_else(|| RepositoryError::Storage(format!("stored timestamp {} is out of range", value)))
+        };
+        Ok(User {
+            id: self
+                .id
+                .parse()
+                .map_err(|err| RepositoryError::Storage(format!("invalid stored user ID: {}", err)))?,
+            name: self.name,
+            email: self.email,
+            status: UserStatus::from_name(&self.status)
+                .ok_or_else(|| RepositoryError::Storage(format!("unknown user status '{}'", self.status)))?,
+            created_at: timestamp(self.created_at)?,
+            updated_at: timestamp(self.updated_at)?,
+            expires_at: self.expires_at.map(timestamp).transpose()?,
+            credentials: self.password_hash.map(|password_hash| Credentials { password_hash }),
+            enrichment: self.enrichment,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AvatarDocument {
+    content_type: String,
+    data: bson::Binary,
+    etag: String,
+}
+
+impl AvatarDocument {
+    fn new(avatar: &Avatar) -> Self {
+        AvatarDocument {
+            content_type: avatar.content_type.clone(),
+            data: bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: avatar.data.to_vec() },
+            etag: avatar.etag.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotaDocument {
+    // "{tenant}:{day}:{subject}"
+    #[serde(rename = "_id")]
+    id: String,
+    used: i64,
+    expires_at: bson::DateTime,
+}
+
+// Views of a user document holding just one of its parts
+#[derive(Deserialize)]
+struct AvatarView {
+    avatar: Option<AvatarDocument>,
+}
+
+#[derive(Deserialize)]
+struct ProfileView {
+    profile: Option<Profile>,
+}
+
+#[derive(Deserialize)]
+struct StatsView {
+    email: String,
+    created_at: bson::DateTime,
+}
+
+// Projection for user reads, which don't need the avatar bytes or profile
+fn user_fields() -> Document {
+    doc! { "avatar": 0, "profile": 0 }
+}
+
+fn bson_now() -> bson::DateTime {
+    bson::DateTime::from_millis(now().timestamp_millis())
+}
+
+// Current time at the precision BSON dates store, so returned users match what
+// a later read gives back
+fn now() -> DateTime<Utc> {
+    truncate_millis(Utc::now())
+}
+
+fn truncate_millis(time: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(time.timestamp_millis()).unwrap_or(time)
+}
+
+// Regex matching `value` literally
+fn regex_literal(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| {
+            let escape = "\\^$.|?*+()[]{}".contains(c).then_some('\\');
+            escape.into_iter().chain(std::iter::once(c))
+        })
+        .collect()
+}
+
+// Run one operation in a client span carrying OpenTelemetry's database
+// attributes, marking it failed when the server returns an error
+async fn traced<T>(
+    database: &str,
+    operation: &'static str,
+    action: impl IntoFuture<Output = Result<T, mongodb::error::Error>>,
+) -> Result<T, mongodb::error::Error> {
+    traced_on(database, COLLECTION, operation, action).await
+}
+
+// The same for an operation on another collection
+async fn traced_on<T>(
+    database: &str,
+    collection: &'static str,
+    operation: &'static str,
+    action: impl IntoFuture<Output = Result<T, mongodb::error::Error>>,
+) -> Result<T, mongodb::error::Error> {
+    let span = info_span!(
+        "db.query",
+        otel.name = %format!("{} {}", operation, collection),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        db.system = "mongodb",
+        db.name = %database,
+        db.operation = operation,
+        db.mongodb.collection = collection,
+        db.duration_ms = Empty,
+    );
+    let started = Instant::now();
+    let result = action.into_future().instrument(span.clone()).await;
+    span.record("db.duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+    if let Err(err) = &result {
+        span.record("otel.status_code", "ERROR");
+        warn!(parent: &span, error = %err, "MongoDB operation failed");
+    }
+    result
+}
+
+// Users stored in a MongoDB collection. Unlike the other backends, a unique
+// index keeps email addresses unique within a tenant; conflicting writes are
+// answered with 409.
+pub struct MongoUserRepository {
+    connection: MongoConnection,
+    tenant: String,
+}
+
+impl MongoUserRepository {
+    pub fn new(connection: MongoConnection, tenant: &str) -> Self {
+        MongoUserRepository { connection, tenant: tenant.to_string() }
+    }
+
+    fn by_id(&self, id: UserId) -> Document {
+        doc! { "tenant": &self.tenant, "_id": id.to_string() }
+    }
+
+    fn filter(&self, query: &ListQuery) -> Document {
+        let mut filter = doc! { "tenant": &self.tenant };
+        let mut created_at = Document::new();
+        if let Some(after) = query.created_after {
+            created_at.insert("$gt", bson::DateTime::from_millis(after.timestamp_millis()));
+        }
+        if let Some(before) = query.created_before {
+            created_at.insert("$lt", bson::DateTime::from_millis(before.timestamp_millis()));
+        }
+        if !created_at.is_empty() {
+            filter.insert("created_at", created_at);
+        }
+        if let Some(name) = &query.name {
+            filter.insert("name_lower", doc! { "$regex": regex_literal(&name.to_lowercase()) });
+        }
+        match &query.email_domain {
+            // The domain is what follows the last '@', so it can't contain one
+            Some(domain) if domain.contains('@') => {
+                filter.insert("_id", doc! { "$exists": false });
+            }
+            Some(domain) => {
+                filter.insert("email_lower", doc! { "$regex": format!("@{}$", regex_literal(&domain.to_lowercase())) });
+            }
+            None => {}
+        }
+        filter
+    }
+
+    async fn collection(&self) -> Result<Collection<UserDocument>, RepositoryError> {
+        Ok(self.connection.collection().await?)
+    }
+
+    async fn find_all(&self, filter: Document) -> Result<Vec<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let find = collection.find(filter).sort(doc! { "_id": 1 }).projection(user_fields());
+        let documents: Vec<UserDocument> =
+            traced(&self.connection.database, "find", async { find.await?.try_collect().await }).await?;
+        documents.into_iter().map(UserDocument::into_user).collect()
+    }
+
+    // Apply an update to one user, answering the user as it is afterwards;
+    // None if it doesn't exist
+    async fn modify(&self, filter: Document, update: Document) -> Result<Option<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let updated = collection
+            .find_one_and_update(filter, update)
+            .projection(user_fields())
+            .return_document(ReturnDocument::After);
+        let found = traced(&self.connection.database, "findAndModify", updated).await?;
+        found.map(UserDocument::into_user).transpose()
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoUserRepository {
+    fn name(&self) -> &'static str {
+        "mongodb"
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        let collection = self.collection().await?;
+        let index = IndexModel::builder()
+            .keys(doc! { "tenant": 1, "email_lower": 1 })
+            .options(IndexOptions::builder().name("users_tenant_email".to_string()).unique(true).build())
+            .build();
+        traced(&self.connection.database, "createIndexes", collection.create_index(index)).await?;
+        let quotas = self.connection.quota_collection().await?;
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("quota_usage_expiry".to_string())
+                    .expire_after(Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        traced_on(&self.connection.database, QUOTA_COLLECTION, "createIndexes", quotas.create_index(index)).await?;
+        info!("MongoDB indexes ready");
+        Ok(())
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let find = collection.find_one(self.by_id(id)).projection(user_fields());
+        let found = traced(&self.connection.database, "find", find).await?;
+        found.map(UserDocument::into_user).transpose()
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        let collection = self.collection().await?;
+        let filter = self.filter(query);
+        let total = traced(
+            &self.connection.database,
+            "countDocuments",
+            collection.count_documents(filter.clone()),
+        )
+        .await?;
+
+        let mut sort = Document::new();
+        if let Some(order) = query.sort {
+            let field = match order.field {
+                SortField::Name => "name_lower",
+                SortField::Email => "email_lower",
+                SortField::CreatedAt => "created_at",
+                SortField::UpdatedAt => "updated_at",
+            };
+            sort.insert(field, if order.descending { -1 } else { 1 });
+        }
+        sort.insert("_id", 1);
+        let find = collection
+            .find(filter)
+            .sort(sort)
+            .skip(query.offset() as u64)
+            .limit(query.limit as i64)
+            .projection(user_fields());
+        let documents: Vec<UserDocument> = traced(&self.connection.database, "find", async {
+            find.await?.try_collect().await
+        })
+        .await?;
+        let users = documents.into_iter().map(UserDocument::into_user).collect::<Result<_, _>>()?;
+        Ok((users, total as usize))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let now = now();
+        let user = User {
+            id: Uuid::now_v7(),
+            name: user.name,
+            email: user.email,
+            status: UserStatus::Pending,
+            created_at: now,
+            updated_at: now,
+            expires_at: user.expires_at.map(truncate_millis),
+            credentials: user.credentials,
+            enrichment: user.enrichment,
+        };
+        let collection = self.collection().await?;
+        let document = UserDocument::new(&self.tenant, &user);
+        match traced(&self.connection.database, "insert", collection.insert_one(document)).await {
+            Ok(_) => Ok(user),
+            Err(err) if is_duplicate_key(&err) => Err(RepositoryError::DuplicateEmail(user.email)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let update = doc! {
+            "$set": {
+                "name": &name,
+                "name_lower": name.to_lowercase(),
+                "email": &email,
+                "email_lower": email.to_lowercase(),
+                "updated_at": bson_now(),
+            }
+        };
+        let updated = collection
+            .find_one_and_update(self.by_id(id), update)
+            .projection(user_fields())
+            .return_document(ReturnDocument::After);
+        match traced(&self.connection.database, "findAndModify", updated).await {
+            Ok(found) => found.map(UserDocument::into_user).transpose(),
+            Err(err) if is_duplicate_key(&err) => Err(RepositoryError::DuplicateEmail(email)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let deleted = collection.find_one_and_delete(self.by_id(id)).projection(user_fields());
+        let found = traced(&self.connection.database, "findAndModify", deleted).await?;
+        found.map(UserDocument::into_user).transpose()
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let filter = doc! {
+            "tenant": &self.tenant,
+            "expires_at": { "$lte": bson::DateTime::from_millis(now.timestamp_millis()) },
+        };
+        let mut deleted = Vec::new();
+        for user in self.find_all(filter).await? {
+            deleted.extend(self.delete(user.id).await?);
+        }
+        Ok(deleted)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let collection = self.collection().await?;
+        let filter = doc! { "tenant": &self.tenant, "email_lower": email.to_lowercase() };
+        let find = collection.find_one(filter).projection(user_fields());
+        let found = traced(&self.connection.database, "find", find).await?;
+        found.map(UserDocument::into_user).transpose()
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<String> = ids.iter().map(UserId::to_string).collect();
+        self.find_all(doc! { "tenant": &self.tenant, "_id": { "$in": ids } }).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        Ok(self.size().await?.entries as usize)
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let collection = self.collection().await?.clone_with_type::<StatsView>();
+        let find = collection
+            .find(doc! { "tenant": &self.tenant })
+            .projection(doc! { "_id": 0, "email": 1, "created_at": 1 });
+        let views: Vec<StatsView> =
+            traced(&self.connection.database, "find", async { find.await?.try_collect().await }).await?;
+        let mut users = Vec::with_capacity(views.len());
+        for view in views {
+            let created_at = DateTime::from_timestamp_millis(view.created_at.timestamp_millis())
+                .ok_or_else(|| RepositoryError::Storage(format!("stored timestamp {} is out of range", view.created_at)))?;
+            users.push((view.email, created_at));
+        }
+        Ok(UserStats::tally(users.iter().map(|(email, created_at)| (email.as_str(), *created_at))))
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        let users = self.find_all(doc! { "tenant": &self.tenant }).await?;
+        Ok(DuplicateMatch::among(&users, name, email))
+    }
+
+    // The update only matches while the status is the one checked, so a
+    // concurrent change makes it check again
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        loop {
+            let (from, to) = repository::next_status(self.get(id).await?.map(|user| user.status), action)?;
+            let mut filter = self.by_id(id);
+            filter.insert("status", from.name());
+            let update = doc! { "$set": { "status": to.name(), "updated_at": bson_now() } };
+            if let Some(user) = self.modify(filter, update).await? {
+                return Ok((from, user));
+            }
+        }
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let update = doc! { "$set": { "password_hash": credentials.password_hash, "updated_at": bson_now() } };
+        self.modify(self.by_id(id), update).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        let collection = self.collection().await?.clone_with_type::<AvatarView>();
+        let find = collection.find_one(self.by_id(id)).projection(doc! { "avatar": 1 });
+        let found = traced(&self.connection.database, "find", find).await?;
+        Ok(found.and_then(|view| view.avatar).map(|avatar| Avatar {
+            content_type: avatar.content_type,
+            data: avatar.data.bytes.into(),
+            etag: avatar.etag,
+        }))
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let avatar = bson::to_bson(&AvatarDocument::new(&avatar))
+            .map_err(|err| RepositoryError::Storage(format!("invalid avatar: {}", err)))?;
+        let update = doc! { "$set": { "avatar": avatar, "updated_at": bson_now() } };
+        self.modify(self.by_id(id), update).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        let collection = self.collection().await?.clone_with_type::<ProfileView>();
+        let find = collection.find_one(self.by_id(id)).projection(doc! { "profile": 1 });
+        let found = traced(&self.connection.database, "find", find).await?;
+        Ok(found.and_then(|view| view.profile))
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let profile =
+            bson::to_bson(&profile).map_err(|err| RepositoryError::Storage(format!("invalid profile: {}", err)))?;
+        let update = doc! { "$set": { "profile": profile, "updated_at": bson_now() } };
+        self.modify(self.by_id(id), update).await
+    }
+
+    // The filter only matches while under the limit. Once the quota is spent
+    // the upsert tries to insert a second document with the same _id, which
+    // the server refuses.
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        let quotas = self.connection.quota_collection().await?;
+        let id = format!("{}:{}:{}", self.tenant, day, subject);
+        let expires_at = (day + chrono::Duration::days(2)).and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+        let update = doc! {
+            "$inc": { "used": 1_i64 },
+            "$setOnInsert": { "expires_at": bson::DateTime::from_millis(expires_at.timestamp_millis()) },
+        };
+        let charge = quotas
+            .find_one_and_update(doc! { "_id": &id, "used": { "$lt": i64::from(limit) } }, update)
+            .upsert(true)
+            .return_document(ReturnDocument::After);
+        match traced_on(&self.connection.database, QUOTA_COLLECTION, "findAndModify", charge).await {
+            Ok(charged) => Ok(charged.map(|quota| quota.used as u32)),
+            Err(err) if is_duplicate_key(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        let quotas = self.connection.quota_collection().await?;
+        let id = format!("{}:{}:{}", self.tenant, day, subject);
+        let refund = quotas.update_one(doc! { "_id": &id, "used": { "$gt": 0_i64 } }, doc! { "$inc": { "used": -1_i64 } });
+        traced_on(&self.connection.database, QUOTA_COLLECTION, "update", refund).await?;
+        Ok(())
+    }
+
+    // Document sizes aren't summed; that would mean reading every document
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let collection = self.collection().await?;
+        let filter = doc! { "tenant": &self.tenant };
+        let entries = traced(&self.connection.database, "countDocuments", collection.count_documents(filter)).await?;
+        Ok(StoreSize { entries, bytes: None })
+    }
+}
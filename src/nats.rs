@@ -0,0 +1,157 @@
+use actix_web::web;
+use async_nats::{Client, ConnectOptions, HeaderMap};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use opentelemetry::global;
+use opentelemetry::trace::TraceContextExt;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::OnceCell;
+use tracing::field::Empty;
+use tracing::{info, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::bus::EventBus;
+use crate::get_env_or_default;
+use crate::webhooks::RetryPolicy;
+
+static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+// Connection shared by the event bus and the command consumer. NATS_URL
+// names the server; the client connects, and reconnects, in the background,
+// so startup doesn't wait on NATS.
+async fn client() -> Result<Client, String> {
+    let client = CLIENT
+        .get_or_try_init(|| async {
+            let url = get_env_or_default("NATS_URL", "nats://127.0.0.1:4222");
+            ConnectOptions::new()
+                .name("actix-web-server")
+                .retry_on_initial_connect()
+                .connect(url.as_str())
+                .await
+                .map_err(|err| format!("invalid NATS_URL: {}", err))
+        })
+        .await?;
+    Ok(client.clone())
+}
+
+// User events published to NATS_SUBJECT (default users.events)
+pub struct NatsBus {
+    client: Client,
+    subject: String,
+    policy: RetryPolicy,
+}
+
+impl NatsBus {
+    pub async fn from_env() -> Result<Self, String> {
+        Ok(NatsBus {
+            client: client().await?,
+            subject: get_env_or_default("NATS_SUBJECT", "users.events"),
+            policy: RetryPolicy::from_env_with_prefix("NATS"),
+        })
+    }
+}
+
+#[async_trait]
+impl EventBus for NatsBus {
+    fn system(&self) -> &'static str {
+        "nats"
+    }
+
+    fn destination(&self) -> &str {
+        &self.subject
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    async fn publish(&self, _key: &str, payload: &[u8], headers: &HashMap<String, String>) -> Result<(), String> {
+        let mut nats_headers = HeaderMap::new();
+        for (name, value) in headers {
+            nats_headers.insert(name.as_str(), value.as_str());
+        }
+        self.client
+            .publish_with_headers(self.subject.clone(), nats_headers, web::Bytes::copy_from_slice(payload))
+            .await
+            .map_err(|err| err.to_string())?;
+        // Core NATS has no acknowledgements; a flush at least confirms the
+        // server has the message
+        self.client.flush().await.map_err(|err| err.to_string())
+    }
+}
+
+// NATS_COMMAND_SUBJECT, e.g. users.commands.create, turns on the command consumer
+pub fn command_subject() -> Option<String> {
+    std::env::var("NATS_COMMAND_SUBJECT").ok().filter(|subject| !subject.is_empty())
+}
+
+// Background task taking commands from `subject`, started in phase 3. Each
+// message goes to `handle`, and its result goes back to the reply subject
+// when the sender asked for one. Replicas share the NATS_COMMAND_QUEUE queue
+// group, so each command runs once.
+pub async fn run_commands<F, Fut>(subject: String, handle: F)
+where
+    F: Fn(web::Bytes) -> Fut,
+    Fut: Future<Output = Result<serde_json::Value, String>>,
+{
+    let subscribed = async {
+        let client = client().await?;
+        let queue = get_env_or_default("NATS_COMMAND_QUEUE", "actix-web-server");
+        let subscriber = client.queue_subscribe(subject.clone(), queue).await.map_err(|err| err.to_string())?;
+        Ok::<_, String>((client, subscriber))
+    };
+    let (client, mut subscriber) = match subscribed.await {
+        Ok(subscribed) => subscribed,
+        Err(err) => {
+            warn!(subject = %subject, error = %err, "Failed to subscribe to NATS commands");
+            return;
+        }
+    };
+    info!(subject = %subject, "Taking user commands from NATS");
+
+    while let Some(message) = subscriber.next().await {
+        // Each command is the root of its own trace, linked to the trace
+        // that sent it
+        let span = info_span!(
+            parent: None,
+            "process_command",
+            otel.name = %format!("{} process", subject),
+            otel.kind = "consumer",
+            otel.status_code = Empty,
+            messaging.system = "nats",
+            messaging.destination.name = %subject,
+            messaging.operation = "process",
+        );
+        if let Some(headers) = &message.headers {
+            let carrier: HashMap<String, String> = headers
+                .iter()
+                .filter_map(|(name, values)| values.first().map(|value| (name.to_string().to_lowercase(), value.to_string())))
+                .collect();
+            let sender = global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+            let sender = sender.span().span_context().clone();
+            if sender.is_valid() {
+                span.add_link(sender);
+            }
+        }
+
+        let reply = match handle(message.payload).instrument(span.clone()).await {
+            Ok(reply) => {
+                info!(parent: &span, "Command processed");
+                reply
+            }
+            Err(err) => {
+                span.record("otel.status_code", "ERROR");
+                warn!(parent: &span, error = %err, "Command failed");
+                serde_json::json!({ "error": err })
+            }
+        };
+        if let Some(reply_to) = message.reply {
+            let body = serde_json::to_vec(&reply).unwrap_or_default();
+            if let Err(err) = client.publish(reply_to, body.into()).await {
+                warn!(parent: &span, error = %err, "Failed to reply to command");
+            }
+        }
+    }
+    info!(subject = %subject, "NATS command subscription closed");
+}
@@ -0,0 +1,115 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Uri;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use tracing::{debug, warn};
+
+use crate::get_env_or_default;
+
+// What to do with a trailing slash on the request path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingSlash {
+    // `/users/` is routed as `/users`
+    Trim,
+    // Paths are routed exactly as sent
+    Keep,
+}
+
+// Path normalization settings, applied before routing
+#[derive(Clone, Copy, Debug)]
+pub struct PathNormalization {
+    pub trailing_slash: TrailingSlash,
+    pub merge_slashes: bool,
+}
+
+impl PathNormalization {
+    pub fn from_env() -> Self {
+        let trailing_slash = match get_env_or_default("TRAILING_SLASH", "trim").to_lowercase().as_str() {
+            "trim" => TrailingSlash::Trim,
+            "keep" => TrailingSlash::Keep,
+            other => {
+                warn!(value = %other, "Unknown TRAILING_SLASH mode, using trim");
+                TrailingSlash::Trim
+            }
+        };
+        let merge_slashes = get_env_or_default("MERGE_SLASHES", "true") != "false";
+        PathNormalization { trailing_slash, merge_slashes }
+    }
+
+    // The normalized form of `path`, or None when it is already normal
+    fn normalize(&self, path: &str) -> Option<String> {
+        let mut normalized = String::with_capacity(path.len());
+        for c in path.chars() {
+            if self.merge_slashes && c == '/' && normalized.ends_with('/') {
+                continue;
+            }
+            normalized.push(c);
+        }
+        if self.trailing_slash == TrailingSlash::Trim && normalized.len() > 1 && normalized.ends_with('/') {
+            normalized.pop();
+        }
+        (normalized != path).then_some(normalized)
+    }
+}
+
+// The path as the client sent it, kept when normalization rewrote it
+#[derive(Clone)]
+pub struct OriginalPath(pub String);
+
+// App-level middleware rewriting sloppy paths such as `/users//1/` before
+// routing. It runs outside the tracing middleware, so the rewrite is only
+// noted in the request extensions here and put on the span by
+// `record_normalization`.
+pub async fn normalize_path(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let settings = req
+        .app_data::<web::Data<PathNormalization>>()
+        .map(|settings| *settings.get_ref());
+
+    if let Some(normalized) = settings.and_then(|settings| settings.normalize(req.path())) {
+        let original = req.path().to_string();
+        let path_and_query = match req.query_string() {
+            "" => normalized.clone(),
+            query => format!("{}?{}", normalized, query),
+        };
+
+        let head = req.head_mut();
+        let mut parts = head.uri.clone().into_parts();
+        if let Ok(path_and_query) = path_and_query.parse() {
+            parts.path_and_query = Some(path_and_query);
+            if let Ok(uri) = Uri::from_parts(parts) {
+                head.uri = uri;
+                let uri = head.uri.clone();
+                req.match_info_mut().get_mut().update(&uri);
+                debug!(original = %original, normalized = %normalized, "Normalized request path");
+                req.extensions_mut().insert(OriginalPath(original));
+            }
+        }
+    }
+
+    next.call(req).await
+}
+
+// Scope-level middleware, inside RequestTracing, that records a rewrite on the request span
+pub async fn record_normalization(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let original = req.extensions().get::<OriginalPath>().cloned();
+    let span_context = Context::current();
+    let span = span_context.span();
+    match original {
+        Some(OriginalPath(original)) => {
+            span.set_attribute(KeyValue::new("http.path_normalized", true));
+            span.set_attribute(KeyValue::new("http.original_path", original));
+        }
+        None => span.set_attribute(KeyValue::new("http.path_normalized", false)),
+    }
+
+    next.call(req).await
+}
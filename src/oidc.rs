@@ -0,0 +1,172 @@
+use actix_web_opentelemetry::ClientExt;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, instrument, warn};
+
+use crate::circuit::{CircuitBreaker, CircuitBreakers};
+use crate::get_env_or_default;
+use crate::telemetry::record_cache_lookup;
+
+// Unknown `kid`s trigger a refetch (the provider may have rotated keys), but
+// at most this often so garbage tokens can't hammer the provider
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Claims read from provider-issued tokens; `exp`, `iss` and `aud` are checked
+// by jsonwebtoken itself
+#[derive(Deserialize)]
+pub struct OidcClaims {
+    pub sub: String,
+    pub iss: String,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    jwks_uri: String,
+}
+
+struct CachedJwks {
+    keys: Arc<JwkSet>,
+    fetched_at: Instant,
+}
+
+// Validates access tokens issued by an external OpenID Connect provider such
+// as Keycloak or Auth0, acting as an OAuth2 resource server. Signing keys are
+// fetched from the provider's JWKS endpoint and cached.
+pub struct OidcValidator {
+    issuer: String,
+    audience: String,
+    // Taken from OIDC discovery when not configured explicitly
+    jwks_url: RwLock<Option<String>>,
+    cache_ttl: Duration,
+    cache: RwLock<Option<CachedJwks>>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl OidcValidator {
+    // Enabled by OIDC_ISSUER; OIDC_AUDIENCE is required alongside it.
+    // OIDC_JWKS_URL skips discovery, OIDC_JWKS_CACHE_SECS sets the key cache TTL.
+    pub fn from_env(breakers: &CircuitBreakers) -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER").ok().filter(|issuer| !issuer.is_empty())?;
+        let Some(audience) = std::env::var("OIDC_AUDIENCE").ok().filter(|audience| !audience.is_empty()) else {
+            warn!(issuer = %issuer, "OIDC_ISSUER is set without OIDC_AUDIENCE; provider tokens will be rejected");
+            return None;
+        };
+        let jwks_url = std::env::var("OIDC_JWKS_URL").ok().filter(|url| !url.is_empty());
+        let cache_secs = get_env_or_default("OIDC_JWKS_CACHE_SECS", "300").parse().unwrap_or(300);
+        info!(issuer = %issuer, audience = %audience, "OIDC token validation enabled");
+        Some(OidcValidator {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            audience,
+            jwks_url: RwLock::new(jwks_url),
+            cache_ttl: Duration::from_secs(cache_secs),
+            cache: RwLock::new(None),
+            breaker: breakers.for_dependency("oidc"),
+        })
+    }
+
+    // Validate a provider-issued token: signature against the JWKS, expiry,
+    // issuer and audience
+    pub async fn verify(&self, token: &str) -> Result<OidcClaims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|err| err.to_string())?;
+        let kid = header.kid.ok_or("token has no key ID")?;
+
+        let jwk_set = self.keys(&kid).await?;
+        let jwk = jwk_set.find(&kid).ok_or_else(|| format!("no signing key with ID '{}'", kid))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|err| err.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        jsonwebtoken::decode::<OidcClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| err.to_string())
+    }
+
+    // The cached key set when it is fresh and knows `kid`, otherwise a newly fetched one
+    async fn keys(&self, kid: &str) -> Result<Arc<JwkSet>, String> {
+        let cached = self.cache.read().ok().and_then(|cache| {
+            cache
+                .as_ref()
+                .map(|cached| (cached.keys.clone(), cached.fetched_at.elapsed()))
+        });
+        if let Some((keys, age)) = &cached {
+            let fresh = *age < self.cache_ttl;
+            if fresh && (keys.find(kid).is_some() || *age < MIN_REFETCH_INTERVAL) {
+                record_cache_lookup("jwks", true);
+                return Ok(keys.clone());
+            }
+        }
+        record_cache_lookup("jwks", false);
+
+        let fetched = if self.breaker.allow() {
+            let fetched = self.fetch_keys().await;
+            self.breaker.record(fetched.is_ok());
+            fetched
+        } else {
+            Err("OIDC provider circuit is open".to_string())
+        };
+        match fetched {
+            Ok(keys) => {
+                let keys = Arc::new(keys);
+                if let Ok(mut cache) = self.cache.write() {
+                    *cache = Some(CachedJwks { keys: keys.clone(), fetched_at: Instant::now() });
+                }
+                Ok(keys)
+            }
+            // Keep serving stale keys while the provider is unreachable
+            Err(err) => match cached {
+                Some((keys, _)) => {
+                    warn!(error = %err, "JWKS refresh failed; using cached keys");
+                    Ok(keys)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    #[instrument(name = "fetch_jwks", skip(self), fields(oidc.issuer = %self.issuer))]
+    async fn fetch_keys(&self) -> Result<JwkSet, String> {
+        let client = awc::Client::builder().timeout(Duration::from_secs(5)).finish();
+
+        let configured = self.jwks_url.read().ok().and_then(|url| url.clone());
+        let jwks_url = match configured {
+            Some(url) => url,
+            None => {
+                let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer);
+                let mut response = client
+                    .get(&discovery_url)
+                    .trace_request()
+                    .send()
+                    .await
+                    .map_err(|err| format!("OIDC discovery failed: {}", err))?;
+                let document: DiscoveryDocument = response
+                    .json()
+                    .await
+                    .map_err(|err| format!("invalid OIDC discovery document: {}", err))?;
+                if let Ok(mut url) = self.jwks_url.write() {
+                    *url = Some(document.jwks_uri.clone());
+                }
+                document.jwks_uri
+            }
+        };
+
+        let mut response = client
+            .get(&jwks_url)
+            .trace_request()
+            .send()
+            .await
+            .map_err(|err| format!("JWKS fetch failed: {}", err))?;
+        if !response.status().is_success() {
+            return Err(format!("JWKS endpoint answered {}", response.status()));
+        }
+        let keys: JwkSet = response
+            .json()
+            .await
+            .map_err(|err| format!("invalid JWKS document: {}", err))?;
+        info!(key_count = keys.keys.len(), "Fetched JWKS");
+        Ok(keys)
+    }
+}
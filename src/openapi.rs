@@ -0,0 +1,102 @@
+use serde_json::{json, Map, Value};
+
+use crate::config;
+use crate::routes::ROUTES;
+
+// An OpenAPI 3.0 outline of the HTTP API, generated from the route table for
+// `export-openapi`. It lists every path and method with its path parameters;
+// request and response bodies aren't described beyond the problem document
+// every error is rendered as.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    // The demo UI's pages aren't part of the API
+    for (pattern, methods) in ROUTES.iter().filter(|(pattern, _)| !pattern.starts_with("/ui")) {
+        let parameters: Vec<Value> = path_parameters(pattern)
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect();
+        let mut operations = Map::new();
+        for method in methods.iter() {
+            let method = method.as_str().to_lowercase();
+            let mut operation = json!({
+                "operationId": operation_id(&method, pattern),
+                "tags": [if pattern.starts_with("/admin") { "admin" } else { "api" }],
+                "responses": {
+                    "2XX": { "description": "Success" },
+                    "default": {
+                        "description": "Error",
+                        "content": {
+                            "application/problem+json": {
+                                "schema": { "$ref": "#/components/schemas/Problem" },
+                            },
+                        },
+                    },
+                },
+            });
+            if !parameters.is_empty() {
+                operation["parameters"] = Value::Array(parameters.clone());
+            }
+            operations.insert(method, operation);
+        }
+        paths.insert(pattern.to_string(), Value::Object(operations));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "actix-web-server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        // Paths are relative to the base path the API is served under
+        "servers": [{ "url": config::settings().server.mount_point() }],
+        "tags": [
+            { "name": "api", "description": "User management API" },
+            { "name": "admin", "description": "Operator endpoints; served on ADMIN_BIND when set" },
+        ],
+        "paths": paths,
+        "components": {
+            "schemas": {
+                // Matches AppError's ProblemDocument; extension members vary by error
+                "Problem": {
+                    "type": "object",
+                    "required": ["type", "title", "status", "detail", "code"],
+                    "properties": {
+                        "type": { "type": "string" },
+                        "title": { "type": "string" },
+                        "status": { "type": "integer" },
+                        "detail": { "type": "string" },
+                        "code": { "type": "string" },
+                        "trace_id": { "type": "string" },
+                    },
+                    "additionalProperties": true,
+                },
+            },
+        },
+    })
+}
+
+// Names of the `{...}` segments of a route pattern
+fn path_parameters(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')))
+}
+
+// e.g. "put_users_id_profile" for PUT /users/{id}/profile
+fn operation_id(method: &str, pattern: &str) -> String {
+    let mut id = method.to_string();
+    for word in pattern.split(|c: char| !c.is_ascii_alphanumeric()).filter(|word| !word.is_empty()) {
+        id.push('_');
+        id.push_str(word);
+    }
+    if pattern == "/" {
+        id.push_str("_root");
+    }
+    id
+}
@@ -0,0 +1,149 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{debug, instrument, warn, Span};
+use uuid::Uuid;
+
+use crate::get_env_or_default;
+use crate::repository::SharedUserRepository;
+use crate::telemetry::record_outbox_relayed;
+use crate::tenant::{TenantState, Tenants, DEFAULT_TENANT};
+use crate::webhooks::{self, WebhookPublisher};
+use crate::User;
+
+// A webhook event written together with the mutation it announces. Its ID
+// becomes the delivered event's ID, so receivers can drop the duplicates a
+// relay restart may cause.
+#[derive(Clone)]
+pub struct OutboxEntry {
+    pub id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl OutboxEntry {
+    pub fn new(event_type: &'static str, user: &User) -> Self {
+        OutboxEntry {
+            id: Uuid::now_v7(),
+            event_type: event_type.to_string(),
+            payload: serde_json::to_value(user).unwrap_or(serde_json::Value::Null),
+            occurred_at: Utc::now(),
+        }
+    }
+}
+
+// Outbox for backends that keep users in the process, updated under the
+// same lock as the users themselves
+#[derive(Default)]
+pub struct Outbox {
+    entries: VecDeque<OutboxEntry>,
+}
+
+impl Outbox {
+    pub fn record(&mut self, event_type: &'static str, user: &User) {
+        self.entries.push_back(OutboxEntry::new(event_type, user));
+    }
+
+    // The oldest undelivered entries
+    pub fn pending(&self, limit: usize) -> Vec<OutboxEntry> {
+        self.entries.iter().take(limit).cloned().collect()
+    }
+
+    pub fn acknowledge(&mut self, ids: &[Uuid]) {
+        self.entries.retain(|entry| !ids.contains(&entry.id));
+    }
+}
+
+// OUTBOX_ENABLED=true makes repositories record webhook events in the same
+// transaction (or under the same lock) as each create, update and delete,
+// so a notification goes out exactly when its mutation commits. The relay
+// delivers them, polling every OUTBOX_RELAY_INTERVAL_MS.
+pub fn enabled() -> bool {
+    get_env_or_default("OUTBOX_ENABLED", "false") == "true"
+}
+
+#[derive(Clone, Copy)]
+pub struct RelayConfig {
+    interval: Duration,
+    batch_size: usize,
+}
+
+impl RelayConfig {
+    pub fn from_env() -> Self {
+        RelayConfig {
+            interval: Duration::from_millis(
+                get_env_or_default("OUTBOX_RELAY_INTERVAL_MS", "500").parse().unwrap_or(500).max(10),
+            ),
+            batch_size: get_env_or_default("OUTBOX_BATCH_SIZE", "100").parse().unwrap_or(100).max(1),
+        }
+    }
+}
+
+// Publish a user event straight away, unless the repository already wrote
+// it to its outbox for the relay to deliver
+pub fn publish(repository: &SharedUserRepository, webhooks: &WebhookPublisher, event_type: &'static str, user: &User) {
+    if !repository.has_outbox() {
+        webhooks.publish(event_type, user);
+    }
+}
+
+// Background task draining every tenant's outbox, started in phase 3 when
+// the outbox is enabled. Tenants are relayed once they are active in this
+// process; rows a previous run left for other tenants wait until then.
+pub async fn run_relay(
+    config: RelayConfig,
+    default_tenant: TenantState,
+    tenants: web::Data<Tenants>,
+    webhooks: web::Data<WebhookPublisher>,
+) {
+    let mut ticks = actix_web::rt::time::interval(config.interval);
+    loop {
+        ticks.tick().await;
+        relay(DEFAULT_TENANT, &default_tenant, &webhooks, config.batch_size).await;
+        for (tenant, state) in tenants.active() {
+            relay(&tenant, &state, &webhooks, config.batch_size).await;
+        }
+    }
+}
+
+// Deliver one batch of a tenant's outbox, its entries concurrently. Entries
+// are removed only once every subscriber and event bus got them, so a crash
+// in between redelivers rather than loses them; ones a delivery gave up on
+// stay for the next tick.
+#[instrument(level = "debug", name = "relay_outbox", skip(state, webhooks), fields(relayed = Empty))]
+async fn relay(tenant: &str, state: &TenantState, webhooks: &WebhookPublisher, batch_size: usize) {
+    let pending = match state.repository.outbox_pending(batch_size).await {
+        Ok(pending) => pending,
+        Err(err) => {
+            warn!(tenant.id = %tenant, error = %err, "Failed to read the outbox");
+            return;
+        }
+    };
+    let deliveries = pending.into_iter().map(|entry| async move {
+        let id = entry.id;
+        let delivered = match webhooks::event_type(&entry.event_type) {
+            Some(event_type) => webhooks.publish_and_wait(id, event_type, entry.occurred_at, entry.payload).await,
+            None => {
+                warn!(tenant.id = %tenant, event.id = %id, event_type = %entry.event_type, "Dropping outbox entry of unknown type");
+                true
+            }
+        };
+        delivered.then_some(id)
+    });
+    let relayed: Vec<Uuid> = join_all(deliveries).await.into_iter().flatten().collect();
+    Span::current().record("relayed", relayed.len());
+    if relayed.is_empty() {
+        return;
+    }
+    if let Err(err) = state.repository.outbox_acknowledge(&relayed).await {
+        warn!(tenant.id = %tenant, error = %err, "Failed to remove relayed outbox entries");
+        return;
+    }
+    record_outbox_relayed(tenant, relayed.len() as u64);
+    debug!(tenant.id = %tenant, relayed = relayed.len(), "Outbox entries relayed");
+}
+
@@ -0,0 +1,57 @@
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+use crate::base_path;
+
+// Pagination details a listing attaches to its response, used for the
+// envelope's meta and the RFC 8288 Link header
+#[derive(Clone, Serialize)]
+pub struct Pagination {
+    pub page: usize,
+    pub limit: usize,
+    pub total: usize,
+    pub total_pages: usize,
+}
+
+impl Pagination {
+    pub fn new(page: usize, limit: usize, total: usize) -> Self {
+        Pagination {
+            page,
+            limit,
+            total,
+            total_pages: total.div_ceil(limit.max(1)),
+        }
+    }
+
+    // `Link` header value with first/prev/next/last relations. Targets are
+    // relative references, under the base path, that keep every other query
+    // parameter as sent.
+    pub fn link_header(&self, req: &HttpRequest) -> String {
+        let last = self.total_pages.max(1);
+        let mut links = vec![(1, "first")];
+        if self.page > 1 {
+            links.push(((self.page - 1).min(last), "prev"));
+        }
+        if self.page < last {
+            links.push((self.page + 1, "next"));
+        }
+        links.push((last, "last"));
+
+        links
+            .into_iter()
+            .map(|(page, rel)| format!("<{}>; rel=\"{}\"", page_url(req, page), rel))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn page_url(req: &HttpRequest, page: usize) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    for (key, value) in form_urlencoded::parse(req.query_string().as_bytes()) {
+        if key != "page" {
+            query.append_pair(&key, &value);
+        }
+    }
+    query.append_pair("page", &page.to_string());
+    format!("{}?{}", base_path::external(req, req.path()), query.finish())
+}
@@ -0,0 +1,99 @@
+use actix_multipart::Multipart;
+use actix_web::http::{header::HeaderMap, StatusCode};
+use actix_web::web;
+use futures_util::StreamExt;
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::get_env_or_default;
+
+// Request body size limits, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct PayloadLimits {
+    pub json: usize,
+    pub upload: usize,
+    pub multipart: usize,
+}
+
+impl PayloadLimits {
+    pub fn from_env() -> Self {
+        PayloadLimits {
+            json: limit_from_env("JSON_PAYLOAD_LIMIT", 64 * 1024),
+            upload: limit_from_env("UPLOAD_PAYLOAD_LIMIT", 2 * 1024 * 1024),
+            multipart: limit_from_env("MULTIPART_PAYLOAD_LIMIT", 2 * 1024 * 1024),
+        }
+    }
+}
+
+fn limit_from_env(env_var: &str, default: usize) -> usize {
+    let raw = get_env_or_default(env_var, &default.to_string());
+    match raw.parse::<usize>() {
+        Ok(limit) if limit > 0 => limit,
+        _ => {
+            warn!(env_var = env_var, value = %raw, default = default, "Invalid payload limit, using default");
+            default
+        }
+    }
+}
+
+pub fn payload_too_large(limit: usize) -> AppError {
+    AppError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        format!("Request body exceeds the {} byte limit", limit),
+    )
+    .with("limit", limit)
+}
+
+// Buffer a raw request body, failing as soon as it grows past `limit`
+pub async fn read_body(mut payload: web::Payload, limit: usize) -> Result<web::Bytes, AppError> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| AppError::bad_request("invalid_body", err.to_string()))?;
+        if body.len() + chunk.len() > limit {
+            return Err(payload_too_large(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+// Read the named file field from a multipart/form-data body, returning its
+// declared content type and bytes. Every part counts towards `limit`.
+pub async fn read_multipart_file(
+    headers: &HeaderMap,
+    payload: web::Payload,
+    field_name: &str,
+    limit: usize,
+) -> Result<(String, web::Bytes), AppError> {
+    let mut multipart = Multipart::new(headers, payload);
+    let mut total = 0usize;
+    let mut file = None;
+
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|err| AppError::bad_request("invalid_multipart", err.to_string()))?;
+        let wanted = file.is_none() && field.name() == Some(field_name);
+        let content_type = field.content_type().map(|mime| mime.essence_str().to_string());
+
+        let mut data = web::BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|err| AppError::bad_request("invalid_multipart", err.to_string()))?;
+            total += chunk.len();
+            if total > limit {
+                return Err(payload_too_large(limit));
+            }
+            if wanted {
+                data.extend_from_slice(&chunk);
+            }
+        }
+
+        if wanted {
+            file = Some((content_type.unwrap_or_default(), data.freeze()));
+        }
+    }
+
+    file.ok_or_else(|| {
+        AppError::bad_request("missing_field", format!("Multipart body has no '{}' part", field_name))
+            .with("field", field_name)
+    })
+}
@@ -0,0 +1,238 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use chrono::Utc;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::field::Empty;
+use tracing::{info, instrument, warn};
+
+use crate::backup::StateSnapshot;
+use crate::get_env_or_default;
+use crate::locks::MeasuredLock;
+use crate::telemetry::record_state_snapshot;
+use crate::{AppState, ProfileState};
+
+// Keeps the default tenant's state in a JSON file so demo data survives
+// restarts. The file is loaded during startup, rewritten shortly after
+// successful mutations and once more on graceful shutdown. Other tenants and
+// users held by an external storage backend are not included.
+pub struct StateFile {
+    // Unset when STATE_FILE isn't configured
+    path: Option<PathBuf>,
+    // Mutations are coalesced: a burst of writes within this window costs one save
+    debounce: Duration,
+    dirty: Notify,
+}
+
+impl StateFile {
+    // STATE_FILE names the file; STATE_SAVE_DEBOUNCE_MS bounds how long a
+    // mutation may wait before it is written out
+    pub fn from_env() -> Self {
+        let path = std::env::var("STATE_FILE").ok().filter(|path| !path.is_empty()).map(PathBuf::from);
+        let debounce_ms = get_env_or_default("STATE_SAVE_DEBOUNCE_MS", "1000").parse().unwrap_or(1000);
+        StateFile {
+            path,
+            debounce: Duration::from_millis(debounce_ms),
+            dirty: Notify::new(),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Replace the state with the file's contents, returning how many users were
+    // loaded; Ok(None) when there is no file yet
+    #[instrument(name = "load_state_file", skip_all)]
+    pub fn load(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<Option<usize>, String> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("cannot read {}: {}", path.display(), err)),
+        };
+        let snapshot: StateSnapshot =
+            serde_json::from_slice(&contents).map_err(|err| format!("{} is not a valid snapshot: {}", path.display(), err))?;
+
+        let mut app_state = data.write_measured("app_state").map_err(|_| "application state lock is poisoned")?;
+        let mut profile_state = profiles.write_measured("profiles").map_err(|_| "profile state lock is poisoned")?;
+        let user_count = snapshot
+            .restore(&mut app_state, &mut profile_state)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(Some(user_count))
+    }
+
+    // Write the current state to a temporary file next to the target and
+    // rename it into place, so a crash mid-write never leaves a torn file
+    #[instrument(name = "save_state_file", skip_all)]
+    pub async fn save(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<(), String> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let (contents, user_count) = capture_json(data, profiles)?;
+        let bytes = contents.len();
+        web::block(move || write_atomically(&path, &contents))
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())?;
+        info!(users = user_count, bytes, "State file saved");
+        Ok(())
+    }
+
+    pub fn mark_dirty(&self) {
+        if self.path.is_some() {
+            self.dirty.notify_one();
+        }
+    }
+}
+
+// The state serialized as the file format, plus its user count
+pub fn capture_json(
+    data: &web::Data<RwLock<AppState>>,
+    profiles: &web::Data<RwLock<ProfileState>>,
+) -> Result<(Vec<u8>, usize), String> {
+    let snapshot = {
+        let app_state = data.read_measured("app_state").map_err(|_| "application state lock is poisoned")?;
+        let profile_state = profiles.read_measured("profiles").map_err(|_| "profile state lock is poisoned")?;
+        StateSnapshot::capture(&app_state, &profile_state, true)
+    };
+    let contents = serde_json::to_vec_pretty(&snapshot).map_err(|err| err.to_string())?;
+    Ok((contents, snapshot.user_count()))
+}
+
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)
+}
+
+// Background task saving the state after mutations, started in phase 3
+pub async fn run_saver(
+    file: web::Data<StateFile>,
+    data: web::Data<RwLock<AppState>>,
+    profiles: web::Data<RwLock<ProfileState>>,
+) {
+    if file.path.is_none() {
+        return;
+    }
+    loop {
+        file.dirty.notified().await;
+        actix_web::rt::time::sleep(file.debounce).await;
+        if let Err(err) = file.save(&data, &profiles).await {
+            warn!(error = %err, "Failed to save state file");
+        }
+    }
+}
+
+// Timestamped copies of the state written by the state_snapshot job (see
+// scheduler), in the same format as the state file, so an older state can be
+// restored through POST /admin/import or by pointing STATE_FILE at one
+pub struct SnapshotSchedule {
+    dir: PathBuf,
+    // Older snapshots beyond this many are deleted
+    keep: usize,
+}
+
+impl SnapshotSchedule {
+    // Enabled by SNAPSHOT_DIR; SNAPSHOT_KEEP tunes it, and scheduler.state_snapshot
+    // says when snapshots are taken
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("SNAPSHOT_DIR").ok().filter(|dir| !dir.is_empty())?;
+        let keep = get_env_or_default("SNAPSHOT_KEEP", "5").parse().unwrap_or(5);
+        Some(SnapshotSchedule {
+            dir: PathBuf::from(dir),
+            keep: keep.max(1),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn keep(&self) -> usize {
+        self.keep
+    }
+
+    #[instrument(name = "snapshot_state", skip_all, fields(snapshot.path = Empty, snapshot.bytes = Empty))]
+    pub async fn take(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        let (contents, _) = capture_json(data, profiles)?;
+        let bytes = contents.len();
+        // Names sort chronologically, which pruning relies on
+        let path = self.dir.join(format!("{}{}.json", SNAPSHOT_PREFIX, Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+        let span = tracing::Span::current();
+        span.record("snapshot.path", path.display().to_string());
+        span.record("snapshot.bytes", bytes);
+
+        let dir = self.dir.clone();
+        let keep = self.keep;
+        web::block(move || {
+            std::fs::create_dir_all(&dir)?;
+            write_atomically(&path, &contents)?;
+            prune_snapshots(&dir, keep)
+        })
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())?;
+        record_state_snapshot(started.elapsed(), bytes);
+        Ok(())
+    }
+}
+
+const SNAPSHOT_PREFIX: &str = "state-";
+
+// Delete all but the newest `keep` snapshots in `dir`
+fn prune_snapshots(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let mut snapshots: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(".json"))
+        })
+        .collect();
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for path in &snapshots[..excess] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Scope-level middleware flagging the state for saving after each successful mutation
+pub async fn track_mutations(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let file = req.app_data::<web::Data<StateFile>>().cloned();
+    let res = next.call(req).await?.map_into_boxed_body();
+    if let (true, Some(file)) = (mutating && res.status().is_success(), file) {
+        file.mark_dirty();
+    }
+    Ok(res)
+}
@@ -0,0 +1,129 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::instrument;
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::error::AppError;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ProfileFormat {
+    // Protobuf, for `go tool pprof` and other pprof viewers
+    Pprof,
+    // SVG flame graph, viewable in a browser
+    Flamegraph,
+}
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    format: Option<ProfileFormat>,
+}
+
+#[cfg(feature = "pprof")]
+async fn capture(duration: Duration, format: ProfileFormat) -> Result<(&'static str, Vec<u8>), AppError> {
+    use pprof::protos::Message;
+
+    // 99 Hz rather than 100, so sampling doesn't run in lockstep with timers
+    const FREQUENCY: i32 = 99;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        // Unwinding through these from the signal handler can deadlock
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|err| {
+            AppError::new(StatusCode::CONFLICT, "profile_in_progress", format!("Could not start the profiler: {}", err))
+        })?;
+    actix_web::rt::time::sleep(duration).await;
+
+    // Resolving symbols takes a while for a large binary, so it runs on the
+    // blocking pool; the profiler stops once the guard drops there
+    let encoded = match web::block(move || {
+        let report = guard.report().build().map_err(|err| err.to_string())?;
+        drop(guard);
+        match format {
+            ProfileFormat::Pprof => {
+                let profile = report.pprof().map_err(|err| err.to_string())?;
+                Ok(("application/octet-stream", profile.encode_to_vec()))
+            }
+            ProfileFormat::Flamegraph => {
+                let mut svg = Vec::new();
+                report.flamegraph(&mut svg).map_err(|err| err.to_string())?;
+                Ok(("image/svg+xml", svg))
+            }
+        }
+    })
+    .await
+    {
+        Ok(encoded) => encoded,
+        Err(err) => Err(err.to_string()),
+    };
+    encoded.map_err(|err| {
+        AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "profile_failed", format!("Could not build the profile: {}", err))
+    })
+}
+
+#[cfg(not(feature = "pprof"))]
+async fn capture(_duration: Duration, _format: ProfileFormat) -> Result<(&'static str, Vec<u8>), AppError> {
+    Err(AppError::new(
+        StatusCode::NOT_IMPLEMENTED,
+        "profiling_unavailable",
+        "CPU profiling requires building with the `pprof` feature",
+    ))
+}
+
+// Handler for GET /admin/debug/pprof/profile
+//
+// Samples the whole process's CPU for `seconds` (default 30) and answers
+// with the profile, as pprof protobuf by default or, with
+// format=flamegraph, as an SVG flame graph. One profile runs at a time.
+#[get("/debug/pprof/profile")]
+#[instrument(
+    name = "admin_pprof_profile_handler",
+    skip_all,
+    fields(service = "actix_example", profile.seconds, profile.format)
+)]
+async fn cpu_profile(
+    identity: web::ReqData<AdminIdentity>,
+    query: web::Query<ProfileQuery>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let seconds = query.seconds.unwrap_or(DEFAULT_SECONDS);
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return AppError::bad_request("invalid_profile", format!("seconds must be between 1 and {}", MAX_SECONDS))
+            .with("field", "seconds")
+            .error_response();
+    }
+    let format = query.format.unwrap_or(ProfileFormat::Pprof);
+    let span = tracing::Span::current();
+    span.record("profile.seconds", seconds);
+    span.record("profile.format", match format {
+        ProfileFormat::Pprof => "pprof",
+        ProfileFormat::Flamegraph => "flamegraph",
+    });
+
+    audit.record(&identity.0, "cpu_profile", format!("{}s", seconds));
+    match capture(Duration::from_secs(seconds), format).await {
+        Ok((content_type, body)) => {
+            let filename = match format {
+                ProfileFormat::Pprof => "profile.pb",
+                ProfileFormat::Flamegraph => "flamegraph.svg",
+            };
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+                .body(body)
+        }
+        Err(err) => err.error_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(cpu_profile);
+}
@@ -0,0 +1,123 @@
+use crate::shutdown::ShutdownCoordinator;
+
+// PUSHGATEWAY_URL, e.g. http://pushgateway:9091, turns on push mode: metrics
+// go to a Prometheus Pushgateway instead of the OTLP endpoint, for batch runs
+// and pods nothing can scrape
+pub fn url_from_env() -> Option<String> {
+    std::env::var("PUSHGATEWAY_URL").ok().filter(|url| !url.is_empty())
+}
+
+#[cfg(feature = "pushgateway")]
+mod push {
+    use actix_web::http::header;
+    use opentelemetry::sdk::export::metrics::aggregation;
+    use opentelemetry::sdk::metrics::{controllers, processors, selectors};
+    use opentelemetry_prometheus::PrometheusExporter;
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use prometheus::{Encoder, TextEncoder};
+    use std::time::Duration;
+    use tracing::warn;
+
+    use crate::get_env_or_default;
+
+    // Histogram buckets, in the milliseconds most duration instruments record
+    const BUCKETS: [f64; 12] = [1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0];
+
+    // Pushes the text exposition of every metric to this process's group,
+    // PUSHGATEWAY_JOB (default actix-web-server) and PUSHGATEWAY_INSTANCE
+    // (default $HOSTNAME). Each push replaces the group, and the group is
+    // left in place at shutdown so a finished run's last values stay visible.
+    pub struct Pusher {
+        exporter: PrometheusExporter,
+        group_url: String,
+        pub interval: Duration,
+    }
+
+    impl Pusher {
+        // Installs the global meter provider, so this must run before any
+        // instrument is created
+        pub fn install(url: &str) -> Result<Self, String> {
+            let controller = controllers::basic(processors::factory(
+                selectors::simple::histogram(BUCKETS),
+                aggregation::cumulative_temporality_selector(),
+            ))
+            .with_resource(opentelemetry::sdk::Resource::new(vec![
+                opentelemetry::KeyValue::new("service.name", "actix-web-server"),
+                opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            ]))
+            .build();
+            let exporter = opentelemetry_prometheus::exporter(controller)
+                .try_init()
+                .map_err(|err| format!("Prometheus exporter: {}", err))?;
+            let job = get_env_or_default("PUSHGATEWAY_JOB", "actix-web-server");
+            let instance = get_env_or_default("PUSHGATEWAY_INSTANCE", &get_env_or_default("HOSTNAME", "localhost"));
+            Ok(Pusher {
+                exporter,
+                group_url: format!(
+                    "{}/metrics/job/{}/instance/{}",
+                    url.trim_end_matches('/'),
+                    utf8_percent_encode(&job, NON_ALPHANUMERIC),
+                    utf8_percent_encode(&instance, NON_ALPHANUMERIC),
+                ),
+                interval: Duration::from_secs(get_env_or_default("PUSHGATEWAY_INTERVAL_SECS", "15").parse().unwrap_or(15)),
+            })
+        }
+
+        // Not traced, so the push itself doesn't add a span every interval
+        pub async fn push(&self) -> Result<(), String> {
+            let mut body = Vec::new();
+            TextEncoder::new()
+                .encode(&self.exporter.registry().gather(), &mut body)
+                .map_err(|err| err.to_string())?;
+            let response = awc::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .finish()
+                .put(&self.group_url)
+                .insert_header((header::CONTENT_TYPE, TextEncoder::new().format_type()))
+                .send_body(body)
+                .await
+                .map_err(|err| err.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("Pushgateway answered {}", response.status()));
+            }
+            Ok(())
+        }
+
+        // Background task pushing every interval until the process exits
+        pub async fn run(self: std::rc::Rc<Self>) {
+            loop {
+                actix_web::rt::time::sleep(self.interval).await;
+                if let Err(err) = self.push().await {
+                    warn!(error = %err, "Failed to push metrics to the Pushgateway");
+                }
+            }
+        }
+    }
+}
+
+// Sends metrics to the Pushgateway at `url` every PUSHGATEWAY_INTERVAL_SECS
+// and once more from a shutdown hook, so a short run's final values arrive
+// even when it ends between pushes
+#[cfg(feature = "pushgateway")]
+pub fn start(url: &str, shutdown: &mut ShutdownCoordinator) -> Result<(), String> {
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tracing::{info, warn};
+
+    let pusher = Rc::new(push::Pusher::install(url)?);
+    info!(url = %url, interval_secs = pusher.interval.as_secs(), "Pushing metrics to a Prometheus Pushgateway");
+    let pushing = actix_web::rt::spawn(pusher.clone().run());
+    shutdown.register("metrics push", Duration::from_secs(10), move || async move {
+        pushing.abort();
+        match pusher.push().await {
+            Ok(()) => info!("Final metrics pushed to the Pushgateway"),
+            Err(err) => warn!(error = %err, "Failed to push final metrics to the Pushgateway"),
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "pushgateway"))]
+pub fn start(_url: &str, _shutdown: &mut ShutdownCoordinator) -> Result<(), String> {
+    Err("PUSHGATEWAY_URL requires building with the `pushgateway` feature".to_string())
+}
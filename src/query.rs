@@ -0,0 +1,206 @@
+use actix_web::error::QueryPayloadError;
+use actix_web::{web, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::fields::FieldSet;
+use crate::User;
+
+// Listing defaults and bounds
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// Fields a listing can be sorted by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortField {
+    Name,
+    Email,
+    CreatedAt,
+    UpdatedAt,
+}
+
+// Sort order parsed from `sort=name` or `sort=-created_at` (leading '-' for descending)
+#[derive(Clone, Copy, Debug)]
+pub struct Sort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (descending, name) = match raw.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, raw),
+        };
+        let field = match name {
+            "name" => SortField::Name,
+            "email" => SortField::Email,
+            "created_at" => SortField::CreatedAt,
+            "updated_at" => SortField::UpdatedAt,
+            other => {
+                return Err(format!(
+                    "unknown sort field '{}', expected one of: name, email, created_at, updated_at",
+                    other
+                ))
+            }
+        };
+        Ok(Sort { field, descending })
+    }
+}
+
+fn deserialize_sort<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Sort>, D::Error> {
+    let raw = Option::<String>::deserialize(deserializer)?;
+    raw.map(|raw| raw.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// Query parameters for the user listing
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListQuery {
+    #[serde(default = "default_page")]
+    pub page: usize,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    #[serde(default, deserialize_with = "deserialize_sort")]
+    pub sort: Option<Sort>,
+    // Batch lookup by comma-separated IDs, bypassing the other filters
+    pub ids: Option<String>,
+    // Case-insensitive substring match on the name
+    pub name: Option<String>,
+    pub email_domain: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "crate::fields::deserialize_fields")]
+    pub fields: Option<FieldSet>,
+    // Read by the envelope middleware; accepted here so it isn't rejected as unknown
+    #[serde(default, rename = "envelope")]
+    pub _envelope: Option<bool>,
+}
+
+fn default_page() -> usize {
+    1
+}
+
+fn default_limit() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+impl ListQuery {
+    // Range checks that serde can't express; errors name the parameter
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.page == 0 {
+            return Err(invalid_parameter("page", "must be at least 1"));
+        }
+        if self.limit == 0 || self.limit > MAX_PAGE_SIZE {
+            return Err(invalid_parameter("limit", format!("must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+        if let Some(fields) = &self.fields {
+            fields.validate(crate::USER_FIELDS)?;
+        }
+        if let (Some(after), Some(before)) = (self.created_after, self.created_before) {
+            if after >= before {
+                return Err(invalid_parameter("created_after", "must be earlier than created_before"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn offset(&self) -> usize {
+        (self.page - 1).saturating_mul(self.limit)
+    }
+
+    // Filter, sort and page users given in storage order, returning the page
+    // and the total match count
+    pub fn apply<'a>(&self, users: impl Iterator<Item = &'a User>) -> (Vec<User>, usize) {
+        let name = self.name.as_ref().map(|name| name.to_lowercase());
+        let email_domain = self.email_domain.as_ref().map(|domain| domain.to_lowercase());
+
+        let mut matches: Vec<&User> = users
+            .filter(|u| self.created_after.is_none_or(|after| u.created_at > after))
+            .filter(|u| self.created_before.is_none_or(|before| u.created_at < before))
+            .filter(|u| name.as_ref().is_none_or(|name| u.name.to_lowercase().contains(name)))
+            .filter(|u| {
+                email_domain.as_ref().is_none_or(|domain| {
+                    u.email.rsplit_once('@').is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain))
+                })
+            })
+            .collect();
+
+        if let Some(sort) = self.sort {
+            // Stable sort keeps insertion order among equal keys
+            matches.sort_by(|a, b| {
+                let ordering = match sort.field {
+                    SortField::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                    SortField::Email => a.email.to_lowercase().cmp(&b.email.to_lowercase()),
+                    SortField::CreatedAt => a.created_at.cmp(&b.created_at),
+                    SortField::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+                };
+                if sort.descending { ordering.reverse() } else { ordering }
+            });
+        }
+
+        let total = matches.len();
+        let page = matches
+            .into_iter()
+            .skip(self.offset())
+            .take(self.limit)
+            .cloned()
+            .collect();
+        (page, total)
+    }
+}
+
+pub fn invalid_parameter(parameter: &str, reason: impl Into<String>) -> AppError {
+    let reason = reason.into();
+    AppError::bad_request(
+        "invalid_query_parameter",
+        format!("Invalid value for query parameter '{}': {}", parameter, reason),
+    )
+    .with("parameter", parameter)
+}
+
+// QueryConfig error handler. actix reports deserialization failures without
+// saying which parameter was at fault, so re-run the parse with path tracking
+// to recover the parameter name for the 400 response.
+pub fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let pairs = form_urlencoded::parse(req.query_string().as_bytes());
+    let deserializer = serde_urlencoded::Deserializer::new(pairs);
+    let located = serde_path_to_error::deserialize::<_, ListQuery>(deserializer).err();
+
+    let app_error = match located {
+        // Other endpoints' queries (e.g. under /admin) re-parse as unknown
+        // ListQuery fields; only trust that when actix saw one too
+        Some(located)
+            if located.inner().to_string().starts_with("unknown field")
+                && !err.to_string().contains("unknown field") =>
+        {
+            AppError::bad_request("invalid_query_parameter", err.to_string())
+        }
+        Some(located) if located.path().to_string() != "." => {
+            let parameter = located.path().to_string();
+            let reason = located.inner().to_string();
+            if reason.starts_with("unknown field") {
+                AppError::bad_request("unknown_query_parameter", format!("Unknown query parameter '{}'", parameter))
+                    .with("parameter", parameter)
+            } else {
+                invalid_parameter(&parameter, reason)
+            }
+        }
+        // Errors without a field path carry their own description
+        Some(located) => AppError::bad_request("invalid_query_parameter", located.inner().to_string()),
+        None => AppError::bad_request("invalid_query_parameter", err.to_string()),
+    };
+
+    info!(path = %req.path(), error = %app_error, "Rejected query string");
+    app_error.into()
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(query_error_handler)
+}
@@ -0,0 +1,157 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::auth::Identity;
+use crate::error::AppError;
+use crate::get_env_or_default;
+use crate::repository::SharedUserRepository;
+
+const LIMIT_HEADER: &str = "x-quota-limit";
+const REMAINING_HEADER: &str = "x-quota-remaining";
+const RESET_HEADER: &str = "x-quota-reset";
+
+// Daily cap on mutating requests per authenticated identity;
+// DAILY_MUTATION_QUOTA=0 turns it off. Days roll over at midnight UTC.
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaConfig {
+    pub daily_limit: u32,
+}
+
+impl QuotaConfig {
+    pub fn from_env() -> Self {
+        QuotaConfig {
+            daily_limit: get_env_or_default("DAILY_MUTATION_QUOTA", "1000").parse().unwrap_or(1000),
+        }
+    }
+}
+
+struct Usage {
+    day: NaiveDate,
+    count: u32,
+}
+
+// Outcome of charging one mutation against an identity's quota
+pub struct QuotaDecision {
+    pub allowed: bool,
+    pub remaining: u32,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl QuotaDecision {
+    // From the count a charge left behind; None when the quota was spent
+    fn new(charged: Option<u32>, limit: u32, day: NaiveDate) -> Self {
+        QuotaDecision {
+            allowed: charged.is_some(),
+            remaining: limit.saturating_sub(charged.unwrap_or(limit)),
+            resets_at: (day + Duration::days(1)).and_hms_opt(0, 0, 0).expect("midnight exists").and_utc(),
+        }
+    }
+}
+
+// Mutation counts per identity for the current day, for the backends that
+// keep users in process. The others store counts alongside their users, so
+// they survive restarts and are shared between instances.
+#[derive(Default)]
+pub struct QuotaStore {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl QuotaStore {
+    // Count one mutation by `subject` on `day` unless `limit` is reached,
+    // answering the new count; None when the quota is spent
+    pub fn charge(&self, subject: &str, day: NaiveDate, limit: u32) -> Option<u32> {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Yesterday's counters are worthless; drop them as a new day starts
+        usage.retain(|_, usage| usage.day == day);
+        let usage = usage.entry(subject.to_string()).or_insert(Usage { day, count: 0 });
+
+        (usage.count < limit).then(|| {
+            usage.count += 1;
+            usage.count
+        })
+    }
+
+    // Give back one mutation counted on `day`
+    pub fn refund(&self, subject: &str, day: NaiveDate) {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(usage) = usage.get_mut(subject).filter(|usage| usage.day == day) {
+            usage.count = usage.count.saturating_sub(1);
+        }
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, limit: u32, decision: &QuotaDecision) {
+    headers.insert(HeaderName::from_static(LIMIT_HEADER), HeaderValue::from(limit));
+    headers.insert(HeaderName::from_static(REMAINING_HEADER), HeaderValue::from(decision.remaining));
+    headers.insert(HeaderName::from_static(RESET_HEADER), HeaderValue::from(decision.resets_at.timestamp()));
+}
+
+// Scope-level middleware, innermost of the auth chain so requests refused for
+// other reasons aren't charged. Mutations by an authenticated identity count
+// against its daily quota; once spent they get a 429 until midnight UTC.
+// The charge is taken up front, so concurrent requests can't overrun the
+// quota, and refunded when the handler doesn't succeed. Anonymous requests
+// are left to the rate limiter.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let limit = req
+        .app_data::<web::Data<QuotaConfig>>()
+        .map(|config| config.daily_limit)
+        .unwrap_or(0);
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let subject = req.extensions().get::<Identity>().map(Identity::subject);
+    let (Some(subject), true) = (subject, mutating && limit > 0) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    // The tenant's repository, as tenant::resolve has put it in place
+    let Some(repository) = req.app_data::<SharedUserRepository>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    let today = Utc::now().date_naive();
+    let mut decision = match repository.charge_quota(&subject, today, limit).await {
+        Ok(charged) => QuotaDecision::new(charged, limit, today),
+        // Let the request through; its own write reports the store failing
+        Err(err) => {
+            warn!(subject = %subject, error = %err, "Failed to charge mutation quota");
+            return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+        }
+    };
+
+    if !decision.allowed {
+        let retry_after = (decision.resets_at - Utc::now()).num_seconds().max(1);
+        info!(subject = %subject, limit = limit, "Daily mutation quota exhausted");
+        let mut response = AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "quota_exceeded",
+            format!("Daily quota of {} mutations is used up", limit),
+        )
+        .with("resets_at", decision.resets_at)
+        .error_response();
+        set_headers(response.headers_mut(), limit, &decision);
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        return Ok(req.into_response(response));
+    }
+
+    let result = next.call(req).await;
+    if !result.as_ref().is_ok_and(|res| res.status().is_success()) {
+        match repository.refund_quota(&subject, today).await {
+            Ok(()) => decision.remaining = (decision.remaining + 1).min(limit),
+            Err(err) => warn!(subject = %subject, error = %err, "Failed to refund mutation quota"),
+        }
+    }
+    let mut res = result?.map_into_boxed_body();
+    set_headers(res.headers_mut(), limit, &decision);
+    Ok(res)
+}
@@ -0,0 +1,166 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::auth::Identity;
+use crate::client_ip::{client_ip, PeerUid};
+use crate::config;
+use crate::error::AppError;
+use crate::telemetry::record_rate_limited;
+
+// Above this many tracked clients, idle buckets are dropped on the next check
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token-bucket limiter: each client may burst up to `burst` requests and is
+// refilled at `per_second`. rate_limit.per_second (RATE_LIMIT_PER_SECOND) = 0
+// turns limiting off. Config reloads can change both limits at runtime.
+pub struct RateLimiter {
+    limits: RwLock<(f64, f64)>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        let settings = &config::settings().rate_limit;
+        RateLimiter {
+            limits: RwLock::new(clamp(settings.per_second, settings.burst)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limits().0 > 0.0
+    }
+
+    // (per_second, burst)
+    pub fn limits(&self) -> (f64, f64) {
+        *self.limits.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Existing buckets keep their tokens and refill at the new rate
+    pub fn set_limits(&self, per_second: f64, burst: f64) {
+        *self.limits.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = clamp(per_second, burst);
+    }
+
+    // Take one token for `key`, or say how long until one is available
+    fn acquire(&self, key: &str) -> Result<(), Duration> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            // Fail open; a poisoned limiter shouldn't take the API down
+            return Ok(());
+        };
+        let now = Instant::now();
+        let (per_second, burst) = self.limits();
+        // Turned off by a reload since the caller checked
+        if per_second <= 0.0 {
+            return Ok(());
+        }
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let full_after = Duration::from_secs_f64(burst / per_second);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+        }
+
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+fn clamp(per_second: f64, burst: f64) -> (f64, f64) {
+    (per_second.max(0.0), burst.max(1.0))
+}
+
+// Where a request comes from: the client IP address, as seen through
+// trusted proxies, or on a Unix socket the peer's user. None when neither is
+// known, as no bucket would tell such clients apart.
+fn address_key(req: &ServiceRequest) -> Option<(&'static str, String)> {
+    if let Some(ip) = client_ip(req.request()) {
+        return Some(("ip", format!("ip:{}", ip)));
+    }
+    let uid = req.conn_data::<PeerUid>()?.0;
+    Some(("peer", format!("uid:{}", uid)))
+}
+
+// The API key a request authenticated with
+fn api_key(req: &ServiceRequest) -> Option<(&'static str, String)> {
+    match req.extensions().get::<Identity>() {
+        Some(Identity::ApiKey(key)) => Some(("api_key", format!("api-key:{}", key.id))),
+        _ => None,
+    }
+}
+
+// Scope-level middleware, outside `authenticate` so failed sign-ins and bad
+// credentials are limited too. Every request is charged to its address.
+// Rejections get a 429 with Retry-After, `rate_limited=true` on the request
+// span, and a metric.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let key = address_key(&req);
+    limit(req, next, key).await
+}
+
+// Scope-level middleware, inside `authenticate`: API key clients are also
+// charged per key, so one key can't take more than its share by spreading
+// requests over many addresses
+pub async fn rate_limit_api_keys(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let key = api_key(&req);
+    limit(req, next, key).await
+}
+
+async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+    key: Option<(&'static str, String)>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    let Some((key_kind, key)) = key.filter(|_| limiter.is_enabled()) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    if let Err(retry_after) = limiter.acquire(&key) {
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        info!(client = %key, retry_after = retry_after, "Rate limited request");
+        Context::current()
+            .span()
+            .set_attribute(KeyValue::new("rate_limited", true));
+        record_rate_limited(key_kind);
+
+        let mut response = AppError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Too many requests")
+            .with("retry_after_seconds", retry_after)
+            .error_response();
+        response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+        return Ok(req.into_response(response));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,235 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use redis::RedisError;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::events::StoredEvent;
+use crate::get_env_or_default;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::redis_client::RedisClient;
+use crate::repository::{CacheLookup, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::telemetry::record_cache_lookup;
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+// Settings shared by every tenant's cache layer
+#[derive(Clone)]
+pub struct RedisCache {
+    client: RedisClient,
+    ttl_secs: u64,
+}
+
+impl RedisCache {
+    // USER_CACHE_TTL_SECS bounds how stale a cached user can be
+    pub fn from_env(client: RedisClient) -> Self {
+        let ttl_secs = get_env_or_default("USER_CACHE_TTL_SECS", "60").parse().unwrap_or(60);
+        info!(ttl_secs, "Redis user cache enabled");
+        RedisCache { client, ttl_secs }
+    }
+
+    pub fn wrap(&self, inner: Arc<dyn UserRepository>, tenant: &str) -> Arc<dyn UserRepository> {
+        Arc::new(CachingUserRepository {
+            inner,
+            cache: self.clone(),
+            prefix: format!("users:{}:", tenant),
+        })
+    }
+
+    // Failures only cost a cache miss, so they are logged rather than returned
+    fn degraded<T: Default>(result: Result<T, RedisError>) -> T {
+        result.unwrap_or_else(|err| {
+            warn!(error = %err, "Redis cache unavailable; using the repository");
+            T::default()
+        })
+    }
+}
+
+// Read-through cache in front of another repository. Lookups by ID are served
+// from Redis when possible and cached on a miss; every mutation drops the
+// cached copy. Cached users carry no credentials, so sign-in lookups by email
+// and password checks always go to the repository. Redis failures fall back
+// to the repository.
+struct CachingUserRepository {
+    inner: Arc<dyn UserRepository>,
+    cache: RedisCache,
+    prefix: String,
+}
+
+impl CachingUserRepository {
+    fn key(&self, id: UserId) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    async fn invalidate(&self, id: UserId) {
+        let key = self.key(id);
+        let () = RedisCache::degraded(self.cache.client.query("DEL", &key, redis::cmd("DEL").arg(&key)).await);
+    }
+}
+
+#[async_trait]
+impl UserRepository for CachingUserRepository {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        self.inner.prepare().await
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.get_with_cache_status(id).await.map(|(user, _)| user)
+    }
+
+    async fn get_with_cache_status(&self, id: UserId) -> Result<(Option<User>, Option<CacheLookup>), RepositoryError> {
+        let key = self.key(id);
+        let cached: Option<String> =
+            RedisCache::degraded(self.cache.client.query("GET", &key, redis::cmd("GET").arg(&key)).await);
+        if let Some(user) = cached.and_then(|json| serde_json::from_str::<User>(&json).ok()) {
+            record_cache_lookup("user_redis", true);
+            return Ok((Some(user), Some(CacheLookup { hit: true, age: None })));
+        }
+        record_cache_lookup("user_redis", false);
+
+        let user = self.inner.get(id).await?;
+        if let Some(json) = user.as_ref().and_then(|user| serde_json::to_string(user).ok()) {
+            let set = redis::cmd("SET").arg(&key).arg(json).arg("EX").arg(self.cache.ttl_secs).clone();
+            let () = RedisCache::degraded(self.cache.client.query("SET", &key, &set).await);
+        }
+        Ok((user, Some(CacheLookup { hit: false, age: None })))
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        self.inner.list(query).await
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        self.inner.create(user).await
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        self.inner.create_many(users).await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.update(id, name, email).await;
+        self.invalidate(id).await;
+        updated
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let deleted = self.inner.delete(id).await;
+        self.invalidate(id).await;
+        deleted
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let deleted = self.inner.delete_expired(now).await?;
+        for user in &deleted {
+            self.invalidate(user.id).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn restored(&self, removed: &[UserId], users: &[User]) {
+        self.inner.restored(removed, users).await;
+        for id in removed.iter().copied().chain(users.iter().map(|user| user.id)) {
+            self.invalidate(id).await;
+        }
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.inner.find_by_email(email).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        self.inner.count().await
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        self.inner.stats().await
+    }
+
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        self.inner.domain_counts().await
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        self.inner.find_duplicates(name, email).await
+    }
+
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        self.inner.credentials(id).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        self.inner.avatar(id).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        self.inner.profile(id).await
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let result = self.inner.set_status(id, action).await;
+        self.invalidate(id).await;
+        result
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.set_credentials(id, credentials).await;
+        self.invalidate(id).await;
+        updated
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_avatar(id, avatar).await;
+        self.invalidate(id).await;
+        touched
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_profile(id, profile).await;
+        self.invalidate(id).await;
+        touched
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        self.inner.charge_quota(subject, day, limit).await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.inner.refund_quota(subject, day).await
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.inner.has_outbox()
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.inner.outbox_pending(limit).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        self.inner.outbox_acknowledge(ids).await
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        self.inner.size().await
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.inner.keeps_history()
+    }
+
+    async fn history(&self, id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        self.inner.history(id).await
+    }
+}
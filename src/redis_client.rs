@@ -0,0 +1,100 @@
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{Cmd, FromRedisValue, Pipeline, RedisError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
+
+use crate::get_env_or_default;
+
+// Redis connection shared by the user cache and the Redis storage backend.
+// Every command runs in a client span carrying OpenTelemetry's database attributes.
+#[derive(Clone)]
+pub struct RedisClient {
+    client: redis::Client,
+    // Opened on first use, so startup doesn't wait on Redis
+    connection: Arc<OnceCell<ConnectionManager>>,
+    // Bounds connecting and each command, so a slow Redis can't stall requests
+    timeout: Duration,
+}
+
+impl RedisClient {
+    // REDIS_URL names the server; REDIS_TIMEOUT_MS bounds each command
+    pub fn from_env() -> Result<Self, String> {
+        let url = crate::config::settings().storage.redis_url.clone().unwrap_or_else(|| "redis://127.0.0.1:6379".to_string());
+        let client = redis::Client::open(url.as_str()).map_err(|err| format!("invalid REDIS_URL: {}", err))?;
+        let timeout_ms = get_env_or_default("REDIS_TIMEOUT_MS", "500").parse().unwrap_or(500);
+        Ok(RedisClient {
+            client,
+            connection: Arc::new(OnceCell::new()),
+            timeout: Duration::from_millis(timeout_ms),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, RedisError> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(self.timeout)
+                    .set_response_timeout(self.timeout)
+                    .set_number_of_retries(1);
+                self.client.get_connection_manager_with_config(config)
+            })
+            .await?;
+        Ok(connection.clone())
+    }
+
+    pub async fn query<T: FromRedisValue>(&self, operation: &'static str, key: &str, cmd: &Cmd) -> Result<T, RedisError> {
+        let command = async { cmd.query_async(&mut self.connection().await?).await };
+        self.traced(operation, key, command).await
+    }
+
+    // Run a pipeline as one span; `operation` names it, e.g. "MULTI" for a transaction
+    pub async fn pipeline<T: FromRedisValue>(
+        &self,
+        operation: &'static str,
+        key: &str,
+        pipeline: &Pipeline,
+    ) -> Result<T, RedisError> {
+        let commands = async { pipeline.query_async(&mut self.connection().await?).await };
+        self.traced(operation, key, commands).await
+    }
+
+    // A dedicated connection subscribed to `channel`, since a subscribed
+    // connection can't run other commands
+    pub async fn subscribe(&self, channel: &str) -> Result<redis::aio::PubSub, RedisError> {
+        let subscribe = async {
+            let mut pubsub = self.client.get_async_pubsub().await?;
+            pubsub.subscribe(channel).await?;
+            Ok(pubsub)
+        };
+        self.traced("SUBSCRIBE", channel, subscribe).await
+    }
+
+    async fn traced<T>(
+        &self,
+        operation: &'static str,
+        key: &str,
+        command: impl std::future::Future<Output = Result<T, RedisError>>,
+    ) -> Result<T, RedisError> {
+        let span = info_span!(
+            "redis.command",
+            otel.name = operation,
+            otel.kind = "client",
+            otel.status_code = Empty,
+            db.system = "redis",
+            db.operation = operation,
+            db.redis.key = %key,
+        );
+        let result = match tokio::time::timeout(self.timeout, command.instrument(span.clone())).await {
+            Ok(result) => result,
+            Err(_) => Err(RedisError::from(std::io::Error::from(std::io::ErrorKind::TimedOut))),
+        };
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        result
+    }
+}
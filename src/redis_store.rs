@@ -0,0 +1,445 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use redis::RedisError;
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::auth::Credentials;
+use crate::query::ListQuery;
+use crate::redis_client::RedisClient;
+use crate::repository::{self, transaction, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+impl From<RedisError> for RepositoryError {
+    fn from(err: RedisError) -> Self {
+        RepositoryError::Storage(err.to_string())
+    }
+}
+
+// Users stored in Redis so several instances can share them. Per tenant:
+//   user-store:{tenant}:user:{id}     hash of the user's fields
+//   user-store:{tenant}:ids           set of every user ID
+//   user-store:{tenant}:email:{email} set of IDs using a (lowercased) address
+//   user-store:{tenant}:avatar:{id}   hash of the avatar's type, ETag and bytes
+//   user-store:{tenant}:profile:{id}  hash of the profile's fields
+//   user-store:{tenant}:quota:{day}:{subject} mutations that day, expiring after it
+// Mutations write all keys in one MULTI/EXEC. Updates and deletes read the
+// user first, so two instances changing the same user at once can leave a
+// stale email index entry; lookups re-check the address to stay correct.
+// Status changes and quota charges check and write in a script, so they
+// never race.
+pub struct RedisUserRepository {
+    client: RedisClient,
+    prefix: String,
+}
+
+impl RedisUserRepository {
+    pub fn new(client: RedisClient, tenant: &str) -> Self {
+        RedisUserRepository { client, prefix: format!("user-store:{}:", tenant) }
+    }
+
+    fn user_key(&self, id: UserId) -> String {
+        format!("{}user:{}", self.prefix, id)
+    }
+
+    fn ids_key(&self) -> String {
+        format!("{}ids", self.prefix)
+    }
+
+    fn email_key(&self, email: &str) -> String {
+        format!("{}email:{}", self.prefix, email.to_lowercase())
+    }
+
+    fn avatar_key(&self, id: UserId) -> String {
+        format!("{}avatar:{}", self.prefix, id)
+    }
+
+    fn profile_key(&self, id: UserId) -> String {
+        format!("{}profile:{}", self.prefix, id)
+    }
+
+    fn quota_key(&self, day: NaiveDate, subject: &str) -> String {
+        format!("{}quota:{}:{}", self.prefix, day, subject)
+    }
+
+    async fn load(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let key = self.user_key(id);
+        let fields: HashMap<String, String> = self.client.query("HGETALL", &key, redis::cmd("HGETALL").arg(&key)).await?;
+        user_from_hash(fields)
+    }
+
+    // Users with these IDs, in ID order (creation order, as IDs are UUIDv7)
+    async fn load_all(&self, mut ids: Vec<UserId>) -> Result<Vec<User>, RepositoryError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        ids.sort();
+        let mut pipeline = redis::pipe();
+        for id in &ids {
+            pipeline.cmd("HGETALL").arg(self.user_key(*id));
+        }
+        let hashes: Vec<HashMap<String, String>> = self.client.pipeline("HGETALL", &self.ids_key(), &pipeline).await?;
+        let mut users = Vec::with_capacity(hashes.len());
+        for fields in hashes {
+            users.extend(user_from_hash(fields)?);
+        }
+        Ok(users)
+    }
+
+    // Every user of the tenant, in ID order
+    async fn all(&self) -> Result<Vec<User>, RepositoryError> {
+        let key = self.ids_key();
+        let ids: Vec<String> = self.client.query("SMEMBERS", &key, redis::cmd("SMEMBERS").arg(&key)).await?;
+        self.load_all(parse_ids(ids)).await
+    }
+
+    // Write a changed user back, along with whatever `also` adds to the
+    // transaction; None if the user doesn't exist
+    async fn change(
+        &self,
+        id: UserId,
+        change: impl FnOnce(&mut User),
+        also: impl FnOnce(&mut redis::Pipeline),
+    ) -> Result<Option<User>, RepositoryError> {
+        let Some(mut user) = self.load(id).await? else {
+            return Ok(None);
+        };
+        change(&mut user);
+        user.updated_at = Utc::now();
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        self.write_user(&mut pipeline, &user);
+        also(&mut pipeline);
+        let () = self.client.pipeline("MULTI", &self.user_key(id), &pipeline).await?;
+        Ok(Some(user))
+    }
+
+    fn write_user(&self, pipeline: &mut redis::Pipeline, user: &User) {
+        let mut fields = vec![
+            ("id", user.id.to_string()),
+            ("name", user.name.clone()),
+            ("email", user.email.clone()),
+            ("status", user.status.name().to_string()),
+            ("created_at", user.created_at.to_rfc3339()),
+            ("updated_at", user.updated_at.to_rfc3339()),
+        ];
+        if let Some(expires_at) = user.expires_at {
+            fields.push(("expires_at", expires_at.to_rfc3339()));
+        }
+        if let Some(credentials) = &user.credentials {
+            fields.push(("password_hash", credentials.password_hash.clone()));
+        }
+        if let Some(enrichment) = user.enrichment.as_ref().and_then(|enrichment| serde_json::to_string(enrichment).ok()) {
+            fields.push(("enrichment", enrichment));
+        }
+        pipeline.hset_multiple(self.user_key(user.id), &fields).ignore();
+    }
+
+    fn add_user(&self, pipeline: &mut redis::Pipeline, user: &User) {
+        self.write_user(pipeline, user);
+        pipeline.sadd(self.ids_key(), user.id.to_string()).ignore();
+        pipeline.sadd(self.email_key(&user.email), user.id.to_string()).ignore();
+    }
+}
+
+// Decode a user hash; an empty hash means the user doesn't exist
+fn user_from_hash(mut fields: HashMap<String, String>) -> Result<Option<User>, RepositoryError> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let mut take = |field: &str| {
+        fields
+            .remove(field)
+            .ok_or_else(|| RepositoryError::Storage(format!("stored user is missing '{}'", field)))
+    };
+    let timestamp = |value: String| {
+        DateTime::parse_from_rfc3339(&value)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|err| RepositoryError::Storage(format!("invalid stored timestamp: {}", err)))
+    };
+    let id = take("id")?
+        .parse()
+        .map_err(|err| RepositoryError::Storage(format!("invalid stored user ID: {}", err)))?;
+    let name = take("name")?;
+    let email = take("email")?;
+    let status = take("status")?;
+    let status =
+        UserStatus::from_name(&status).ok_or_else(|| RepositoryError::Storage(format!("unknown user status '{}'", status)))?;
+    let created_at = timestamp(take("created_at")?)?;
+    let updated_at = timestamp(take("updated_at")?)?;
+    let expires_at = fields.remove("expires_at").map(timestamp).transpose()?;
+    let enrichment = fields
+        .remove("enrichment")
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|err| RepositoryError::Storage(format!("invalid stored enrichment: {}", err)))?;
+    Ok(Some(User {
+        id,
+        name,
+        email,
+        status,
+        created_at,
+        updated_at,
+        expires_at,
+        credentials: fields.remove("password_hash").map(|password_hash| Credentials { password_hash }),
+        enrichment,
+    }))
+}
+
+// Set a user's status (ARGV[2]) and updated_at (ARGV[3]) only if the status
+// is still ARGV[1]; 1 when it was set
+const SWAP_STATUS: &str = r#"
+if redis.call('HGET', KEYS[1], 'status') == ARGV[1] then
+    redis.call('HSET', KEYS[1], 'status', ARGV[2], 'updated_at', ARGV[3])
+    return 1
+end
+return 0
+"#;
+
+// Count a mutation (KEYS[1]) unless ARGV[1] were made already, keeping the
+// count ARGV[2] seconds; the new count, or -1 when the quota is spent
+const CHARGE_QUOTA: &str = r#"
+local used = tonumber(redis.call('GET', KEYS[1]) or '0')
+if used >= tonumber(ARGV[1]) then
+    return -1
+end
+used = redis.call('INCR', KEYS[1])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+return used
+"#;
+
+// Decrements the count, never below zero, without creating a missing key
+const REFUND_QUOTA: &str = r#"
+if tonumber(redis.call('GET', KEYS[1]) or '0') > 0 then
+    redis.call('DECR', KEYS[1])
+end
+return 0
+"#;
+
+// How long a day's quota counts are kept, past the day itself
+const QUOTA_TTL_SECS: i64 = 2 * 24 * 60 * 60;
+
+fn parse_ids(ids: Vec<String>) -> Vec<UserId> {
+    ids.iter().filter_map(|id| id.parse().ok()).collect()
+}
+
+#[async_trait]
+impl UserRepository for RedisUserRepository {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        let () = self.client.query("PING", "", &redis::cmd("PING")).await?;
+        info!("Redis storage ready");
+        Ok(())
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.load(id).await
+    }
+
+    // Redis can't filter or sort hashes, so the tenant's users are loaded and
+    // paged in process, like the in-memory backend
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        let users = self.all().await?;
+        Ok(query.apply(users.iter()))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let user = user.into_user();
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        self.add_user(&mut pipeline, &user);
+        let () = self.client.pipeline("MULTI", &self.user_key(user.id), &pipeline).await?;
+        Ok(user)
+    }
+
+    // The whole batch goes out as one MULTI/EXEC
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        transaction(self.name(), "create_many", async {
+            let users: Vec<User> = users.into_iter().map(NewUser::into_user).collect();
+            let mut pipeline = redis::pipe();
+            pipeline.atomic();
+            for user in &users {
+                self.add_user(&mut pipeline, user);
+            }
+            let () = self.client.pipeline("MULTI", &self.ids_key(), &pipeline).await?;
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let Some(mut user) = self.load(id).await? else {
+            return Ok(None);
+        };
+        let previous_email = std::mem::replace(&mut user.email, email);
+        user.name = name;
+        user.updated_at = Utc::now();
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        self.write_user(&mut pipeline, &user);
+        pipeline.srem(self.email_key(&previous_email), id.to_string()).ignore();
+        pipeline.sadd(self.email_key(&user.email), id.to_string()).ignore();
+        let () = self.client.pipeline("MULTI", &self.user_key(id), &pipeline).await?;
+        Ok(Some(user))
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let Some(user) = self.load(id).await? else {
+            return Ok(None);
+        };
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        pipeline.del(self.user_key(id)).ignore();
+        pipeline.del(self.avatar_key(id)).ignore();
+        pipeline.del(self.profile_key(id)).ignore();
+        pipeline.srem(self.ids_key(), id.to_string()).ignore();
+        pipeline.srem(self.email_key(&user.email), id.to_string()).ignore();
+        let () = self.client.pipeline("MULTI", &self.user_key(id), &pipeline).await?;
+        Ok(Some(user))
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let mut deleted = Vec::new();
+        for user in self.all().await? {
+            if user.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                deleted.extend(self.delete(user.id).await?);
+            }
+        }
+        Ok(deleted)
+    }
+
+    // The earliest-created user with this address, matching the in-memory index
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let key = self.email_key(email);
+        let ids: Vec<String> = self.client.query("SMEMBERS", &key, redis::cmd("SMEMBERS").arg(&key)).await?;
+        let users = self.load_all(parse_ids(ids)).await?;
+        Ok(users.into_iter().find(|user| user.email.eq_ignore_ascii_case(email)))
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.load_all(ids.to_vec()).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        let key = self.ids_key();
+        let count: usize = self.client.query("SCARD", &key, redis::cmd("SCARD").arg(&key)).await?;
+        Ok(count)
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let users = self.all().await?;
+        Ok(UserStats::tally(users.iter().map(|user| (user.email.as_str(), user.created_at))))
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        Ok(DuplicateMatch::among(&self.all().await?, name, email))
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let key = self.user_key(id);
+        loop {
+            let user = self.load(id).await?;
+            let (from, to) = repository::next_status(user.as_ref().map(|user| user.status), action)?;
+            let updated_at = Utc::now();
+            let swap = redis::cmd("EVAL")
+                .arg(SWAP_STATUS)
+                .arg(1)
+                .arg(&key)
+                .arg(from.name())
+                .arg(to.name())
+                .arg(updated_at.to_rfc3339())
+                .to_owned();
+            let swapped: i64 = self.client.query("EVAL", &key, &swap).await.map_err(RepositoryError::from)?;
+            if let (1, Some(mut user)) = (swapped, user) {
+                user.status = to;
+                user.updated_at = updated_at;
+                return Ok((from, user));
+            }
+        }
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        self.change(id, |user| user.credentials = Some(credentials), |_| {}).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        let key = self.avatar_key(id);
+        let mut fields: HashMap<String, Vec<u8>> = self.client.query("HGETALL", &key, redis::cmd("HGETALL").arg(&key)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let mut text = |field: &str| {
+            let value = fields.remove(field).unwrap_or_default();
+            String::from_utf8(value).map_err(|_| RepositoryError::Storage(format!("stored avatar '{}' is not UTF-8", field)))
+        };
+        let (content_type, etag) = (text("content_type")?, text("etag")?);
+        let data = fields.remove("data").unwrap_or_default();
+        Ok(Some(Avatar { content_type, data: data.into(), etag }))
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let key = self.avatar_key(id);
+        self.change(id, |_| {}, |pipeline| {
+            pipeline.del(&key).ignore();
+            pipeline
+                .cmd("HSET")
+                .arg(&key)
+                .arg("content_type")
+                .arg(&avatar.content_type)
+                .arg("etag")
+                .arg(&avatar.etag)
+                .arg("data")
+                .arg(avatar.data.as_ref())
+                .ignore();
+        })
+        .await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        let key = self.profile_key(id);
+        let mut fields: HashMap<String, String> = self.client.query("HGETALL", &key, redis::cmd("HGETALL").arg(&key)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let defaults = Profile::default();
+        Ok(Some(Profile {
+            bio: fields.remove("bio").unwrap_or(defaults.bio),
+            locale: fields.remove("locale").unwrap_or(defaults.locale),
+            timezone: fields.remove("timezone").unwrap_or(defaults.timezone),
+        }))
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let key = self.profile_key(id);
+        let fields = [("bio", &profile.bio), ("locale", &profile.locale), ("timezone", &profile.timezone)];
+        self.change(id, |_| {}, |pipeline| {
+            pipeline.hset_multiple(&key, &fields).ignore();
+        })
+        .await
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        let key = self.quota_key(day, subject);
+        let charge = redis::cmd("EVAL").arg(CHARGE_QUOTA).arg(1).arg(&key).arg(limit).arg(QUOTA_TTL_SECS).to_owned();
+        let used: i64 = self.client.query("EVAL", &key, &charge).await?;
+        Ok(u32::try_from(used).ok())
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        let key = self.quota_key(day, subject);
+        let refund = redis::cmd("EVAL").arg(REFUND_QUOTA).arg(1).arg(&key).to_owned();
+        self.client.query::<()>("EVAL", &key, &refund).await?;
+        Ok(())
+    }
+
+    // Memory use isn't reported: MEMORY USAGE would cost a call per user
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let key = self.ids_key();
+        let entries: u64 = self.client.query("SCARD", &key, redis::cmd("SCARD").arg(&key)).await?;
+        Ok(StoreSize { entries, bytes: None })
+    }
+}
@@ -0,0 +1,497 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpResponse, HttpResponseBuilder, ResponseError};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::{info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::enrichment::Enrichment;
+use crate::error::AppError;
+use crate::events::StoredEvent;
+use crate::locks::MeasuredLock;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::quota::QuotaStore;
+use crate::webhooks;
+use crate::{
+    AppState, Avatar, DuplicateMatch, Profile, ProfileState, StatusAction, TransitionError, User, UserId, UserStats,
+    UserStatus,
+};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    // The storage behind a repository failed, as opposed to a user not existing
+    Storage(String),
+    // The backend enforces unique addresses and already holds this one
+    #[cfg_attr(not(feature = "mongodb"), allow(dead_code))]
+    DuplicateEmail(String),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Storage(cause) => write!(f, "user storage error: {}", cause),
+            RepositoryError::DuplicateEmail(email) => write!(f, "email {} is already in use", email),
+        }
+    }
+}
+
+// Storage failures are rendered as a 500 problem document; the cause is
+// logged but not returned
+impl ResponseError for RepositoryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RepositoryError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RepositoryError::DuplicateEmail(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            RepositoryError::Storage(_) => {
+                warn!(error = %self, "User storage error");
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", "User storage is unavailable")
+                    .error_response()
+            }
+            RepositoryError::DuplicateEmail(email) => {
+                AppError::new(StatusCode::CONFLICT, "email_taken", "Another user already has this email address")
+                    .with("field", "email")
+                    .with("email", email)
+                    .error_response()
+            }
+        }
+    }
+}
+
+// A user about to be created; the repository assigns the ID and timestamps
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+    pub credentials: Option<Credentials>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub enrichment: Option<Enrichment>,
+}
+
+impl NewUser {
+    // The user as an external backend stores it: a pending user with a
+    // UUIDv7 ID, so IDs sort by creation
+    pub fn into_user(self) -> User {
+        let now = Utc::now();
+        User {
+            id: Uuid::now_v7(),
+            name: self.name,
+            email: self.email,
+            status: UserStatus::Pending,
+            created_at: now,
+            updated_at: now,
+            expires_at: self.expires_at,
+            credentials: self.credentials,
+            enrichment: self.enrichment,
+        }
+    }
+}
+
+// Storage for users and their avatars and profiles, so handlers don't depend
+// on where users live. Lookups answer Ok(None) for users that don't exist.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Create whatever the backend needs (tables, indexes) before first use
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError>;
+    // The same lookup, saying whether a cache in front of the backend answered it
+    async fn get_with_cache_status(&self, id: UserId) -> Result<(Option<User>, Option<CacheLookup>), RepositoryError> {
+        Ok((self.get(id).await?, None))
+    }
+    // One page of users matching the query, plus the total match count
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError>;
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError>;
+    // Create several users atomically: all of them, or on error none. Backends
+    // without transactions fall back to deleting what was already created.
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        transaction(self.name(), "create_many", async {
+            let mut created = Vec::with_capacity(users.len());
+            for user in users {
+                match self.create(user).await {
+                    Ok(user) => created.push(user),
+                    Err(err) => {
+                        for user in &created {
+                            if let Err(err) = self.delete(user.id).await {
+                                warn!(user_id = %user.id, error = %err, "Failed to roll back created user");
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            Ok(created)
+        })
+        .await
+    }
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError>;
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError>;
+    // Delete every user whose expires_at is at or before `now`, returning them
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError>;
+    // The users were replaced wholesale behind the repository's back, as
+    // POST /admin/import does: `removed` are gone and `users` is all there is
+    // now. Layers keeping their own copies (caches, STATE_SYNC) catch up here.
+    async fn restored(&self, _removed: &[UserId], _users: &[User]) {}
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError>;
+    // The users among `ids` that exist, in no particular order
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError>;
+    async fn count(&self) -> Result<usize, RepositoryError>;
+    // Counts per email domain and per creation day, for GET /users/stats
+    async fn stats(&self) -> Result<UserStats, RepositoryError>;
+    // Counts per email domain alone, for GET /stats/domains
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        Ok(Arc::new(self.stats().await?.by_email_domain))
+    }
+    // Users with the same email or a similar name, in storage order
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError>;
+    // Move a user through the lifecycle, returning the previous status and the
+    // updated user. Where the backend allows, the check and the change are one
+    // step, so two concurrent actions can't both apply to the same status.
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError>;
+    // A user's stored password, for confirming it before a change. Caches
+    // forward this to the backend, as cached users may carry no credentials.
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        Ok(self.get(id).await?.map(|user| user.credentials))
+    }
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError>;
+    // The uploaded avatar; None when there is none (or no such user)
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError>;
+    // Store a user's avatar and bump its updated_at; None if the user doesn't exist
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError>;
+    // The stored profile; None when none was stored (or no such user)
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError>;
+    // Store a user's profile and bump its updated_at; None if the user doesn't exist
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError>;
+    // Count one mutation by `subject` on `day` unless it has made `limit`
+    // already, answering the new count; None when the quota is spent. The
+    // check and the increment are one step, so instances sharing a store
+    // share the quota.
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError>;
+    // Give back one mutation charged on `day`, for a request that failed
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError>;
+    // Whether the backend records every change to a user, as the events backend does
+    fn keeps_history(&self) -> bool {
+        false
+    }
+    // A user's events, oldest first; None when none were recorded for it
+    async fn history(&self, _id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        Ok(None)
+    }
+    // Whether mutations write their webhook events to an outbox, atomically
+    // with the change itself, for the relay to deliver (OUTBOX_ENABLED)
+    fn has_outbox(&self) -> bool {
+        false
+    }
+    // The oldest outbox entries not yet delivered
+    async fn outbox_pending(&self, _limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        Ok(Vec::new())
+    }
+    // Remove entries the relay has delivered
+    async fn outbox_acknowledge(&self, _ids: &[Uuid]) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+    // How many users the backend holds for this tenant and roughly how much
+    // room they take, sampled for the store gauges
+    async fn size(&self) -> Result<StoreSize, RepositoryError>;
+}
+
+// A repository's size as sampled for metrics. `bytes` is an estimate (heap
+// use for in-process backends, stored row size for SQL) and None where the
+// backend can't tell cheaply.
+#[derive(Clone, Copy)]
+pub struct StoreSize {
+    pub entries: u64,
+    pub bytes: Option<u64>,
+}
+
+// How a cache layer answered a lookup by ID, reported to clients as
+// X-Cache: HIT or MISS, with the entry's age in Age when the cache knows it
+#[derive(Clone, Copy)]
+pub struct CacheLookup {
+    pub hit: bool,
+    pub age: Option<Duration>,
+}
+
+impl CacheLookup {
+    pub fn headers(&self, response: &mut HttpResponseBuilder) {
+        response.insert_header(("X-Cache", if self.hit { "HIT" } else { "MISS" }));
+        if let Some(age) = self.age.filter(|_| self.hit) {
+            response.insert_header((header::AGE, age.as_secs().to_string()));
+        }
+    }
+}
+
+// Run a multi-step operation in a `db.transaction` span, so its queries show
+// up as children, recording whether it committed or rolled back
+pub async fn transaction<T>(
+    system: &'static str,
+    operation: &'static str,
+    body: impl Future<Output = Result<T, RepositoryError>>,
+) -> Result<T, RepositoryError> {
+    let span = info_span!(
+        "db.transaction",
+        otel.name = %format!("{} transaction", operation),
+        otel.status_code = Empty,
+        db.system = system,
+        db.operation = operation,
+        db.transaction.outcome = Empty,
+    );
+    let result = body.instrument(span.clone()).await;
+    match &result {
+        Ok(_) => {
+            span.record("db.transaction.outcome", "commit");
+        }
+        Err(err) => {
+            span.record("db.transaction.outcome", "rollback");
+            span.record("otel.status_code", "ERROR");
+            warn!(parent: &span, error = %err, "Transaction rolled back");
+        }
+    }
+    result
+}
+
+// The status change `action` makes to a user with status `current` (None if
+// the user doesn't exist). Backends that can't hold a lock between reading a
+// user and writing it back store the new status only if the old one is still
+// in place, and otherwise retry against the status that won.
+pub fn next_status(current: Option<UserStatus>, action: StatusAction) -> Result<(UserStatus, UserStatus), TransitionError> {
+    let from = current.ok_or(TransitionError::NotFound)?;
+    let to = from.apply(action).ok_or(TransitionError::Illegal(from))?;
+    Ok((from, to))
+}
+
+// How handlers receive the repository
+pub type SharedUserRepository = web::Data<Arc<dyn UserRepository>>;
+
+// Users and avatars kept in AppState, shared with the sessions and API keys
+// that refer to them; profiles live in ProfileState
+pub struct InMemoryUserRepository {
+    state: web::Data<RwLock<AppState>>,
+    profiles: web::Data<RwLock<ProfileState>>,
+    // Record webhook events in AppState's outbox under the same write lock
+    outbox: bool,
+    // Kept apart from AppState so charging doesn't take its write lock
+    quotas: QuotaStore,
+}
+
+impl InMemoryUserRepository {
+    pub fn new(state: web::Data<RwLock<AppState>>, profiles: web::Data<RwLock<ProfileState>>, outbox: bool) -> Self {
+        InMemoryUserRepository { state, profiles, outbox, quotas: QuotaStore::default() }
+    }
+
+    fn forget_profiles(&self, ids: impl IntoIterator<Item = UserId>) -> Result<(), RepositoryError> {
+        let mut profiles = self.profiles.write_measured("profiles").map_err(poisoned)?;
+        for id in ids {
+            profiles.profiles.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+fn poisoned<T>(_: T) -> RepositoryError {
+    RepositoryError::Storage("application state lock is poisoned".to_string())
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUserRepository {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.users.get(id).cloned())
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.list_users(query))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        let user = state.create_user(user.name, user.email, user.credentials, user.expires_at, user.enrichment);
+        if self.outbox {
+            state.outbox.record(webhooks::USER_CREATED, &user);
+        }
+        Ok(user)
+    }
+
+    // One write lock covers the whole batch, so readers never see part of it
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        transaction(self.name(), "create_many", async {
+            let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+            let created: Vec<User> = users
+                .into_iter()
+                .map(|user| state.create_user(user.name, user.email, user.credentials, user.expires_at, user.enrichment))
+                .collect();
+            if self.outbox {
+                for user in &created {
+                    state.outbox.record(webhooks::USER_CREATED, user);
+                }
+            }
+            Ok(created)
+        })
+        .await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        let updated = state.update_user(id, name, email);
+        if let Some(user) = updated.as_ref().filter(|_| self.outbox) {
+            state.outbox.record(webhooks::USER_UPDATED, user);
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let deleted = {
+            let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+            let deleted = state.delete_user(id);
+            if let Some(user) = deleted.as_ref().filter(|_| self.outbox) {
+                state.outbox.record(webhooks::USER_DELETED, user);
+            }
+            deleted
+        };
+        if deleted.is_some() {
+            self.forget_profiles([id])?;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let expired = {
+            let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+            let expired = state.delete_expired(now);
+            if self.outbox {
+                for user in &expired {
+                    state.outbox.record(webhooks::USER_DELETED, user);
+                }
+            }
+            expired
+        };
+        self.forget_profiles(expired.iter().map(|user| user.id))?;
+        Ok(expired)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.find_by_email(email).cloned())
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(ids.iter().filter_map(|&id| state.users.get(id).cloned()).collect())
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.count_users())
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.user_stats())
+    }
+
+    // Served from AppState's cache; a miss retakes the lock for writing to fill it
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        let cached = self.state.read_measured("app_state").map_err(poisoned)?.domain_stats.clone();
+        if let Some(domains) = cached {
+            crate::telemetry::record_cache_lookup("domain_stats", true);
+            return Ok(domains);
+        }
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        Ok(state.domain_stats())
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.find_duplicates(name, email))
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        let (from, user) = state.transition_user(id, action)?;
+        if self.outbox {
+            state.outbox.record(webhooks::USER_UPDATED, &user);
+        }
+        Ok((from, user))
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        Ok(state.set_credentials(id, credentials))
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.avatars.get(&id).cloned())
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        let touched = state.touch_user(id);
+        if touched.is_some() {
+            state.avatars.insert(id, avatar);
+        }
+        Ok(touched)
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        let profiles = self.profiles.read_measured("profiles").map_err(poisoned)?;
+        Ok(profiles.profiles.get(&id).cloned())
+    }
+
+    // AppState is unlocked before the profile lock is taken, so profile
+    // writes never hold up requests that only touch the user list
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let touched = self.state.write_measured("app_state").map_err(poisoned)?.touch_user(id);
+        if touched.is_some() {
+            self.profiles.write_measured("profiles").map_err(poisoned)?.profiles.insert(id, profile);
+        }
+        Ok(touched)
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        Ok(self.quotas.charge(subject, day, limit))
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.quotas.refund(subject, day);
+        Ok(())
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.outbox
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(state.outbox.pending(limit))
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        let mut state = self.state.write_measured("app_state").map_err(poisoned)?;
+        state.outbox.acknowledge(ids);
+        Ok(())
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let state = self.state.read_measured("app_state").map_err(poisoned)?;
+        Ok(StoreSize { entries: state.users.len() as u64, bytes: Some(state.users.estimated_bytes() as u64) })
+    }
+}
@@ -0,0 +1,109 @@
+use actix_web::dev::ResourceDef;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use tracing::info;
+
+use crate::base_path;
+use crate::error::AppError;
+
+// Every route the app serves and the methods it accepts. actix turns a method
+// mismatch into a 404, so the default service consults this table to answer
+// 405 with an accurate Allow header instead. Keep it in sync with the
+// `.service(...)` registrations in main and admin::scope.
+pub const ROUTES: &[(&str, &[Method])] = &[
+    ("/", &[Method::GET]),
+    ("/healthz", &[Method::GET]),
+    ("/readyz", &[Method::GET]),
+    ("/version", &[Method::GET]),
+    ("/auth/login", &[Method::POST]),
+    ("/auth/refresh", &[Method::POST]),
+    ("/auth/session/login", &[Method::POST]),
+    ("/auth/session/logout", &[Method::POST]),
+    ("/stats/domains", &[Method::GET]),
+    ("/users", &[Method::GET, Method::POST]),
+    ("/users/count", &[Method::GET]),
+    ("/users/stats", &[Method::GET]),
+    ("/users/batch-get", &[Method::POST]),
+    ("/users/bulk", &[Method::POST]),
+    ("/users/by-email/{email}", &[Method::GET]),
+    ("/users/check-duplicates", &[Method::POST]),
+    ("/users/{id}", &[Method::GET, Method::PUT, Method::DELETE]),
+    ("/users/{id}/activate", &[Method::POST]),
+    ("/users/{id}/suspend", &[Method::POST]),
+    ("/users/{id}/change-password", &[Method::POST]),
+    ("/users/{id}/avatar", &[Method::GET, Method::PUT]),
+    ("/users/{id}/profile", &[Method::GET, Method::PUT]),
+    ("/users/{id}/events", &[Method::GET]),
+    ("/ui", &[Method::GET]),
+    ("/ui/{file}*", &[Method::GET]),
+    ("/admin/flush", &[Method::POST]),
+    ("/admin/log-level", &[Method::GET, Method::PUT]),
+    ("/admin/maintenance", &[Method::GET, Method::PUT]),
+    ("/admin/audit", &[Method::GET]),
+    ("/admin/drain", &[Method::POST]),
+    ("/admin/config/reload", &[Method::POST]),
+    ("/admin/export", &[Method::GET]),
+    ("/admin/import", &[Method::POST]),
+    ("/admin/webhooks", &[Method::GET, Method::POST]),
+    ("/admin/webhooks/{id}", &[Method::DELETE]),
+    ("/admin/api-keys", &[Method::GET, Method::POST]),
+    ("/admin/api-keys/{id}", &[Method::DELETE]),
+    ("/admin/telemetry/sampling", &[Method::GET, Method::PUT]),
+    ("/admin/features", &[Method::GET]),
+    ("/admin/features/{name}", &[Method::PUT, Method::DELETE]),
+    ("/admin/store/benchmark", &[Method::POST]),
+    ("/admin/dashboard", &[Method::GET]),
+    ("/admin/jobs", &[Method::GET]),
+    ("/admin/jobs/{name}/run", &[Method::POST]),
+];
+
+// Methods allowed on `path`, across every route pattern that matches it
+fn allowed_methods(path: &str) -> Vec<Method> {
+    let mut allowed: Vec<Method> = Vec::new();
+    for (pattern, methods) in ROUTES {
+        if ResourceDef::new(*pattern).is_match(path) {
+            for method in methods.iter() {
+                if !allowed.contains(method) {
+                    allowed.push(method.clone());
+                }
+            }
+        }
+    }
+    allowed
+}
+
+// Default service for unmatched requests: 405 when the path exists under
+// another method, 404 otherwise
+pub async fn default_handler(req: HttpRequest) -> HttpResponse {
+    let allowed = allowed_methods(req.path());
+    // Messages name the path the client sent, prefix and all
+    let path = base_path::external(&req, req.path());
+    // A known route that wasn't served under its own method isn't mounted on
+    // this listener (e.g. /admin when it runs on a separate port)
+    if allowed.is_empty() || allowed.contains(req.method()) {
+        info!(method = %req.method(), path = %path, "No route matched");
+        return AppError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No resource found at {}", path),
+        )
+        .with("instance", path)
+        .error_response();
+    }
+
+    let allowed_names: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+    let allow = allowed_names.join(", ");
+    info!(method = %req.method(), path = %path, allow = %allow, "Method not allowed");
+
+    let mut response = AppError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        format!("{} is not supported on {}", req.method(), path),
+    )
+    .with("allowed_methods", allowed_names)
+    .error_response();
+    if let Ok(value) = header::HeaderValue::from_str(&allow) {
+        response.headers_mut().insert(header::ALLOW, value);
+    }
+    response
+}
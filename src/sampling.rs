@@ -0,0 +1,172 @@
+use actix_web::{get, put, web, HttpResponse, Responder, ResponseError};
+use opentelemetry::trace::{Link, OrderMap, SamplingResult, SpanKind, TraceId};
+use opentelemetry::{Context, InstrumentationLibrary, Key, Value};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use tracing::instrument;
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::config;
+use crate::error::AppError;
+
+// Sampling for requests to one route, matched against the route pattern the
+// request span is named after, e.g. "/users/{id}"
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteRule {
+    pub route: String,
+    pub ratio: f64,
+}
+
+// Share of new traces to keep, overall and per route. Spans with a parent
+// follow the parent's decision, so a trace is never half-sampled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SamplingPolicy {
+    pub ratio: f64,
+    #[serde(default)]
+    pub rules: Vec<RouteRule>,
+}
+
+impl SamplingPolicy {
+    // telemetry.sampling_ratio (TRACE_SAMPLING_RATIO) sets the starting
+    // ratio; everything is sampled by default
+    pub fn from_env() -> Self {
+        SamplingPolicy {
+            ratio: config::settings().telemetry.sampling_ratio,
+            rules: Vec::new(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        let valid = |ratio: f64| (0.0..=1.0).contains(&ratio);
+        if !valid(self.ratio) {
+            return Err(AppError::bad_request("invalid_ratio", "ratio must be between 0 and 1").with("field", "ratio"));
+        }
+        if let Some(rule) = self.rules.iter().find(|rule| !valid(rule.ratio)) {
+            return Err(AppError::bad_request("invalid_ratio", "rule ratios must be between 0 and 1")
+                .with("field", "rules")
+                .with("route", rule.route.clone()));
+        }
+        Ok(())
+    }
+}
+
+fn ratio_sampler(ratio: f64) -> Sampler {
+    Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+}
+
+struct ActiveSampling {
+    policy: SamplingPolicy,
+    default: Sampler,
+    routes: Vec<(String, Sampler)>,
+}
+
+impl ActiveSampling {
+    fn new(policy: SamplingPolicy) -> Self {
+        ActiveSampling {
+            default: ratio_sampler(policy.ratio),
+            routes: policy
+                .rules
+                .iter()
+                .map(|rule| (rule.route.clone(), ratio_sampler(rule.ratio)))
+                .collect(),
+            policy,
+        }
+    }
+}
+
+// Sampler installed on the TracerProvider once at startup. The SDK doesn't
+// allow swapping samplers on a live provider, so this one delegates to a
+// policy that PUT /admin/telemetry/sampling replaces.
+#[derive(Clone)]
+pub struct ReloadableSampler {
+    active: Arc<RwLock<ActiveSampling>>,
+}
+
+impl ReloadableSampler {
+    pub fn new(policy: SamplingPolicy) -> Self {
+        ReloadableSampler {
+            active: Arc::new(RwLock::new(ActiveSampling::new(policy))),
+        }
+    }
+
+    pub fn policy(&self) -> SamplingPolicy {
+        self.active
+            .read()
+            .map(|active| active.policy.clone())
+            .unwrap_or_else(|poisoned| poisoned.into_inner().policy.clone())
+    }
+
+    pub fn replace(&self, policy: SamplingPolicy) {
+        let mut active = self.active.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *active = ActiveSampling::new(policy);
+    }
+}
+
+impl std::fmt::Debug for ReloadableSampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadableSampler").field("policy", &self.policy()).finish()
+    }
+}
+
+impl ShouldSample for ReloadableSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &OrderMap<Key, Value>,
+        links: &[Link],
+        instrumentation_library: &InstrumentationLibrary,
+    ) -> SamplingResult {
+        let active = self.active.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let sampler = active
+            .routes
+            .iter()
+            .find(|(route, _)| route == name)
+            .map_or(&active.default, |(_, sampler)| sampler);
+        sampler.should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+            instrumentation_library,
+        )
+    }
+}
+
+// Handler for GET /admin/telemetry/sampling
+#[get("/telemetry/sampling")]
+async fn get_sampling(sampler: web::Data<ReloadableSampler>) -> impl Responder {
+    HttpResponse::Ok().json(sampler.policy())
+}
+
+// Handler for PUT /admin/telemetry/sampling
+#[put("/telemetry/sampling")]
+#[instrument(name = "admin_set_sampling_handler", skip_all, fields(service = "actix_example"))]
+async fn set_sampling(
+    identity: web::ReqData<AdminIdentity>,
+    body: web::Json<SamplingPolicy>,
+    sampler: web::Data<ReloadableSampler>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let policy = body.into_inner();
+    if let Err(app_error) = policy.validate() {
+        return app_error.error_response();
+    }
+
+    sampler.replace(policy.clone());
+    audit.record(
+        &identity.0,
+        "set_sampling",
+        format!("ratio={} rules={}", policy.ratio, policy.rules.len()),
+    );
+    HttpResponse::Ok().json(policy)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_sampling).service(set_sampling);
+}
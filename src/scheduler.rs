@@ -0,0 +1,204 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::config;
+use crate::error::AppError;
+use crate::telemetry::record_job_run;
+
+type JobFn = Box<dyn Fn() -> LocalBoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+// What the last run of a job did, for GET /admin/jobs
+#[derive(Clone, Default, Serialize)]
+struct JobStatus {
+    last_started_at: Option<DateTime<Utc>>,
+    last_duration_ms: Option<f64>,
+    last_error: Option<String>,
+    runs: u64,
+    failures: u64,
+}
+
+struct Job {
+    name: &'static str,
+    expression: String,
+    schedule: Schedule,
+    run: JobFn,
+    // Set for the length of a run, so runs of one job never overlap
+    running: AtomicBool,
+    status: Mutex<JobStatus>,
+}
+
+impl Job {
+    fn try_start(&self) -> bool {
+        self.running.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    // One run, started by try_start. Each run is the root of its own trace.
+    async fn execute(self: Arc<Self>, trigger: &'static str) {
+        let span = info_span!(
+            parent: None,
+            "scheduled_job",
+            otel.name = %format!("job {}", self.name),
+            otel.status_code = Empty,
+            job.name = self.name,
+            job.trigger = trigger,
+        );
+        let started_at = Utc::now();
+        let started = Instant::now();
+        let result = (self.run)().instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+        record_job_run(self.name, result.is_ok(), elapsed);
+
+        let mut status = self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.last_started_at = Some(started_at);
+        status.last_duration_ms = Some(elapsed.as_secs_f64() * 1000.0);
+        status.runs += 1;
+        match result {
+            Ok(()) => {
+                info!(parent: &span, elapsed_ms = elapsed.as_millis() as u64, "Job finished");
+                status.last_error = None;
+            }
+            Err(err) => {
+                span.record("otel.status_code", "ERROR");
+                warn!(parent: &span, error = %err, "Job failed");
+                status.last_error = Some(err);
+                status.failures += 1;
+            }
+        }
+        drop(status);
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+// Background jobs run on cron schedules (see config::SchedulerSettings).
+// Jobs are registered during startup and run once phase 3 starts `run`; the
+// admin API lists them and runs them on demand.
+pub struct Scheduler {
+    jobs: Vec<Arc<Job>>,
+}
+
+#[derive(Serialize)]
+struct JobInfo {
+    name: &'static str,
+    schedule: String,
+    next_run_at: Option<DateTime<Utc>>,
+    running: bool,
+    #[serde(flatten)]
+    status: JobStatus,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { jobs: Vec::new() }
+    }
+
+    // Schedule `job` by `expression`; an empty expression or "off" leaves it
+    // out. Expressions are checked by SchedulerSettings::validate at startup.
+    pub fn register<F, Fut>(&mut self, name: &'static str, expression: &str, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + 'static,
+    {
+        if config::job_disabled(expression) {
+            info!(job = name, "Job disabled");
+            return;
+        }
+        let schedule = match Schedule::from_str(expression) {
+            Ok(schedule) => schedule,
+            Err(err) => {
+                warn!(job = name, schedule = expression, error = %err, "Invalid job schedule; job disabled");
+                return;
+            }
+        };
+        info!(job = name, schedule = expression, "Job scheduled");
+        self.jobs.push(Arc::new(Job {
+            name,
+            expression: expression.to_string(),
+            schedule,
+            run: Box::new(move || Box::pin(job())),
+            running: AtomicBool::new(false),
+            status: Mutex::new(JobStatus::default()),
+        }));
+    }
+
+    fn job(&self, name: &str) -> Option<&Arc<Job>> {
+        self.jobs.iter().find(|job| job.name == name)
+    }
+
+    fn list(&self) -> Vec<JobInfo> {
+        self.jobs
+            .iter()
+            .map(|job| JobInfo {
+                name: job.name,
+                schedule: job.expression.clone(),
+                next_run_at: job.schedule.upcoming(Utc).next(),
+                running: job.running.load(Ordering::Acquire),
+                status: job.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone(),
+            })
+            .collect()
+    }
+}
+
+// Background task running every job on its schedule, started in phase 3
+pub async fn run(scheduler: web::Data<Scheduler>) {
+    for job in scheduler.jobs.iter().cloned() {
+        actix_web::rt::spawn(run_on_schedule(job));
+    }
+}
+
+async fn run_on_schedule(job: Arc<Job>) {
+    while let Some(next) = job.schedule.upcoming(Utc).next() {
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        actix_web::rt::time::sleep(wait).await;
+        if !job.try_start() {
+            info!(job = job.name, "Job still running; skipping this run");
+            continue;
+        }
+        job.clone().execute("schedule").await;
+    }
+}
+
+// Handler for GET /admin/jobs
+#[get("/jobs")]
+#[instrument(name = "admin_list_jobs_handler", skip_all, fields(service = "actix_example"))]
+async fn list_jobs(scheduler: web::Data<Scheduler>) -> impl Responder {
+    HttpResponse::Ok().json(scheduler.list())
+}
+
+// Handler for POST /admin/jobs/{name}/run: start a run now, unless one is
+// already going. The run happens in the background; GET /admin/jobs shows
+// how it went.
+#[post("/jobs/{name}/run")]
+#[instrument(name = "admin_run_job_handler", skip_all, fields(service = "actix_example", job.name = %name))]
+async fn run_job(
+    name: web::Path<String>,
+    identity: web::ReqData<AdminIdentity>,
+    scheduler: web::Data<Scheduler>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let Some(job) = scheduler.job(&name) else {
+        return AppError::new(StatusCode::NOT_FOUND, "not_found", format!("Job '{}' not found", name)).error_response();
+    };
+    if !job.try_start() {
+        return AppError::new(StatusCode::CONFLICT, "job_running", format!("Job '{}' is already running", name))
+            .error_response();
+    }
+    audit.record(&identity.0, "run_job", name.as_str());
+    actix_web::rt::spawn(job.clone().execute("admin"));
+    HttpResponse::Accepted().json(serde_json::json!({ "job": job.name, "status": "started" }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_jobs).service(run_job);
+}
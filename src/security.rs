@@ -0,0 +1,43 @@
+use actix_web::http::header;
+use actix_web::middleware::DefaultHeaders;
+
+use crate::get_env_or_default;
+
+// Strict default for a JSON API: no page may load anything from us or frame us
+const DEFAULT_CSP: &str = "default-src 'none'; frame-ancestors 'none'; base-uri 'none'; form-action 'none'";
+
+// Security headers added to every response that doesn't already set them.
+// CONTENT_SECURITY_POLICY overrides the policy (empty disables it) and
+// HSTS_MAX_AGE_SECS=0 drops Strict-Transport-Security.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    pub hsts_max_age: u64,
+}
+
+impl SecurityHeaders {
+    pub fn from_env() -> Self {
+        SecurityHeaders {
+            content_security_policy: get_env_or_default("CONTENT_SECURITY_POLICY", DEFAULT_CSP),
+            hsts_max_age: get_env_or_default("HSTS_MAX_AGE_SECS", "31536000").parse().unwrap_or(31_536_000),
+        }
+    }
+
+    // Build the middleware; called once per worker
+    pub fn middleware(&self) -> DefaultHeaders {
+        let mut headers = DefaultHeaders::new()
+            .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .add((header::X_FRAME_OPTIONS, "DENY"))
+            .add((header::REFERRER_POLICY, "no-referrer"));
+        if self.hsts_max_age > 0 {
+            headers = headers.add((
+                header::STRICT_TRANSPORT_SECURITY,
+                format!("max-age={}; includeSubDomains", self.hsts_max_age),
+            ));
+        }
+        if !self.content_security_policy.is_empty() {
+            headers = headers.add((header::CONTENT_SECURITY_POLICY, self.content_security_policy.clone()));
+        }
+        headers
+    }
+}
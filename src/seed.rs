@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::auth::{self, Credentials};
+use crate::repository::{NewUser, SharedUserRepository};
+
+// One user in a fixtures file; users without a password can't log in
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeedUser {
+    name: String,
+    email: String,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeedFile {
+    users: Vec<SeedUser>,
+}
+
+// Users created at startup from SEED_FILE, e.g. fixtures/users.yaml. Seeds
+// whose email is already taken are skipped, so restarting against a
+// persistent backend doesn't create them twice.
+pub struct Seeds {
+    path: PathBuf,
+    users: Vec<SeedUser>,
+}
+
+impl Seeds {
+    // None when SEED_FILE is unset or seeding is disabled with `--no-seed`.
+    // Errors name the file and, where possible, the offending entry.
+    pub fn from_env(disabled: bool) -> Result<Option<Self>, String> {
+        let Some(path) = std::env::var("SEED_FILE").ok().filter(|path| !path.is_empty()) else {
+            return Ok(None);
+        };
+        if disabled {
+            info!(path = %path, "Seeding disabled by --no-seed");
+            return Ok(None);
+        }
+        let path = PathBuf::from(path);
+        Seeds::load(&path)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // JSON, or YAML for files ending in .yaml or .yml
+    fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+        let yaml = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("yaml") || extension.eq_ignore_ascii_case("yml"));
+        let file: SeedFile = if yaml {
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_slice(&bytes)).map_err(located)?
+        } else {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&bytes)).map_err(located)?
+        };
+        let seeds = Seeds { path: path.to_path_buf(), users: file.users };
+        seeds.validate()?;
+        Ok(seeds)
+    }
+
+    // Every problem in the file at once, so fixing it takes one round trip
+    fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        let mut emails = HashSet::new();
+        for (index, user) in self.users.iter().enumerate() {
+            if user.name.trim().is_empty() {
+                problems.push(format!("users[{}].name: must not be empty", index));
+            }
+            let valid_email = user
+                .email
+                .split_once('@')
+                .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty() && !domain.contains('@'));
+            if !valid_email {
+                problems.push(format!("users[{}].email: '{}' is not an email address", index, user.email));
+            } else if !emails.insert(user.email.to_lowercase()) {
+                problems.push(format!("users[{}].email: {} appears more than once", index, user.email));
+            }
+            if let Some(Err(err)) = user.password.as_deref().map(auth::validate_password) {
+                problems.push(format!("users[{}].password: {}", index, err));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Create the seeds through the repository, returning how many were new
+    pub async fn apply(self, repository: &SharedUserRepository) -> Result<usize, String> {
+        let mut created = 0;
+        for seed in self.users {
+            if repository.find_by_email(&seed.email).await.map_err(|err| err.to_string())?.is_some() {
+                continue;
+            }
+            let credentials = match seed.password {
+                Some(password) => Some(Credentials::from_password(password).await?),
+                None => None,
+            };
+            repository
+                .create(NewUser { name: seed.name, email: seed.email, credentials, expires_at: None, enrichment: None })
+                .await
+                .map_err(|err| err.to_string())?;
+            created += 1;
+        }
+        Ok(created)
+    }
+}
+
+// A parse error prefixed with the path of the field it concerns
+fn located<E: std::fmt::Display>(err: serde_path_to_error::Error<E>) -> String {
+    let path = err.path().to_string();
+    if path == "." {
+        err.into_inner().to_string()
+    } else {
+        format!("{}: {}", path, err.into_inner())
+    }
+}
@@ -0,0 +1,220 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::admin::constant_time_eq;
+use crate::auth::{sign_in, LoginRequest};
+use crate::config;
+use crate::csrf;
+use crate::locks::MeasuredLock;
+use crate::repository::SharedUserRepository;
+use crate::{get_env_or_default, AppState, UserId};
+
+pub const COOKIE_NAME: &str = "session";
+
+// Cookie signing and lifetime settings for browser sessions
+pub struct SessionConfig {
+    secret: Vec<u8>,
+    ttl: Duration,
+    secure: bool,
+    // The base path, so the cookies go only to the API's own paths
+    path: String,
+}
+
+impl SessionConfig {
+    // SESSION_SECRET signs the cookies; without it a random key is used, so
+    // sessions end when the process restarts (they live in memory anyway)
+    pub fn from_env() -> Self {
+        let secret = match std::env::var("SESSION_SECRET").ok().filter(|secret| !secret.is_empty()) {
+            Some(secret) => secret.into_bytes(),
+            None => {
+                warn!("No SESSION_SECRET set; using a random per-process cookie signing key");
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        SessionConfig {
+            secret,
+            ttl: Duration::seconds(get_env_or_default("SESSION_TTL_SECS", "86400").parse().unwrap_or(86_400)),
+            // Browsers drop Secure cookies over plain http, so this is opt-in for the demo
+            secure: get_env_or_default("SESSION_COOKIE_SECURE", "false") == "true",
+            path: config::settings().server.mount_point().to_string(),
+        }
+    }
+
+    fn mac(&self, context: &[u8], session_id: Uuid) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(context);
+        mac.update(session_id.as_bytes());
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn signature(&self, session_id: Uuid) -> String {
+        self.mac(b"session:", session_id)
+    }
+
+    // CSRF token bound to a session, so a token planted by another site or
+    // left over from an earlier session doesn't verify
+    pub fn csrf_token(&self, session_id: Uuid) -> String {
+        self.mac(b"csrf:", session_id)
+    }
+
+    // Cookie value "<session id>.<hex HMAC-SHA256 of the id>"
+    fn sign(&self, session_id: Uuid) -> String {
+        format!("{}.{}", session_id, self.signature(session_id))
+    }
+
+    fn unsign(&self, value: &str) -> Option<Uuid> {
+        let (id, signature) = value.split_once('.')?;
+        let id = id.parse::<Uuid>().ok()?;
+        constant_time_eq(self.signature(id).as_bytes(), signature.as_bytes()).then_some(id)
+    }
+
+    fn cookie(&self, session_id: Uuid) -> Cookie<'static> {
+        Cookie::build(COOKIE_NAME, self.sign(session_id))
+            .path(self.path.clone())
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.ttl.num_seconds()))
+            .finish()
+    }
+
+    // Readable by page scripts, which echo it back in the X-CSRF-Token header
+    fn csrf_cookie(&self, session_id: Uuid) -> Cookie<'static> {
+        Cookie::build(csrf::COOKIE_NAME, self.csrf_token(session_id))
+            .path(self.path.clone())
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.ttl.num_seconds()))
+            .finish()
+    }
+}
+
+struct Session {
+    user_id: UserId,
+    expires_at: DateTime<Utc>,
+}
+
+// Server-side session state, held in AppState so logout and account changes
+// can end sessions immediately
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: HashMap<Uuid, Session>,
+}
+
+impl SessionStore {
+    fn create(&mut self, user_id: UserId, ttl: Duration) -> (Uuid, DateTime<Utc>) {
+        let id = Uuid::now_v7();
+        let expires_at = Utc::now() + ttl;
+        self.sessions.insert(id, Session { user_id, expires_at });
+        (id, expires_at)
+    }
+
+    // The user a live session belongs to. Expired sessions are ignored here
+    // and dropped by the periodic purge, so lookups only need a read lock.
+    fn resolve(&self, id: Uuid) -> Option<UserId> {
+        let session = self.sessions.get(&id)?;
+        (session.expires_at > Utc::now()).then_some(session.user_id)
+    }
+
+    fn remove(&mut self, id: Uuid) -> bool {
+        self.sessions.remove(&id).is_some()
+    }
+
+    // Drop sessions past their expiry, returning how many there were
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Utc::now();
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| session.expires_at > now);
+        before - self.sessions.len()
+    }
+
+    // End every session of a user, e.g. when the account is deleted or suspended
+    pub fn remove_user(&mut self, user_id: UserId) {
+        self.sessions.retain(|_, session| session.user_id != user_id);
+    }
+}
+
+// The live session named by the request's cookie, as (session ID, user ID)
+pub fn session_from_request(req: &ServiceRequest) -> Option<(Uuid, UserId)> {
+    let config = req.app_data::<web::Data<SessionConfig>>()?;
+    let session_id = config.unsign(req.cookie(COOKIE_NAME)?.value())?;
+    let data = req.app_data::<web::Data<RwLock<AppState>>>()?;
+    let user_id = data.read_measured("app_state").ok()?.sessions.resolve(session_id)?;
+    Some((session_id, user_id))
+}
+
+#[derive(Serialize)]
+struct SessionCreated {
+    user_id: UserId,
+    expires_at: DateTime<Utc>,
+}
+
+// Handler for POST /auth/session/login
+#[post("/auth/session/login")]
+#[instrument(name = "session_login_handler", skip_all, fields(service = "actix_example"))]
+async fn login(
+    body: web::Json<LoginRequest>,
+    data: web::Data<RwLock<AppState>>,
+    repository: SharedUserRepository,
+    config: web::Data<SessionConfig>,
+) -> impl Responder {
+    info!("Session login attempt");
+
+    let user = match sign_in(&repository, &body.email, &body.password).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let (session_id, expires_at) = match data.write_measured("app_state") {
+        Ok(mut state) => state.sessions.create(user.id, config.ttl),
+        Err(_) => {
+            info!("Failed to lock application state");
+            return HttpResponse::InternalServerError().body("Failed to lock application state");
+        }
+    };
+
+    info!(user_id = %user.id, session.id = %session_id, "Session started");
+    HttpResponse::Ok()
+        .cookie(config.cookie(session_id))
+        .cookie(config.csrf_cookie(session_id))
+        .json(SessionCreated { user_id: user.id, expires_at })
+}
+
+// Handler for POST /auth/session/logout
+#[post("/auth/session/logout")]
+#[instrument(name = "session_logout_handler", skip_all, fields(service = "actix_example"))]
+async fn logout(
+    req: HttpRequest,
+    data: web::Data<RwLock<AppState>>,
+    config: web::Data<SessionConfig>,
+) -> impl Responder {
+    let session_id = req.cookie(COOKIE_NAME).and_then(|cookie| config.unsign(cookie.value()));
+    if let Some(session_id) = session_id {
+        match data.write_measured("app_state") {
+            Ok(mut state) => {
+                if state.sessions.remove(session_id) {
+                    info!(session.id = %session_id, "Session ended");
+                }
+            }
+            Err(_) => warn!("Failed to lock application state; session left to expire"),
+        }
+    }
+
+    // Always clear the cookies, even when the session was already gone
+    let mut removal = config.cookie(Uuid::nil());
+    removal.make_removal();
+    let mut csrf_removal = config.csrf_cookie(Uuid::nil());
+    csrf_removal.make_removal();
+    HttpResponse::NoContent().cookie(removal).cookie(csrf_removal).finish()
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use dashmap::DashMap;
+use std::sync::Arc;
+
+use crate::auth::Credentials;
+use crate::query::ListQuery;
+use crate::quota::QuotaStore;
+use crate::repository::{transaction, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::store::ShardedUserStore;
+use crate::telemetry::observe_shards;
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+fn poisoned<T>(_: T) -> RepositoryError {
+    RepositoryError::Storage("user shard lock is poisoned".to_string())
+}
+
+// Users in memory, split over STORE_SHARDS locks (STORAGE_BACKEND=sharded)
+// so concurrent writes to different users don't queue behind one another.
+// Lookups and changes by ID lock one shard; listings, email lookups and the
+// expiry sweep visit every shard in turn. Avatars and profiles are changed
+// under their user's shard lock, so they can't outlive a concurrent delete.
+pub struct ShardedUserRepository {
+    store: Arc<ShardedUserStore>,
+    avatars: DashMap<UserId, Avatar>,
+    profiles: DashMap<UserId, Profile>,
+    quotas: QuotaStore,
+}
+
+impl ShardedUserRepository {
+    pub fn new(shards: usize, tenant: &str) -> Self {
+        let store = Arc::new(ShardedUserStore::new(shards));
+        let observed = store.clone();
+        observe_shards(tenant, move || observed.occupancy());
+        ShardedUserRepository { store, avatars: DashMap::new(), profiles: DashMap::new(), quotas: QuotaStore::default() }
+    }
+
+    // Every user, in ID order (creation order, as IDs are UUIDv7)
+    fn all(&self) -> Result<Vec<User>, RepositoryError> {
+        let mut users = Vec::new();
+        for shard in self.store.shards() {
+            users.extend(shard.read().map_err(poisoned)?.iter().cloned());
+        }
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    fn forget(&self, id: UserId) {
+        self.avatars.remove(&id);
+        self.profiles.remove(&id);
+    }
+
+    // Bump a user's updated_at and, if it exists, store `change` alongside
+    fn touch(&self, id: UserId, change: impl FnOnce()) -> Result<Option<User>, RepositoryError> {
+        let mut shard = self.store.write(id).map_err(poisoned)?;
+        let Some(user) = shard.get_mut(id) else {
+            return Ok(None);
+        };
+        user.updated_at = Utc::now();
+        change();
+        Ok(Some(user.clone()))
+    }
+}
+
+#[async_trait]
+impl UserRepository for ShardedUserRepository {
+    fn name(&self) -> &'static str {
+        "sharded"
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let shard = self.store.read(id).map_err(poisoned)?;
+        Ok(shard.get(id).cloned())
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        Ok(query.apply(self.all()?.iter()))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let user = user.into_user();
+        self.store.write(user.id).map_err(poisoned)?.insert(user.clone());
+        Ok(user)
+    }
+
+    // Shards are locked one at a time, so a failure part way removes the
+    // users already inserted rather than leaving half a batch behind
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        transaction(self.name(), "create_many", async {
+            let users: Vec<User> = users.into_iter().map(NewUser::into_user).collect();
+            for (index, user) in users.iter().enumerate() {
+                if let Err(err) = self.store.write(user.id).map(|mut shard| shard.insert(user.clone())) {
+                    for created in &users[..index] {
+                        if let Ok(mut shard) = self.store.write(created.id) {
+                            shard.remove(created.id);
+                        }
+                    }
+                    return Err(poisoned(err));
+                }
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let mut shard = self.store.write(id).map_err(poisoned)?;
+        let Some(user) = shard.get_mut(id) else {
+            return Ok(None);
+        };
+        user.name = name;
+        user.email = email;
+        user.updated_at = Utc::now();
+        Ok(Some(user.clone()))
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let mut shard = self.store.write(id).map_err(poisoned)?;
+        let deleted = shard.remove(id);
+        self.forget(id);
+        Ok(deleted)
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let mut expired = Vec::new();
+        for shard in self.store.shards() {
+            let mut shard = shard.write().map_err(poisoned)?;
+            let ids: Vec<UserId> = shard
+                .iter()
+                .filter(|user| user.expires_at.is_some_and(|expires_at| expires_at <= now))
+                .map(|user| user.id)
+                .collect();
+            for id in ids {
+                expired.extend(shard.remove(id));
+                self.forget(id);
+            }
+        }
+        Ok(expired)
+    }
+
+    // The earliest-created user with this address, matching the in-memory index
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let mut found: Option<User> = None;
+        for shard in self.store.shards() {
+            let shard = shard.read().map_err(poisoned)?;
+            let candidate = shard.iter().find(|user| user.email.eq_ignore_ascii_case(email)).cloned();
+            if let Some(user) = candidate.filter(|user| found.as_ref().is_none_or(|found| user.id < found.id)) {
+                found = Some(user);
+            }
+        }
+        Ok(found)
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        let mut users = Vec::with_capacity(ids.len());
+        for &id in ids {
+            users.extend(self.store.read(id).map_err(poisoned)?.get(id).cloned());
+        }
+        Ok(users)
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        let mut count = 0;
+        for shard in self.store.shards() {
+            count += shard.read().map_err(poisoned)?.len();
+        }
+        Ok(count)
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let users = self.all()?;
+        Ok(UserStats::tally(users.iter().map(|user| (user.email.as_str(), user.created_at))))
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        Ok(DuplicateMatch::among(&self.all()?, name, email))
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let mut shard = self.store.write(id).map_err(poisoned)?;
+        let user = shard.get_mut(id).ok_or(TransitionError::NotFound)?;
+        let from = user.status;
+        user.status = from.apply(action).ok_or(TransitionError::Illegal(from))?;
+        user.updated_at = Utc::now();
+        Ok((from, user.clone()))
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let mut shard = self.store.write(id).map_err(poisoned)?;
+        let Some(user) = shard.get_mut(id) else {
+            return Ok(None);
+        };
+        user.credentials = Some(credentials);
+        user.updated_at = Utc::now();
+        Ok(Some(user.clone()))
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        Ok(self.avatars.get(&id).map(|avatar| avatar.clone()))
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        self.touch(id, || {
+            self.avatars.insert(id, avatar);
+        })
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        Ok(self.profiles.get(&id).map(|profile| profile.clone()))
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        self.touch(id, || {
+            self.profiles.insert(id, profile);
+        })
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        Ok(self.quotas.charge(subject, day, limit))
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.quotas.refund(subject, day);
+        Ok(())
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let mut size = StoreSize { entries: 0, bytes: Some(0) };
+        for shard in self.store.shards() {
+            let shard = shard.read().map_err(poisoned)?;
+            size.entries += shard.len() as u64;
+            size.bytes = size.bytes.map(|bytes| bytes + shard.estimated_bytes() as u64);
+        }
+        Ok(size)
+    }
+}
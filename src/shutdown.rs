@@ -0,0 +1,40 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+type Hook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>>>;
+
+// Work to do once the servers have stopped. Subsystems register a hook as
+// they start, and the hooks run in reverse: whatever started last, and may
+// depend on what started before it, stops first. Telemetry registers first so
+// it flushes after everything else has logged its shutdown. A hook that
+// overruns its timeout is abandoned and the next one runs.
+pub struct ShutdownCoordinator {
+    hooks: Vec<(&'static str, Duration, Hook)>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        ShutdownCoordinator { hooks: Vec::new() }
+    }
+
+    pub fn register<F, Fut>(&mut self, name: &'static str, timeout: Duration, hook: F)
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.hooks.push((name, timeout, Box::new(move || Box::pin(hook()))));
+    }
+
+    pub async fn run(self) {
+        info!(hooks = self.hooks.len(), "Running shutdown hooks");
+        for (name, timeout, hook) in self.hooks.into_iter().rev() {
+            let started = Instant::now();
+            match actix_web::rt::time::timeout(timeout, hook()).await {
+                Ok(()) => info!(hook = name, elapsed_ms = started.elapsed().as_millis() as u64, "Shutdown hook finished"),
+                Err(_) => warn!(hook = name, timeout_secs = timeout.as_secs(), "Shutdown hook timed out; moving on"),
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::pool::PoolConnection;
+use sqlx::{ColumnIndex, Connection, Database, Decode, Encode, Pool, QueryBuilder, Row, Type};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::outbox::OutboxEntry;
+use crate::query::{ListQuery, SortField};
+use crate::repository::{transaction, RepositoryError};
+use crate::telemetry::{observe_pool, record_pool_acquire};
+use crate::{config, Avatar, Profile, User, UserStatus};
+
+// Schema changes shared by the SQL backends, embedded from migrations/ at
+// build time and tracked in the _sqlx_migrations table
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub const COLUMNS: &str = "id, name, email, status, created_at, updated_at, expires_at, password_hash, enrichment";
+
+// Export the pool's size and idle count as gauges
+pub fn observe<DB: Database>(system: &'static str, pool: &Pool<DB>) {
+    let pool = pool.clone();
+    observe_pool(system, move || (pool.size() as u64, pool.num_idle() as u64));
+}
+
+// Take a connection from the pool in its own span, so time spent waiting on
+// an exhausted pool shows up in traces ahead of the queries
+pub async fn acquire<DB: Database>(system: &'static str, pool: &Pool<DB>) -> Result<PoolConnection<DB>, RepositoryError> {
+    let span = info_span!(
+        "db.connection.acquire",
+        otel.status_code = Empty,
+        db.system = system,
+        db.pool.size = pool.size(),
+        db.pool.idle = pool.num_idle() as u64,
+        db.pool.wait_ms = Empty,
+    );
+    let started = Instant::now();
+    let result = pool.acquire().instrument(span.clone()).await;
+    let wait = started.elapsed();
+    span.record("db.pool.wait_ms", wait.as_secs_f64() * 1000.0);
+    record_pool_acquire(system, wait, matches!(result, Err(sqlx::Error::PoolTimedOut)));
+    result.map_err(|err| {
+        span.record("otel.status_code", "ERROR");
+        warn!(parent: &span, error = %err, "Failed to acquire a database connection");
+        RepositoryError::Storage(err.to_string())
+    })
+}
+
+// DATABASE_AUTO_MIGRATE=false leaves migrations to `--migrate`, e.g. a
+// deploy job, and only checks the schema at startup
+pub fn auto_migrate() -> bool {
+    config::settings().storage.database_auto_migrate
+}
+
+fn migration_error(err: MigrateError) -> RepositoryError {
+    RepositoryError::Storage(format!("migration failed: {}", err))
+}
+
+// Bring the schema up to date, or with `apply` false only check that it is:
+// pending migrations are then an error, so readiness fails until someone
+// runs them (e.g. with --migrate). Each applied migration gets a
+// `db.migration` span. Returns how many migrations were applied.
+pub async fn migrate<DB>(system: &'static str, pool: &Pool<DB>, apply: bool) -> Result<usize, RepositoryError>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut connection = acquire(system, pool).await?;
+    // Serializes replicas starting at the same time
+    connection.lock().await.map_err(migration_error)?;
+    let result = run_migrations(system, &mut *connection, apply).await;
+    connection.unlock().await.map_err(migration_error)?;
+    result
+}
+
+async fn run_migrations<C: Migrate>(system: &'static str, connection: &mut C, apply: bool) -> Result<usize, RepositoryError> {
+    connection.ensure_migrations_table().await.map_err(migration_error)?;
+    if let Some(version) = connection.dirty_version().await.map_err(migration_error)? {
+        return Err(migration_error(MigrateError::Dirty(version)));
+    }
+    let applied: HashMap<i64, _> = connection
+        .list_applied_migrations()
+        .await
+        .map_err(migration_error)?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect();
+
+    let mut pending = Vec::new();
+    for migration in MIGRATOR.iter().filter(|migration| !migration.migration_type.is_down_migration()) {
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != migration.checksum => {
+                return Err(migration_error(MigrateError::VersionMismatch(migration.version)));
+            }
+            Some(_) => {}
+            None => pending.push(migration),
+        }
+    }
+    if pending.is_empty() {
+        info!(db.system = system, applied = applied.len(), "Database schema is up to date");
+        return Ok(0);
+    }
+    if !apply {
+        let versions: Vec<String> = pending.iter().map(|migration| migration.version.to_string()).collect();
+        return Err(RepositoryError::Storage(format!(
+            "{} pending migration(s): {}",
+            pending.len(),
+            versions.join(", ")
+        )));
+    }
+
+    for migration in &pending {
+        let span = info_span!(
+            "db.migration",
+            otel.status_code = Empty,
+            db.system = system,
+            db.migration.version = migration.version,
+            db.migration.description = %migration.description,
+            db.duration_ms = Empty,
+        );
+        match connection.apply(migration).instrument(span.clone()).await {
+            Ok(elapsed) => {
+                span.record("db.duration_ms", elapsed.as_secs_f64() * 1000.0);
+                info!(
+                    parent: &span,
+                    version = migration.version,
+                    description = %migration.description,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    "Applied migration"
+                );
+            }
+            Err(err) => {
+                span.record("otel.status_code", "ERROR");
+                warn!(parent: &span, error = %err, "Migration failed");
+                return Err(migration_error(err));
+            }
+        }
+    }
+    Ok(pending.len())
+}
+
+// Run one statement on the users table in a `db.query` client span
+// carrying OpenTelemetry's database attributes, recording how long it took
+// and marking the span as failed when the database returns an error
+pub async fn traced<T>(
+    system: &'static str,
+    operation: &'static str,
+    statement: &str,
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, RepositoryError> {
+    traced_on(system, operation, "users", statement, query).await
+}
+
+// The same for a statement on another table
+pub async fn traced_on<T>(
+    system: &'static str,
+    operation: &'static str,
+    table: &'static str,
+    statement: &str,
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, RepositoryError> {
+    let span = info_span!(
+        "db.query",
+        otel.name = %format!("{} {}", operation, table),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        db.system = system,
+        db.operation = operation,
+        db.sql.table = table,
+        db.statement = %statement,
+        db.duration_ms = Empty,
+    );
+    let started = Instant::now();
+    let result = query.instrument(span.clone()).await;
+    span.record("db.duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+    result.map_err(|err| {
+        span.record("otel.status_code", "ERROR");
+        warn!(parent: &span, error = %err, "Database query failed");
+        RepositoryError::Storage(err.to_string())
+    })
+}
+
+// Run `body` on a connection from `pool`, inside a transaction when
+// `atomic`: committed when `body` succeeds and rolled back when it fails
+pub async fn atomically<DB, T>(
+    system: &'static str,
+    operation: &'static str,
+    pool: &Pool<DB>,
+    atomic: bool,
+    body: impl AsyncFnOnce(&mut DB::Connection) -> Result<T, RepositoryError>,
+) -> Result<T, RepositoryError>
+where
+    DB: Database,
+{
+    let mut connection = acquire(system, pool).await?;
+    if !atomic {
+        return body(&mut *connection).await;
+    }
+    transaction(system, operation, async {
+        let mut tx = traced(system, "BEGIN", "BEGIN", (*connection).begin()).await?;
+        match body(&mut *tx).await {
+            Ok(value) => {
+                traced(system, "COMMIT", "COMMIT", tx.commit()).await?;
+                Ok(value)
+            }
+            Err(err) => {
+                traced(system, "ROLLBACK", "ROLLBACK", tx.rollback()).await?;
+                Err(err)
+            }
+        }
+    })
+    .await
+}
+
+pub fn user_from_row<'r, R>(row: &'r R) -> Result<User, sqlx::Error>
+where
+    R: Row,
+    &'r str: ColumnIndex<R>,
+    Uuid: Decode<'r, R::Database> + Type<R::Database>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+    DateTime<Utc>: Decode<'r, R::Database> + Type<R::Database>,
+{
+    let status: String = row.try_get("status")?;
+    let status = UserStatus::from_name(&status)
+        .ok_or_else(|| sqlx::Error::Decode(format!("unknown user status '{}'", status).into()))?;
+    let password_hash: Option<String> = row.try_get("password_hash")?;
+    let enrichment: Option<String> = row.try_get("enrichment")?;
+    let enrichment = enrichment
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|err| sqlx::Error::Decode(format!("invalid stored enrichment: {}", err).into()))?;
+    Ok(User {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        email: row.try_get("email")?,
+        status,
+        created_at: row.try_get("created_at")?,
+        updated_at: row.try_get("updated_at")?,
+        expires_at: row.try_get("expires_at")?,
+        credentials: password_hash.map(|password_hash| Credentials { password_hash }),
+        enrichment,
+    })
+}
+
+// Enrichment is stored as JSON text, which both databases take as it is
+pub fn enrichment_column(user: &User) -> Option<String> {
+    user.enrichment.as_ref().and_then(|enrichment| serde_json::to_string(enrichment).ok())
+}
+
+// Avatar images are stored as base64 text
+pub fn avatar_column(avatar: &Avatar) -> String {
+    base64::engine::general_purpose::STANDARD.encode(&avatar.data)
+}
+
+pub fn avatar_from_row<'r, R>(row: &'r R) -> Result<Avatar, sqlx::Error>
+where
+    R: Row,
+    &'r str: ColumnIndex<R>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+{
+    let data: String = row.try_get("data")?;
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|err| sqlx::Error::Decode(format!("invalid stored avatar: {}", err).into()))?;
+    Ok(Avatar { content_type: row.try_get("content_type")?, data: data.into(), etag: row.try_get("etag")? })
+}
+
+pub fn profile_from_row<'r, R>(row: &'r R) -> Result<Profile, sqlx::Error>
+where
+    R: Row,
+    &'r str: ColumnIndex<R>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+{
+    Ok(Profile { bio: row.try_get("bio")?, locale: row.try_get("locale")?, timezone: row.try_get("timezone")? })
+}
+
+pub fn outbox_entry_from_row<'r, R>(row: &'r R) -> Result<OutboxEntry, sqlx::Error>
+where
+    R: Row,
+    &'r str: ColumnIndex<R>,
+    Uuid: Decode<'r, R::Database> + Type<R::Database>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+    DateTime<Utc>: Decode<'r, R::Database> + Type<R::Database>,
+{
+    let payload: String = row.try_get("payload")?;
+    Ok(OutboxEntry {
+        id: row.try_get("id")?,
+        event_type: row.try_get("event_type")?,
+        payload: serde_json::from_str(&payload).map_err(|err| sqlx::Error::Decode(err.into()))?,
+        occurred_at: row.try_get("occurred_at")?,
+    })
+}
+
+// LIKE pattern matching `value` literally, with '\' as the escape character
+fn like_literal(value: &str) -> String {
+    value.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// WHERE clause for a listing query. Time bounds are exclusive, name matching
+// is a case-insensitive substring match and the domain is everything after
+// the last '@', as in memory.
+pub fn push_filters<'args, DB>(builder: &mut QueryBuilder<'args, DB>, tenant: &str, query: &ListQuery)
+where
+    DB: Database,
+    String: Encode<'args, DB> + Type<DB>,
+    DateTime<Utc>: Encode<'args, DB> + Type<DB>,
+{
+    builder.push(" WHERE tenant = ").push_bind(tenant.to_string());
+    if let Some(after) = query.created_after {
+        builder.push(" AND created_at > ").push_bind(after);
+    }
+    if let Some(before) = query.created_before {
+        builder.push(" AND created_at < ").push_bind(before);
+    }
+    if let Some(name) = &query.name {
+        builder
+            .push(" AND lower(name) LIKE ")
+            .push_bind(format!("%{}%", like_literal(name)))
+            .push(" ESCAPE '\\'");
+    }
+    if let Some(domain) = query.email_domain.as_ref().filter(|domain| !domain.contains('@')) {
+        builder
+            .push(" AND lower(email) LIKE ")
+            .push_bind(format!("%@{}", like_literal(domain)))
+            .push(" ESCAPE '\\'");
+    } else if query.email_domain.is_some() {
+        builder.push(" AND 1 = 0");
+    }
+}
+
+// ORDER BY, LIMIT and OFFSET for a listing query; insertion order breaks ties
+pub fn push_page<'args, DB>(builder: &mut QueryBuilder<'args, DB>, query: &ListQuery)
+where
+    DB: Database,
+    i64: Encode<'args, DB> + Type<DB>,
+{
+    builder.push(" ORDER BY ");
+    if let Some(sort) = query.sort {
+        builder.push(match sort.field {
+            SortField::Name => "lower(name)",
+            SortField::Email => "lower(email)",
+            SortField::CreatedAt => "created_at",
+            SortField::UpdatedAt => "updated_at",
+        });
+        builder.push(if sort.descending { " DESC, " } else { " ASC, " });
+    }
+    builder
+        .push("id LIMIT ")
+        .push_bind(query.limit as i64)
+        .push(" OFFSET ")
+        .push_bind(query.offset() as i64);
+}
@@ -0,0 +1,477 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::QueryBuilder;
+use std::str::FromStr;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::config;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::repository::{self, NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::sql::{self, user_from_row, COLUMNS};
+use crate::webhooks;
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+pub const DB_SYSTEM: &str = "sqlite";
+
+// Connection pool shared by every tenant's repository. DATABASE_URL names the
+// file ("sqlite://users.db", created if missing) or "sqlite::memory:".
+pub fn pool_from_env() -> Result<SqlitePool, String> {
+    let settings = &config::settings().storage;
+    let url = settings.database_url.clone().unwrap_or_else(|| "sqlite://users.db".to_string());
+    let options = SqliteConnectOptions::from_str(&url)
+        .map_err(|err| format!("invalid DATABASE_URL: {}", err))?
+        .create_if_missing(true);
+    // Every connection to an in-memory database gets its own empty copy, so
+    // keep exactly one connection open for the life of the process
+    let pool = if url.contains(":memory:") || url.contains("mode=memory") {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+    } else {
+        SqlitePoolOptions::new().max_connections(settings.database_max_connections.unwrap_or(5))
+    };
+    let pool = pool.connect_lazy_with(options);
+    sql::observe(DB_SYSTEM, &pool);
+    Ok(pool)
+}
+
+// Users stored in SQLite, so data survives restarts without a database server
+pub struct SqliteUserRepository {
+    pool: SqlitePool,
+    tenant: String,
+    // Write webhook events to the outbox table in each mutation's transaction
+    outbox: bool,
+}
+
+impl SqliteUserRepository {
+    pub fn new(pool: SqlitePool, tenant: &str, outbox: bool) -> Self {
+        SqliteUserRepository { pool, tenant: tenant.to_string(), outbox }
+    }
+
+    async fn insert(&self, connection: &mut SqliteConnection, user: &User) -> Result<(), RepositoryError> {
+        let statement = "INSERT INTO users (id, tenant, name, email, status, created_at, updated_at, expires_at, password_hash, enrichment) \
+                         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let query = sqlx::query(statement)
+            .bind(user.id)
+            .bind(&self.tenant)
+            .bind(&user.name)
+            .bind(&user.email)
+            .bind(user.status.name())
+            .bind(user.created_at)
+            .bind(user.updated_at)
+            .bind(user.expires_at)
+            .bind(user.credentials.as_ref().map(|credentials| credentials.password_hash.clone()))
+            .bind(sql::enrichment_column(user))
+            .execute(connection);
+        sql::traced(DB_SYSTEM, "INSERT", statement, query).await?;
+        Ok(())
+    }
+
+    // Outbox rows announcing `users`, when the outbox is enabled
+    async fn record(&self, connection: &mut SqliteConnection, event_type: &'static str, users: &[User]) -> Result<(), RepositoryError> {
+        if !self.outbox || users.is_empty() {
+            return Ok(());
+        }
+        let mut insert = QueryBuilder::new("INSERT INTO outbox (id, tenant, event_type, payload, occurred_at) ");
+        insert.push_values(users.iter().map(|user| OutboxEntry::new(event_type, user)), |mut row, entry| {
+            row.push_bind(entry.id)
+                .push_bind(self.tenant.clone())
+                .push_bind(entry.event_type)
+                .push_bind(entry.payload.to_string())
+                .push_bind(entry.occurred_at);
+        });
+        let statement = insert.sql().to_string();
+        sql::traced_on(DB_SYSTEM, "INSERT", "outbox", &statement, insert.build().execute(connection)).await?;
+        Ok(())
+    }
+
+    // Set a user's status to `to` if it is still `from`; None otherwise
+    async fn swap_status(&self, id: UserId, from: UserStatus, to: UserStatus) -> Result<Option<User>, RepositoryError> {
+        let statement = format!(
+            "UPDATE users SET status = ?, updated_at = ? WHERE tenant = ? AND id = ? AND status = ? RETURNING {}",
+            COLUMNS
+        );
+        sql::atomically(DB_SYSTEM, "set_status", &self.pool, self.outbox, async |connection| {
+            let query = sqlx::query(&statement)
+                .bind(to.name())
+                .bind(Utc::now())
+                .bind(&self.tenant)
+                .bind(id)
+                .bind(from.name())
+                .try_map(|row: SqliteRow| user_from_row(&row))
+                .fetch_optional(&mut *connection);
+            let updated = sql::traced(DB_SYSTEM, "UPDATE", &statement, query).await?;
+            if let Some(user) = &updated {
+                self.record(connection, webhooks::USER_UPDATED, std::slice::from_ref(user)).await?;
+            }
+            Ok(updated)
+        })
+        .await
+    }
+
+    // Bump a user's updated_at, returning the user; None if it doesn't exist
+    async fn touch(&self, connection: &mut SqliteConnection, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let statement = format!("UPDATE users SET updated_at = ? WHERE tenant = ? AND id = ? RETURNING {}", COLUMNS);
+        let query = sqlx::query(&statement)
+            .bind(Utc::now())
+            .bind(&self.tenant)
+            .bind(id)
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_optional(connection);
+        sql::traced(DB_SYSTEM, "UPDATE", &statement, query).await
+    }
+}
+
+#[async_trait]
+impl UserRepository for SqliteUserRepository {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        sql::migrate(DB_SYSTEM, &self.pool, sql::auto_migrate()).await?;
+        info!("SQLite schema ready");
+        Ok(())
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = format!("SELECT {} FROM users WHERE tenant = ? AND id = ?", COLUMNS);
+        let query = sqlx::query(&statement)
+            .bind(&self.tenant)
+            .bind(id)
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_optional(&mut *connection);
+        sql::traced(DB_SYSTEM, "SELECT", &statement, query).await
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let mut count = QueryBuilder::new("SELECT COUNT(*) FROM users");
+        sql::push_filters(&mut count, &self.tenant, query);
+        let statement = count.sql().to_string();
+        let total: i64 =
+            sql::traced(DB_SYSTEM, "SELECT", &statement, count.build_query_scalar().fetch_one(&mut *connection)).await?;
+
+        let mut page = QueryBuilder::new(format!("SELECT {} FROM users", COLUMNS));
+        sql::push_filters(&mut page, &self.tenant, query);
+        sql::push_page(&mut page, query);
+        let statement = page.sql().to_string();
+        let rows = page
+            .build()
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_all(&mut *connection);
+        let users = sql::traced(DB_SYSTEM, "SELECT", &statement, rows).await?;
+        Ok((users, total as usize))
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let user = user.into_user();
+        sql::atomically(DB_SYSTEM, "create", &self.pool, self.outbox, async |connection| {
+            self.insert(connection, &user).await?;
+            self.record(connection, webhooks::USER_CREATED, std::slice::from_ref(&user)).await
+        })
+        .await?;
+        Ok(user)
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        let users: Vec<User> = users.into_iter().map(NewUser::into_user).collect();
+        sql::atomically(DB_SYSTEM, "create_many", &self.pool, true, async |connection| {
+            for user in &users {
+                self.insert(connection, user).await?;
+            }
+            self.record(connection, webhooks::USER_CREATED, &users).await
+        })
+        .await?;
+        Ok(users)
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let statement = format!(
+            "UPDATE users SET name = ?, email = ?, updated_at = ? WHERE tenant = ? AND id = ? RETURNING {}",
+            COLUMNS
+        );
+        sql::atomically(DB_SYSTEM, "update", &self.pool, self.outbox, async |connection| {
+            let query = sqlx::query(&statement)
+                .bind(name)
+                .bind(email)
+                .bind(Utc::now())
+                .bind(&self.tenant)
+                .bind(id)
+                .try_map(|row: SqliteRow| user_from_row(&row))
+                .fetch_optional(&mut *connection);
+            let updated = sql::traced(DB_SYSTEM, "UPDATE", &statement, query).await?;
+            if let Some(user) = &updated {
+                self.record(connection, webhooks::USER_UPDATED, std::slice::from_ref(user)).await?;
+            }
+            Ok(updated)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let statement = format!("DELETE FROM users WHERE tenant = ? AND id = ? RETURNING {}", COLUMNS);
+        sql::atomically(DB_SYSTEM, "delete", &self.pool, self.outbox, async |connection| {
+            let query = sqlx::query(&statement)
+                .bind(&self.tenant)
+                .bind(id)
+                .try_map(|row: SqliteRow| user_from_row(&row))
+                .fetch_optional(&mut *connection);
+            let deleted = sql::traced(DB_SYSTEM, "DELETE", &statement, query).await?;
+            if let Some(user) = &deleted {
+                self.record(connection, webhooks::USER_DELETED, std::slice::from_ref(user)).await?;
+            }
+            Ok(deleted)
+        })
+        .await
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let statement = format!("DELETE FROM users WHERE tenant = ? AND expires_at <= ? RETURNING {}", COLUMNS);
+        sql::atomically(DB_SYSTEM, "delete_expired", &self.pool, self.outbox, async |connection| {
+            let query = sqlx::query(&statement)
+                .bind(&self.tenant)
+                .bind(now)
+                .try_map(|row: SqliteRow| user_from_row(&row))
+                .fetch_all(&mut *connection);
+            let expired = sql::traced(DB_SYSTEM, "DELETE", &statement, query).await?;
+            self.record(connection, webhooks::USER_DELETED, &expired).await?;
+            Ok(expired)
+        })
+        .await
+    }
+
+    // The earliest-created user with this address, matching the in-memory index
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = format!(
+            "SELECT {} FROM users WHERE tenant = ? AND lower(email) = lower(?) ORDER BY id LIMIT 1",
+            COLUMNS
+        );
+        let query = sqlx::query(&statement)
+            .bind(&self.tenant)
+            .bind(email)
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_optional(&mut *connection);
+        sql::traced(DB_SYSTEM, "SELECT", &statement, query).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let mut select = QueryBuilder::new(format!("SELECT {} FROM users WHERE tenant = ", COLUMNS));
+        select.push_bind(&self.tenant).push(" AND id IN (");
+        let mut separated = select.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        select.push(")");
+        let statement = select.sql().to_string();
+        let rows = select
+            .build()
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_all(&mut *connection);
+        sql::traced(DB_SYSTEM, "SELECT", &statement, rows).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT COUNT(*) FROM users WHERE tenant = ?";
+        let query = sqlx::query_scalar::<_, i64>(statement).bind(&self.tenant).fetch_one(&mut *connection);
+        let count = sql::traced(DB_SYSTEM, "SELECT", statement, query).await?;
+        Ok(count as usize)
+    }
+
+    // Only the two columns the counts need are read; domains are split off
+    // here, so they group exactly as in memory
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT email, created_at FROM users WHERE tenant = ?";
+        let query = sqlx::query_as::<_, (String, DateTime<Utc>)>(statement)
+            .bind(&self.tenant)
+            .fetch_all(&mut *connection);
+        let rows = sql::traced(DB_SYSTEM, "SELECT", statement, query).await?;
+        Ok(UserStats::tally(rows.iter().map(|(email, created_at)| (email.as_str(), *created_at))))
+    }
+
+    // Names are compared after normalize_name, which SQL can't express, so
+    // the tenant's users are matched here
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = format!("SELECT {} FROM users WHERE tenant = ? ORDER BY id", COLUMNS);
+        let query = sqlx::query(&statement)
+            .bind(&self.tenant)
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_all(&mut *connection);
+        let users = sql::traced(DB_SYSTEM, "SELECT", &statement, query).await?;
+        Ok(DuplicateMatch::among(&users, name, email))
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        loop {
+            let (from, to) = repository::next_status(self.get(id).await?.map(|user| user.status), action)?;
+            if let Some(user) = self.swap_status(id, from, to).await? {
+                return Ok((from, user));
+            }
+        }
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = format!(
+            "UPDATE users SET password_hash = ?, updated_at = ? WHERE tenant = ? AND id = ? RETURNING {}",
+            COLUMNS
+        );
+        let query = sqlx::query(&statement)
+            .bind(credentials.password_hash)
+            .bind(Utc::now())
+            .bind(&self.tenant)
+            .bind(id)
+            .try_map(|row: SqliteRow| user_from_row(&row))
+            .fetch_optional(&mut *connection);
+        sql::traced(DB_SYSTEM, "UPDATE", &statement, query).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT avatars.content_type, avatars.data, avatars.etag FROM avatars \
+                         JOIN users ON users.id = avatars.id WHERE users.tenant = ? AND avatars.id = ?";
+        let query = sqlx::query(statement)
+            .bind(&self.tenant)
+            .bind(id)
+            .try_map(|row: SqliteRow| sql::avatar_from_row(&row))
+            .fetch_optional(&mut *connection);
+        sql::traced_on(DB_SYSTEM, "SELECT", "avatars", statement, query).await
+    }
+
+    // The user is touched and the avatar stored in one transaction
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let statement = "INSERT INTO avatars (id, content_type, data, etag) VALUES (?, ?, ?, ?) \
+                         ON CONFLICT (id) DO UPDATE SET content_type = excluded.content_type, data = excluded.data, etag = excluded.etag";
+        sql::atomically(DB_SYSTEM, "set_avatar", &self.pool, true, async |connection| {
+            let Some(user) = self.touch(connection, id).await? else {
+                return Ok(None);
+            };
+            let query = sqlx::query(statement)
+                .bind(id)
+                .bind(&avatar.content_type)
+                .bind(sql::avatar_column(&avatar))
+                .bind(&avatar.etag)
+                .execute(connection);
+            sql::traced_on(DB_SYSTEM, "INSERT", "avatars", statement, query).await?;
+            Ok(Some(user))
+        })
+        .await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT profiles.bio, profiles.locale, profiles.timezone FROM profiles \
+                         JOIN users ON users.id = profiles.id WHERE users.tenant = ? AND profiles.id = ?";
+        let query = sqlx::query(statement)
+            .bind(&self.tenant)
+            .bind(id)
+            .try_map(|row: SqliteRow| sql::profile_from_row(&row))
+            .fetch_optional(&mut *connection);
+        sql::traced_on(DB_SYSTEM, "SELECT", "profiles", statement, query).await
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let statement = "INSERT INTO profiles (id, bio, locale, timezone) VALUES (?, ?, ?, ?) \
+                         ON CONFLICT (id) DO UPDATE SET bio = excluded.bio, locale = excluded.locale, timezone = excluded.timezone";
+        sql::atomically(DB_SYSTEM, "set_profile", &self.pool, true, async |connection| {
+            let Some(user) = self.touch(connection, id).await? else {
+                return Ok(None);
+            };
+            let query = sqlx::query(statement)
+                .bind(id)
+                .bind(&profile.bio)
+                .bind(&profile.locale)
+                .bind(&profile.timezone)
+                .execute(connection);
+            sql::traced_on(DB_SYSTEM, "INSERT", "profiles", statement, query).await?;
+            Ok(Some(user))
+        })
+        .await
+    }
+
+    // The upsert only counts while under the limit, so nothing is returned
+    // once the quota is spent
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        let day = day.to_string();
+        let cleanup = "DELETE FROM quota_usage WHERE tenant = ? AND subject = ? AND day < ?";
+        let charge = "INSERT INTO quota_usage (tenant, subject, day, used) VALUES (?, ?, ?, 1) \
+                      ON CONFLICT (tenant, subject, day) DO UPDATE SET used = quota_usage.used + 1 \
+                      WHERE quota_usage.used < ? RETURNING used";
+        sql::atomically(DB_SYSTEM, "charge_quota", &self.pool, true, async |connection| {
+            let query = sqlx::query(cleanup).bind(&self.tenant).bind(subject).bind(&day).execute(&mut *connection);
+            sql::traced_on(DB_SYSTEM, "DELETE", "quota_usage", cleanup, query).await?;
+            let query = sqlx::query_scalar::<_, i64>(charge)
+                .bind(&self.tenant)
+                .bind(subject)
+                .bind(&day)
+                .bind(i64::from(limit))
+                .fetch_optional(connection);
+            let used = sql::traced_on(DB_SYSTEM, "INSERT", "quota_usage", charge, query).await?;
+            Ok(used.map(|used| used as u32))
+        })
+        .await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let refund = "UPDATE quota_usage SET used = used - 1 WHERE tenant = ? AND subject = ? AND day = ? AND used > 0";
+        let query = sqlx::query(refund).bind(&self.tenant).bind(subject).bind(day.to_string()).execute(&mut *connection);
+        sql::traced_on(DB_SYSTEM, "UPDATE", "quota_usage", refund, query).await?;
+        Ok(())
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.outbox
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT id, event_type, payload, occurred_at FROM outbox WHERE tenant = ? ORDER BY id LIMIT ?";
+        let query = sqlx::query(statement)
+            .bind(&self.tenant)
+            .bind(limit as i64)
+            .try_map(|row: SqliteRow| sql::outbox_entry_from_row(&row))
+            .fetch_all(&mut *connection);
+        sql::traced_on(DB_SYSTEM, "SELECT", "outbox", statement, query).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let mut delete = QueryBuilder::new("DELETE FROM outbox WHERE tenant = ");
+        delete.push_bind(&self.tenant).push(" AND id IN (");
+        let mut separated = delete.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        delete.push(")");
+        let statement = delete.sql().to_string();
+        sql::traced_on(DB_SYSTEM, "DELETE", "outbox", &statement, delete.build().execute(&mut *connection)).await?;
+        Ok(())
+    }
+
+    // The summed length of each row's values, before indexes and page
+    // overhead
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        let mut connection = sql::acquire(DB_SYSTEM, &self.pool).await?;
+        let statement = "SELECT count(*), coalesce(sum(length(id) + length(tenant) + length(name) + length(email) \
+            + length(status) + length(created_at) + length(updated_at) + coalesce(length(password_hash), 0) \
+            + coalesce(length(expires_at), 0) + coalesce(length(enrichment), 0)), 0) FROM users WHERE tenant = ?";
+        let query = sqlx::query_as::<_, (i64, i64)>(statement).bind(&self.tenant).fetch_one(&mut *connection);
+        let (entries, bytes) = sql::traced(DB_SYSTEM, "SELECT", statement, query).await?;
+        Ok(StoreSize { entries: entries as u64, bytes: Some(bytes as u64) })
+    }
+}
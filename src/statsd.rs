@@ -0,0 +1,283 @@
+use opentelemetry::metrics::{
+    AsyncCounter, AsyncGauge, AsyncUpDownCounter, Counter, Histogram, InstrumentProvider, MeterProvider, MetricsError,
+    ObservableCounter, ObservableGauge, ObservableUpDownCounter, SyncCounter, SyncHistogram, SyncUpDownCounter, Unit,
+    UpDownCounter,
+};
+use opentelemetry::metrics::Meter;
+use opentelemetry::{global, Context, InstrumentationLibrary, KeyValue};
+use std::fmt::Display;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::get_env_or_default;
+use crate::shutdown::ShutdownCoordinator;
+
+// Largest datagram sent, leaving room for headers in a 1500-byte MTU
+const MAX_PACKET_BYTES: usize = 1432;
+
+type Callback = Box<dyn Fn(&Context) + Send + Sync>;
+
+// STATSD_ADDR, e.g. 127.0.0.1:8125 for a local Datadog agent, turns on the
+// DogStatsD exporter in place of OTLP metrics
+pub fn addr_from_env() -> Option<String> {
+    std::env::var("STATSD_ADDR").ok().filter(|addr| !addr.is_empty())
+}
+
+// DogStatsD forbids these in metric names and tags
+fn sanitize(value: &str, reserved: &[char]) -> String {
+    value.chars().map(|c| if reserved.contains(&c) { '_' } else { c }).collect()
+}
+
+// Lines waiting to go out, packed into as few datagrams as fit
+struct Sink {
+    socket: UdpSocket,
+    prefix: String,
+    // Tags on every line: service, version, and env from DD_ENV when set
+    constant_tags: Vec<String>,
+    buffer: Mutex<String>,
+}
+
+impl Sink {
+    fn send(&self, name: &str, value: impl Display, kind: &str, attributes: &[KeyValue]) {
+        let mut line = format!("{}{}:{}|{}", self.prefix, sanitize(name, &[':', '|', '@', '#']), value, kind);
+        let tags = attributes.iter().map(|attribute| {
+            let tag = format!("{}:{}", attribute.key.as_str(), attribute.value.as_str());
+            sanitize(&tag, &[',', '|', '#'])
+        });
+        let tags: Vec<String> = self.constant_tags.iter().cloned().chain(tags).collect();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+
+        let mut buffer = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !buffer.is_empty() && buffer.len() + 1 + line.len() > MAX_PACKET_BYTES {
+            self.write(&mut buffer);
+        }
+        if !buffer.is_empty() {
+            buffer.push('\n');
+        }
+        buffer.push_str(&line);
+    }
+
+    fn flush(&self) {
+        let mut buffer = self.buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.write(&mut buffer);
+    }
+
+    // Metrics are fire-and-forget: a datagram the agent isn't there to take
+    // is dropped, and only logged at debug level so an absent agent doesn't
+    // flood the logs
+    fn write(&self, buffer: &mut String) {
+        if buffer.is_empty() {
+            return;
+        }
+        if let Err(err) = self.socket.send(buffer.as_bytes()) {
+            debug!(error = %err, bytes = buffer.len(), "Dropped StatsD datagram");
+        }
+        buffer.clear();
+    }
+}
+
+// One instrument, sending every reading as a DogStatsD line of `kind`
+struct Instrument {
+    sink: Arc<Sink>,
+    name: String,
+    kind: &'static str,
+}
+
+impl<T: Display> SyncCounter<T> for Instrument {
+    fn add(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+impl<T: Display> SyncUpDownCounter<T> for Instrument {
+    fn add(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+impl<T: Display> SyncHistogram<T> for Instrument {
+    fn record(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+impl<T: Display> AsyncGauge<T> for Instrument {
+    fn observe(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+impl<T: Display> AsyncCounter<T> for Instrument {
+    fn observe(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+impl<T: Display> AsyncUpDownCounter<T> for Instrument {
+    fn observe(&self, _cx: &Context, value: T, attributes: &[KeyValue]) {
+        self.sink.send(&self.name, value, self.kind, attributes);
+    }
+}
+
+// Maps OpenTelemetry instruments onto DogStatsD types, leaving aggregation to
+// the agent: counters send their increments as counts, histograms send every
+// value, as timers when recorded in milliseconds, and observable instruments
+// send gauges each time their callbacks run.
+struct StatsdCore {
+    sink: Arc<Sink>,
+    callbacks: Mutex<Vec<Callback>>,
+}
+
+impl StatsdCore {
+    fn instrument(&self, name: String, kind: &'static str) -> Arc<Instrument> {
+        Arc::new(Instrument { sink: self.sink.clone(), name, kind })
+    }
+
+    fn histogram(&self, name: String, unit: Option<Unit>) -> Arc<Instrument> {
+        let kind = if unit.is_some_and(|unit| unit.as_str() == "ms") { "ms" } else { "h" };
+        self.instrument(name, kind)
+    }
+
+    fn observe(&self) {
+        let cx = Context::current();
+        for callback in self.callbacks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+            callback(&cx);
+        }
+    }
+}
+
+impl InstrumentProvider for StatsdCore {
+    fn u64_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<Counter<u64>, MetricsError> {
+        Ok(Counter::new(self.instrument(name, "c")))
+    }
+
+    fn f64_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<Counter<f64>, MetricsError> {
+        Ok(Counter::new(self.instrument(name, "c")))
+    }
+
+    fn u64_observable_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<ObservableCounter<u64>, MetricsError> {
+        Ok(ObservableCounter::new(self.instrument(name, "g")))
+    }
+
+    fn f64_observable_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<ObservableCounter<f64>, MetricsError> {
+        Ok(ObservableCounter::new(self.instrument(name, "g")))
+    }
+
+    fn i64_up_down_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<UpDownCounter<i64>, MetricsError> {
+        Ok(UpDownCounter::new(self.instrument(name, "c")))
+    }
+
+    fn f64_up_down_counter(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<UpDownCounter<f64>, MetricsError> {
+        Ok(UpDownCounter::new(self.instrument(name, "c")))
+    }
+
+    fn i64_observable_up_down_counter(
+        &self,
+        name: String,
+        _: Option<String>,
+        _: Option<Unit>,
+    ) -> Result<ObservableUpDownCounter<i64>, MetricsError> {
+        Ok(ObservableUpDownCounter::new(self.instrument(name, "g")))
+    }
+
+    fn f64_observable_up_down_counter(
+        &self,
+        name: String,
+        _: Option<String>,
+        _: Option<Unit>,
+    ) -> Result<ObservableUpDownCounter<f64>, MetricsError> {
+        Ok(ObservableUpDownCounter::new(self.instrument(name, "g")))
+    }
+
+    fn u64_observable_gauge(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<ObservableGauge<u64>, MetricsError> {
+        Ok(ObservableGauge::new(self.instrument(name, "g")))
+    }
+
+    fn i64_observable_gauge(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<ObservableGauge<i64>, MetricsError> {
+        Ok(ObservableGauge::new(self.instrument(name, "g")))
+    }
+
+    fn f64_observable_gauge(&self, name: String, _: Option<String>, _: Option<Unit>) -> Result<ObservableGauge<f64>, MetricsError> {
+        Ok(ObservableGauge::new(self.instrument(name, "g")))
+    }
+
+    fn f64_histogram(&self, name: String, _: Option<String>, unit: Option<Unit>) -> Result<Histogram<f64>, MetricsError> {
+        Ok(Histogram::new(self.histogram(name, unit)))
+    }
+
+    fn u64_histogram(&self, name: String, _: Option<String>, unit: Option<Unit>) -> Result<Histogram<u64>, MetricsError> {
+        Ok(Histogram::new(self.histogram(name, unit)))
+    }
+
+    fn i64_histogram(&self, name: String, _: Option<String>, unit: Option<Unit>) -> Result<Histogram<i64>, MetricsError> {
+        Ok(Histogram::new(self.histogram(name, unit)))
+    }
+
+    fn register_callback(&self, callback: Box<dyn Fn(&Context) + Send + Sync>) -> Result<(), MetricsError> {
+        self.callbacks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(callback);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct StatsdMeterProvider {
+    core: Arc<StatsdCore>,
+}
+
+impl MeterProvider for StatsdMeterProvider {
+    fn versioned_meter(&self, name: &'static str, version: Option<&'static str>, schema_url: Option<&'static str>) -> Meter {
+        Meter::new(InstrumentationLibrary::new(name, version, schema_url), self.core.clone())
+    }
+}
+
+// Installs the DogStatsD exporter as the global meter provider, sending to
+// `addr` over UDP. STATSD_PREFIX is put before every metric name, and
+// STATSD_INTERVAL_SECS (default 10, the agent's flush interval) sets how
+// often gauges are observed and buffered lines are sent.
+pub fn start(addr: &str, shutdown: &mut ShutdownCoordinator) -> Result<(), String> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.connect(addr).map(|()| socket))
+        .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+        .map_err(|err| format!("invalid STATSD_ADDR {}: {}", addr, err))?;
+    let mut constant_tags = vec![
+        "service:actix-web-server".to_string(),
+        format!("version:{}", env!("CARGO_PKG_VERSION")),
+    ];
+    if let Some(env) = std::env::var("DD_ENV").ok().filter(|env| !env.is_empty()) {
+        constant_tags.push(format!("env:{}", sanitize(&env, &[',', '|', '#'])));
+    }
+    let core = Arc::new(StatsdCore {
+        sink: Arc::new(Sink {
+            socket,
+            prefix: get_env_or_default("STATSD_PREFIX", ""),
+            constant_tags,
+            buffer: Mutex::new(String::new()),
+        }),
+        callbacks: Mutex::new(Vec::new()),
+    });
+    global::set_meter_provider(StatsdMeterProvider { core: core.clone() });
+
+    let interval = Duration::from_secs(get_env_or_default("STATSD_INTERVAL_SECS", "10").parse().unwrap_or(10));
+    info!(address = %addr, interval_secs = interval.as_secs(), "Sending metrics to DogStatsD");
+    let flushing = actix_web::rt::spawn({
+        let core = core.clone();
+        async move {
+            loop {
+                actix_web::rt::time::sleep(interval).await;
+                core.observe();
+                core.sink.flush();
+            }
+        }
+    });
+    shutdown.register("statsd flush", Duration::from_secs(5), move || async move {
+        flushing.abort();
+        core.observe();
+        core.sink.flush();
+    });
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use tracing::info;
+
+// Subsystems that must finish initializing before the app takes traffic
+pub const TELEMETRY: &str = "telemetry";
+pub const STORAGE: &str = "storage";
+pub const STATE: &str = "state";
+pub const WORKERS: &str = "workers";
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "state", content = "detail", rename_all = "lowercase")]
+pub enum SubsystemState {
+    Starting,
+    Ready,
+    Failed(String),
+}
+
+// Startup progress of every subsystem, shared between main and the probes
+pub struct AppStatus {
+    subsystems: RwLock<BTreeMap<&'static str, SubsystemState>>,
+    // Set by POST /admin/drain; fails readiness while traffic is still served
+    draining: AtomicBool,
+}
+
+impl AppStatus {
+    pub fn new(subsystems: &[&'static str]) -> Self {
+        AppStatus {
+            subsystems: RwLock::new(
+                subsystems
+                    .iter()
+                    .map(|name| (*name, SubsystemState::Starting))
+                    .collect(),
+            ),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    fn set(&self, name: &'static str, state: SubsystemState) {
+        if let Ok(mut subsystems) = self.subsystems.write() {
+            subsystems.insert(name, state);
+        }
+    }
+
+    pub fn mark_ready(&self, name: &'static str) {
+        info!(subsystem = name, "Subsystem ready");
+        self.set(name, SubsystemState::Ready);
+    }
+
+    pub fn mark_failed(&self, name: &'static str, detail: impl Into<String>) {
+        let detail = detail.into();
+        tracing::error!(subsystem = name, error = %detail, "Subsystem failed to initialize");
+        self.set(name, SubsystemState::Failed(detail));
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.subsystems
+            .read()
+            .map(|subsystems| subsystems.values().all(|state| matches!(state, SubsystemState::Ready)))
+            .unwrap_or(false)
+    }
+
+    // Start draining; false when a drain was already under way
+    pub fn begin_drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, SubsystemState> {
+        self.subsystems
+            .read()
+            .map(|subsystems| subsystems.clone())
+            .unwrap_or_default()
+    }
+}
+
+// Middleware that refuses application traffic with 503 until startup completes
+pub async fn startup_gate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let ready = req
+        .app_data::<web::Data<AppStatus>>()
+        .map(|status| status.is_ready())
+        .unwrap_or(true);
+
+    if !ready {
+        info!(path = %req.path(), "Rejecting request during startup");
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .body("Service is starting up");
+        return Ok(req.into_response(response));
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,383 @@
+use actix_web::web;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+use crate::events::EventSourcedUserRepository;
+use crate::outbox;
+use crate::repository::{InMemoryUserRepository, RepositoryError, SharedUserRepository, UserRepository};
+use crate::sharded::ShardedUserRepository;
+use crate::store_metrics::MeasuredUserRepository;
+use crate::tenant::DEFAULT_TENANT;
+use crate::wal::MutationLog;
+use crate::{config, AppState, ProfileState};
+
+// Backends STORAGE_BACKEND can name, whether or not this build includes them
+const BACKENDS: &[&str] = &["memory", "sharded", "events", "postgres", "sqlite", "redis", "mongodb"];
+
+// Settings a backend can't start without; everything else has a local default
+const REQUIRED_ENV: &[(&str, &str)] = &[("postgres", "DATABASE_URL")];
+
+// Connection URLs each backend reads and the schemes it accepts, checked up
+// front so a URL meant for another backend fails with a clear message
+const URL_SCHEMES: &[(&str, &str, &[&str])] = &[
+    ("postgres", "DATABASE_URL", &["postgres://", "postgresql://"]),
+    ("sqlite", "DATABASE_URL", &["sqlite:"]),
+    ("redis", "REDIS_URL", &["redis://", "rediss://", "redis+unix://", "unix://"]),
+    ("mongodb", "MONGODB_URL", &["mongodb://", "mongodb+srv://"]),
+];
+
+// The connection URL a variable set, whether in the environment or the
+// config file
+fn configured(var: &str) -> Option<String> {
+    let storage = &config::settings().storage;
+    let url = match var {
+        "DATABASE_URL" => &storage.database_url,
+        "REDIS_URL" => &storage.redis_url,
+        "MONGODB_URL" => &storage.mongodb_url,
+        _ => &None,
+    };
+    url.clone().filter(|url| !url.is_empty())
+}
+
+// Check the environment for `backend` before connecting: required settings
+// must be present and URLs must suit the backend. URLs only another backend
+// reads are most likely a mistake, but harmless, so they are just logged.
+fn validate_env(backend: &str) -> Result<(), String> {
+    let mut problems = Vec::new();
+    for (_, var) in REQUIRED_ENV.iter().filter(|(name, _)| *name == backend) {
+        if configured(var).is_none() {
+            problems.push(format!("STORAGE_BACKEND={} requires {}", backend, var));
+        }
+    }
+    for (name, var, schemes) in URL_SCHEMES {
+        let Some(url) = configured(var) else {
+            continue;
+        };
+        if *name == backend {
+            if !schemes.iter().any(|scheme| url.starts_with(scheme)) {
+                problems.push(format!("{} for STORAGE_BACKEND={} must start with {}", var, backend, schemes.join(" or ")));
+            }
+        } else {
+            let used = URL_SCHEMES.iter().any(|(name, other, _)| *name == backend && other == var)
+                || (*var == "REDIS_URL" && config::settings().storage.cache == "redis");
+            if !used {
+                warn!(var = *var, backend, "Storage setting is ignored by the selected backend");
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+// Settings that save or restore AppState, which only the memory backend
+// keeps its users in
+const MEMORY_ONLY_ENV: &[&str] = &["WAL_FILE", "STATE_FILE", "SNAPSHOT_DIR"];
+
+// Reject persistence settings the selected backend would silently ignore
+fn check_memory_only(backend: &StorageBackend) -> Result<(), String> {
+    if matches!(backend, StorageBackend::Memory) {
+        return Ok(());
+    }
+    match MEMORY_ONLY_ENV.iter().find(|var| std::env::var(var).is_ok_and(|value| !value.is_empty())) {
+        Some(var) => Err(format!("{} only applies to STORAGE_BACKEND=memory", var)),
+        None => Ok(()),
+    }
+}
+
+fn shared(repository: impl UserRepository + 'static) -> Arc<dyn UserRepository> {
+    Arc::new(repository)
+}
+
+// Where users are stored, picked once at startup; every tenant gets a
+// repository on the same backend
+#[derive(Clone)]
+enum StorageBackend {
+    Memory,
+    // Shard count, from STORE_SHARDS
+    Sharded(usize),
+    Events,
+    #[cfg(feature = "postgres")]
+    Postgres(sqlx::PgPool),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlx::SqlitePool),
+    #[cfg(feature = "redis")]
+    Redis(crate::redis_client::RedisClient),
+    #[cfg(feature = "mongodb")]
+    Mongo(crate::mongo::MongoConnection),
+}
+
+impl StorageBackend {
+    // STORAGE_BACKEND selects memory (the default), sharded, events, postgres,
+    // sqlite, redis or mongodb
+    fn from_env() -> Result<Self, String> {
+        let settings = &config::settings().storage;
+        let backend = settings.backend.clone();
+        if !BACKENDS.contains(&backend.as_str()) {
+            return Err(format!("unknown STORAGE_BACKEND '{}'; expected one of {}", backend, BACKENDS.join(", ")));
+        }
+        validate_env(&backend)?;
+        match backend.as_str() {
+            "memory" => Ok(StorageBackend::Memory),
+            "sharded" if settings.shards == 0 => Err("STORE_SHARDS must be a positive number".to_string()),
+            "sharded" => Ok(StorageBackend::Sharded(settings.shards)),
+            "events" => Ok(StorageBackend::Events),
+            #[cfg(feature = "postgres")]
+            "postgres" => Ok(StorageBackend::Postgres(crate::postgres::pool_from_env()?)),
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => Err("STORAGE_BACKEND=postgres requires building with the `postgres` feature".to_string()),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(StorageBackend::Sqlite(crate::sqlite::pool_from_env()?)),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => Err("STORAGE_BACKEND=sqlite requires building with the `sqlite` feature".to_string()),
+            #[cfg(feature = "redis")]
+            "redis" => Ok(StorageBackend::Redis(crate::redis_client::RedisClient::from_env()?)),
+            #[cfg(not(feature = "redis"))]
+            "redis" => Err("STORAGE_BACKEND=redis requires building with the `redis` feature".to_string()),
+            #[cfg(feature = "mongodb")]
+            "mongodb" => Ok(StorageBackend::Mongo(crate::mongo::MongoConnection::from_env()?)),
+            #[cfg(not(feature = "mongodb"))]
+            "mongodb" => Err("STORAGE_BACKEND=mongodb requires building with the `mongodb` feature".to_string()),
+            other => Err(format!("unknown STORAGE_BACKEND '{}'", other)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            StorageBackend::Memory => "memory",
+            StorageBackend::Sharded(_) => "sharded",
+            StorageBackend::Events => "events",
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres(_) => "postgres",
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite(_) => "sqlite",
+            #[cfg(feature = "redis")]
+            StorageBackend::Redis(_) => "redis",
+            #[cfg(feature = "mongodb")]
+            StorageBackend::Mongo(_) => "mongodb",
+        }
+    }
+
+    // Whether mutations and their outbox entries can be written atomically
+    fn supports_outbox(&self) -> bool {
+        match self {
+            StorageBackend::Memory | StorageBackend::Events => true,
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres(_) => true,
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite(_) => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    // Repository for one tenant; the in-memory backend keeps users in `state`
+    // and their profiles in `profiles`
+    fn repository(
+        &self,
+        tenant: &str,
+        state: web::Data<RwLock<AppState>>,
+        profiles: web::Data<RwLock<ProfileState>>,
+        outbox: bool,
+    ) -> Arc<dyn UserRepository> {
+        match self {
+            StorageBackend::Memory => shared(InMemoryUserRepository::new(state, profiles, outbox)),
+            StorageBackend::Sharded(shards) => shared(ShardedUserRepository::new(*shards, tenant)),
+            StorageBackend::Events => shared(EventSourcedUserRepository::new(outbox)),
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres(pool) => {
+                shared(crate::postgres::PostgresUserRepository::new(pool.clone(), tenant, outbox))
+            }
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite(pool) => shared(crate::sqlite::SqliteUserRepository::new(pool.clone(), tenant, outbox)),
+            #[cfg(feature = "redis")]
+            StorageBackend::Redis(client) => shared(crate::redis_store::RedisUserRepository::new(client.clone(), tenant)),
+            #[cfg(feature = "mongodb")]
+            StorageBackend::Mongo(connection) => shared(crate::mongo::MongoUserRepository::new(connection.clone(), tenant)),
+        }
+    }
+}
+
+// Read-through cache USER_CACHE puts in front of every repository
+#[derive(Clone)]
+enum UserCache {
+    #[cfg(feature = "redis")]
+    Redis(crate::redis_cache::RedisCache),
+    #[cfg(feature = "moka")]
+    Moka(crate::moka_cache::MokaCache),
+}
+
+impl UserCache {
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "redis")]
+            UserCache::Redis(_) => "redis",
+            #[cfg(feature = "moka")]
+            UserCache::Moka(_) => "moka",
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "redis", feature = "moka")), allow(unused_variables))]
+    fn wrap(&self, repository: Arc<dyn UserRepository>, tenant: &str) -> Arc<dyn UserRepository> {
+        match *self {
+            #[cfg(feature = "redis")]
+            UserCache::Redis(ref cache) => cache.wrap(repository, tenant),
+            #[cfg(feature = "moka")]
+            UserCache::Moka(ref cache) => cache.wrap(repository, tenant),
+        }
+    }
+}
+
+// The storage in use, as reported by /version and /readyz
+#[derive(Clone, Serialize)]
+pub struct StorageInfo {
+    pub backend: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<&'static str>,
+    pub wal: bool,
+    pub outbox: bool,
+    pub state_sync: bool,
+}
+
+// The storage backend plus the layers wrapped around every repository it creates
+#[derive(Clone)]
+pub struct Storage {
+    backend: StorageBackend,
+    // Mutation log for the default tenant's in-memory users
+    wal: Option<Arc<MutationLog>>,
+    // Whether repositories write webhook events to an outbox
+    outbox: bool,
+    cache: Option<UserCache>,
+    // Broadcasts in-memory mutations to other instances (STATE_SYNC)
+    #[cfg(feature = "redis")]
+    sync: Option<Arc<crate::sync::StateSync>>,
+}
+
+impl Storage {
+    // STORAGE_BACKEND picks the backend; USER_CACHE=redis or moka puts a
+    // read-through cache in front of it, WAL_FILE logs in-memory mutations and
+    // STATE_SYNC=redis shares them with other instances
+    pub fn from_env() -> Result<Self, String> {
+        let backend = StorageBackend::from_env()?;
+        // Checked before the WAL is opened, so a rejected WAL_FILE isn't created
+        check_memory_only(&backend)?;
+        let wal = MutationLog::from_env()?.map(Arc::new);
+        let outbox = outbox::enabled();
+        if outbox && !backend.supports_outbox() {
+            return Err(format!(
+                "OUTBOX_ENABLED needs a backend that writes the outbox in the same transaction; STORAGE_BACKEND={} can't",
+                backend.name()
+            ));
+        }
+        let settings = &config::settings().storage;
+        let cache = match settings.cache.as_str() {
+            "none" => None,
+            #[cfg(feature = "redis")]
+            "redis" => {
+                // Reuse the backend's connection when users already live in Redis
+                let client = match &backend {
+                    StorageBackend::Redis(client) => client.clone(),
+                    _ => crate::redis_client::RedisClient::from_env()?,
+                };
+                Some(UserCache::Redis(crate::redis_cache::RedisCache::from_env(client)))
+            }
+            #[cfg(not(feature = "redis"))]
+            "redis" => return Err("USER_CACHE=redis requires building with the `redis` feature".to_string()),
+            #[cfg(feature = "moka")]
+            "moka" => Some(UserCache::Moka(crate::moka_cache::MokaCache::from_env())),
+            #[cfg(not(feature = "moka"))]
+            "moka" => return Err("USER_CACHE=moka requires building with the `moka` feature".to_string()),
+            other => return Err(format!("unknown USER_CACHE '{}'", other)),
+        };
+        #[cfg(feature = "redis")]
+        let sync = crate::sync::StateSync::from_env(backend.name())?.map(Arc::new);
+        #[cfg(not(feature = "redis"))]
+        if settings.state_sync != "none" {
+            return Err("STATE_SYNC requires building with the `redis` feature".to_string());
+        }
+        Ok(Storage {
+            backend,
+            wal,
+            outbox,
+            cache,
+            #[cfg(feature = "redis")]
+            sync,
+        })
+    }
+
+    // Apply pending schema migrations for `--migrate`; backends without a
+    // schema have nothing to do. Returns how many migrations were applied.
+    pub async fn migrate(&self) -> Result<usize, RepositoryError> {
+        match &self.backend {
+            #[cfg(feature = "postgres")]
+            StorageBackend::Postgres(pool) => crate::sql::migrate(crate::postgres::DB_SYSTEM, pool, true).await,
+            #[cfg(feature = "sqlite")]
+            StorageBackend::Sqlite(pool) => crate::sql::migrate(crate::sqlite::DB_SYSTEM, pool, true).await,
+            #[allow(unreachable_patterns)]
+            _ => Ok(0),
+        }
+    }
+
+    // What /version and /readyz report about the storage in use
+    pub fn info(&self) -> StorageInfo {
+        StorageInfo {
+            backend: self.backend.name(),
+            cache: self.cache.as_ref().map(UserCache::name),
+            wal: self.wal.is_some(),
+            outbox: self.outbox,
+            #[cfg(feature = "redis")]
+            state_sync: self.sync.is_some(),
+            #[cfg(not(feature = "redis"))]
+            state_sync: false,
+        }
+    }
+
+    pub fn mutation_log(&self) -> Option<Arc<MutationLog>> {
+        self.wal.clone()
+    }
+
+    #[cfg(feature = "redis")]
+    pub fn state_sync(&self) -> Option<Arc<crate::sync::StateSync>> {
+        self.sync.clone()
+    }
+
+    // Plain in-memory storage, the fallback when the configuration is unusable
+    pub fn memory() -> Self {
+        Storage {
+            backend: StorageBackend::Memory,
+            wal: None,
+            outbox: false,
+            cache: None,
+            #[cfg(feature = "redis")]
+            sync: None,
+        }
+    }
+
+    // Repository for one tenant; the in-memory backend keeps users in `state`
+    pub fn repository(
+        &self,
+        tenant: &str,
+        state: web::Data<RwLock<AppState>>,
+        profiles: web::Data<RwLock<ProfileState>>,
+    ) -> SharedUserRepository {
+        let repository = MeasuredUserRepository::wrap(self.backend.repository(tenant, state, profiles, self.outbox));
+        let repository = match &self.wal {
+            Some(wal) if tenant == DEFAULT_TENANT => wal.wrap(repository),
+            _ => repository,
+        };
+        #[cfg(feature = "redis")]
+        let repository = match &self.sync {
+            Some(sync) => sync.wrap(repository, tenant),
+            None => repository,
+        };
+        let repository = match &self.cache {
+            Some(cache) => cache.wrap(repository, tenant),
+            None => repository,
+        };
+        web::Data::new(repository)
+    }
+}
+
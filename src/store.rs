@@ -0,0 +1,154 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::locks::MeasuredLock;
+use crate::{User, UserId};
+
+// Users keyed by ID so lookups don't scan the whole list. `order` keeps IDs in
+// insertion order, which listings and exports rely on.
+#[derive(Default)]
+pub struct UserStore {
+    by_id: HashMap<UserId, User>,
+    order: Vec<UserId>,
+}
+
+impl UserStore {
+    pub fn get(&self, id: UserId) -> Option<&User> {
+        self.by_id.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: UserId) -> Option<&mut User> {
+        self.by_id.get_mut(&id)
+    }
+
+    pub fn contains(&self, id: UserId) -> bool {
+        self.by_id.contains_key(&id)
+    }
+
+    // Append a user, replacing any existing one with the same ID in place
+    pub fn insert(&mut self, user: User) {
+        if self.by_id.insert(user.id, user.clone()).is_none() {
+            self.order.push(user.id);
+        }
+    }
+
+    // Removal is linear in the number of users, to keep `order` dense
+    pub fn remove(&mut self, id: UserId) -> Option<User> {
+        let user = self.by_id.remove(&id)?;
+        self.order.retain(|&other| other != id);
+        Some(user)
+    }
+
+    // Users in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.order.iter().filter_map(|id| self.by_id.get(id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    // Rough heap footprint: the map and order slots allocated, plus each
+    // user's strings. Allocator overhead and hash map control bytes aren't
+    // counted.
+    pub fn estimated_bytes(&self) -> usize {
+        let slots = self.by_id.capacity() * std::mem::size_of::<(UserId, User)>()
+            + self.order.capacity() * std::mem::size_of::<UserId>();
+        let strings: usize = self
+            .by_id
+            .values()
+            .map(|user| {
+                user.name.capacity()
+                    + user.email.capacity()
+                    + user.credentials.as_ref().map_or(0, |credentials| credentials.password_hash.capacity())
+            })
+            .sum();
+        slots + strings
+    }
+}
+
+impl FromIterator<User> for UserStore {
+    fn from_iter<I: IntoIterator<Item = User>>(users: I) -> Self {
+        let mut store = UserStore::default();
+        for user in users {
+            store.insert(user);
+        }
+        store
+    }
+}
+
+// The same store on a sharded concurrent map, so callers touching different
+// users never wait on one global lock. Each user carries the sequence number
+// it was inserted with, so insertion order can be recovered for listings.
+#[derive(Default)]
+pub struct ConcurrentUserStore {
+    users: DashMap<UserId, (u64, User)>,
+    next_sequence: AtomicU64,
+}
+
+impl ConcurrentUserStore {
+    pub fn get(&self, id: UserId) -> Option<User> {
+        self.users.get(&id).map(|entry| entry.1.clone())
+    }
+
+    // Append a user, replacing any existing one with the same ID in place
+    pub fn insert(&self, user: User) {
+        self.users
+            .entry(user.id)
+            .and_modify(|entry| entry.1 = user.clone())
+            .or_insert_with(|| (self.next_sequence.fetch_add(1, Ordering::Relaxed), user.clone()));
+    }
+
+    // Change a user in place, holding only the lock of its shard
+    pub fn update(&self, id: UserId, change: impl FnOnce(&mut User)) -> Option<User> {
+        let mut entry = self.users.get_mut(&id)?;
+        change(&mut entry.1);
+        Some(entry.1.clone())
+    }
+}
+
+// The same store split into shards by a hash of the user ID, each behind its
+// own lock, so writes to users in different shards never wait on each other.
+// Shards keep insertion order among their own users only; UUIDv7 IDs sort by
+// creation, which is how listings restore the overall order.
+pub struct ShardedUserStore {
+    shards: Vec<RwLock<UserStore>>,
+    hasher: RandomState,
+}
+
+impl ShardedUserStore {
+    pub fn new(shards: usize) -> Self {
+        ShardedUserStore {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(UserStore::default())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    // The shard holding `id`, for callers that lock it without reporting
+    // contention
+    pub fn shard(&self, id: UserId) -> &RwLock<UserStore> {
+        &self.shards[(self.hasher.hash_one(id) % self.shards.len() as u64) as usize]
+    }
+
+    pub fn read(&self, id: UserId) -> LockResult<RwLockReadGuard<'_, UserStore>> {
+        self.shard(id).read_measured("user_shard")
+    }
+
+    pub fn write(&self, id: UserId) -> LockResult<RwLockWriteGuard<'_, UserStore>> {
+        self.shard(id).write_measured("user_shard")
+    }
+
+    // Every shard, for operations that span all users; each is locked on its
+    // own, so they see no single consistent snapshot
+    pub fn shards(&self) -> &[RwLock<UserStore>] {
+        &self.shards
+    }
+
+    // Users per shard, in shard order
+    pub fn occupancy(&self) -> Vec<usize> {
+        self.shards.iter().map(|shard| shard.read().map_or(0, |shard| shard.len())).collect()
+    }
+}
@@ -0,0 +1,242 @@
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::error::AppError;
+use crate::store::{ConcurrentUserStore, ShardedUserStore, UserStore};
+use crate::{User, UserStatus};
+
+const MAX_THREADS: usize = 64;
+const MAX_OPERATIONS: usize = 1_000_000;
+const MAX_USERS: usize = 100_000;
+// Matches the STORE_SHARDS default
+const SHARDS: usize = 16;
+
+// Workload for POST /admin/store/benchmark. Each thread performs `operations`
+// lookups or updates of random users, `read_ratio` of them lookups.
+#[derive(Deserialize)]
+struct BenchmarkParams {
+    threads: Option<usize>,
+    #[serde(default = "default_operations")]
+    operations: usize,
+    #[serde(default = "default_users")]
+    users: usize,
+    #[serde(default = "default_read_ratio")]
+    read_ratio: f64,
+}
+
+fn default_operations() -> usize {
+    50_000
+}
+
+fn default_users() -> usize {
+    1_000
+}
+
+fn default_read_ratio() -> f64 {
+    0.9
+}
+
+#[derive(Serialize)]
+struct VariantResult {
+    store: &'static str,
+    elapsed_ms: f64,
+    ops_per_sec: f64,
+}
+
+#[derive(Serialize)]
+struct BenchmarkReport {
+    threads: usize,
+    operations: usize,
+    users: usize,
+    read_ratio: f64,
+    results: Vec<VariantResult>,
+}
+
+// The two operations the benchmark drives, implemented by each store variant
+trait BenchStore: Sync {
+    fn lookup(&self, id: Uuid);
+    fn touch(&self, id: Uuid);
+}
+
+impl BenchStore for Mutex<UserStore> {
+    fn lookup(&self, id: Uuid) {
+        let user = self.lock().ok().and_then(|store| store.get(id).cloned());
+        std::hint::black_box(user);
+    }
+
+    fn touch(&self, id: Uuid) {
+        if let Some(user) = self.lock().ok().as_mut().and_then(|store| store.get_mut(id)) {
+            user.updated_at = Utc::now();
+        }
+    }
+}
+
+impl BenchStore for RwLock<UserStore> {
+    fn lookup(&self, id: Uuid) {
+        let user = self.read().ok().and_then(|store| store.get(id).cloned());
+        std::hint::black_box(user);
+    }
+
+    fn touch(&self, id: Uuid) {
+        if let Some(user) = self.write().ok().as_mut().and_then(|store| store.get_mut(id)) {
+            user.updated_at = Utc::now();
+        }
+    }
+}
+
+impl BenchStore for ConcurrentUserStore {
+    fn lookup(&self, id: Uuid) {
+        std::hint::black_box(self.get(id));
+    }
+
+    fn touch(&self, id: Uuid) {
+        self.update(id, |user| user.updated_at = Utc::now());
+    }
+}
+
+impl BenchStore for ShardedUserStore {
+    fn lookup(&self, id: Uuid) {
+        let user = self.shard(id).read().ok().and_then(|shard| shard.get(id).cloned());
+        std::hint::black_box(user);
+    }
+
+    fn touch(&self, id: Uuid) {
+        if let Some(user) = self.shard(id).write().ok().as_mut().and_then(|shard| shard.get_mut(id)) {
+            user.updated_at = Utc::now();
+        }
+    }
+}
+
+fn synthetic_users(count: usize) -> Vec<User> {
+    let now = Utc::now();
+    (0..count)
+        .map(|n| User {
+            id: Uuid::now_v7(),
+            name: format!("Bench User {}", n),
+            email: format!("bench{}@example.com", n),
+            status: UserStatus::Active,
+            created_at: now,
+            updated_at: now,
+            expires_at: None,
+            credentials: None,
+            enrichment: None,
+        })
+        .collect()
+}
+
+// Run the workload on `threads` OS threads at once and time it end to end
+fn run(store: &impl BenchStore, ids: &[Uuid], params: &BenchmarkParams, threads: usize) -> Duration {
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                // xorshift64, seeded per thread so runs are repeatable
+                let mut state = 0x9E37_79B9_7F4A_7C15_u64 ^ (thread as u64 + 1);
+                for _ in 0..params.operations {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let id = ids[(state % ids.len() as u64) as usize];
+                    if (state >> 11) as f64 / (1u64 << 53) as f64 <= params.read_ratio {
+                        store.lookup(id);
+                    } else {
+                        store.touch(id);
+                    }
+                }
+            });
+        }
+    });
+    started.elapsed()
+}
+
+fn result(store: &'static str, elapsed: Duration, total_operations: usize) -> VariantResult {
+    let seconds = elapsed.as_secs_f64();
+    info!(store, elapsed_ms = seconds * 1000.0, "Store benchmark variant finished");
+    VariantResult {
+        store,
+        elapsed_ms: seconds * 1000.0,
+        ops_per_sec: total_operations as f64 / seconds.max(f64::EPSILON),
+    }
+}
+
+fn benchmark(params: BenchmarkParams, threads: usize) -> BenchmarkReport {
+    let users = synthetic_users(params.users);
+    let ids: Vec<Uuid> = users.iter().map(|user| user.id).collect();
+    let total_operations = threads * params.operations;
+
+    let mutex = Mutex::new(users.iter().cloned().collect::<UserStore>());
+    let rwlock = RwLock::new(users.iter().cloned().collect::<UserStore>());
+    let concurrent = ConcurrentUserStore::default();
+    let sharded = ShardedUserStore::new(SHARDS);
+    for user in users {
+        if let Ok(mut shard) = sharded.shard(user.id).write() {
+            shard.insert(user.clone());
+        }
+        concurrent.insert(user);
+    }
+
+    let results = vec![
+        result("mutex", run(&mutex, &ids, &params, threads), total_operations),
+        result("rwlock", run(&rwlock, &ids, &params, threads), total_operations),
+        result("dashmap", run(&concurrent, &ids, &params, threads), total_operations),
+        result("sharded", run(&sharded, &ids, &params, threads), total_operations),
+    ];
+    BenchmarkReport {
+        threads,
+        operations: params.operations,
+        users: params.users,
+        read_ratio: params.read_ratio,
+        results,
+    }
+}
+
+// Handler for POST /admin/store/benchmark
+#[post("/store/benchmark")]
+#[instrument(name = "admin_store_benchmark_handler", skip_all, fields(service = "actix_example"))]
+async fn store_benchmark(
+    identity: web::ReqData<AdminIdentity>,
+    body: web::Json<BenchmarkParams>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let params = body.into_inner();
+    let threads = params
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+    let invalid = if !(1..=MAX_THREADS).contains(&threads) {
+        Some(("threads", format!("threads must be between 1 and {}", MAX_THREADS)))
+    } else if !(1..=MAX_OPERATIONS).contains(&params.operations) {
+        Some(("operations", format!("operations must be between 1 and {}", MAX_OPERATIONS)))
+    } else if !(1..=MAX_USERS).contains(&params.users) {
+        Some(("users", format!("users must be between 1 and {}", MAX_USERS)))
+    } else if !(0.0..=1.0).contains(&params.read_ratio) {
+        Some(("read_ratio", "read_ratio must be between 0 and 1".to_string()))
+    } else {
+        None
+    };
+    if let Some((field, message)) = invalid {
+        return AppError::bad_request("invalid_benchmark", message)
+            .with("field", field)
+            .error_response();
+    }
+
+    audit.record(
+        &identity.0,
+        "store_benchmark",
+        format!("{} thread(s) x {} operation(s)", threads, params.operations),
+    );
+    // Runs on the blocking pool; the synthetic stores never touch live data
+    match web::block(move || benchmark(params, threads)).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(_) => HttpResponse::InternalServerError().body("Store benchmark failed"),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(store_benchmark);
+}
@@ -0,0 +1,216 @@
+use actix_web::web;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::events::StoredEvent;
+use crate::get_env_or_default;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::repository::{NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::telemetry::{observe_store_size, record_store_operation};
+use crate::tenant::{TenantState, Tenants, DEFAULT_TENANT};
+use crate::{Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus};
+
+// Times every call into a backend, as `store.operation.duration` by backend,
+// operation and outcome. It sits directly on the backend, under the WAL and
+// any cache, so cache hits don't dilute the backend's latency. Waits inside
+// an operation are already recorded: `lock.wait_time` for in-process locks
+// and `db.pool.wait_time` for SQL connections.
+pub struct MeasuredUserRepository {
+    inner: Arc<dyn UserRepository>,
+}
+
+impl MeasuredUserRepository {
+    pub fn wrap(inner: Arc<dyn UserRepository>) -> Arc<dyn UserRepository> {
+        Arc::new(MeasuredUserRepository { inner })
+    }
+
+    async fn measured<T>(
+        &self,
+        operation: &'static str,
+        body: impl Future<Output = Result<T, RepositoryError>>,
+    ) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = body.await;
+        record_store_operation(self.inner.name(), operation, started.elapsed(), result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl UserRepository for MeasuredUserRepository {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        self.inner.prepare().await
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.measured("get", self.inner.get(id)).await
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        self.measured("list", self.inner.list(query)).await
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        self.measured("create", self.inner.create(user)).await
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        self.measured("create_many", self.inner.create_many(users)).await
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        self.measured("update", self.inner.update(id, name, email)).await
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.measured("delete", self.inner.delete(id)).await
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        self.measured("delete_expired", self.inner.delete_expired(now)).await
+    }
+
+    async fn restored(&self, removed: &[UserId], users: &[User]) {
+        self.inner.restored(removed, users).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.measured("find_by_email", self.inner.find_by_email(email)).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.measured("get_many", self.inner.get_many(ids)).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        self.measured("count", self.inner.count()).await
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        self.measured("stats", self.inner.stats()).await
+    }
+
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        self.measured("domain_counts", self.inner.domain_counts()).await
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        self.measured("find_duplicates", self.inner.find_duplicates(name, email)).await
+    }
+
+    // A refused transition is an answer rather than a failure of the store
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let started = Instant::now();
+        let result = self.inner.set_status(id, action).await;
+        let ok = !matches!(result, Err(TransitionError::Storage(_)));
+        record_store_operation(self.inner.name(), "set_status", started.elapsed(), ok);
+        result
+    }
+
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        self.measured("credentials", self.inner.credentials(id)).await
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        self.measured("set_credentials", self.inner.set_credentials(id, credentials)).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        self.measured("avatar", self.inner.avatar(id)).await
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        self.measured("set_avatar", self.inner.set_avatar(id, avatar)).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        self.measured("profile", self.inner.profile(id)).await
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        self.measured("set_profile", self.inner.set_profile(id, profile)).await
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        self.measured("charge_quota", self.inner.charge_quota(subject, day, limit)).await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.measured("refund_quota", self.inner.refund_quota(subject, day)).await
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.inner.keeps_history()
+    }
+
+    async fn history(&self, id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        self.measured("history", self.inner.history(id)).await
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.inner.has_outbox()
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.measured("outbox_pending", self.inner.outbox_pending(limit)).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        self.measured("outbox_acknowledge", self.inner.outbox_acknowledge(ids)).await
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        self.inner.size().await
+    }
+}
+
+// Sizes can take a query to work out, so they are sampled in the background
+// every STORE_METRICS_INTERVAL_SECS rather than when metrics are collected
+pub fn sample_interval() -> Duration {
+    Duration::from_secs(get_env_or_default("STORE_METRICS_INTERVAL_SECS", "15").parse().unwrap_or(15).max(1))
+}
+
+// Background task behind the `store.entries` and `store.memory` gauges,
+// started in phase 3. Tenants are sampled once active in this process.
+pub async fn run_sampler(interval: Duration, default_tenant: TenantState, tenants: web::Data<Tenants>) {
+    let backend = default_tenant.repository.name();
+    let sizes: Arc<Mutex<HashMap<String, StoreSize>>> = Arc::default();
+    let observed = sizes.clone();
+    observe_store_size(backend, move || {
+        observed
+            .lock()
+            .map(|sizes| sizes.iter().map(|(tenant, size)| (tenant.clone(), size.entries, size.bytes)).collect())
+            .unwrap_or_default()
+    });
+
+    let mut ticks = actix_web::rt::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let mut sampled = HashMap::new();
+        let active = tenants.active();
+        let states = std::iter::once((DEFAULT_TENANT.to_string(), default_tenant.clone())).chain(active);
+        for (tenant, state) in states {
+            match state.repository.size().await {
+                Ok(size) => {
+                    sampled.insert(tenant, size);
+                }
+                Err(err) => warn!(tenant.id = %tenant, error = %err, "Failed to sample store size"),
+            }
+        }
+        if let Ok(mut sizes) = sizes.lock() {
+            *sizes = sampled;
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use actix_web::web;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::events::StoredEvent;
+use crate::get_env_or_default;
+use crate::locks::MeasuredLock;
+use crate::outbox::OutboxEntry;
+use crate::query::ListQuery;
+use crate::redis_client::RedisClient;
+use crate::repository::{NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::telemetry::record_state_sync;
+use crate::tenant::{Tenants, DEFAULT_TENANT};
+use crate::{
+    AppState, Avatar, DuplicateMatch, Profile, StatusAction, TransitionError, User, UserId, UserStats, UserStatus,
+};
+
+// How long a deleted user's tombstone is kept. Messages delayed longer than
+// this could resurrect the user.
+const TOMBSTONE_TTL: chrono::TimeDelta = chrono::TimeDelta::minutes(10);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// A user mutation as broadcast to the other instances. Puts carry the whole
+// user, so applying one is the same whether it was a create or an update.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    Put { user: Box<User>, password_hash: Option<String> },
+    Delete { id: UserId, deleted_at: DateTime<Utc> },
+}
+
+impl Change {
+    fn put(user: &User) -> Self {
+        let password_hash = user.credentials.as_ref().map(|credentials| credentials.password_hash.clone());
+        Change::Put { user: Box::new(user.clone()), password_hash }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SyncMessage {
+    // The instance that made the change, which ignores its own messages
+    origin: Uuid,
+    tenant: String,
+    sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    change: Change,
+}
+
+// STATE_SYNC=redis keeps the in-memory users of several instances in step:
+// every mutation made through the repository is published on the Redis
+// channel SYNC_CHANNEL and applied by the others. Conflicts resolve by
+// timestamp, the last write winning: a put applies only if its user is newer
+// than the local copy, a delete only if the local copy isn't newer than the
+// deletion. Avatars and profiles stay with the instance they were uploaded
+// to; only the bump of the user's updated_at is broadcast. Messages
+// published while an instance is disconnected are lost to it.
+pub struct StateSync {
+    client: RedisClient,
+    channel: String,
+    instance: Uuid,
+    // When recently deleted users went, so a put delayed behind the delete
+    // can't bring them back
+    tombstones: Mutex<HashMap<(String, UserId), DateTime<Utc>>>,
+}
+
+impl StateSync {
+    pub fn from_env(backend: &str) -> Result<Option<Self>, String> {
+        match crate::config::settings().storage.state_sync.as_str() {
+            "none" => Ok(None),
+            "redis" if backend != "memory" => {
+                Err(format!("STATE_SYNC=redis keeps in-memory users in step; STORAGE_BACKEND={} can't use it", backend))
+            }
+            "redis" => Ok(Some(StateSync {
+                client: RedisClient::from_env()?,
+                channel: get_env_or_default("SYNC_CHANNEL", "user-sync"),
+                instance: Uuid::now_v7(),
+                tombstones: Mutex::new(HashMap::new()),
+            })),
+            other => Err(format!("unknown STATE_SYNC '{}'", other)),
+        }
+    }
+
+    pub fn wrap(self: &Arc<Self>, inner: Arc<dyn UserRepository>, tenant: &str) -> Arc<dyn UserRepository> {
+        Arc::new(SyncedUserRepository { inner, sync: self.clone(), tenant: tenant.to_string() })
+    }
+
+    // Publish a local change. The change already happened here, so a failure
+    // only leaves the other instances behind and is logged rather than returned.
+    async fn broadcast(&self, tenant: &str, change: Change) {
+        let message = SyncMessage { origin: self.instance, tenant: tenant.to_string(), sent_at: Utc::now(), change };
+        let payload = match serde_json::to_string(&message) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(error = %err, "Failed to encode state sync message");
+                return;
+            }
+        };
+        let publish = redis::cmd("PUBLISH").arg(&self.channel).arg(payload).to_owned();
+        if let Err(err) = self.client.query::<()>("PUBLISH", &self.channel, &publish).await {
+            warn!(tenant.id = %tenant, error = %err, "Failed to broadcast user change");
+        }
+    }
+
+    fn bury(&self, tenant: &str, id: UserId, deleted_at: DateTime<Utc>) {
+        if let Ok(mut tombstones) = self.tombstones.lock() {
+            let cutoff = Utc::now() - TOMBSTONE_TTL;
+            tombstones.retain(|_, deleted_at| *deleted_at > cutoff);
+            let entry = tombstones.entry((tenant.to_string(), id)).or_insert(deleted_at);
+            *entry = (*entry).max(deleted_at);
+        }
+    }
+
+    fn deleted_at(&self, tenant: &str, id: UserId) -> Option<DateTime<Utc>> {
+        self.tombstones.lock().ok()?.get(&(tenant.to_string(), id)).copied()
+    }
+
+    // Apply another instance's change unless the local state is newer;
+    // false when it lost
+    fn apply(&self, tenant: &str, state: &mut AppState, change: Change) -> bool {
+        match change {
+            Change::Put { mut user, password_hash } => {
+                let local = state.users.get(user.id).map(|user| user.updated_at);
+                let deleted = self.deleted_at(tenant, user.id);
+                if local.into_iter().chain(deleted).any(|newer| newer >= user.updated_at) {
+                    return false;
+                }
+                user.credentials = password_hash.map(|password_hash| Credentials { password_hash });
+                state.users.insert(*user);
+                true
+            }
+            Change::Delete { id, deleted_at } => {
+                self.bury(tenant, id, deleted_at);
+                if state.users.get(id).is_none_or(|user| user.updated_at > deleted_at) {
+                    return false;
+                }
+                state.users.remove(id);
+                state.forget_user(id);
+                true
+            }
+        }
+    }
+
+    fn receive(&self, payload: &[u8], default_users: &web::Data<RwLock<AppState>>, tenants: &Tenants) {
+        let message: SyncMessage = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(error = %err, "Ignoring malformed state sync message");
+                return;
+            }
+        };
+        if message.origin == self.instance {
+            return;
+        }
+        let users = if message.tenant == DEFAULT_TENANT {
+            default_users.clone()
+        } else {
+            match tenants.users(&message.tenant) {
+                Ok(users) => users,
+                Err(err) => {
+                    warn!(tenant.id = %message.tenant, error = %err, "Ignoring state sync message for unusable tenant");
+                    return;
+                }
+            }
+        };
+        let Ok(mut state) = users.write_measured("app_state") else {
+            warn!("Application state lock is poisoned; dropping state sync message");
+            return;
+        };
+        let applied = self.apply(&message.tenant, &mut state, message.change);
+        let lag = (Utc::now() - message.sent_at).to_std().unwrap_or_default();
+        record_state_sync(lag, applied);
+        debug!(tenant.id = %message.tenant, origin = %message.origin, applied, lag_ms = lag.as_millis() as u64, "Applied state sync message");
+    }
+
+    // Background task applying the other instances' changes, started in
+    // phase 3. Resubscribes after losing the connection.
+    pub async fn run(self: Arc<Self>, default_users: web::Data<RwLock<AppState>>, tenants: web::Data<Tenants>) {
+        loop {
+            match self.client.subscribe(&self.channel).await {
+                Ok(pubsub) => {
+                    info!(channel = %self.channel, instance = %self.instance, "Subscribed to state sync channel");
+                    let mut messages = pubsub.into_on_message();
+                    while let Some(message) = messages.next().await {
+                        self.receive(message.get_payload_bytes(), &default_users, &tenants);
+                    }
+                    warn!(channel = %self.channel, "State sync subscription closed");
+                }
+                Err(err) => warn!(channel = %self.channel, error = %err, "Failed to subscribe to state sync channel"),
+            }
+            actix_web::rt::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+// Broadcasts each mutation after the wrapped repository applied it
+struct SyncedUserRepository {
+    inner: Arc<dyn UserRepository>,
+    sync: Arc<StateSync>,
+    tenant: String,
+}
+
+impl SyncedUserRepository {
+    async fn put(&self, user: Option<&User>) {
+        if let Some(user) = user {
+            self.sync.broadcast(&self.tenant, Change::put(user)).await;
+        }
+    }
+
+    async fn deleted(&self, id: UserId) {
+        let deleted_at = Utc::now();
+        self.sync.bury(&self.tenant, id, deleted_at);
+        self.sync.broadcast(&self.tenant, Change::Delete { id, deleted_at }).await;
+    }
+}
+
+#[async_trait]
+impl UserRepository for SyncedUserRepository {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        self.inner.prepare().await
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.inner.get(id).await
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        self.inner.list(query).await
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let user = self.inner.create(user).await?;
+        self.sync.broadcast(&self.tenant, Change::put(&user)).await;
+        Ok(user)
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        let users = self.inner.create_many(users).await?;
+        for user in &users {
+            self.sync.broadcast(&self.tenant, Change::put(user)).await;
+        }
+        Ok(users)
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.update(id, name, email).await?;
+        if let Some(user) = &updated {
+            self.sync.broadcast(&self.tenant, Change::put(user)).await;
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let deleted = self.inner.delete(id).await?;
+        if deleted.is_some() {
+            self.deleted(id).await;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let expired = self.inner.delete_expired(now).await?;
+        for user in &expired {
+            self.deleted(user.id).await;
+        }
+        Ok(expired)
+    }
+
+    // Peers get every restored user, and a delete for each one that went.
+    // Puts still lose to a newer copy on the peer, as for any other change.
+    async fn restored(&self, removed: &[UserId], users: &[User]) {
+        self.inner.restored(removed, users).await;
+        for id in removed {
+            self.deleted(*id).await;
+        }
+        for user in users {
+            self.sync.broadcast(&self.tenant, Change::put(user)).await;
+        }
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.inner.find_by_email(email).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        self.inner.count().await
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        self.inner.stats().await
+    }
+
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        self.inner.domain_counts().await
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        self.inner.find_duplicates(name, email).await
+    }
+
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        self.inner.credentials(id).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        self.inner.avatar(id).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        self.inner.profile(id).await
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let (from, user) = self.inner.set_status(id, action).await?;
+        self.put(Some(&user)).await;
+        Ok((from, user))
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let updated = self.inner.set_credentials(id, credentials).await?;
+        self.put(updated.as_ref()).await;
+        Ok(updated)
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_avatar(id, avatar).await?;
+        self.put(touched.as_ref()).await;
+        Ok(touched)
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let touched = self.inner.set_profile(id, profile).await?;
+        self.put(touched.as_ref()).await;
+        Ok(touched)
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        self.inner.charge_quota(subject, day, limit).await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.inner.refund_quota(subject, day).await
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.inner.keeps_history()
+    }
+
+    async fn history(&self, id: UserId) -> Result<Option<Vec<StoredEvent>>, RepositoryError> {
+        self.inner.history(id).await
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.inner.has_outbox()
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.inner.outbox_pending(limit).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        self.inner.outbox_acknowledge(ids).await
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        self.inner.size().await
+    }
+}
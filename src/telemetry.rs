@@ -0,0 +1,478 @@
+use opentelemetry::metrics::{Counter, Histogram, Unit};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{global, Context, KeyValue};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Trace ID of the request currently being handled, when it is traced. The
+// RequestTracing middleware makes the request span the current OTel context
+// while the handler runs.
+pub fn current_trace_id() -> Option<String> {
+    let context = Context::current();
+    let span_context = context.span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+// Count a lookup against one of the in-process caches, tagged with the cache
+// name and whether it was served from the cache
+pub fn record_cache_lookup(cache: &'static str, hit: bool) {
+    static LOOKUPS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = LOOKUPS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("cache.lookups")
+            .with_description("In-process cache lookups by result")
+            .init()
+    });
+    counter.add(
+        &Context::current(),
+        1,
+        &[
+            KeyValue::new("cache", cache),
+            KeyValue::new("result", if hit { "hit" } else { "miss" }),
+        ],
+    );
+}
+
+// Count a request refused by the rate limiter, tagged with what it was keyed on
+pub fn record_rate_limited(key_kind: &'static str) {
+    static REJECTED: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = REJECTED.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("http.server.rate_limited")
+            .with_description("Requests rejected by the per-client rate limiter")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &[KeyValue::new("key_kind", key_kind)]);
+}
+
+// Count a circuit breaker moving between states
+pub fn record_circuit_transition(dependency: &str, from: &'static str, to: &'static str) {
+    static TRANSITIONS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = TRANSITIONS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("circuit_breaker.transitions")
+            .with_description("Circuit breaker state changes per outbound dependency")
+            .init()
+    });
+    counter.add(
+        &Context::current(),
+        1,
+        &[
+            KeyValue::new("dependency", dependency.to_string()),
+            KeyValue::new("from", from),
+            KeyValue::new("to", to),
+        ],
+    );
+}
+
+// Count an outbound call refused because its circuit is open
+pub fn record_circuit_rejection(dependency: &str) {
+    static REJECTIONS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = REJECTIONS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("circuit_breaker.rejections")
+            .with_description("Outbound calls short-circuited by an open breaker")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &[KeyValue::new("dependency", dependency.to_string())]);
+}
+
+// Count a request shed by admission control because too many were in flight
+pub fn record_load_shed() {
+    static SHED: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = SHED.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("http.server.load_shed")
+            .with_description("Requests rejected with 503 because the concurrency limit was reached")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &[]);
+}
+
+// Count a connection actix closed because one of its timers ran out:
+// keep_alive, client_request or client_disconnect
+pub fn record_connection_timeout(timeout: &'static str) {
+    static TIMEOUTS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = TIMEOUTS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("http.server.connection_timeouts")
+            .with_description("Connections closed by the keep-alive, client request or client disconnect timeout")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &[KeyValue::new("timeout", timeout)]);
+}
+
+// Count a request served for a tenant, for per-tenant traffic and error rates
+pub fn record_tenant_request(tenant: &str, status: u16) {
+    static REQUESTS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = REQUESTS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("http.server.tenant_requests")
+            .with_description("Requests per tenant by response status")
+            .init()
+    });
+    counter.add(
+        &Context::current(),
+        1,
+        &[
+            KeyValue::new("tenant.id", tenant.to_string()),
+            KeyValue::new("http.status_code", status as i64),
+        ],
+    );
+}
+
+// Count users deleted by the expiry reaper
+pub fn record_user_evictions(tenant: &str, count: u64) {
+    static EVICTIONS: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = EVICTIONS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("users.evicted")
+            .with_description("Users deleted after their TTL expired")
+            .init()
+    });
+    counter.add(&Context::current(), count, &[KeyValue::new("tenant.id", tenant.to_string())]);
+}
+
+// Count outbox entries the relay delivered and removed
+pub fn record_outbox_relayed(tenant: &str, count: u64) {
+    static RELAYED: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = RELAYED.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("outbox.relayed")
+            .with_description("Outbox entries handed to webhook subscribers")
+            .init()
+    });
+    counter.add(&Context::current(), count, &[KeyValue::new("tenant.id", tenant.to_string())]);
+}
+
+// Count an acquisition of a shared-state lock, and for contended ones (the
+// lock was held elsewhere) record how long the caller waited
+pub fn record_lock_acquisition(lock: &'static str, mode: &'static str, wait: Option<Duration>) {
+    static ACQUISITIONS: OnceLock<Counter<u64>> = OnceLock::new();
+    static WAIT: OnceLock<Histogram<f64>> = OnceLock::new();
+    let counter = ACQUISITIONS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("lock.acquisitions")
+            .with_description("Shared-state lock acquisitions by mode and whether they had to wait")
+            .init()
+    });
+    let attributes = [KeyValue::new("lock", lock), KeyValue::new("mode", mode)];
+    counter.add(
+        &Context::current(),
+        1,
+        &[attributes[0].clone(), attributes[1].clone(), KeyValue::new("contended", wait.is_some())],
+    );
+    if let Some(wait) = wait {
+        let histogram = WAIT.get_or_init(|| {
+            global::meter("actix-web-server")
+                .f64_histogram("lock.wait_time")
+                .with_description("Time spent blocked on contended shared-state locks")
+                .with_unit(Unit::new("ms"))
+                .init()
+        });
+        histogram.record(&Context::current(), wait.as_secs_f64() * 1000.0, &attributes);
+    }
+}
+
+// Time taken and bytes written by one periodic state snapshot
+pub fn record_state_snapshot(duration: Duration, bytes: usize) {
+    static DURATION: OnceLock<Histogram<f64>> = OnceLock::new();
+    static SIZE: OnceLock<Histogram<u64>> = OnceLock::new();
+    let duration_histogram = DURATION.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("state.snapshot.duration")
+            .with_description("Time taken to capture and write a state snapshot")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    duration_histogram.record(&Context::current(), duration.as_secs_f64() * 1000.0, &[]);
+    let size_histogram = SIZE.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_histogram("state.snapshot.size")
+            .with_description("Size of written state snapshots")
+            .with_unit(Unit::new("By"))
+            .init()
+    });
+    size_histogram.record(&Context::current(), bytes as u64, &[]);
+}
+
+// Outcome and duration of one run of a scheduled job
+pub fn record_job_run(job: &'static str, succeeded: bool, duration: Duration) {
+    static RUNS: OnceLock<Counter<u64>> = OnceLock::new();
+    static DURATION: OnceLock<Histogram<f64>> = OnceLock::new();
+    let attributes = [
+        KeyValue::new("job", job),
+        KeyValue::new("outcome", if succeeded { "success" } else { "failure" }),
+    ];
+    let counter = RUNS.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("scheduler.job.runs")
+            .with_description("Runs of scheduled jobs, by job and outcome")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &attributes);
+    let histogram = DURATION.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("scheduler.job.duration")
+            .with_description("Time taken by runs of scheduled jobs")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    histogram.record(&Context::current(), duration.as_secs_f64() * 1000.0, &attributes);
+}
+
+// Outcome and duration of one attempt to publish an event to a message broker
+pub fn record_bus_publish(system: &'static str, destination: &str, succeeded: bool, duration: Duration) {
+    static PUBLISHES: OnceLock<Counter<u64>> = OnceLock::new();
+    static DURATION: OnceLock<Histogram<f64>> = OnceLock::new();
+    let attributes = [
+        KeyValue::new("messaging.system", system),
+        KeyValue::new("messaging.destination.name", destination.to_string()),
+        KeyValue::new("outcome", if succeeded { "success" } else { "failure" }),
+    ];
+    let counter = PUBLISHES.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("messaging.publish.attempts")
+            .with_description("Attempts to publish events to message brokers, by broker and outcome")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &attributes);
+    let histogram = DURATION.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("messaging.publish.duration")
+            .with_description("Time until the broker acknowledged or rejected a published event")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    histogram.record(&Context::current(), duration.as_secs_f64() * 1000.0, &attributes);
+}
+
+// An event given up on after every publish attempt failed
+pub fn record_bus_dropped(system: &'static str, destination: &str) {
+    static DROPPED: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = DROPPED.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("messaging.publish.dropped")
+            .with_description("Events never acknowledged by a message broker after all retries")
+            .init()
+    });
+    counter.add(
+        &Context::current(),
+        1,
+        &[
+            KeyValue::new("messaging.system", system),
+            KeyValue::new("messaging.destination.name", destination.to_string()),
+        ],
+    );
+}
+
+// A publish the AMQP broker didn't confirm: "nack" when it rejected the
+// message, "error" when the channel failed first, "disconnected" when there
+// was no connection to publish on
+#[cfg(feature = "amqp")]
+pub fn record_amqp_confirm_failure(exchange: &str, reason: &'static str) {
+    static FAILURES: OnceLock<Counter<u64>> = OnceLock::new();
+    let counter = FAILURES.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("amqp.publish.confirm_failures")
+            .with_description("AMQP publishes without a broker confirmation, by reason")
+            .init()
+    });
+    counter.add(
+        &Context::current(),
+        1,
+        &[KeyValue::new("messaging.destination.name", exchange.to_string()), KeyValue::new("reason", reason)],
+    );
+}
+
+// Export how many messages the Kafka producer holds that the brokers haven't
+// acknowledged yet, read whenever metrics are collected
+#[cfg(feature = "kafka")]
+pub fn observe_kafka_queue(in_flight: impl Fn() -> u64 + Send + Sync + 'static) {
+    let meter = global::meter("actix-web-server");
+    let queued = meter
+        .u64_observable_gauge("kafka.producer.queue")
+        .with_description("Messages queued in the Kafka producer awaiting acknowledgement")
+        .init();
+    let registered = meter.register_callback(move |cx| queued.observe(cx, in_flight(), &[]));
+    if let Err(err) = registered {
+        tracing::warn!(error = %err, "Failed to register Kafka producer gauge");
+    }
+}
+
+// Export the global allocator's memory counters and fragmentation, read
+// whenever metrics are collected
+#[cfg(feature = "jemalloc")]
+pub fn observe_allocator(stats: impl Fn() -> Option<crate::memory::AllocatorStats> + Send + Sync + 'static) {
+    let meter = global::meter("actix-web-server");
+    let memory = meter
+        .u64_observable_gauge("process.memory.allocator")
+        .with_description("Memory held by the allocator, by state")
+        .with_unit(Unit::new("By"))
+        .init();
+    let fragmentation = meter
+        .f64_observable_gauge("process.memory.allocator.fragmentation")
+        .with_description("Share of active allocator memory not holding live allocations")
+        .init();
+    let registered = meter.register_callback(move |cx| {
+        let Some(stats) = stats() else {
+            return;
+        };
+        for (state, bytes) in [
+            ("allocated", stats.allocated),
+            ("active", stats.active),
+            ("resident", stats.resident),
+            ("mapped", stats.mapped),
+            ("retained", stats.retained),
+            ("metadata", stats.metadata),
+        ] {
+            memory.observe(cx, bytes, &[KeyValue::new("state", state)]);
+        }
+        fragmentation.observe(cx, stats.fragmentation, &[]);
+    });
+    if let Err(err) = registered {
+        tracing::warn!(error = %err, "Failed to register allocator gauges");
+    }
+}
+
+// Export a database pool's size and idle connection count, read whenever
+// metrics are collected
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub fn observe_pool(system: &'static str, stats: impl Fn() -> (u64, u64) + Send + Sync + 'static) {
+    let meter = global::meter("actix-web-server");
+    let size = meter
+        .u64_observable_gauge("db.pool.size")
+        .with_description("Open connections in the database pool")
+        .init();
+    let idle = meter
+        .u64_observable_gauge("db.pool.idle")
+        .with_description("Idle connections in the database pool")
+        .init();
+    let registered = meter.register_callback(move |cx| {
+        let (open, available) = stats();
+        let attributes = [KeyValue::new("db.system", system)];
+        size.observe(cx, open, &attributes);
+        idle.observe(cx, available, &attributes);
+    });
+    if let Err(err) = registered {
+        tracing::warn!(error = %err, "Failed to register database pool gauges");
+    }
+}
+
+// Export how many users each shard of the sharded store holds, so an uneven
+// spread shows up next to lock wait times
+pub fn observe_shards(tenant: &str, occupancy: impl Fn() -> Vec<usize> + Send + Sync + 'static) {
+    let tenant = tenant.to_string();
+    let users = global::meter("actix-web-server")
+        .u64_observable_gauge("store.shard.users")
+        .with_description("Users held by each shard of the sharded store")
+        .init();
+    let registered = global::meter("actix-web-server").register_callback(move |cx| {
+        for (shard, count) in occupancy().into_iter().enumerate() {
+            let attributes = [KeyValue::new("shard", shard as i64), KeyValue::new("tenant.id", tenant.clone())];
+            users.observe(cx, count as u64, &attributes);
+        }
+    });
+    if let Err(err) = registered {
+        tracing::warn!(error = %err, "Failed to register store shard gauge");
+    }
+}
+
+// Time one repository operation took on the storage backend
+pub fn record_store_operation(backend: &'static str, operation: &'static str, duration: Duration, ok: bool) {
+    static DURATION: OnceLock<Histogram<f64>> = OnceLock::new();
+    let histogram = DURATION.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("store.operation.duration")
+            .with_description("Time taken by user storage operations")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    histogram.record(
+        &Context::current(),
+        duration.as_secs_f64() * 1000.0,
+        &[
+            KeyValue::new("store.backend", backend),
+            KeyValue::new("operation", operation),
+            KeyValue::new("outcome", if ok { "ok" } else { "error" }),
+        ],
+    );
+}
+
+// Export each tenant's user count and estimated storage size, as last
+// sampled; backends that can't estimate size report only the count
+pub fn observe_store_size(backend: &'static str, sizes: impl Fn() -> Vec<(String, u64, Option<u64>)> + Send + Sync + 'static) {
+    let meter = global::meter("actix-web-server");
+    let entries = meter
+        .u64_observable_gauge("store.entries")
+        .with_description("Users held by the storage backend")
+        .init();
+    let memory = meter
+        .u64_observable_gauge("store.memory")
+        .with_description("Estimated space the stored users take")
+        .with_unit(Unit::new("By"))
+        .init();
+    let registered = meter.register_callback(move |cx| {
+        for (tenant, count, bytes) in sizes() {
+            let attributes = [KeyValue::new("store.backend", backend), KeyValue::new("tenant.id", tenant)];
+            entries.observe(cx, count, &attributes);
+            if let Some(bytes) = bytes {
+                memory.observe(cx, bytes, &attributes);
+            }
+        }
+    });
+    if let Err(err) = registered {
+        tracing::warn!(error = %err, "Failed to register store size gauges");
+    }
+}
+
+// A change received from another instance: how long after it was sent it
+// arrived, and whether it applied or lost to a newer local state
+#[cfg(feature = "redis")]
+pub fn record_state_sync(lag: Duration, applied: bool) {
+    static LAG: OnceLock<Histogram<f64>> = OnceLock::new();
+    static MESSAGES: OnceLock<Counter<u64>> = OnceLock::new();
+    let histogram = LAG.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("state_sync.lag")
+            .with_description("Time between another instance publishing a user change and it arriving here")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    histogram.record(&Context::current(), lag.as_secs_f64() * 1000.0, &[]);
+    let counter = MESSAGES.get_or_init(|| {
+        global::meter("actix-web-server")
+            .u64_counter("state_sync.messages")
+            .with_description("User changes received from other instances, by whether they applied")
+            .init()
+    });
+    counter.add(&Context::current(), 1, &[KeyValue::new("outcome", if applied { "applied" } else { "stale" })]);
+}
+
+// Time a request waited for a pooled database connection, and whether it gave
+// up because the pool stayed exhausted
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+pub fn record_pool_acquire(system: &'static str, wait: Duration, timed_out: bool) {
+    static WAIT: OnceLock<Histogram<f64>> = OnceLock::new();
+    static TIMEOUTS: OnceLock<Counter<u64>> = OnceLock::new();
+    let attributes = [KeyValue::new("db.system", system)];
+    let histogram = WAIT.get_or_init(|| {
+        global::meter("actix-web-server")
+            .f64_histogram("db.pool.wait_time")
+            .with_description("Time spent waiting for a connection from the database pool")
+            .with_unit(Unit::new("ms"))
+            .init()
+    });
+    histogram.record(&Context::current(), wait.as_secs_f64() * 1000.0, &attributes);
+    if timed_out {
+        let counter = TIMEOUTS.get_or_init(|| {
+            global::meter("actix-web-server")
+                .u64_counter("db.pool.acquire_timeouts")
+                .with_description("Connection requests that timed out waiting on an exhausted pool")
+                .init()
+        });
+        counter.add(&Context::current(), 1, &attributes);
+    }
+}
@@ -0,0 +1,236 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use jsonwebtoken::Algorithm;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::auth::{TokenIssuer, TokenType};
+use crate::error::AppError;
+use crate::repository::SharedUserRepository;
+use crate::storage::Storage;
+use crate::telemetry::record_tenant_request;
+use crate::{get_env_or_default, AppState, ProfileState};
+
+pub const TENANT_HEADER: &str = "X-Tenant-Id";
+
+// Requests without a tenant use the state registered on the App
+pub const DEFAULT_TENANT: &str = "default";
+
+// Tenant a request was resolved to, stored in request extensions
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantId(pub String);
+
+impl TenantId {
+    pub fn is_default(&self) -> bool {
+        self.0 == DEFAULT_TENANT
+    }
+
+    // The JWT claim for tokens issued in this tenant; the default tenant has none
+    pub fn claim(&self) -> Option<String> {
+        (!self.is_default()).then(|| self.0.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct TenantState {
+    pub users: web::Data<RwLock<AppState>>,
+    pub profiles: web::Data<RwLock<ProfileState>>,
+    pub repository: SharedUserRepository,
+}
+
+// Per-tenant user and profile state. Tenants are created on first use; TENANTS
+// restricts them to a comma-separated list and MAX_TENANTS caps how many
+// unlisted ones may be created.
+pub struct Tenants {
+    storage: Storage,
+    allowed: Option<HashSet<String>>,
+    max_tenants: usize,
+    states: RwLock<HashMap<String, TenantState>>,
+}
+
+impl Tenants {
+    pub fn from_env(storage: Storage) -> Self {
+        let allowed = std::env::var("TENANTS").ok().filter(|list| !list.trim().is_empty()).map(|list| {
+            list.split(',')
+                .map(|tenant| tenant.trim().to_string())
+                .filter(|tenant| !tenant.is_empty())
+                .collect()
+        });
+        Tenants {
+            storage,
+            allowed,
+            max_tenants: get_env_or_default("MAX_TENANTS", "100").parse().unwrap_or(100),
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn allowed(&self) -> Option<&HashSet<String>> {
+        self.allowed.as_ref()
+    }
+
+    // Every tenant created so far, for background tasks that visit them all
+    pub fn active(&self) -> Vec<(String, TenantState)> {
+        self.states
+            .read()
+            .map(|states| states.iter().map(|(tenant, state)| (tenant.clone(), state.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    // The requests without a tenant as DEFAULT_TENANT, then every active tenant
+    pub fn all(&self, default: &TenantState) -> Vec<(String, TenantState)> {
+        std::iter::once((DEFAULT_TENANT.to_string(), default.clone())).chain(self.active()).collect()
+    }
+
+    // The tenant's user and profile state, created on first use
+    fn state(&self, tenant: &str) -> Result<TenantState, AppError> {
+        if self.allowed.as_ref().is_some_and(|allowed| !allowed.contains(tenant)) {
+            return Err(unknown_tenant(tenant));
+        }
+
+        let existing = self.states.read().ok().and_then(|states| states.get(tenant).cloned());
+        match existing {
+            Some(state) => Ok(state),
+            None => {
+                let Ok(mut states) = self.states.write() else {
+                    return Err(AppError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal_error",
+                        "Failed to lock tenant state",
+                    ));
+                };
+                if !states.contains_key(tenant) && self.allowed.is_none() && states.len() >= self.max_tenants {
+                    warn!(tenant.id = %tenant, max_tenants = self.max_tenants, "Tenant limit reached");
+                    return Err(unknown_tenant(tenant));
+                }
+                let state = states.entry(tenant.to_string()).or_insert_with(|| {
+                    info!(tenant.id = %tenant, "Created tenant state");
+                    let users = web::Data::new(RwLock::new(AppState::new()));
+                    let profiles = web::Data::new(RwLock::new(ProfileState::default()));
+                    TenantState {
+                        repository: self.storage.repository(tenant, users.clone(), profiles.clone()),
+                        users,
+                        profiles,
+                    }
+                });
+                Ok(state.clone())
+            }
+        }
+    }
+
+    // State of a non-default tenant, for admin endpoints acting on its behalf
+    pub fn users(&self, tenant: &str) -> Result<web::Data<RwLock<AppState>>, AppError> {
+        if !valid_tenant_id(tenant) {
+            return Err(unknown_tenant(tenant));
+        }
+        self.state(tenant).map(|state| state.users)
+    }
+
+    // App data container that makes handlers see the tenant's state
+    fn container(&self, tenant: &str) -> Result<Extensions, AppError> {
+        let state = self.state(tenant)?;
+        let mut container = Extensions::new();
+        container.insert(state.users);
+        container.insert(state.profiles);
+        container.insert(state.repository);
+        Ok(container)
+    }
+}
+
+fn unknown_tenant(tenant: &str) -> AppError {
+    AppError::new(StatusCode::FORBIDDEN, "unknown_tenant", format!("Tenant '{}' is not available", tenant))
+        .with("tenant", tenant)
+}
+
+fn valid_tenant_id(tenant: &str) -> bool {
+    (1..=64).contains(&tenant.len())
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// The `tenant` claim of one of our own access tokens. Tokens that don't
+// verify are left for `authenticate` to reject.
+fn token_tenant(req: &ServiceRequest) -> Option<Option<String>> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))?
+        .trim();
+    let algorithm = jsonwebtoken::decode_header(token).ok()?.alg;
+    if algorithm != Algorithm::HS256 {
+        return None;
+    }
+    let issuer = req.app_data::<web::Data<TokenIssuer>>()?;
+    issuer.verify(token, TokenType::Access).ok().map(|claims| claims.tenant)
+}
+
+// Scope-level middleware, outside `authenticate` so API keys, tokens and
+// sessions are all looked up in the tenant's state. The tenant comes from
+// X-Tenant-Id or the access token's `tenant` claim; when both are present
+// they must agree. Handlers extracting `web::Data<RwLock<AppState>>` get the
+// tenant's copy through a request-level app data container, and the tenant
+// is recorded as `tenant.id` on the span and in metrics.
+pub async fn resolve(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(tenants) = req.app_data::<web::Data<Tenants>>().cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let header_tenant = req
+        .headers()
+        .get(TENANT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+    if let Some(tenant) = header_tenant.as_deref().filter(|tenant| !valid_tenant_id(tenant)) {
+        let response = AppError::bad_request(
+            "invalid_tenant_id",
+            "X-Tenant-Id must be 1-64 letters, digits, '-' or '_'",
+        )
+        .with("tenant", tenant)
+        .error_response();
+        return Ok(req.into_response(response));
+    }
+
+    let claim_tenant = token_tenant(&req).map(|claim| claim.unwrap_or_else(|| DEFAULT_TENANT.to_string()));
+    let tenant = match (header_tenant, claim_tenant) {
+        (Some(header), Some(claim)) if header != claim => {
+            info!(tenant.header = %header, tenant.claim = %claim, "Rejected token used outside its tenant");
+            let response = AppError::new(
+                StatusCode::FORBIDDEN,
+                "tenant_mismatch",
+                "The access token was issued for a different tenant",
+            )
+            .error_response();
+            return Ok(req.into_response(response));
+        }
+        (Some(tenant), _) | (None, Some(tenant)) => TenantId(tenant),
+        (None, None) => TenantId(DEFAULT_TENANT.to_string()),
+    };
+
+    if !tenant.is_default() {
+        match tenants.container(&tenant.0) {
+            Ok(container) => req.add_data_container(Rc::new(container)),
+            Err(app_error) => return Ok(req.into_response(app_error.error_response())),
+        }
+    }
+
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("tenant.id", tenant.0.clone()));
+    req.extensions_mut().insert(tenant.clone());
+
+    let res = next.call(req).await?;
+    record_tenant_request(&tenant.0, res.status().as_u16());
+    Ok(res.map_into_boxed_body())
+}
@@ -0,0 +1,91 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ResourceDef, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::error::InternalError;
+use actix_web::{web, Error, ResponseError};
+use opentelemetry::trace::{Status, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::get_env_or_default;
+
+// How long a request may take before it is abandoned with a 504. Routes
+// listed in REQUEST_TIMEOUT_OVERRIDES, e.g. "/users/{id}/avatar=120000",
+// get their own limit in milliseconds.
+#[derive(Clone, Debug)]
+pub struct TimeoutConfig {
+    pub default: Duration,
+    pub overrides: Vec<(String, Duration)>,
+}
+
+impl TimeoutConfig {
+    pub fn from_env() -> Self {
+        let default_ms = get_env_or_default("REQUEST_TIMEOUT_MS", "30000").parse().unwrap_or(30_000);
+        let overrides = get_env_or_default("REQUEST_TIMEOUT_OVERRIDES", "")
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .filter_map(|entry| {
+                let parsed = entry
+                    .split_once('=')
+                    .and_then(|(pattern, ms)| Some((pattern.trim().to_string(), ms.trim().parse().ok()?)));
+                if parsed.is_none() {
+                    warn!(entry = %entry, "Ignoring malformed REQUEST_TIMEOUT_OVERRIDES entry");
+                }
+                parsed.map(|(pattern, ms)| (pattern, Duration::from_millis(ms)))
+            })
+            .collect();
+        TimeoutConfig {
+            default: Duration::from_millis(default_ms),
+            overrides,
+        }
+    }
+
+    fn for_path(&self, path: &str) -> Duration {
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| ResourceDef::new(pattern.as_str()).is_match(path))
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.default)
+    }
+}
+
+// Scope-level middleware, inside RequestTracing. When the deadline passes the
+// rest of the chain is dropped, which cancels the handler at its next await
+// point and closes its spans; the request span is marked `http.timed_out`
+// with an error status so abandoned requests stand out in traces.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limit) = req
+        .app_data::<web::Data<TimeoutConfig>>()
+        .map(|config| config.for_path(req.path()))
+    else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+    let path = req.path().to_string();
+
+    match actix_web::rt::time::timeout(limit, next.call(req)).await {
+        Ok(result) => result.map(ServiceResponse::map_into_boxed_body),
+        Err(_) => {
+            warn!(path = %path, timeout_ms = limit.as_millis() as u64, "Request timed out");
+            let span_context = Context::current();
+            let span = span_context.span();
+            span.set_attribute(KeyValue::new("http.timed_out", true));
+            span.set_status(Status::error("request timed out"));
+
+            let response = AppError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "request_timeout",
+                format!("Request did not complete within {} ms", limit.as_millis()),
+            )
+            .error_response();
+            // The request went down with the dropped chain, so the response
+            // travels back as an error that renders to the prepared 504
+            Err(InternalError::from_response("request timed out", response).into())
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::Version;
+use actix_web::middleware::Next;
+use actix_web::Error;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::any::Any;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::config;
+
+// TLS for the API listeners, set up when server.tls_cert_file and
+// server.tls_key_file are configured. The certificate is served from memory
+// and reloaded on SIGHUP or when either file changes, so renewed certificates
+// take effect without a restart; a reload that fails keeps the old one.
+pub struct Certificates {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // Modification times of the files behind `current`
+    loaded: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl Certificates {
+    pub fn from_env() -> Result<Option<Arc<Self>>, String> {
+        let settings = &config::settings().server;
+        let (cert_file, key_file) = match (&settings.tls_cert_file, &settings.tls_key_file) {
+            (None, None) => return Ok(None),
+            (Some(cert_file), Some(key_file)) => (PathBuf::from(cert_file), PathBuf::from(key_file)),
+            _ => return Err("TLS needs both TLS_CERT_FILE and TLS_KEY_FILE".to_string()),
+        };
+        let loaded = (modified(&cert_file), modified(&key_file));
+        let current = load(&cert_file, &key_file)?;
+        Ok(Some(Arc::new(Certificates {
+            cert_file,
+            key_file,
+            current: RwLock::new(Arc::new(current)),
+            loaded: RwLock::new(loaded),
+        })))
+    }
+
+    // Config for HttpServer::bind_rustls_0_23. actix offers h2 and http/1.1
+    // over ALPN itself, so clients that support it get HTTP/2.
+    pub fn server_config(self: &Arc<Self>) -> rustls::ServerConfig {
+        rustls::ServerConfig::builder().with_no_client_auth().with_cert_resolver(self.clone())
+    }
+
+    pub fn reload(&self, reason: &str) {
+        let loaded = (modified(&self.cert_file), modified(&self.key_file));
+        match load(&self.cert_file, &self.key_file) {
+            Ok(certified) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = Arc::new(certified);
+                }
+                info!(reason, cert_file = %self.cert_file.display(), "TLS certificate reloaded");
+            }
+            Err(err) => warn!(reason, error = %err, "Failed to reload TLS certificate; keeping the current one"),
+        }
+        // Recorded either way, so a broken file is reported once rather than
+        // on every check
+        if let Ok(mut recorded) = self.loaded.write() {
+            *recorded = loaded;
+        }
+    }
+
+    fn changed(&self) -> bool {
+        let current = (modified(&self.cert_file), modified(&self.key_file));
+        self.loaded.read().map(|loaded| *loaded != current).unwrap_or(false)
+    }
+
+    // Background task reloading on SIGHUP, started in phase 3
+    pub async fn reload_on_hangup(self: Arc<Self>) {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                warn!(error = %err, "Failed to listen for SIGHUP; TLS certificates reload on file changes only");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            self.reload("sighup");
+        }
+    }
+
+    // Background task checking the files for changes every
+    // TLS_WATCH_INTERVAL_SECS, started in phase 3 unless that is 0
+    pub async fn watch_files(self: Arc<Self>, interval: Duration) {
+        let mut ticks = actix_web::rt::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if self.changed() {
+                self.reload("file_changed");
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificates").field("cert_file", &self.cert_file).finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+// What a TLS handshake settled on, kept with the connection for the spans of
+// its requests
+#[derive(Clone)]
+struct Negotiated {
+    version: &'static str,
+    alpn: Option<String>,
+}
+
+// HttpServer::on_connect hook. Connections accepted without TLS (the admin
+// listeners) have nothing to record.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<actix_web::rt::net::TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    let version = match session.protocol_version() {
+        Some(rustls::ProtocolVersion::TLSv1_3) => "1.3",
+        Some(rustls::ProtocolVersion::TLSv1_2) => "1.2",
+        _ => "unknown",
+    };
+    let alpn = session.alpn_protocol().map(|protocol| String::from_utf8_lossy(protocol).into_owned());
+    extensions.insert(Negotiated { version, alpn });
+}
+
+// Scope-level middleware, inside RequestTracing, that records the HTTP
+// version a request arrived over and, under TLS, the negotiated TLS version
+// and ALPN protocol on the request span
+pub async fn record_protocol(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let version = match req.version() {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_11 => "1.1",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "unknown",
+    };
+    let negotiated = req.conn_data::<Negotiated>().cloned();
+    let span_context = Context::current();
+    let span = span_context.span();
+    span.set_attribute(KeyValue::new("network.protocol.version", version));
+    if let Some(negotiated) = negotiated {
+        span.set_attribute(KeyValue::new("tls.protocol.version", negotiated.version));
+        if let Some(alpn) = negotiated.alpn {
+            span.set_attribute(KeyValue::new("tls.next_protocol", alpn));
+        }
+    }
+
+    next.call(req).await
+}
+
+pub fn watch_interval() -> Duration {
+    Duration::from_secs(config::settings().server.tls_watch_interval_secs)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Read a PEM certificate chain and private key, checking they belong together
+fn load(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("failed to read TLS certificate {}: {}", cert_file.display(), err))?;
+    if chain.is_empty() {
+        return Err(format!("no certificate found in {}", cert_file.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|err| format!("failed to read TLS key {}: {}", key_file.display(), err))?;
+    let provider = rustls::crypto::ring::default_provider();
+    CertifiedKey::from_der(chain, key, &provider)
+        .map_err(|err| format!("TLS key {} doesn't fit certificate {}: {}", key_file.display(), cert_file.display(), err))
+}
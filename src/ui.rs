@@ -0,0 +1,68 @@
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use rust_embed::RustEmbed;
+use tracing::instrument;
+
+use crate::base_path;
+use crate::routes;
+use crate::telemetry::current_trace_id;
+
+// The demo frontend under ui/, compiled into the binary. Debug builds read
+// the files from disk instead, so edits show up on reload.
+#[derive(RustEmbed)]
+#[folder = "ui/"]
+struct Assets;
+
+// The page loads its own script and stylesheet and calls the API on the same
+// origin; the API's default policy allows none of that
+const UI_CSP: &str =
+    "default-src 'none'; script-src 'self'; style-src 'self'; connect-src 'self'; frame-ancestors 'none'; base-uri 'self'; form-action 'none'";
+
+// DEMO_UI=false leaves /ui unregistered
+pub fn is_enabled() -> bool {
+    crate::get_env_or_default("DEMO_UI", "true") != "false"
+}
+
+// Handler for GET /ui and the assets below it. The tail also matches /uiabc,
+// which goes to the default service instead.
+#[get("/ui{tail:.*}")]
+#[instrument(name = "ui_handler", skip(req), fields(service = "actix_example"))]
+pub async fn ui(req: HttpRequest, tail: web::Path<String>) -> impl Responder {
+    let file = match tail.as_str() {
+        "" | "/" => "index.html",
+        tail => match tail.strip_prefix('/') {
+            Some(file) => file,
+            None => return routes::default_handler(req).await,
+        },
+    };
+    let Some(asset) = Assets::get(file) else {
+        return routes::default_handler(req).await;
+    };
+
+    if file == "index.html" {
+        // Filled in per request: the base path for relative links, and the
+        // trace this page load belongs to
+        let page = String::from_utf8_lossy(&asset.data)
+            .replace("{{base_href}}", &base_path::external(&req, "/ui/"))
+            .replace("{{trace_id}}", &current_trace_id().unwrap_or_default());
+        return HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .insert_header((header::CONTENT_SECURITY_POLICY, UI_CSP))
+            .body(page);
+    }
+
+    let etag = crate::compute_etag(&asset.data);
+    if crate::etag_matches(&req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(asset.metadata.mimetype())
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, UI_CSP))
+        .body(asset.data.into_owned())
+}
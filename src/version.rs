@@ -0,0 +1,44 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::instrument;
+
+use crate::storage::StorageInfo;
+
+// Build metadata embedded by build.rs, plus the storage picked at startup.
+// build_timestamp is when build.rs last ran (new commit, checkout or
+// migration), not when the binary was last relinked.
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    build_timestamp: Option<DateTime<Utc>>,
+    rustc_version: &'static str,
+    features: Vec<&'static str>,
+    storage: StorageInfo,
+}
+
+impl VersionInfo {
+    fn current(storage: StorageInfo) -> Self {
+        let build_timestamp = env!("BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0));
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("BUILD_GIT_COMMIT"),
+            build_timestamp,
+            rustc_version: env!("BUILD_RUSTC_VERSION"),
+            features: env!("BUILD_FEATURES").split(',').filter(|f| !f.is_empty()).collect(),
+            storage,
+        }
+    }
+}
+
+// Handler for GET /version
+#[get("/version")]
+#[instrument(name = "version_handler", skip(storage), fields(service = "actix_example"))]
+pub async fn version(storage: web::Data<StorageInfo>) -> impl Responder {
+    HttpResponse::Ok().json(VersionInfo::current(storage.get_ref().clone()))
+}
@@ -0,0 +1,434 @@
+use actix_web::web;
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::auth::Credentials;
+use crate::backup::StateSnapshot;
+use crate::get_env_or_default;
+use crate::locks::MeasuredLock;
+use crate::outbox::OutboxEntry;
+use crate::persistence::{capture_json, write_atomically};
+use crate::query::ListQuery;
+use crate::repository::{NewUser, RepositoryError, StoreSize, UserRepository};
+use crate::{
+    AppState, Avatar, DuplicateMatch, Profile, ProfileState, StatusAction, TransitionError, User, UserId, UserStats,
+    UserStatus,
+};
+
+// One line of the log. Creates and updates carry the whole user as it was
+// stored, and avatars and profiles the whole value, so replaying an entry
+// twice (e.g. one also covered by the last compaction) is harmless. Status
+// and password changes are logged as updates.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Mutation {
+    Create { user: User, password_hash: Option<String> },
+    Update { user: User, password_hash: Option<String> },
+    Delete { id: UserId },
+    // Image bytes base64-encoded, as in snapshots
+    Avatar { id: UserId, content_type: String, etag: String, data: String },
+    Profile { id: UserId, profile: Profile },
+}
+
+impl Mutation {
+    fn put(user: &User, create: bool) -> Self {
+        let password_hash = user.credentials.as_ref().map(|credentials| credentials.password_hash.clone());
+        match create {
+            true => Mutation::Create { user: user.clone(), password_hash },
+            false => Mutation::Update { user: user.clone(), password_hash },
+        }
+    }
+
+    fn avatar(id: UserId, avatar: &Avatar) -> Self {
+        Mutation::Avatar {
+            id,
+            content_type: avatar.content_type.clone(),
+            etag: avatar.etag.clone(),
+            data: base64::engine::general_purpose::STANDARD.encode(&avatar.data),
+        }
+    }
+
+    fn apply(self, state: &mut AppState, profiles: &mut ProfileState) -> Result<(), String> {
+        match self {
+            Mutation::Create { mut user, password_hash } | Mutation::Update { mut user, password_hash } => {
+                user.credentials = password_hash.map(|password_hash| Credentials { password_hash });
+                // Keep sequential IDs issued later from colliding with replayed ones
+                if user.id.as_u128() <= u32::MAX as u128 {
+                    state.user_counter = state.user_counter.max(user.id.as_u128() as u32);
+                }
+                state.users.insert(user);
+            }
+            Mutation::Delete { id } => {
+                state.users.remove(id);
+                state.avatars.remove(&id);
+                profiles.profiles.remove(&id);
+            }
+            Mutation::Avatar { id, content_type, etag, data } => {
+                let data = base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|err| format!("avatar of user {} is not valid base64: {}", id, err))?;
+                state.avatars.insert(id, Avatar { content_type, data: data.into(), etag });
+            }
+            Mutation::Profile { id, profile } => {
+                profiles.profiles.insert(id, profile);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct LogFile {
+    // Shared with the blocking pool, which does the writing
+    file: Arc<File>,
+    // Entries written since the last compaction
+    entries: usize,
+    // Set when the state changed in a way the log doesn't hold, so the next
+    // compaction runs even with no entries
+    dirty: bool,
+}
+
+// Append-only log of user mutations for the in-memory store. State is rebuilt
+// at boot from the last compacted snapshot plus the log; compaction
+// periodically folds the log into a fresh snapshot and truncates it. Only the
+// default tenant is logged, like the state file.
+pub struct MutationLog {
+    path: PathBuf,
+    snapshot_path: PathBuf,
+    compact_every: Duration,
+    // Held across applying a mutation and logging it, so the log's order is
+    // the order mutations were applied in, and compaction can't slip between
+    file: Mutex<LogFile>,
+}
+
+impl MutationLog {
+    // Enabled by WAL_FILE; the compacted snapshot lives next to it with a
+    // ".snapshot" suffix. WAL_COMPACT_SECS sets the compaction interval.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Some(path) = std::env::var("WAL_FILE").ok().filter(|path| !path.is_empty()).map(PathBuf::from) else {
+            return Ok(None);
+        };
+        let mut snapshot_path = path.as_os_str().to_owned();
+        snapshot_path.push(".snapshot");
+        let compact_secs: u64 = get_env_or_default("WAL_COMPACT_SECS", "300").parse().unwrap_or(300);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| format!("cannot open WAL_FILE {}: {}", path.display(), err))?;
+        Ok(Some(MutationLog {
+            path,
+            snapshot_path: PathBuf::from(snapshot_path),
+            compact_every: Duration::from_secs(compact_secs.max(1)),
+            file: Mutex::new(LogFile { file: Arc::new(file), entries: 0, dirty: false }),
+        }))
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub fn wrap(self: &Arc<Self>, inner: Arc<dyn UserRepository>) -> Arc<dyn UserRepository> {
+        Arc::new(LoggedUserRepository { inner, log: self.clone() })
+    }
+
+    // Rebuild the state from the snapshot and the log, returning the number of
+    // log entries replayed. A torn final line, left by a crash mid-append, is
+    // dropped; damage anywhere else fails the replay.
+    #[instrument(name = "replay_wal", skip_all, fields(wal.path = %self.path.display()))]
+    pub async fn replay(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<usize, String> {
+        let mut log = self.file.lock().await;
+        let snapshot = match std::fs::read(&self.snapshot_path) {
+            Ok(contents) => Some(
+                serde_json::from_slice::<StateSnapshot>(&contents)
+                    .map_err(|err| format!("{} is not a valid snapshot: {}", self.snapshot_path.display(), err))?,
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(format!("cannot read {}: {}", self.snapshot_path.display(), err)),
+        };
+        let file = File::open(&self.path).map_err(|err| format!("cannot read {}: {}", self.path.display(), err))?;
+        let lines = BufReader::new(file)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("cannot read {}: {}", self.path.display(), err))?;
+        let mut mutations = Vec::with_capacity(lines.len());
+        for (number, line) in lines.iter().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            match serde_json::from_str::<Mutation>(line) {
+                Ok(mutation) => mutations.push(mutation),
+                Err(err) if number + 1 == lines.len() => warn!(error = %err, "Dropping torn last WAL entry"),
+                Err(err) => return Err(format!("{} line {}: {}", self.path.display(), number + 1, err)),
+            }
+        }
+
+        let mut app_state = data.write_measured("app_state").map_err(|_| "application state lock is poisoned")?;
+        let mut profile_state = profiles.write_measured("profiles").map_err(|_| "profile state lock is poisoned")?;
+        if let Some(snapshot) = snapshot {
+            let users = snapshot
+                .restore(&mut app_state, &mut profile_state)
+                .map_err(|err| format!("{}: {}", self.snapshot_path.display(), err))?;
+            info!(users, "WAL snapshot loaded");
+        }
+        let replayed = mutations.len();
+        for mutation in mutations {
+            mutation.apply(&mut app_state, &mut profile_state).map_err(|err| format!("{}: {}", self.path.display(), err))?;
+        }
+        app_state.reindex_emails();
+        app_state.domain_stats = None;
+        log.entries = replayed;
+        Ok(replayed)
+    }
+
+    // Write and sync the entries on the blocking pool, keeping the worker free
+    async fn append(&self, log: &mut LogFile, mutations: &[Mutation]) -> Result<(), RepositoryError> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Vec::new();
+        for mutation in mutations {
+            serde_json::to_writer(&mut lines, mutation).map_err(|err| RepositoryError::Storage(err.to_string()))?;
+            lines.push(b'\n');
+        }
+        let file = log.file.clone();
+        web::block(move || (&*file).write_all(&lines).and_then(|()| file.sync_data()))
+            .await
+            .map_err(|err| RepositoryError::Storage(err.to_string()))?
+            .map_err(|err| RepositoryError::Storage(format!("cannot append to {}: {}", self.path.display(), err)))?;
+        log.entries += mutations.len();
+        Ok(())
+    }
+
+    // Record a change made to the state outside the repository, such as an
+    // import, and compact right away, as the log can't replay it
+    pub async fn rebase(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<(), String> {
+        self.file.lock().await.dirty = true;
+        self.compact(data, profiles).await
+    }
+
+    // Fold the log into a new snapshot and truncate it; skipped when nothing
+    // changed since the last compaction
+    #[instrument(name = "compact_wal", skip_all, fields(wal.path = %self.path.display()))]
+    pub async fn compact(
+        &self,
+        data: &web::Data<RwLock<AppState>>,
+        profiles: &web::Data<RwLock<ProfileState>>,
+    ) -> Result<(), String> {
+        let mut log = self.file.lock().await;
+        if log.entries == 0 && !log.dirty {
+            return Ok(());
+        }
+        let (contents, users) = capture_json(data, profiles)?;
+        let (file, snapshot_path) = (log.file.clone(), self.snapshot_path.clone());
+        web::block(move || write_atomically(&snapshot_path, &contents).and_then(|()| file.set_len(0)))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|result| result.map_err(|err| err.to_string()))
+            .map_err(|err| format!("WAL compaction failed: {}", err))?;
+        info!(entries = log.entries, users, "WAL compacted");
+        log.entries = 0;
+        log.dirty = false;
+        Ok(())
+    }
+}
+
+// Background task compacting the log, started in phase 3
+pub async fn run_compaction(
+    log: Arc<MutationLog>,
+    data: web::Data<RwLock<AppState>>,
+    profiles: web::Data<RwLock<ProfileState>>,
+) {
+    let mut ticks = actix_web::rt::time::interval(log.compact_every);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        if let Err(err) = log.compact(&data, &profiles).await {
+            warn!(error = %err, "Failed to compact WAL");
+        }
+    }
+}
+
+// Logs every successful mutation of the wrapped repository, avatars and
+// profiles included. A failed append is returned as a storage error; the
+// change stays in memory but won't survive a restart.
+struct LoggedUserRepository {
+    inner: Arc<dyn UserRepository>,
+    log: Arc<MutationLog>,
+}
+
+#[async_trait]
+impl UserRepository for LoggedUserRepository {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prepare(&self) -> Result<(), RepositoryError> {
+        self.inner.prepare().await
+    }
+
+    async fn get(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        self.inner.get(id).await
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<(Vec<User>, usize), RepositoryError> {
+        self.inner.list(query).await
+    }
+
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let user = self.inner.create(user).await?;
+        self.log.append(&mut log, &[Mutation::put(&user, true)]).await?;
+        Ok(user)
+    }
+
+    async fn create_many(&self, users: Vec<NewUser>) -> Result<Vec<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let users = self.inner.create_many(users).await?;
+        let mutations: Vec<_> = users.iter().map(|user| Mutation::put(user, true)).collect();
+        self.log.append(&mut log, &mutations).await?;
+        Ok(users)
+    }
+
+    async fn update(&self, id: UserId, name: String, email: String) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let updated = self.inner.update(id, name, email).await?;
+        if let Some(user) = &updated {
+            self.log.append(&mut log, &[Mutation::put(user, false)]).await?;
+        }
+        Ok(updated)
+    }
+
+    async fn delete(&self, id: UserId) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let deleted = self.inner.delete(id).await?;
+        if deleted.is_some() {
+            self.log.append(&mut log, &[Mutation::Delete { id }]).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_expired(&self, now: DateTime<Utc>) -> Result<Vec<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let deleted = self.inner.delete_expired(now).await?;
+        let mutations: Vec<_> = deleted.iter().map(|user| Mutation::Delete { id: user.id }).collect();
+        self.log.append(&mut log, &mutations).await?;
+        Ok(deleted)
+    }
+
+    // The import that replaced the users rebases the log itself
+    async fn restored(&self, removed: &[UserId], users: &[User]) {
+        self.inner.restored(removed, users).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.inner.find_by_email(email).await
+    }
+
+    async fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, RepositoryError> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn count(&self) -> Result<usize, RepositoryError> {
+        self.inner.count().await
+    }
+
+    async fn stats(&self) -> Result<UserStats, RepositoryError> {
+        self.inner.stats().await
+    }
+
+    async fn domain_counts(&self) -> Result<Arc<BTreeMap<String, usize>>, RepositoryError> {
+        self.inner.domain_counts().await
+    }
+
+    async fn find_duplicates(&self, name: &str, email: &str) -> Result<Vec<DuplicateMatch>, RepositoryError> {
+        self.inner.find_duplicates(name, email).await
+    }
+
+    async fn credentials(&self, id: UserId) -> Result<Option<Option<Credentials>>, RepositoryError> {
+        self.inner.credentials(id).await
+    }
+
+    async fn avatar(&self, id: UserId) -> Result<Option<Avatar>, RepositoryError> {
+        self.inner.avatar(id).await
+    }
+
+    async fn profile(&self, id: UserId) -> Result<Option<Profile>, RepositoryError> {
+        self.inner.profile(id).await
+    }
+
+    async fn set_status(&self, id: UserId, action: StatusAction) -> Result<(UserStatus, User), TransitionError> {
+        let mut log = self.log.file.lock().await;
+        let (from, user) = self.inner.set_status(id, action).await?;
+        self.log.append(&mut log, &[Mutation::put(&user, false)]).await?;
+        Ok((from, user))
+    }
+
+    async fn set_credentials(&self, id: UserId, credentials: Credentials) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let updated = self.inner.set_credentials(id, credentials).await?;
+        if let Some(user) = &updated {
+            self.log.append(&mut log, &[Mutation::put(user, false)]).await?;
+        }
+        Ok(updated)
+    }
+
+    async fn set_avatar(&self, id: UserId, avatar: Avatar) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let mutation = Mutation::avatar(id, &avatar);
+        let touched = self.inner.set_avatar(id, avatar).await?;
+        if let Some(user) = &touched {
+            self.log.append(&mut log, &[Mutation::put(user, false), mutation]).await?;
+        }
+        Ok(touched)
+    }
+
+    async fn set_profile(&self, id: UserId, profile: Profile) -> Result<Option<User>, RepositoryError> {
+        let mut log = self.log.file.lock().await;
+        let mutation = Mutation::Profile { id, profile: profile.clone() };
+        let touched = self.inner.set_profile(id, profile).await?;
+        if let Some(user) = &touched {
+            self.log.append(&mut log, &[Mutation::put(user, false), mutation]).await?;
+        }
+        Ok(touched)
+    }
+
+    async fn charge_quota(&self, subject: &str, day: NaiveDate, limit: u32) -> Result<Option<u32>, RepositoryError> {
+        self.inner.charge_quota(subject, day, limit).await
+    }
+
+    async fn refund_quota(&self, subject: &str, day: NaiveDate) -> Result<(), RepositoryError> {
+        self.inner.refund_quota(subject, day).await
+    }
+
+    fn has_outbox(&self) -> bool {
+        self.inner.has_outbox()
+    }
+
+    async fn outbox_pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, RepositoryError> {
+        self.inner.outbox_pending(limit).await
+    }
+
+    async fn outbox_acknowledge(&self, ids: &[Uuid]) -> Result<(), RepositoryError> {
+        self.inner.outbox_acknowledge(ids).await
+    }
+
+    async fn size(&self) -> Result<StoreSize, RepositoryError> {
+        self.inner.size().await
+    }
+}
@@ -0,0 +1,496 @@
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+use actix_web_opentelemetry::ClientExt;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::field::Empty;
+use tracing::{info, info_span, instrument, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use crate::admin::{AdminIdentity, AuditLog};
+use crate::bus::{self, EventBus};
+use crate::circuit::{CircuitBreaker, CircuitBreakers};
+use crate::error::AppError;
+use crate::get_env_or_default;
+
+// Event types a webhook can subscribe to
+pub const USER_CREATED: &str = "user.created";
+pub const USER_UPDATED: &str = "user.updated";
+pub const USER_DELETED: &str = "user.deleted";
+const EVENT_TYPES: &[&str] = &[USER_CREATED, USER_UPDATED, USER_DELETED];
+
+// The constant for an event type named in stored data, e.g. an outbox row
+pub fn event_type(name: &str) -> Option<&'static str> {
+    EVENT_TYPES.iter().find(|event_type| **event_type == name).copied()
+}
+
+const QUEUE_CAPACITY: usize = 1024;
+// How often drained() checks for outstanding work
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// A registered callback
+#[derive(Clone, Serialize)]
+pub struct Webhook {
+    id: Uuid,
+    url: String,
+    events: Vec<String>,
+    #[serde(skip)]
+    secret: String,
+    created_at: DateTime<Utc>,
+}
+
+// Registered webhooks, shared between the admin endpoints and the dispatcher
+pub struct WebhookRegistry {
+    hooks: RwLock<Vec<Webhook>>,
+}
+
+impl WebhookRegistry {
+    pub fn new() -> Self {
+        WebhookRegistry { hooks: RwLock::new(Vec::new()) }
+    }
+
+    fn subscribers(&self, event_type: &str) -> Vec<Webhook> {
+        self.hooks
+            .read()
+            .map(|hooks| {
+                hooks
+                    .iter()
+                    .filter(|hook| hook.events.iter().any(|event| event == event_type))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+// Payload POSTed to subscribers
+#[derive(Clone, Serialize)]
+pub struct WebhookEvent {
+    id: Uuid,
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    occurred_at: DateTime<Utc>,
+    data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    // The ID of the user the event is about, as the message key on event buses
+    fn key(&self) -> String {
+        match self.data.get("id") {
+            Some(serde_json::Value::String(id)) => id.clone(),
+            Some(id) => id.to_string(),
+            None => self.id.to_string(),
+        }
+    }
+}
+
+struct QueuedEvent {
+    event: WebhookEvent,
+    parent: Span,
+    // Signalled once every delivery of the event has succeeded; dropped
+    // unsignalled when one gave up
+    finished: Option<oneshot::Sender<()>>,
+    // Counts the event until the dispatcher has started its deliveries
+    _pending: Pending,
+}
+
+// One unit of outstanding work on the publisher's in-flight count, counted
+// from creation until dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        Pending(in_flight.clone())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Retry policy for a single delivery
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self::from_env_with_prefix("WEBHOOK")
+    }
+
+    // {prefix}_MAX_ATTEMPTS, {prefix}_INITIAL_BACKOFF_MS and
+    // {prefix}_TIMEOUT_SECS, so event buses can be tuned apart from webhooks
+    pub fn from_env_with_prefix(prefix: &str) -> Self {
+        let var = |name: &str, default: &str| get_env_or_default(&format!("{}_{}", prefix, name), default);
+        RetryPolicy {
+            max_attempts: var("MAX_ATTEMPTS", "5").parse().unwrap_or(5).max(1),
+            initial_backoff: Duration::from_millis(var("INITIAL_BACKOFF_MS", "500").parse().unwrap_or(500)),
+            timeout: Duration::from_secs(var("TIMEOUT_SECS", "10").parse().unwrap_or(10)),
+        }
+    }
+}
+
+// Handle used by handlers to publish user mutations. Publishing only queues
+// the event; the dispatcher fans it out to subscribers and event buses in
+// the background.
+#[derive(Clone)]
+pub struct WebhookPublisher {
+    sender: mpsc::Sender<QueuedEvent>,
+    // Events queued and deliveries not yet finished, retries included. An
+    // event is counted before it's queued and its deliveries before it's
+    // uncounted, so the count never dips to zero between the two.
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl WebhookPublisher {
+    // Create the publisher and the dispatcher future that must be spawned to drain it
+    pub fn new(
+        registry: web::Data<WebhookRegistry>,
+        policy: RetryPolicy,
+        breakers: CircuitBreakers,
+        buses: Vec<Arc<dyn EventBus>>,
+    ) -> (Self, impl std::future::Future<Output = ()>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        (
+            WebhookPublisher { sender, in_flight: in_flight.clone() },
+            run_dispatcher(registry, policy, breakers, buses, receiver, in_flight),
+        )
+    }
+
+    // Resolves once every queued event has been delivered or given up on,
+    // for the shutdown hook
+    pub async fn drained(&self) {
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    pub fn publish(&self, event_type: &'static str, data: impl Serialize) {
+        let event = WebhookEvent {
+            id: Uuid::now_v7(),
+            event_type,
+            occurred_at: Utc::now(),
+            data: serde_json::to_value(data).unwrap_or(serde_json::Value::Null),
+        };
+        let pending = Pending::new(&self.in_flight);
+        let queued = QueuedEvent { event, parent: Span::current(), finished: None, _pending: pending };
+        if let Err(err) = self.sender.try_send(queued) {
+            warn!(event_type = event_type, error = %err, "Dropping webhook event");
+        }
+    }
+
+    // Publish an event recorded earlier, e.g. in the outbox, and wait until
+    // each subscriber and event bus received it or delivery gave up. False when
+    // a delivery gave up or the dispatcher couldn't take it, so the caller
+    // should try again later.
+    pub async fn publish_and_wait(
+        &self,
+        id: Uuid,
+        event_type: &'static str,
+        occurred_at: DateTime<Utc>,
+        data: serde_json::Value,
+    ) -> bool {
+        let (finished, delivered) = oneshot::channel();
+        let event = WebhookEvent { id, event_type, occurred_at, data };
+        let queued = QueuedEvent {
+            event,
+            parent: Span::current(),
+            finished: Some(finished),
+            _pending: Pending::new(&self.in_flight),
+        };
+        if self.sender.send(queued).await.is_err() {
+            return false;
+        }
+        delivered.await.is_ok()
+    }
+}
+
+// Hex HMAC-SHA256 over "{timestamp}.{body}", so receivers can reject replays
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+async fn run_dispatcher(
+    registry: web::Data<WebhookRegistry>,
+    policy: RetryPolicy,
+    breakers: CircuitBreakers,
+    buses: Vec<Arc<dyn EventBus>>,
+    mut receiver: mpsc::Receiver<QueuedEvent>,
+    in_flight: Arc<AtomicUsize>,
+) {
+    info!("Webhook dispatcher started");
+    let client = awc::Client::builder().timeout(policy.timeout).finish();
+    while let Some(queued) = receiver.recv().await {
+        let subscribers = registry.subscribers(queued.event.event_type);
+        if subscribers.is_empty() && buses.is_empty() {
+            if let Some(finished) = queued.finished {
+                let _ = finished.send(());
+            }
+            continue;
+        }
+        let body = match serde_json::to_vec(&queued.event) {
+            Ok(body) => web::Bytes::from(body),
+            Err(err) => {
+                warn!(error = %err, "Failed to serialize webhook event");
+                // Retrying can't help, so the relay mustn't keep the entry waiting
+                if let Some(finished) = queued.finished {
+                    let _ = finished.send(());
+                }
+                continue;
+            }
+        };
+        let mut deliveries = Vec::with_capacity(subscribers.len() + buses.len());
+        // Every event goes to every bus, subscribed or not
+        for event_bus in &buses {
+            let span = info_span!(
+                parent: &queued.parent,
+                "publish_event",
+                otel.name = %format!("{} publish", event_bus.destination()),
+                otel.kind = "producer",
+                otel.status_code = Empty,
+                messaging.system = event_bus.system(),
+                messaging.destination.name = %event_bus.destination(),
+                messaging.operation = "publish",
+                messaging.message.id = %queued.event.id,
+                webhook.event = queued.event.event_type,
+            );
+            let headers = HashMap::from([
+                ("content-type".to_string(), "application/json".to_string()),
+                ("event-type".to_string(), queued.event.event_type.to_string()),
+                ("event-id".to_string(), queued.event.id.to_string()),
+            ]);
+            let delivery = bus::deliver(event_bus.clone(), queued.event.key(), body.clone(), headers).instrument(span);
+            let pending = Pending::new(&in_flight);
+            deliveries.push(actix_web::rt::spawn(async move {
+                let delivered = delivery.await;
+                drop(pending);
+                delivered
+            }));
+        }
+        // Deliveries run concurrently so one slow subscriber doesn't hold up the rest
+        for hook in subscribers {
+            let span = info_span!(
+                parent: &queued.parent,
+                "deliver_webhook",
+                webhook.id = %hook.id,
+                webhook.event = queued.event.event_type,
+                event.id = %queued.event.id,
+            );
+            // Breakers are per receiving host, so one dead receiver doesn't stall the others
+            let host = hook
+                .url
+                .parse::<actix_web::http::Uri>()
+                .ok()
+                .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+                .unwrap_or_else(|| hook.url.clone());
+            let breaker = breakers.for_dependency(&format!("webhook:{}", host));
+            let delivery = deliver(client.clone(), hook, body.clone(), policy, breaker).instrument(span);
+            let pending = Pending::new(&in_flight);
+            deliveries.push(actix_web::rt::spawn(async move {
+                let delivered = delivery.await;
+                drop(pending);
+                delivered
+            }));
+        }
+        if let Some(finished) = queued.finished {
+            actix_web::rt::spawn(async move {
+                let results = join_all(deliveries).await;
+                if results.into_iter().all(|delivered| matches!(delivered, Ok(true))) {
+                    let _ = finished.send(());
+                }
+            });
+        }
+    }
+    info!("Webhook dispatcher stopped");
+}
+
+// POST one event to one subscriber, retrying with exponential backoff on
+// network errors and non-2xx responses; false when it gave up. Attempts made
+// while the host's circuit is open are skipped without a request.
+async fn deliver(
+    client: awc::Client,
+    hook: Webhook,
+    body: web::Bytes,
+    policy: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
+) -> bool {
+    let mut backoff = policy.initial_backoff;
+    for attempt in 1..=policy.max_attempts {
+        if !breaker.allow() {
+            warn!(attempt = attempt, "Webhook host circuit is open; skipping attempt");
+            if attempt < policy.max_attempts {
+                actix_web::rt::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+            continue;
+        }
+
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(&hook.secret, timestamp, &body);
+        // The client span (and the injected traceparent) hang off the delivery span
+        let result = client
+            .post(&hook.url)
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("X-Webhook-Id", hook.id.to_string()))
+            .insert_header(("X-Webhook-Timestamp", timestamp.to_string()))
+            .insert_header(("X-Webhook-Signature", format!("sha256={}", signature)))
+            .trace_request_with_context(Span::current().context())
+            .send_body(body.clone())
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                breaker.record(true);
+                info!(attempt = attempt, status = response.status().as_u16(), "Webhook delivered");
+                return true;
+            }
+            Ok(response) => {
+                // Only server errors say the receiver is unhealthy
+                breaker.record(!response.status().is_server_error());
+                warn!(attempt = attempt, status = response.status().as_u16(), "Webhook rejected by receiver");
+            }
+            Err(err) => {
+                breaker.record(false);
+                warn!(attempt = attempt, error = %err, "Webhook delivery failed");
+            }
+        }
+
+        if attempt < policy.max_attempts {
+            actix_web::rt::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+    warn!(attempts = policy.max_attempts, url = %hook.url, "Giving up on webhook delivery");
+    false
+}
+
+#[derive(Deserialize)]
+struct CreateWebhook {
+    url: String,
+    #[serde(default)]
+    secret: Option<String>,
+    #[serde(default)]
+    events: Option<Vec<String>>,
+}
+
+// Returned once on registration; the secret is never shown again
+#[derive(Serialize)]
+struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Handler for GET /admin/webhooks
+#[get("/webhooks")]
+async fn list_webhooks(registry: web::Data<WebhookRegistry>) -> impl Responder {
+    let hooks = registry.hooks.read().map(|hooks| hooks.clone()).unwrap_or_default();
+    HttpResponse::Ok().json(hooks)
+}
+
+// Handler for POST /admin/webhooks
+#[post("/webhooks")]
+#[instrument(name = "admin_create_webhook_handler", skip_all, fields(service = "actix_example"))]
+async fn create_webhook(
+    identity: web::ReqData<AdminIdentity>,
+    body: web::Json<CreateWebhook>,
+    registry: web::Data<WebhookRegistry>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let url_ok = body
+        .url
+        .parse::<actix_web::http::Uri>()
+        .ok()
+        .is_some_and(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some());
+    if !url_ok {
+        return AppError::bad_request("invalid_url", "url must be an absolute http(s) URL")
+            .with("field", "url")
+            .error_response();
+    }
+
+    let events = body.events.unwrap_or_else(|| EVENT_TYPES.iter().map(|e| e.to_string()).collect());
+    if let Some(unknown) = events.iter().find(|event| !EVENT_TYPES.contains(&event.as_str())) {
+        return AppError::bad_request(
+            "unknown_event_type",
+            format!("unknown event type '{}', expected any of: {}", unknown, EVENT_TYPES.join(", ")),
+        )
+        .with("field", "events")
+        .error_response();
+    }
+
+    let webhook = Webhook {
+        id: Uuid::now_v7(),
+        url: body.url,
+        events,
+        secret: body.secret.filter(|secret| !secret.is_empty()).unwrap_or_else(generate_secret),
+        created_at: Utc::now(),
+    };
+    match registry.hooks.write() {
+        Ok(mut hooks) => hooks.push(webhook.clone()),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock webhook registry"),
+    }
+
+    audit.record(&identity.0, "create_webhook", format!("{} -> {}", webhook.id, webhook.url));
+    HttpResponse::Created().json(CreatedWebhook { secret: webhook.secret.clone(), webhook })
+}
+
+// Handler for DELETE /admin/webhooks/{id}
+#[delete("/webhooks/{id}")]
+#[instrument(name = "admin_delete_webhook_handler", skip_all, fields(service = "actix_example"))]
+async fn delete_webhook(
+    identity: web::ReqData<AdminIdentity>,
+    path: web::Path<Uuid>,
+    registry: web::Data<WebhookRegistry>,
+    audit: web::Data<AuditLog>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let removed = match registry.hooks.write() {
+        Ok(mut hooks) => {
+            let before = hooks.len();
+            hooks.retain(|hook| hook.id != id);
+            hooks.len() != before
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock webhook registry"),
+    };
+
+    if !removed {
+        return AppError::new(StatusCode::NOT_FOUND, "not_found", format!("Webhook {} not found", id))
+            .error_response();
+    }
+    audit.record(&identity.0, "delete_webhook", id.to_string());
+    HttpResponse::NoContent().finish()
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_webhooks)
+        .service(create_webhook)
+        .service(delete_webhook);
+}
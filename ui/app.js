@@ -0,0 +1,95 @@
+// Talks to the API relative to the page's <base>, so it works under any
+// base path. Requests ask for the response envelope, whose meta carries the
+// trace ID; problem documents carry one of their own.
+"use strict";
+
+const PAGE_SIZE = 10;
+let page = 1;
+
+function api(path) {
+  const separator = path.includes("?") ? "&" : "?";
+  return new URL(`../${path}${separator}envelope=true`, document.baseURI);
+}
+
+function showTrace(traceId, source) {
+  document.getElementById("trace-id").textContent = traceId || "none (not sampled)";
+  document.getElementById("trace-source").textContent = source;
+}
+
+function showStatus(message, isError) {
+  const status = document.getElementById("status");
+  status.textContent = message;
+  status.className = isError ? "error" : "";
+}
+
+// Fetch JSON, recording the request's trace ID. Returns the unwrapped data,
+// or throws with the problem detail.
+async function request(path, options, label) {
+  const response = await fetch(api(path), options);
+  const body = await response.json().catch(() => null);
+  if (!response.ok) {
+    showTrace(body && body.trace_id, label);
+    throw new Error((body && body.detail) || `${response.status} ${response.statusText}`);
+  }
+  showTrace(body.meta && body.meta.trace_id, label);
+  return body;
+}
+
+function cell(text) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  return td;
+}
+
+async function loadUsers() {
+  try {
+    const body = await request(`users?page=${page}&limit=${PAGE_SIZE}&sort=created_at`, {}, `GET users, page ${page}`);
+    const rows = document.getElementById("users");
+    rows.replaceChildren(
+      ...body.data.map((user) => {
+        const row = document.createElement("tr");
+        row.append(cell(user.id), cell(user.name), cell(user.email), cell(user.status), cell(user.created_at));
+        return row;
+      }),
+    );
+    const totalPages = Math.max(1, (body.meta.pagination && body.meta.pagination.total_pages) || 1);
+    document.getElementById("page").textContent = `Page ${page} of ${totalPages}`;
+    document.getElementById("prev").disabled = page <= 1;
+    document.getElementById("next").disabled = page >= totalPages;
+  } catch (err) {
+    showStatus(`Loading users failed: ${err.message}`, true);
+  }
+}
+
+async function createUser(event) {
+  event.preventDefault();
+  const form = event.target;
+  const user = { name: form.elements.name.value, email: form.elements.email.value };
+  try {
+    const body = await request(
+      "users",
+      { method: "POST", headers: { "Content-Type": "application/json" }, body: JSON.stringify(user) },
+      "POST users",
+    );
+    showStatus(`Created user ${body.data.id}`, false);
+    form.reset();
+    await loadUsers();
+  } catch (err) {
+    showStatus(`Creating the user failed: ${err.message}`, true);
+  }
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+  showTrace(document.querySelector('meta[name="trace-id"]').content, "page load");
+  document.getElementById("create-user").addEventListener("submit", createUser);
+  document.getElementById("refresh").addEventListener("click", loadUsers);
+  document.getElementById("prev").addEventListener("click", () => {
+    page -= 1;
+    loadUsers();
+  });
+  document.getElementById("next").addEventListener("click", () => {
+    page += 1;
+    loadUsers();
+  });
+  loadUsers();
+});